@@ -0,0 +1,1536 @@
+// File: cpi_virtualbox/src/bin/vboxmanage-sim.rs
+//! Stateful stand-in for `VBoxManage` used by the end-to-end tests.
+//!
+//! Registry state is kept as JSON in `$VBOX_USER_HOME/vboxmanage-sim.json`
+//! (falling back to a directory under the system temp dir), so every
+//! invocation sees the changes made by the previous ones. Only the
+//! subcommands and output shapes the extension relies on are modelled;
+//! failures are reported on stderr in VBoxManage's own format. Setting
+//! `VBOXSIM_HANG_ON=<subcommand>` makes that subcommand hang.
+//!
+//! Settings files, disk images and saved states are written as empty files
+//! and `--delete` removes them again, so callers can check what is on disk.
+//! VMs go under `$VBOX_USER_HOME/VirtualBox VMs` unless given a base folder.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+const VERSION: &str = "7.0.14_SIMr161095";
+
+#[derive(Default, Serialize, Deserialize)]
+struct Registry {
+    next_id: u64,
+    vms: Vec<Vm>,
+    media: Vec<Medium>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Vm {
+    name: String,
+    uuid: String,
+    ostype: String,
+    groups: String,
+    cfg_file: String,
+    memory: u64,
+    cpus: u64,
+    state: String,
+    nics: BTreeMap<u32, String>,
+    /// NAT port forwarding rules per NIC, as `name,proto,hostip,hostport,guestip,guestport`
+    #[serde(default)]
+    forwards: BTreeMap<u32, Vec<String>>,
+    settings: BTreeMap<String, String>,
+    controllers: Vec<Controller>,
+    attachments: Vec<Attachment>,
+    snapshots: Vec<Snapshot>,
+    extradata: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Controller {
+    name: String,
+    bus: String,
+    chipset: String,
+    port_count: u32,
+    host_io_cache: bool,
+    bootable: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Attachment {
+    controller: String,
+    port: u32,
+    device: u32,
+    kind: String,
+    medium: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Snapshot {
+    name: String,
+    uuid: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Medium {
+    uuid: String,
+    location: String,
+    kind: String,
+    format: String,
+    size_mb: u64,
+    /// Base image of a differencing image
+    #[serde(default)]
+    parent: Option<String>,
+}
+
+/// What `export` writes in place of a real OVF/OVA: enough to import the
+/// VM again
+#[derive(Serialize, Deserialize)]
+struct Appliance {
+    name: String,
+    ostype: String,
+    memory: u64,
+    cpus: u64,
+    nics: BTreeMap<u32, String>,
+    disks: Vec<ApplianceDisk>,
+    ovf_version: String,
+    manifest: bool,
+    product: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ApplianceDisk {
+    file: String,
+    size_mb: u64,
+}
+
+/// A VBoxManage-style failure: the message plus the result code line
+struct SimError {
+    message: String,
+    code: &'static str,
+    hex: &'static str,
+    component: &'static str,
+    interface: &'static str,
+}
+
+type SimResult = Result<String, SimError>;
+
+fn error(message: impl Into<String>, code: &'static str, component: &'static str, interface: &'static str) -> SimError {
+    let hex = match code {
+        "VBOX_E_OBJECT_NOT_FOUND" => "0x80bb0001",
+        "VBOX_E_INVALID_VM_STATE" => "0x80bb0002",
+        "VBOX_E_FILE_ERROR" => "0x80bb0004",
+        "VBOX_E_INVALID_OBJECT_STATE" => "0x80bb0007",
+        "VBOX_E_OBJECT_IN_USE" => "0x80bb000c",
+        "E_INVALIDARG" => "0x80070057",
+        _ => "0x80004005",
+    };
+    SimError {
+        message: message.into(),
+        code,
+        hex,
+        component,
+        interface,
+    }
+}
+
+fn not_found_vm(name: &str) -> SimError {
+    error(
+        format!("Could not find a registered machine named '{}'", name),
+        "VBOX_E_OBJECT_NOT_FOUND",
+        "VirtualBoxWrap",
+        "IVirtualBox",
+    )
+}
+
+fn locked(name: &str) -> SimError {
+    error(
+        format!("The machine '{}' is already locked for a session (or being unlocked)", name),
+        "VBOX_E_INVALID_OBJECT_STATE",
+        "MachineWrap",
+        "IMachine",
+    )
+}
+
+fn not_running(name: &str) -> SimError {
+    error(
+        format!("Machine '{}' is not currently running", name),
+        "VBOX_E_INVALID_VM_STATE",
+        "ConsoleWrap",
+        "IConsole",
+    )
+}
+
+fn syntax(message: impl Into<String>) -> SimError {
+    error(message, "E_INVALIDARG", "VirtualBoxWrap", "IVirtualBox")
+}
+
+fn state_path() -> PathBuf {
+    let home = std::env::var_os("VBOX_USER_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| std::env::temp_dir().join("vboxmanage-sim"));
+    home.join("vboxmanage-sim.json")
+}
+
+fn default_base_folder() -> String {
+    let home = state_path().parent().map(PathBuf::from).unwrap_or_default();
+    home.join("VirtualBox VMs").to_string_lossy().into_owned()
+}
+
+/// Create an empty file the way VirtualBox would write the real one; the
+/// simulator carries on if it can't
+fn touch(path: &str) {
+    let path = std::path::Path::new(path);
+    if let Some(parent) = path.parent() {
+        let _ = std::fs::create_dir_all(parent);
+    }
+    if !path.exists() {
+        let _ = std::fs::write(path, b"");
+    }
+}
+
+fn remove(path: &str) {
+    let _ = std::fs::remove_file(path);
+}
+
+fn vm_folder(vm: &Vm) -> &str {
+    vm.cfg_file.rsplit_once('/').map_or("", |(folder, _)| folder)
+}
+
+fn saved_state_file(vm: &Vm) -> String {
+    format!("{}/Snapshots/{{{}}}.sav", vm_folder(vm), vm.uuid)
+}
+
+fn load(path: &PathBuf) -> Registry {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+fn lock(path: &std::path::Path) -> std::io::Result<std::fs::File> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let file = std::fs::File::create(path.with_extension("lock"))?;
+    file.lock()?;
+    Ok(file)
+}
+
+fn save(path: &PathBuf, registry: &Registry) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_json::to_string_pretty(registry).unwrap())
+}
+
+/// Value following a `--flag` in the argument list
+fn opt<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|a| a == flag)
+        .and_then(|i| args.get(i + 1))
+        .map(|s| s.as_str())
+}
+
+fn required<'a>(args: &'a [String], flag: &str) -> Result<&'a str, SimError> {
+    opt(args, flag).ok_or_else(|| syntax(format!("Missing {} parameter", flag)))
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str, flag: &str) -> Result<T, SimError> {
+    value
+        .parse()
+        .map_err(|_| syntax(format!("Invalid value '{}' for {}", value, flag)))
+}
+
+/// OS types the simulator knows: id, description and family
+const OS_TYPES: &[(&str, &str, &str)] = &[
+    ("Ubuntu_64", "Ubuntu (64-bit)", "Linux"),
+    ("Debian_64", "Debian (64-bit)", "Linux"),
+    ("Windows11_64", "Windows 11 (64-bit)", "Windows"),
+    ("Other_64", "Other/Unknown (64-bit)", "Other"),
+];
+
+fn os_description(ostype: &str) -> String {
+    OS_TYPES
+        .iter()
+        .find(|(id, _, _)| *id == ostype)
+        .map_or_else(|| ostype.to_string(), |(_, description, _)| description.to_string())
+}
+
+/// Extradata key that makes the simulated guest ignore the ACPI power button
+const IGNORE_ACPI_KEY: &str = "VBOXSIM/IgnoreAcpi";
+
+/// Extradata key set by `unattended install --install-additions`; running
+/// guests with it report Guest Additions run level 2
+const ADDITIONS_KEY: &str = "VBOXSIM/GuestAdditions";
+
+fn state_description(state: &str) -> &str {
+    match state {
+        "poweroff" => "powered off",
+        "gurumeditation" => "guru meditation",
+        other => other,
+    }
+}
+
+fn devices_per_port(bus: &str) -> u32 {
+    if bus == "ide" { 2 } else { 1 }
+}
+
+fn is_locked(vm: &Vm) -> bool {
+    matches!(vm.state.as_str(), "running" | "paused")
+}
+
+impl Registry {
+    fn new_uuid(&mut self) -> String {
+        self.next_id += 1;
+        format!("5153a100-0000-4000-8000-{:012x}", self.next_id)
+    }
+
+    fn vm_index(&self, key: &str) -> Result<usize, SimError> {
+        self.vms
+            .iter()
+            .position(|vm| vm.name == key || vm.uuid == key)
+            .ok_or_else(|| not_found_vm(key))
+    }
+
+    fn medium_index(&self, key: &str) -> Option<usize> {
+        self.media
+            .iter()
+            .position(|m| m.location == key || m.uuid == key)
+    }
+
+    fn medium_users(&self, uuid: &str) -> Vec<&Vm> {
+        self.vms
+            .iter()
+            .filter(|vm| vm.attachments.iter().any(|a| a.medium.as_deref() == Some(uuid)))
+            .collect()
+    }
+}
+
+fn run(registry: &mut Registry, args: &[String]) -> SimResult {
+    let command = args.first().map(|s| s.as_str()).unwrap_or("");
+    let rest = args.get(1..).unwrap_or_default();
+
+    match command {
+        "--version" | "-v" => Ok(format!("{}\n", VERSION)),
+        "list" => list(registry, rest),
+        "createvm" => createvm(registry, rest),
+        "clonevm" => clonevm(registry, rest),
+        "import" => import(registry, rest),
+        "export" => export(registry, rest),
+        "unattended" => unattended(registry, rest),
+        "modifyvm" => modifyvm(registry, rest),
+        "showvminfo" => showvminfo(registry, rest),
+        "unregistervm" => unregistervm(registry, rest),
+        "startvm" => startvm(registry, rest),
+        "controlvm" => controlvm(registry, rest),
+        "discardstate" => discardstate(registry, rest),
+        "setextradata" => setextradata(registry, rest),
+        "getextradata" => getextradata(registry, rest),
+        "createmedium" => createmedium(registry, rest),
+        "showmediuminfo" => showmediuminfo(registry, rest),
+        "closemedium" => closemedium(registry, rest),
+        "clonemedium" => clonemedium(registry, rest),
+        "storagectl" => storagectl(registry, rest),
+        "storageattach" => storageattach(registry, rest),
+        "snapshot" => snapshot(registry, rest),
+        other => Err(syntax(format!("Unknown command '{}'", other))),
+    }
+}
+
+fn list(registry: &mut Registry, args: &[String]) -> SimResult {
+    let mut out = String::new();
+    let long = matches!(args.first().map(|s| s.as_str()), Some("-l" | "--long"));
+    let args = if long { &args[1..] } else { args };
+    match args.first().map(|s| s.as_str()) {
+        Some("vms") if long => {
+            // Human-readable `showvminfo` blocks, as real VBoxManage prints them
+            for vm in &registry.vms {
+                let field = |label: &str, value: &str| format!("{:<29}{}\n", format!("{}:", label), value);
+                out.push_str(&field("Name", &vm.name));
+                out.push_str(&field("Encryption", "disabled"));
+                out.push_str(&field("Groups", &vm.groups));
+                out.push_str(&field("Guest OS", &os_description(&vm.ostype)));
+                out.push_str(&field("UUID", &vm.uuid));
+                out.push_str(&field("Config file", &vm.cfg_file));
+                out.push_str(&field("Memory size", &format!("{}MB", vm.memory)));
+                out.push_str(&field("Number of CPUs", &vm.cpus.to_string()));
+                out.push_str(&field("State", &format!("{} (since 2024-01-01T10:00:00.000000000)", state_description(&vm.state))));
+                for (name, uuid) in vm.snapshots.iter().map(|s| (&s.name, &s.uuid)) {
+                    out.push_str(&format!("   Name: {} (UUID: {})\n", name, uuid));
+                }
+                out.push('\n');
+            }
+        }
+        Some("vms") => {
+            for vm in &registry.vms {
+                out.push_str(&format!("\"{}\" {{{}}}\n", vm.name, vm.uuid));
+            }
+        }
+        Some("ostypes") => {
+            for (id, description, family) in OS_TYPES {
+                out.push_str(&format!(
+                    "ID:          {}\nDescription: {}\nFamily ID:   {}\nFamily Desc: {}\n64 bit:      true\n\n",
+                    id, description, family, family
+                ));
+            }
+        }
+        Some("runningvms") => {
+            for vm in registry.vms.iter().filter(|vm| is_locked(vm)) {
+                out.push_str(&format!("\"{}\" {{{}}}\n", vm.name, vm.uuid));
+            }
+        }
+        Some("hdds") => {
+            let blocks: Vec<String> = registry
+                .media
+                .iter()
+                .filter(|m| m.kind == "hdd")
+                .map(|m| {
+                    format!(
+                        "UUID:           {}\nParent UUID:    {}\nState:          created\nType:           {}\nLocation:       {}\nStorage format: {}\nCapacity:       {} MBytes\nEncryption:     disabled\n",
+                        m.uuid,
+                        m.parent.as_deref().unwrap_or("base"),
+                        if m.parent.is_some() { "normal (differencing)" } else { "normal (base)" },
+                        m.location,
+                        m.format,
+                        m.size_mb
+                    )
+                })
+                .collect();
+            out = blocks.join("\n");
+        }
+        Some(kind @ ("dvds" | "floppies")) => {
+            let kind = if kind == "dvds" { "dvd" } else { "floppy" };
+            let blocks: Vec<String> = registry
+                .media
+                .iter()
+                .filter(|m| m.kind == kind)
+                .map(|m| format!("UUID:           {}\nState:          created\nType:           readonly\nLocation:       {}\nStorage format: {}\nCapacity:       {} MBytes\nEncryption:     disabled\n", m.uuid, m.location, m.format, m.size_mb))
+                .collect();
+            out = blocks.join("\n");
+        }
+        other => return Err(syntax(format!("Unknown list type '{}'", other.unwrap_or("")))),
+    }
+    Ok(out)
+}
+
+fn createvm(registry: &mut Registry, args: &[String]) -> SimResult {
+    let name = required(args, "--name")?.to_string();
+    let ostype = opt(args, "--ostype").unwrap_or("Other").to_string();
+    let groups = opt(args, "--groups").unwrap_or("/").to_string();
+    let base = opt(args, "--basefolder")
+        .map(|s| s.to_string())
+        .unwrap_or_else(default_base_folder);
+    let cfg_file = format!("{}/{}/{}.vbox", base, name, name);
+
+    if registry.vms.iter().any(|vm| vm.name == name) {
+        return Err(error(
+            format!("Machine settings file '{}' already exists", cfg_file),
+            "VBOX_E_FILE_ERROR",
+            "MachineWrap",
+            "IMachine",
+        ));
+    }
+
+    let uuid = registry.new_uuid();
+    let mut settings = BTreeMap::new();
+    settings.insert("firmware".to_string(), "BIOS".to_string());
+    settings.insert("graphicscontroller".to_string(), "vboxvga".to_string());
+    settings.insert("vram".to_string(), "8".to_string());
+
+    let out = format!(
+        "Virtual machine '{}' is created{}.\nUUID: {}\nSettings file: '{}'\n",
+        name,
+        if args.iter().any(|a| a == "--register") { " and registered" } else { "" },
+        uuid,
+        cfg_file
+    );
+
+    touch(&cfg_file);
+    registry.vms.push(Vm {
+        name,
+        uuid,
+        ostype,
+        groups,
+        cfg_file,
+        memory: 128,
+        cpus: 1,
+        state: "poweroff".to_string(),
+        nics: BTreeMap::new(),
+        forwards: BTreeMap::new(),
+        settings,
+        controllers: Vec::new(),
+        attachments: Vec::new(),
+        snapshots: Vec::new(),
+        extradata: BTreeMap::new(),
+    });
+    Ok(out)
+}
+
+fn clonevm(registry: &mut Registry, args: &[String]) -> SimResult {
+    let key = args.first().ok_or_else(|| syntax("Missing VM name"))?;
+    let source = registry.vms[registry.vm_index(key)?].clone();
+    let name = opt(args, "--name").map(String::from).unwrap_or_else(|| format!("{} Clone", source.name));
+    let options = opt(args, "--options").unwrap_or_default().to_ascii_lowercase();
+    let linked = options.split(',').any(|o| o == "link");
+    let base = opt(args, "--basefolder")
+        .map(|s| s.to_string())
+        .unwrap_or_else(default_base_folder);
+    let folder = format!("{}/{}", base, name);
+    let cfg_file = format!("{}/{}.vbox", folder, name);
+
+    if let Some(snapshot) = opt(args, "--snapshot")
+        && !source.snapshots.iter().any(|s| s.name == snapshot || s.uuid == snapshot)
+    {
+        return Err(error(
+            format!("Could not find a snapshot named '{}'", snapshot),
+            "VBOX_E_OBJECT_NOT_FOUND",
+            "MachineWrap",
+            "IMachine",
+        ));
+    }
+    if linked && opt(args, "--snapshot").is_none() {
+        return Err(error(
+            "Linked clone can only be created from a snapshot",
+            "E_INVALIDARG",
+            "MachineWrap",
+            "IMachine",
+        ));
+    }
+    if registry.vms.iter().any(|vm| vm.cfg_file == cfg_file) {
+        return Err(error(
+            format!("Machine settings file '{}' already exists", cfg_file),
+            "VBOX_E_FILE_ERROR",
+            "MachineWrap",
+            "IMachine",
+        ));
+    }
+    let uuid = match opt(args, "--uuid") {
+        Some(uuid) if registry.vms.iter().any(|vm| vm.uuid == uuid) => {
+            return Err(error(
+                format!("A machine with UUID {{{}}} is already registered", uuid),
+                "VBOX_E_OBJECT_IN_USE",
+                "VirtualBoxWrap",
+                "IVirtualBox",
+            ));
+        }
+        Some(uuid) => uuid.to_string(),
+        None => registry.new_uuid(),
+    };
+
+    // Full clones copy every disk; linked clones get differencing disks
+    let mut attachments = Vec::new();
+    for attachment in &source.attachments {
+        let mut attachment = attachment.clone();
+        if let Some(medium) = attachment.medium.as_ref().and_then(|u| registry.media.iter().find(|m| &m.uuid == u)).cloned()
+            && medium.kind == "hdd"
+        {
+            let file = medium.location.rsplit('/').next().unwrap_or_default().to_string();
+            let mut copy = medium.clone();
+            copy.uuid = registry.new_uuid();
+            copy.parent = linked.then_some(medium.uuid);
+            copy.location = if linked { format!("{}/Snapshots/{{{}}}.vdi", folder, copy.uuid) } else { format!("{}/{}", folder, file) };
+            attachment.medium = Some(copy.uuid.clone());
+            touch(&copy.location);
+            registry.media.push(copy);
+        }
+        attachments.push(attachment);
+    }
+
+    let mut groups = source.groups.clone();
+    if let Some(g) = opt(args, "--groups") {
+        groups = g.to_string();
+    }
+    registry.vms.push(Vm {
+        name: name.clone(),
+        uuid,
+        groups,
+        cfg_file,
+        state: "poweroff".to_string(),
+        attachments,
+        snapshots: Vec::new(),
+        ..source
+    });
+    touch(&format!("{}/{}.vbox", folder, name));
+    if !args.iter().any(|a| a == "--register") {
+        registry.vms.pop();
+    }
+    Ok(format!(
+        "0%...10%...20%...30%...40%...50%...60%...70%...80%...90%...100%\nMachine has been successfully cloned as \"{}\"\n",
+        name
+    ))
+}
+
+fn export(registry: &mut Registry, args: &[String]) -> SimResult {
+    let key = args.first().ok_or_else(|| syntax("Missing VM name"))?;
+    let output = required(args, "--output")?;
+    let vm = &registry.vms[registry.vm_index(key)?];
+    if !output.ends_with(".ova") && !output.ends_with(".ovf") {
+        return Err(syntax("Appliance file must have .ovf or .ova extension"));
+    }
+    if std::path::Path::new(output).exists() {
+        return Err(error(
+            format!("Appliance file '{}' already exists", output),
+            "VBOX_E_FILE_ERROR",
+            "ApplianceWrap",
+            "IAppliance",
+        ));
+    }
+
+    let disks = vm
+        .attachments
+        .iter()
+        .filter_map(|a| registry.media.iter().find(|m| Some(&m.uuid) == a.medium.as_ref() && m.kind == "hdd"))
+        .map(|m| ApplianceDisk {
+            file: m.location.rsplit('/').next().unwrap_or_default().replace(".vdi", ".vmdk"),
+            size_mb: m.size_mb,
+        })
+        .collect();
+    let product = ["--product", "--producturl", "--vendor", "--vendorurl", "--version", "--description"]
+        .iter()
+        .filter_map(|flag| opt(args, flag).map(|v| (flag.trim_start_matches('-').to_string(), v.to_string())))
+        .collect();
+    let appliance = Appliance {
+        name: vm.name.clone(),
+        ostype: vm.ostype.clone(),
+        memory: vm.memory,
+        cpus: vm.cpus,
+        nics: vm.nics.clone(),
+        disks,
+        ovf_version: if args.iter().any(|a| a == "--ovf20") { "2.0" } else { "1.0" }.to_string(),
+        manifest: opt(args, "--options").is_some_and(|o| o.split(',').any(|o| o == "manifest")),
+        product,
+    };
+    std::fs::write(output, serde_json::to_string_pretty(&appliance).unwrap())
+        .map_err(|e| error(format!("Could not create '{}': {}", output, e), "VBOX_E_FILE_ERROR", "ApplianceWrap", "IAppliance"))?;
+    Ok("0%...10%...20%...30%...40%...50%...60%...70%...80%...90%...100%\nSuccessfully exported 1 machine(s).\n".to_string())
+}
+
+// Numbered description entries as `import --dry-run` lists them, with the
+// index of the disk each hard disk entry stands for
+fn appliance_units(appliance: &Appliance, base: &str) -> Vec<(String, Option<usize>)> {
+    let mut units = vec![
+        (format!("Suggested OS type: \"{}\"", appliance.ostype), None),
+        (format!("Suggested VM name \"{}\"", appliance.name), None),
+    ];
+    for (key, value) in &appliance.product {
+        let label = match key.as_str() {
+            "product" => "Product",
+            "producturl" => "Product URL",
+            "vendor" => "Vendor",
+            "vendorurl" => "Vendor URL",
+            "version" => "Version",
+            _ => "Description",
+        };
+        units.push((format!("{} (ignored): {}", label, value), None));
+    }
+    units.push((format!("Number of CPUs: {}", appliance.cpus), None));
+    units.push((format!("Guest memory: {} MB", appliance.memory), None));
+    for nic in appliance.nics.values().filter(|n| n.as_str() != "none") {
+        units.push((format!("Network adapter: orig {}, config 3, extra type={}", nic, nic), None));
+    }
+    let controller = units.len();
+    units.push(("SATA controller, type AHCI".to_string(), None));
+    for (i, disk) in appliance.disks.iter().enumerate() {
+        units.push((
+            format!(
+                "Hard disk image: source image={}, target path={}/{}/{}, controller={};channel={}",
+                disk.file, base, appliance.name, disk.file, controller, i
+            ),
+            Some(i),
+        ));
+    }
+    units
+}
+
+fn import(registry: &mut Registry, args: &[String]) -> SimResult {
+    let path = args.first().ok_or_else(|| syntax("Missing appliance file"))?;
+    let appliance: Appliance = std::fs::read_to_string(path)
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .ok_or_else(|| {
+            error(
+                format!("Appliance read failed\nCould not open the file '{}' (VERR_FILE_NOT_FOUND)", path),
+                "VBOX_E_FILE_ERROR",
+                "ApplianceWrap",
+                "IAppliance",
+            )
+        })?;
+    let base = &default_base_folder();
+    let units = appliance_units(&appliance, base);
+
+    if args.iter().any(|a| a == "--dry-run" || a == "-n") {
+        let mut out = format!(
+            "0%...10%...20%...30%...40%...50%...60%...70%...80%...90%...100%\nInterpreting {}...\nOK.\nVirtual system 0:\n",
+            path
+        );
+        for (unit, (text, disk)) in units.iter().enumerate() {
+            out.push_str(&format!("{:2}: {}\n", unit, text));
+            if disk.is_some() {
+                out.push_str(&format!(
+                    "    (change target path with \"--vsys 0 --unit {} --disk path\";\n    disable with \"--vsys 0 --unit {} --ignore\")\n",
+                    unit, unit
+                ));
+            }
+        }
+        return Ok(out);
+    }
+
+    let name = opt(args, "--vmname").unwrap_or(&appliance.name).to_string();
+    if registry.vms.iter().any(|vm| vm.name == name) {
+        return Err(error(
+            format!("Machine settings file '{}/{}/{}.vbox' already exists", base, name, name),
+            "VBOX_E_FILE_ERROR",
+            "MachineWrap",
+            "IMachine",
+        ));
+    }
+    // --unit <n> --disk <path> pairs retarget hard disks
+    let mut targets = BTreeMap::new();
+    for pair in args.windows(4) {
+        if pair[0] == "--unit" && pair[2] == "--disk" {
+            let unit: usize = parse_number(&pair[1], "--unit")?;
+            let disk = units
+                .get(unit)
+                .and_then(|(_, disk)| *disk)
+                .ok_or_else(|| error(format!("Unit {} is not a hard disk", unit), "E_INVALIDARG", "ApplianceWrap", "IAppliance"))?;
+            targets.insert(disk, pair[3].clone());
+        }
+    }
+
+    let mut attachments = Vec::new();
+    for (i, disk) in appliance.disks.iter().enumerate() {
+        let location = targets
+            .remove(&i)
+            .unwrap_or_else(|| format!("{}/{}/{}", base, name, disk.file));
+        touch(&location);
+        let uuid = registry.new_uuid();
+        registry.media.push(Medium {
+            uuid: uuid.clone(),
+            location,
+            kind: "hdd".to_string(),
+            format: "VMDK".to_string(),
+            size_mb: disk.size_mb,
+            parent: None,
+        });
+        attachments.push(Attachment {
+            controller: "SATA".to_string(),
+            port: i as u32,
+            device: 0,
+            kind: "hdd".to_string(),
+            medium: Some(uuid),
+        });
+    }
+    let uuid = registry.new_uuid();
+    let cfg_file = format!("{}/{}/{}.vbox", base, name, name);
+    touch(&cfg_file);
+    registry.vms.push(Vm {
+        cfg_file,
+        name,
+        uuid,
+        ostype: appliance.ostype,
+        groups: "/".to_string(),
+        memory: opt(args, "--memory").map(|m| parse_number(m, "--memory")).transpose()?.unwrap_or(appliance.memory),
+        cpus: opt(args, "--cpus").map(|c| parse_number(c, "--cpus")).transpose()?.unwrap_or(appliance.cpus),
+        state: "poweroff".to_string(),
+        nics: appliance.nics,
+        forwards: BTreeMap::new(),
+        settings: BTreeMap::new(),
+        controllers: vec![Controller {
+            name: "SATA".to_string(),
+            bus: "sata".to_string(),
+            chipset: "IntelAhci".to_string(),
+            port_count: appliance.disks.len().max(1) as u32,
+            host_io_cache: false,
+            bootable: true,
+        }],
+        attachments,
+        snapshots: Vec::new(),
+        extradata: BTreeMap::new(),
+    });
+    Ok("0%...10%...20%...30%...40%...50%...60%...70%...80%...90%...100%\nSuccessfully imported the appliance.\n".to_string())
+}
+
+// The OS type and version an ISO's file name suggests, e.g.
+// `ubuntu-22.04.3-live-server-amd64.iso`
+fn detect_iso(iso: &str) -> Option<(&'static str, String)> {
+    let file = iso.rsplit('/').next().unwrap_or(iso).to_ascii_lowercase();
+    let os_type = [("ubuntu", "Ubuntu_64"), ("debian", "Debian_64"), ("win11", "Windows11_64")]
+        .iter()
+        .find(|(marker, _)| file.contains(marker))
+        .map(|(_, os_type)| *os_type)?;
+    let version = file
+        .split(['-', '_'])
+        .find(|part| part.starts_with(|c: char| c.is_ascii_digit()))
+        .unwrap_or_default()
+        .to_string();
+    Some((os_type, version))
+}
+
+fn unattended(registry: &mut Registry, args: &[String]) -> SimResult {
+    let action = args.first().map(|s| s.as_str()).unwrap_or("");
+    let iso = required(args, "--iso")?;
+    if !std::path::Path::new(iso).is_file() {
+        return Err(error(
+            format!("Failed to open '{}' (VERR_FILE_NOT_FOUND)", iso),
+            "VBOX_E_FILE_ERROR",
+            "Unattended",
+            "IUnattended",
+        ));
+    }
+    let detected = detect_iso(iso);
+
+    match action {
+        "detect" => {
+            let supported = detected.is_some();
+            let (os_type, version) = detected.unwrap_or(("Other", String::new()));
+            Ok(format!(
+                "OSTypeId=\"{}\"\nOSVersion=\"{}\"\nOSFlavor=\"\"\nOSLanguages=\"en-US\"\nOSHints=\"\"\nIsInstallSupported=\"{}\"\n",
+                os_type,
+                version, supported
+            ))
+        }
+        "install" => {
+            let key = args.get(1).ok_or_else(|| syntax("Missing VM name"))?;
+            required(args, "--user")?;
+            if opt(args, "--password").is_none() {
+                let file = required(args, "--password-file")?;
+                if std::fs::read_to_string(file).map_or(true, |password| password.is_empty()) {
+                    return Err(error(
+                        format!("Failed to read the password from '{}' (VERR_FILE_NOT_FOUND)", file),
+                        "VBOX_E_FILE_ERROR",
+                        "Unattended",
+                        "IUnattended",
+                    ));
+                }
+            }
+            let (os_type, _) = detected.ok_or_else(|| {
+                error(
+                    format!("Unattended installation of the OS on '{}' is not supported", iso),
+                    "VBOX_E_NOT_SUPPORTED",
+                    "Unattended",
+                    "IUnattended",
+                )
+            })?;
+            let index = registry.vm_index(key)?;
+            if is_locked(&registry.vms[index]) {
+                return Err(locked(&registry.vms[index].name));
+            }
+
+            // The ISO goes into a DVD drive on an IDE controller, added if missing
+            let medium = match registry.medium_index(iso) {
+                Some(i) => registry.media[i].uuid.clone(),
+                None => {
+                    let uuid = registry.new_uuid();
+                    registry.media.push(Medium {
+                        uuid: uuid.clone(),
+                        location: iso.to_string(),
+                        kind: "dvd".to_string(),
+                        format: "RAW".to_string(),
+                        size_mb: 0,
+                        parent: None,
+                    });
+                    uuid
+                }
+            };
+            let vm = &mut registry.vms[index];
+            if !vm.controllers.iter().any(|c| c.name == "IDE") {
+                vm.controllers.push(Controller {
+                    name: "IDE".to_string(),
+                    bus: "ide".to_string(),
+                    chipset: "PIIX4".to_string(),
+                    port_count: 2,
+                    host_io_cache: true,
+                    bootable: true,
+                });
+            }
+            vm.attachments.retain(|a| !(a.controller == "IDE" && a.port == 1 && a.device == 0));
+            vm.attachments.push(Attachment {
+                controller: "IDE".to_string(),
+                port: 1,
+                device: 0,
+                kind: "dvddrive".to_string(),
+                medium: Some(medium),
+            });
+            vm.ostype = os_type.to_string();
+            vm.settings.insert("boot1".to_string(), "dvd".to_string());
+            vm.settings.insert("boot2".to_string(), "disk".to_string());
+            if args.iter().any(|a| a == "--install-additions") {
+                vm.extradata.insert(ADDITIONS_KEY.to_string(), "installed".to_string());
+            }
+
+            let mut out = format!("VM name: {}\nOS type: {}\nUnattended installation prepared.\n", vm.name, os_type);
+            if args.iter().any(|a| a.starts_with("--start-vm")) {
+                vm.state = "running".to_string();
+                out.push_str(&format!("VM \"{}\" has been successfully started.\n", vm.name));
+            }
+            Ok(out)
+        }
+        other => Err(syntax(format!("Unknown unattended action '{}'", other))),
+    }
+}
+
+fn modifyvm(registry: &mut Registry, args: &[String]) -> SimResult {
+    let key = args.first().ok_or_else(|| syntax("Missing VM name"))?;
+    let index = registry.vm_index(key)?;
+    let vm = &mut registry.vms[index];
+    if is_locked(vm) {
+        return Err(locked(&vm.name));
+    }
+
+    let mut i = 1;
+    while i < args.len() {
+        let flag = args[i].trim_start_matches('-').to_string();
+        let value = args
+            .get(i + 1)
+            .ok_or_else(|| syntax(format!("Missing argument to '{}'", args[i])))?;
+        match flag.as_str() {
+            "memory" => vm.memory = parse_number(value, "--memory")?,
+            "cpus" => vm.cpus = parse_number(value, "--cpus")?,
+            "ostype" => vm.ostype = value.clone(),
+            "name" => vm.name = value.clone(),
+            "groups" => vm.groups = value.clone(),
+            "firmware" => {
+                vm.settings.insert(flag, value.to_ascii_uppercase());
+            }
+            // Reported under different keys than the flags that set them
+            "paravirt-provider" => {
+                vm.settings.insert("paravirtprovider".to_string(), value.clone());
+            }
+            "rtc-use-utc" => {
+                vm.settings.insert("rtcuseutc".to_string(), value.clone());
+            }
+            "audio-enabled" => {
+                let driver = if value == "off" { "none" } else { "default" };
+                vm.settings.insert("audio".to_string(), driver.to_string());
+            }
+            f if f.starts_with("natpf") && f[5..].parse::<u32>().is_ok() => {
+                let rules = vm.forwards.entry(f[5..].parse().unwrap()).or_default();
+                if value == "delete" {
+                    let name = args
+                        .get(i + 2)
+                        .ok_or_else(|| syntax(format!("Missing rule name to '{}'", args[i])))?;
+                    let before = rules.len();
+                    rules.retain(|r| r.split(',').next() != Some(name.as_str()));
+                    if rules.len() == before {
+                        return Err(error(
+                            format!("A NAT rule for this name '{}' does not exist", name),
+                            "E_INVALIDARG",
+                            "NATEngineWrap",
+                            "INATEngine",
+                        ));
+                    }
+                    i += 3;
+                    continue;
+                }
+                let name = value.split(',').next().unwrap_or_default();
+                if value.split(',').count() != 6 {
+                    return Err(syntax(format!("Invalid NAT rule '{}'", value)));
+                }
+                if rules.iter().any(|r| r.split(',').next() == Some(name)) {
+                    return Err(error(
+                        format!("A NAT rule of this name '{}' already exists", name),
+                        "E_INVALIDARG",
+                        "NATEngineWrap",
+                        "INATEngine",
+                    ));
+                }
+                rules.push(value.clone());
+            }
+            f if f.starts_with("nic") && f[3..].parse::<u32>().is_ok() => {
+                vm.nics.insert(f[3..].parse().unwrap(), value.clone());
+            }
+            _ => {
+                vm.settings.insert(flag, value.clone());
+            }
+        }
+        i += 2;
+    }
+    Ok(String::new())
+}
+
+fn showvminfo(registry: &mut Registry, args: &[String]) -> SimResult {
+    let key = args.first().ok_or_else(|| syntax("Missing VM name"))?;
+    let vm = &registry.vms[registry.vm_index(key)?];
+    let mut out = String::new();
+    let mut line = |k: &str, v: &str| out.push_str(&format!("{}=\"{}\"\n", k, escape(v)));
+
+    line("name", &vm.name);
+    line("groups", &vm.groups);
+    line("ostype", &os_description(&vm.ostype));
+    line("UUID", &vm.uuid);
+    line("CfgFile", &vm.cfg_file);
+    line("SnapFldr", &format!("{}/Snapshots", vm_folder(vm)));
+    line("LogFldr", &format!("{}/Logs", vm_folder(vm)));
+    if vm.state == "saved" {
+        line("VMStateFile", &saved_state_file(vm));
+    }
+    out.push_str(&format!("memory={}\n", vm.memory));
+    out.push_str(&format!("cpus={}\n", vm.cpus));
+    for (k, v) in &vm.settings {
+        out.push_str(&format!("{}=\"{}\"\n", k, escape(v)));
+    }
+    out.push_str(&format!("VMState=\"{}\"\n", vm.state));
+
+    for (i, c) in vm.controllers.iter().enumerate() {
+        out.push_str(&format!("storagecontrollername{}=\"{}\"\n", i, c.name));
+        out.push_str(&format!("storagecontrollertype{}=\"{}\"\n", i, c.chipset));
+        out.push_str(&format!("storagecontrollerinstance{}=\"0\"\n", i));
+        out.push_str(&format!("storagecontrollermaxportcount{}=\"{}\"\n", i, c.port_count));
+        out.push_str(&format!("storagecontrollerportcount{}=\"{}\"\n", i, c.port_count));
+        out.push_str(&format!("storagecontrollerbootable{}=\"{}\"\n", i, if c.bootable { "on" } else { "off" }));
+    }
+    for c in &vm.controllers {
+        for port in 0..c.port_count {
+            for device in 0..devices_per_port(&c.bus) {
+                let attachment = vm
+                    .attachments
+                    .iter()
+                    .find(|a| a.controller == c.name && a.port == port && a.device == device);
+                match attachment {
+                    Some(a) => match a.medium.as_ref().and_then(|u| registry.media.iter().find(|m| &m.uuid == u)) {
+                        Some(m) => {
+                            out.push_str(&format!("\"{}-{}-{}\"=\"{}\"\n", escape(&c.name), port, device, escape(&m.location)));
+                            out.push_str(&format!("\"{}-ImageUUID-{}-{}\"=\"{}\"\n", c.name, port, device, m.uuid));
+                        }
+                        None => out.push_str(&format!("\"{}-{}-{}\"=\"emptydrive\"\n", c.name, port, device)),
+                    },
+                    None => out.push_str(&format!("\"{}-{}-{}\"=\"none\"\n", c.name, port, device)),
+                }
+            }
+        }
+    }
+
+    let mut forwarding = 0;
+    for i in 1..=8 {
+        let nic = vm.nics.get(&i).map(|s| s.as_str()).unwrap_or("none");
+        if nic != "none" {
+            out.push_str(&format!("macaddress{}=\"080027{:06X}\"\n", i, i));
+            out.push_str(&format!("cableconnected{}=\"on\"\n", i));
+        }
+        out.push_str(&format!("nic{}=\"{}\"\n", i, nic));
+        for rule in vm.forwards.get(&i).into_iter().flatten() {
+            out.push_str(&format!("Forwarding({})=\"{}\"\n", forwarding, escape(rule)));
+            forwarding += 1;
+        }
+    }
+
+    if !vm.snapshots.is_empty() {
+        out.push_str(&snapshot_listing(vm));
+    }
+    if vm.state == "running" && vm.extradata.contains_key(ADDITIONS_KEY) {
+        out.push_str("GuestAdditionsRunLevel=2\n");
+    }
+    Ok(out)
+}
+
+/// Quote-escape a value the way `--machinereadable` output does
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn snapshot_listing(vm: &Vm) -> String {
+    let mut out = String::new();
+    let mut suffix = String::new();
+    for (i, s) in vm.snapshots.iter().enumerate() {
+        if i > 0 {
+            suffix.push_str("-1");
+        }
+        out.push_str(&format!("SnapshotName{}=\"{}\"\n", suffix, escape(&s.name)));
+        out.push_str(&format!("SnapshotUUID{}=\"{}\"\n", suffix, s.uuid));
+    }
+    if let Some(current) = vm.snapshots.last() {
+        out.push_str(&format!("CurrentSnapshotName=\"{}\"\n", escape(&current.name)));
+        out.push_str(&format!("CurrentSnapshotUUID=\"{}\"\n", current.uuid));
+        out.push_str(&format!("CurrentSnapshotNode=\"SnapshotName{}\"\n", suffix));
+    }
+    out
+}
+
+fn unregistervm(registry: &mut Registry, args: &[String]) -> SimResult {
+    let key = args.first().ok_or_else(|| syntax("Missing VM name"))?;
+    let index = registry.vm_index(key)?;
+    if is_locked(&registry.vms[index]) {
+        return Err(error(
+            format!("Cannot unregister the machine '{}' while it is locked", registry.vms[index].name),
+            "VBOX_E_INVALID_OBJECT_STATE",
+            "MachineWrap",
+            "IMachine",
+        ));
+    }
+
+    let vm = registry.vms.remove(index);
+    if args.iter().any(|a| a == "--delete") {
+        let attached: Vec<String> = vm.attachments.iter().filter_map(|a| a.medium.clone()).collect();
+        registry.media.retain(|m| {
+            let deleted = m.kind == "hdd" && attached.contains(&m.uuid);
+            if deleted {
+                remove(&m.location);
+            }
+            !deleted
+        });
+        let folder = vm_folder(&vm);
+        for file in [vm.cfg_file.clone(), format!("{}-prev", vm.cfg_file), format!("{}/{}.nvram", folder, vm.name), saved_state_file(&vm)] {
+            remove(&file);
+        }
+        if let Ok(logs) = std::fs::read_dir(format!("{}/Logs", folder)) {
+            for log in logs.filter_map(Result::ok).filter(|e| e.file_name().to_string_lossy().starts_with("VBox.log")) {
+                let _ = std::fs::remove_file(log.path());
+            }
+        }
+        // Like VirtualBox, leave folders that still hold something else
+        for dir in [format!("{}/Logs", folder), format!("{}/Snapshots", folder), folder.to_string()] {
+            let _ = std::fs::remove_dir(dir);
+        }
+    }
+    Ok(String::new())
+}
+
+fn startvm(registry: &mut Registry, args: &[String]) -> SimResult {
+    let key = args.first().ok_or_else(|| syntax("Missing VM name"))?;
+    let index = registry.vm_index(key)?;
+    let mode = opt(args, "--type").unwrap_or("gui");
+    if !matches!(mode, "headless" | "gui" | "sdl" | "separate") {
+        return Err(syntax(format!("Invalid session type '{}'", mode)));
+    }
+    for (i, _) in args.iter().enumerate().filter(|(_, a)| *a == "--putenv") {
+        if !args.get(i + 1).is_some_and(|pair| pair.contains('=')) {
+            return Err(syntax("--putenv expects NAME=VALUE"));
+        }
+    }
+    let vm = &mut registry.vms[index];
+    if is_locked(vm) {
+        return Err(locked(&vm.name));
+    }
+    // Restoring a saved state consumes it
+    remove(&saved_state_file(vm));
+    vm.state = "running".to_string();
+    Ok(format!(
+        "Waiting for VM \"{}\" to power on...\nVM \"{}\" has been successfully started.\n",
+        vm.name, vm.name
+    ))
+}
+
+fn controlvm(registry: &mut Registry, args: &[String]) -> SimResult {
+    let key = args.first().ok_or_else(|| syntax("Missing VM name"))?;
+    let action = args.get(1).ok_or_else(|| syntax("Missing control action"))?;
+    let index = registry.vm_index(key)?;
+    let vm = &mut registry.vms[index];
+    if !is_locked(vm) {
+        return Err(not_running(&vm.name));
+    }
+
+    let next = match (action.as_str(), vm.state.as_str()) {
+        ("poweroff", _) => "poweroff",
+        ("reset", "running") => "running",
+        // A guest without ACPI support (or one that hangs on shutdown) ignores the button
+        ("acpipowerbutton", "running") if vm.extradata.get(IGNORE_ACPI_KEY).is_some_and(|v| v == "1") => "running",
+        ("acpipowerbutton", "running") => "poweroff",
+        ("pause", "running") => "paused",
+        ("resume", "paused") => "running",
+        ("savestate", _) => "saved",
+        (other, state) => {
+            return Err(error(
+                format!("Cannot {} machine '{}' in state {}", other, vm.name, state),
+                "VBOX_E_INVALID_VM_STATE",
+                "ConsoleWrap",
+                "IConsole",
+            ));
+        }
+    };
+    if next == "saved" {
+        touch(&saved_state_file(vm));
+    }
+    vm.state = next.to_string();
+    Ok(String::new())
+}
+
+fn discardstate(registry: &mut Registry, args: &[String]) -> SimResult {
+    let key = args.first().ok_or_else(|| syntax("Missing VM name"))?;
+    let index = registry.vm_index(key)?;
+    let vm = &mut registry.vms[index];
+    if vm.state != "saved" {
+        return Err(error(
+            format!("Machine '{}' is not in the saved state", vm.name),
+            "VBOX_E_INVALID_VM_STATE",
+            "MachineWrap",
+            "IMachine",
+        ));
+    }
+    remove(&saved_state_file(vm));
+    vm.state = "poweroff".to_string();
+    Ok(String::new())
+}
+
+fn setextradata(registry: &mut Registry, args: &[String]) -> SimResult {
+    let key = args.first().ok_or_else(|| syntax("Missing VM name"))?;
+    let name = args.get(1).ok_or_else(|| syntax("Missing key"))?.clone();
+    let index = registry.vm_index(key)?;
+    let vm = &mut registry.vms[index];
+    match args.get(2) {
+        Some(value) => vm.extradata.insert(name, value.clone()),
+        None => vm.extradata.remove(&name),
+    };
+    Ok(String::new())
+}
+
+fn getextradata(registry: &mut Registry, args: &[String]) -> SimResult {
+    let key = args.first().ok_or_else(|| syntax("Missing VM name"))?;
+    let name = args.get(1).ok_or_else(|| syntax("Missing key"))?;
+    let vm = &registry.vms[registry.vm_index(key)?];
+    if name == "enumerate" {
+        return Ok(vm
+            .extradata
+            .iter()
+            .map(|(k, v)| format!("Key: {}, Value: {}\n", k, v))
+            .collect());
+    }
+    Ok(match vm.extradata.get(name) {
+        Some(value) => format!("Value: {}\n", value),
+        None => "No value set!\n".to_string(),
+    })
+}
+
+fn createmedium(registry: &mut Registry, args: &[String]) -> SimResult {
+    let filename = required(args, "--filename")?.to_string();
+    let size_mb = parse_number(required(args, "--size")?, "--size")?;
+    let format = opt(args, "--format").unwrap_or("VDI").to_string();
+    if registry.medium_index(&filename).is_some() {
+        return Err(error(
+            format!("Failed to create medium\nCould not create the medium storage unit '{}'.\nVD: error VERR_ALREADY_EXISTS", filename),
+            "VBOX_E_FILE_ERROR",
+            "MediumWrap",
+            "IMedium",
+        ));
+    }
+
+    let uuid = registry.new_uuid();
+    touch(&filename);
+    registry.media.push(Medium {
+        uuid: uuid.clone(),
+        location: filename,
+        kind: "hdd".to_string(),
+        format,
+        size_mb,
+        parent: None,
+    });
+    Ok(format!(
+        "0%...10%...20%...30%...40%...50%...60%...70%...80%...90%...100%\nMedium created. UUID: {}\n",
+        uuid
+    ))
+}
+
+fn showmediuminfo(registry: &mut Registry, args: &[String]) -> SimResult {
+    let key = args.last().ok_or_else(|| syntax("Missing medium"))?;
+    let medium = registry
+        .medium_index(key)
+        .map(|i| &registry.media[i])
+        .ok_or_else(|| {
+            error(
+                format!("Could not find file for the medium '{}' (VERR_FILE_NOT_FOUND)", key),
+                "VBOX_E_FILE_ERROR",
+                "MediumWrap",
+                "IMedium",
+            )
+        })?;
+
+    let mut out = format!(
+        "UUID:           {}\nParent UUID:    {}\nState:          created\nType:           normal (base)\nLocation:       {}\nStorage format: {}\nCapacity:       {} MBytes\n",
+        medium.uuid,
+        medium.parent.as_deref().unwrap_or("base"),
+        medium.location,
+        medium.format,
+        medium.size_mb
+    );
+    let users = registry.medium_users(&medium.uuid);
+    if !users.is_empty() {
+        let names: Vec<String> = users.iter().map(|vm| format!("{} (UUID: {})", vm.name, vm.uuid)).collect();
+        out.push_str(&format!("In use by VMs:  {}\n", names.join(", ")));
+    }
+    Ok(out)
+}
+
+fn closemedium(registry: &mut Registry, args: &[String]) -> SimResult {
+    let key = args
+        .iter()
+        .skip(1)
+        .find(|a| !a.starts_with("--"))
+        .ok_or_else(|| syntax("Missing medium"))?;
+    let index = registry.medium_index(key).ok_or_else(|| {
+        error(
+            format!("Could not find file for the medium '{}' (VERR_FILE_NOT_FOUND)", key),
+            "VBOX_E_FILE_ERROR",
+            "MediumWrap",
+            "IMedium",
+        )
+    })?;
+    let uuid = registry.media[index].uuid.clone();
+    if let Some(vm) = registry.medium_users(&uuid).first() {
+        return Err(error(
+            format!("Cannot close medium '{}' because it is still attached to virtual machine '{}'", key, vm.name),
+            "VBOX_E_OBJECT_IN_USE",
+            "MediumWrap",
+            "IMedium",
+        ));
+    }
+    let medium = registry.media.remove(index);
+    if args.iter().any(|a| a == "--delete") {
+        remove(&medium.location);
+    }
+    Ok(String::new())
+}
+
+fn clonemedium(registry: &mut Registry, args: &[String]) -> SimResult {
+    let positional: Vec<&String> = args.iter().skip(1).filter(|a| !a.starts_with("--")).collect();
+    let (source, target) = match positional.as_slice() {
+        [source, target, ..] => (*source, *target),
+        _ => return Err(syntax("Expected source and target media")),
+    };
+    let index = registry.medium_index(source).ok_or_else(|| {
+        error(
+            format!("Could not find file for the medium '{}' (VERR_FILE_NOT_FOUND)", source),
+            "VBOX_E_FILE_ERROR",
+            "MediumWrap",
+            "IMedium",
+        )
+    })?;
+
+    let mut clone = registry.media[index].clone();
+    clone.uuid = registry.new_uuid();
+    clone.location = target.clone();
+    touch(&clone.location);
+    let out = format!(
+        "0%...10%...20%...30%...40%...50%...60%...70%...80%...90%...100%\nClone medium created in format '{}'. UUID: {}\n",
+        clone.format, clone.uuid
+    );
+    registry.media.push(clone);
+    Ok(out)
+}
+
+fn storagectl(registry: &mut Registry, args: &[String]) -> SimResult {
+    let key = args.first().ok_or_else(|| syntax("Missing VM name"))?;
+    let name = required(args, "--name")?.to_string();
+    let index = registry.vm_index(key)?;
+    let vm = &mut registry.vms[index];
+    if is_locked(vm) {
+        return Err(locked(&vm.name));
+    }
+
+    if args.iter().any(|a| a == "--remove") {
+        let before = vm.controllers.len();
+        vm.controllers.retain(|c| c.name != name);
+        if vm.controllers.len() == before {
+            return Err(error(
+                format!("Could not find a storage controller named '{}'", name),
+                "VBOX_E_OBJECT_NOT_FOUND",
+                "SessionMachine",
+                "IMachine",
+            ));
+        }
+        vm.attachments.retain(|a| a.controller != name);
+        return Ok(String::new());
+    }
+
+    let bus = required(args, "--add")?.to_string();
+    if vm.controllers.iter().any(|c| c.name == name) {
+        return Err(error(
+            format!("Storage controller named '{}' already exists", name),
+            "VBOX_E_OBJECT_IN_USE",
+            "SessionMachine",
+            "IMachine",
+        ));
+    }
+    let (chipset, ports) = match bus.as_str() {
+        "ide" => ("PIIX4", 2),
+        "sata" => ("IntelAhci", 30),
+        "scsi" => ("LsiLogic", 16),
+        "sas" => ("LsiLogicSas", 8),
+        "pcie" => ("NVMe", 1),
+        "virtio" => ("VirtIO", 1),
+        "floppy" => ("I82078", 1),
+        other => return Err(syntax(format!("Invalid --add argument '{}'", other))),
+    };
+    vm.controllers.push(Controller {
+        name,
+        chipset: opt(args, "--controller").unwrap_or(chipset).to_string(),
+        port_count: opt(args, "--portcount").map(|p| parse_number(p, "--portcount")).transpose()?.unwrap_or(ports),
+        host_io_cache: opt(args, "--hostiocache") == Some("on"),
+        bootable: opt(args, "--bootable") != Some("off"),
+        bus,
+    });
+    Ok(String::new())
+}
+
+fn storageattach(registry: &mut Registry, args: &[String]) -> SimResult {
+    let key = args.first().ok_or_else(|| syntax("Missing VM name"))?.clone();
+    let controller = required(args, "--storagectl")?.to_string();
+    let port: u32 = parse_number(opt(args, "--port").unwrap_or("0"), "--port")?;
+    let device: u32 = parse_number(opt(args, "--device").unwrap_or("0"), "--device")?;
+    let kind = opt(args, "--type").unwrap_or("hdd").to_string();
+    let medium = required(args, "--medium")?.to_string();
+    let index = registry.vm_index(&key)?;
+
+    match registry.vms[index].controllers.iter().find(|c| c.name == controller) {
+        Some(c) if port >= c.port_count || device >= devices_per_port(&c.bus) => {
+            return Err(error(
+                "The port and/or device parameter are out of range",
+                "E_INVALIDARG",
+                "SessionMachine",
+                "IMachine",
+            ));
+        }
+        Some(_) => {}
+        None => {
+            return Err(error(
+                format!("Could not find a controller named '{}'", controller),
+                "VBOX_E_OBJECT_NOT_FOUND",
+                "SessionMachine",
+                "IMachine",
+            ));
+        }
+    }
+
+    let medium_uuid = match medium.as_str() {
+        "none" => {
+            registry.vms[index]
+                .attachments
+                .retain(|a| !(a.controller == controller && a.port == port && a.device == device));
+            return Ok(String::new());
+        }
+        "emptydrive" => None,
+        location => {
+            let medium_kind = match kind.as_str() {
+                "dvddrive" => "dvd",
+                "fdd" => "floppy",
+                _ => "hdd",
+            };
+            let uuid = match registry.medium_index(location) {
+                Some(i) if registry.media[i].kind != medium_kind => {
+                    return Err(error(
+                        format!("The medium '{}' is a {} image and cannot be attached as {}", location, registry.media[i].kind, kind),
+                        "E_INVALIDARG",
+                        "SessionMachine",
+                        "IMachine",
+                    ));
+                }
+                Some(i) => registry.media[i].uuid.clone(),
+                None => {
+                    let uuid = registry.new_uuid();
+                    registry.media.push(Medium {
+                        uuid: uuid.clone(),
+                        location: location.to_string(),
+                        kind: medium_kind.to_string(),
+                        format: if medium_kind == "hdd" { "VDI" } else { "RAW" }.to_string(),
+                        size_mb: 0,
+                        parent: None,
+                    });
+                    uuid
+                }
+            };
+            Some(uuid)
+        }
+    };
+
+    let vm = &mut registry.vms[index];
+    vm.attachments
+        .retain(|a| !(a.controller == controller && a.port == port && a.device == device));
+    vm.attachments.push(Attachment {
+        controller,
+        port,
+        device,
+        kind,
+        medium: medium_uuid,
+    });
+    Ok(String::new())
+}
+
+fn snapshot(registry: &mut Registry, args: &[String]) -> SimResult {
+    let key = args.first().ok_or_else(|| syntax("Missing VM name"))?;
+    let action = args.get(1).map(|s| s.as_str()).unwrap_or("");
+    let index = registry.vm_index(key)?;
+
+    match action {
+        "take" => {
+            let name = args.get(2).ok_or_else(|| syntax("Missing snapshot name"))?.clone();
+            let uuid = registry.new_uuid();
+            registry.vms[index].snapshots.push(Snapshot { name, uuid: uuid.clone() });
+            Ok(format!(
+                "0%...10%...20%...30%...40%...50%...60%...70%...80%...90%...100%\nSnapshot taken. UUID: {}\n",
+                uuid
+            ))
+        }
+        "delete" => {
+            let name = args.get(2).ok_or_else(|| syntax("Missing snapshot name"))?;
+            let vm = &mut registry.vms[index];
+            let position = vm
+                .snapshots
+                .iter()
+                .position(|s| &s.name == name || &s.uuid == name)
+                .ok_or_else(|| {
+                    error(
+                        format!("Could not find a snapshot named '{}'", name),
+                        "VBOX_E_OBJECT_NOT_FOUND",
+                        "MachineWrap",
+                        "IMachine",
+                    )
+                })?;
+            vm.snapshots.remove(position);
+            Ok("0%...10%...20%...30%...40%...50%...60%...70%...80%...90%...100%\n".to_string())
+        }
+        "list" => {
+            let vm = &registry.vms[index];
+            if vm.snapshots.is_empty() {
+                return Err(error(
+                    "This machine does not have any snapshots",
+                    "VBOX_E_OBJECT_NOT_FOUND",
+                    "MachineWrap",
+                    "IMachine",
+                ));
+            }
+            Ok(snapshot_listing(vm))
+        }
+        other => Err(syntax(format!("Invalid snapshot operation '{}'", other))),
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    // Simulate a wedged VBoxManage for the named subcommand
+    if let Ok(hang_on) = std::env::var("VBOXSIM_HANG_ON")
+        && args.first() == Some(&hang_on)
+    {
+        std::thread::sleep(std::time::Duration::from_secs(300));
+    }
+    let path = state_path();
+    // Concurrent invocations take turns, as VBoxSVC serializes them
+    let _lock = match lock(&path) {
+        Ok(lock) => lock,
+        Err(e) => {
+            eprintln!("VBoxManage: error: Failed to lock registry '{}': {}", path.display(), e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut registry = load(&path);
+
+    match run(&mut registry, &args) {
+        Ok(out) => {
+            if let Err(e) = save(&path, &registry) {
+                eprintln!("VBoxManage: error: Failed to save registry '{}': {}", path.display(), e);
+                return ExitCode::FAILURE;
+            }
+            print!("{}", out);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            for line in e.message.lines() {
+                eprintln!("VBoxManage: error: {}", line);
+            }
+            eprintln!(
+                "VBoxManage: error: Details: code {} ({}), component {}, interface {}, callee nsISupports",
+                e.code, e.hex, e.component, e.interface
+            );
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,1672 @@
+// File: cpi_virtualbox/src/client.rs
+//! Typed VirtualBox API over VBoxManage. `VirtualBoxExtension` is a thin
+//! JSON adapter over this client; Rust callers can use it directly.
+use crate::cloudinit::CloudInitSeed;
+use crate::error::{VBoxError, VBoxFailure, VBoxResult};
+use crate::executor::{ExecOptions, ProcessExecutor, ResolvedBinary, VBoxExecutor};
+use crate::machinereadable::{self, MachineReadable};
+use crate::spec::{SpecPlan, WorkerSpec};
+use crate::types::{
+    ApplianceEntry, AttachOptions, ControllerSpec, DeviceType, CloneOptions, DetectedOs, ExportOptions, ImportOptions, OvfVersion, LINKED_CLONE_SNAPSHOT, MacPolicy, DeleteOptions, DeleteReport, Medium, StorageAttachment, Nic, Snapshot, StateTransition, StopMethod, StopOutcome, Vm, VmConfig, VmFilter, VmState, VmSummary,
+    StartGroup, StartOptions, StartReport, StorageBus, StorageController, UnattendedOptions, WaitTarget,
+};
+use crate::{logging, retry};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often waits on a VM's state re-check it
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Drives VBoxManage through an executor with a fixed set of options
+/// (timeout, cancellation, retry policy) applied to every invocation
+#[derive(Clone)]
+pub struct VirtualBoxClient {
+    executor: Arc<dyn VBoxExecutor>,
+    options: ExecOptions,
+    poll_interval: Duration,
+}
+
+impl Default for VirtualBoxClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VirtualBoxClient {
+    /// Client for the VBoxManage found by `resolve_vboxmanage`
+    pub fn new() -> Self {
+        Self::with_executor(Arc::new(ProcessExecutor::new()))
+    }
+
+    pub fn with_executor(executor: Arc<dyn VBoxExecutor>) -> Self {
+        Self {
+            executor,
+            options: ExecOptions::default(),
+            poll_interval: DEFAULT_POLL_INTERVAL,
+        }
+    }
+
+    /// Use these options for every VBoxManage invocation
+    pub fn with_options(mut self, options: ExecOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Set how often waits on a VM's state re-check it
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    pub fn options(&self) -> &ExecOptions {
+        &self.options
+    }
+
+    /// The VBoxManage binary in use, when the executor knows it
+    pub fn binary(&self) -> Option<&ResolvedBinary> {
+        self.executor.binary()
+    }
+
+    /// Run VBoxManage with raw arguments and return its stdout, retrying
+    /// transient failures according to the configured policy
+    pub fn run(&self, args: &[&str]) -> VBoxResult<String> {
+        let opts = &self.options;
+        // Never let secrets reach the log or the error payload
+        let command = logging::redact_args(args).join(" ");
+        log::debug!("Running VBoxManage {}", command);
+
+        let mut attempt = 1;
+        loop {
+            let output = self
+                .executor
+                .execute(args, opts)
+                .map_err(|e| VBoxError::from_exec(e, &command))?;
+
+            if output.success {
+                return Ok(output.stdout);
+            }
+
+            // Session locks and busy states clear up on their own; anything else fails fast
+            if attempt >= opts.retry.max_attempts || !retry::is_transient(&output.stderr) {
+                return Err(VBoxError::Command(Box::new(VBoxFailure::parse(&command, &output.stderr))));
+            }
+
+            let delay = opts.retry.backoff(attempt);
+            log::warn!(
+                "VBoxManage {} hit a transient error, retrying in {:?} (attempt {} of {})",
+                command,
+                delay,
+                attempt + 1,
+                opts.retry.max_attempts
+            );
+            opts.sleep(delay).map_err(|e| VBoxError::from_exec(e, &command))?;
+            attempt += 1;
+        }
+    }
+
+    /// Installed VirtualBox version, e.g. `7.0.14r161095`
+    pub fn version(&self) -> VBoxResult<String> {
+        Ok(self.run(&["--version"])?.trim().to_string())
+    }
+
+    /// All registered VMs
+    pub fn list_vms(&self) -> VBoxResult<Vec<VmSummary>> {
+        let output = self.run(&["list", "vms"])?;
+        Ok(parse_vm_list(&output))
+    }
+
+    /// All registered VMs with state, OS type, memory, CPUs and groups,
+    /// from a single `list -l vms` call
+    pub fn list_vms_detailed(&self) -> VBoxResult<Vec<Vm>> {
+        let output = self.run(&["list", "-l", "vms"])?;
+        Ok(parse_vm_long_list(&output))
+    }
+
+    /// Fill in `os_type` from `os_description`, which is all VBoxManage
+    /// prints for a VM, using one `list ostypes` call
+    pub fn resolve_os_types(&self, vms: &mut [Vm]) -> VBoxResult<()> {
+        if vms.iter().all(|vm| vm.os_type.is_some() || vm.os_description.is_none()) {
+            return Ok(());
+        }
+        let ids = parse_os_types(&self.run(&["list", "ostypes"])?);
+        for vm in vms.iter_mut().filter(|vm| vm.os_type.is_none()) {
+            vm.os_type = vm.os_description.as_ref().and_then(|description| ids.get(description)).cloned();
+        }
+        Ok(())
+    }
+
+    /// VMs matching a filter. Extradata is only queried for VMs that pass
+    /// the other criteria, and only when the filter has a tag.
+    pub fn find_vms(&self, filter: &VmFilter) -> VBoxResult<Vec<Vm>> {
+        let mut vms = Vec::new();
+        for vm in self.list_vms_detailed()? {
+            if !filter.matches(&vm) {
+                continue;
+            }
+            if let Some((key, expected)) = &filter.tag {
+                let name = vm.id.as_deref().or(vm.name.as_deref()).unwrap_or_default();
+                let value = self.get_extradata(name, key)?;
+                let tagged = match expected {
+                    Some(expected) => value.as_ref() == Some(expected),
+                    None => value.is_some(),
+                };
+                if !tagged {
+                    continue;
+                }
+            }
+            vms.push(vm);
+        }
+        Ok(vms)
+    }
+
+    /// Create and register a VM with the given hardware, disks and NICs (NAT
+    /// on the first NIC when none are given). Creation is all-or-nothing: if
+    /// any step after `createvm` fails the VM is unregistered and its files
+    /// and new disks deleted, and the error names the step.
+    pub fn create_vm(&self, config: &VmConfig) -> VBoxResult<VmSummary> {
+        config.validate()?;
+
+        let mut args = vec!["createvm", "--name", &config.name, "--ostype", &config.os_type, "--register"];
+        let groups = config.groups.join(",");
+        if !groups.is_empty() {
+            args.extend(["--groups", &groups]);
+        }
+        if let Some(folder) = &config.base_folder {
+            args.extend(["--basefolder", folder]);
+        }
+        let output = self.run(&args).map_err(|e| VBoxError::StepFailed {
+            vm: config.name.clone(),
+            step: "createvm".to_string(),
+            rolled_back: false,
+            source: Box::new(e),
+        })?;
+        let id = output
+            .lines()
+            .find(|line| line.contains("UUID"))
+            .and_then(|line| line.split_once(':'))
+            .map(|(_, uuid)| uuid.trim().to_string())
+            .unwrap_or_default();
+        let vm = VmSummary {
+            name: config.name.clone(),
+            id,
+        };
+
+        let hardware = hardware_args(config);
+        self.creation_step(&vm, "configure hardware", || {
+            let mut args = vec!["modifyvm", &config.name];
+            args.extend(hardware.iter().map(String::as_str));
+            self.run(&args)
+        })?;
+
+        if !config.disks.is_empty() {
+            // New disks go next to the settings file unless a path is given
+            let folder = parse_field(&output, "Settings file:")
+                .map(|file| file.trim_matches('\'').to_string())
+                .and_then(|file| Path::new(&file).parent().map(|dir| dir.to_path_buf()))
+                .unwrap_or_default();
+            self.creation_step(&vm, "configure storage", || {
+                self.add_sata_controller(&config.name, &config.controller_name)?;
+                for (port, disk) in config.disks.iter().enumerate() {
+                    let path = match &disk.path {
+                        Some(path) => path.clone(),
+                        None => folder.join(format!("{}-disk{}.vdi", config.name, port + 1)).to_string_lossy().into_owned(),
+                    };
+                    let medium = self.create_medium(&path, disk.size_mb)?;
+                    let path = medium.path.unwrap_or(path);
+                    if let Err(e) = self.attach_disk(&config.name, &config.controller_name, port as u32, &path) {
+                        // Not attached yet, so unregistering the VM would leave it behind
+                        if let Err(cleanup) = self.delete_medium(&path) {
+                            log::error!("Could not remove disk '{}' created for VM '{}': {}", path, config.name, cleanup);
+                        }
+                        return Err(e);
+                    }
+                }
+                Ok(())
+            })?;
+        }
+
+        self.creation_step(&vm, "configure network", || {
+            if config.nics.is_empty() {
+                return self.run(&["modifyvm", &config.name, "--nic1", "nat"]).map(drop);
+            }
+            let mut args = vec!["modifyvm".to_string(), config.name.clone()];
+            for nic in &config.nics {
+                args.push(format!("--nic{}", nic.index));
+                args.push(nic.nic_type.as_str().to_string());
+            }
+            self.run(&args.iter().map(String::as_str).collect::<Vec<_>>()).map(drop)
+        })?;
+
+        Ok(vm)
+    }
+
+    // Run one step of creating `vm`, removing the VM again if it fails
+    fn creation_step<T>(&self, vm: &VmSummary, step: &str, f: impl FnOnce() -> VBoxResult<T>) -> VBoxResult<T> {
+        f().map_err(|error| {
+            log::warn!("Creating VM '{}' failed at step '{}', rolling back: {}", vm.name, step, error);
+            // Clean up even when the action itself was cancelled
+            let cleanup = self.clone().with_options(ExecOptions {
+                cancel: None,
+                ..self.options.clone()
+            });
+            let target = if vm.id.is_empty() { &vm.name } else { &vm.id };
+            let rolled_back = match cleanup.run(&["unregistervm", target, "--delete"]) {
+                Ok(_) => true,
+                Err(e) => {
+                    log::error!("Could not remove partially created VM '{}': {}", vm.name, e);
+                    false
+                }
+            };
+            VBoxError::StepFailed {
+                vm: vm.name.clone(),
+                step: step.to_string(),
+                rolled_back,
+                source: Box::new(error),
+            }
+        })
+    }
+
+    /// Clone `source` into a new registered VM. The new VM's UUID is
+    /// the one requested, or else looked up among the VMs that were not
+    /// registered before the clone, since `clonevm` does not print it.
+    pub fn clone_vm(&self, source: &str, options: &CloneOptions) -> VBoxResult<VmSummary> {
+        if options.name.trim().is_empty() {
+            return Err(VBoxError::InvalidParameter("Clone name must not be empty".to_string()));
+        }
+        if let Some(group) = options.groups.iter().find(|g| !g.starts_with('/')) {
+            return Err(VBoxError::InvalidParameter(format!("Group '{}' must start with '/'", group)));
+        }
+
+        let snapshot = match (&options.snapshot, options.linked) {
+            (Some(name), _) => Some(name.as_str()),
+            (None, true) => Some(LINKED_CLONE_SNAPSHOT),
+            (None, false) => None,
+        };
+        let mut taken = None;
+        if let Some(name) = snapshot
+            && !self.list_snapshots(source)?.iter().any(|s| s.name == name || s.id == name)
+        {
+            log::info!("Taking snapshot '{}' of VM '{}' to clone from", name, source);
+            taken = Some(self.take_snapshot(source, name)?);
+        }
+
+        self.clone_from(source, options, snapshot).inspect_err(|error| {
+            // Don't leave behind a snapshot nothing was cloned from
+            if let Some(taken) = &taken {
+                log::warn!("Cloning VM '{}' failed, deleting snapshot '{}': {}", source, taken.name, error);
+                let cleanup = self.clone().with_options(ExecOptions {
+                    cancel: None,
+                    ..self.options.clone()
+                });
+                let target = if taken.id.is_empty() { &taken.name } else { &taken.id };
+                if let Err(e) = cleanup.delete_snapshot(source, target) {
+                    log::error!("Could not delete snapshot '{}' of VM '{}': {}", taken.name, source, e);
+                }
+            }
+        })
+    }
+
+    fn clone_from(&self, source: &str, options: &CloneOptions, snapshot: Option<&str>) -> VBoxResult<VmSummary> {
+        let before: Vec<String> = self.list_vms()?.into_iter().map(|vm| vm.id).collect();
+
+        let mut args = vec!["clonevm", source, "--name", &options.name, "--mode", "machine", "--register"];
+        if let Some(name) = snapshot {
+            args.extend(["--snapshot", name]);
+        }
+        let mut flags = Vec::new();
+        if options.linked {
+            flags.push("Link");
+        }
+        match options.mac_policy {
+            MacPolicy::Regenerate => {}
+            MacPolicy::KeepNat => flags.push("KeepNATMACs"),
+            MacPolicy::KeepAll => flags.push("KeepAllMACs"),
+        }
+        let flags = flags.join(",");
+        if !flags.is_empty() {
+            args.extend(["--options", &flags]);
+        }
+        if let Some(uuid) = &options.uuid {
+            args.extend(["--uuid", uuid]);
+        }
+        let groups = options.groups.join(",");
+        if !groups.is_empty() {
+            args.extend(["--groups", &groups]);
+        }
+        if let Some(folder) = &options.base_folder {
+            args.extend(["--basefolder", folder]);
+        }
+        self.run(&args)?;
+
+        if let Some(uuid) = &options.uuid {
+            return Ok(VmSummary {
+                name: options.name.clone(),
+                id: uuid.clone(),
+            });
+        }
+        self.new_vm_named(&options.name, &before)
+    }
+
+    // The one VM called `name` whose UUID is not in `before`
+    fn new_vm_named(&self, name: &str, before: &[String]) -> VBoxResult<VmSummary> {
+        let mut added: Vec<VmSummary> = self
+            .list_vms()?
+            .into_iter()
+            .filter(|vm| vm.name == name && !before.contains(&vm.id))
+            .collect();
+        match added.len() {
+            1 => Ok(added.remove(0)),
+            found => Err(VBoxError::UnexpectedOutput(format!(
+                "Expected one new VM named '{}' to be registered, found {}",
+                name, found
+            ))),
+        }
+    }
+
+    /// The virtual system description of an OVF/OVA appliance, without
+    /// importing it
+    pub fn inspect_appliance(&self, path: &str) -> VBoxResult<Vec<ApplianceEntry>> {
+        let output = self.run(&["import", path, "--dry-run"])?;
+        Ok(parse_appliance(&output))
+    }
+
+    /// Import the first VM of an OVF/OVA appliance with `options` applied
+    pub fn import_appliance(&self, path: &str, options: &ImportOptions) -> VBoxResult<VmSummary> {
+        let entries = self.inspect_appliance(path)?;
+        let disks: Vec<&ApplianceEntry> = entries.iter().filter(|e| e.system == 0 && e.kind == "hard_disk").collect();
+        if options.disk_paths.len() > disks.len() {
+            return Err(VBoxError::InvalidParameter(format!(
+                "{} disk paths given but the appliance has {} hard disks",
+                options.disk_paths.len(),
+                disks.len()
+            )));
+        }
+        let name = match &options.name {
+            Some(name) => name.clone(),
+            None => entries
+                .iter()
+                .find(|e| e.system == 0 && e.kind == "name")
+                .and_then(|e| e.value.clone())
+                .ok_or_else(|| VBoxError::UnexpectedOutput(format!("Appliance '{}' does not suggest a VM name", path)))?,
+        };
+
+        let mut args = vec!["import".to_string(), path.to_string(), "--vsys".to_string(), "0".to_string()];
+        let mut push = |flag: &str, value: String| {
+            args.push(flag.to_string());
+            args.push(value);
+        };
+        if let Some(name) = &options.name {
+            push("--vmname", name.clone());
+        }
+        if let Some(memory) = options.memory_mb {
+            push("--memory", memory.to_string());
+        }
+        if let Some(cpus) = options.cpu_count {
+            push("--cpus", cpus.to_string());
+        }
+        for (disk, target) in disks.iter().zip(&options.disk_paths) {
+            push("--unit", disk.unit.to_string());
+            push("--disk", target.clone());
+        }
+        if options.accept_eula {
+            push("--eula", "accept".to_string());
+        }
+        match options.mac_policy {
+            MacPolicy::Regenerate => {}
+            MacPolicy::KeepNat => push("--options", "keepnatmacs".to_string()),
+            MacPolicy::KeepAll => push("--options", "keepallmacs".to_string()),
+        }
+
+        let before: Vec<String> = self.list_vms()?.into_iter().map(|vm| vm.id).collect();
+        self.run(&args.iter().map(String::as_str).collect::<Vec<_>>())?;
+        self.new_vm_named(&name, &before)
+    }
+
+    /// Export a VM as an OVF/OVA appliance
+    pub fn export_appliance(&self, vm: &str, options: &ExportOptions) -> VBoxResult<()> {
+        let lower = options.output.to_ascii_lowercase();
+        if !lower.ends_with(".ova") && !lower.ends_with(".ovf") {
+            return Err(VBoxError::InvalidParameter(format!("Appliance file '{}' must end in .ova or .ovf", options.output)));
+        }
+        let mut args = vec!["export", vm, "--output", &options.output];
+        args.push(match options.ovf_version {
+            OvfVersion::Ovf10 => "--ovf10",
+            OvfVersion::Ovf20 => "--ovf20",
+        });
+        if options.manifest {
+            args.extend(["--options", "manifest"]);
+        }
+        // Product metadata belongs to the exported virtual system
+        let metadata = [
+            ("--product", &options.product),
+            ("--producturl", &options.product_url),
+            ("--vendor", &options.vendor),
+            ("--vendorurl", &options.vendor_url),
+            ("--version", &options.version),
+            ("--description", &options.description),
+        ];
+        if metadata.iter().any(|(_, value)| value.is_some()) {
+            args.extend(["--vsys", "0"]);
+        }
+        for (flag, value) in &metadata {
+            if let Some(value) = value {
+                args.extend([*flag, value.as_str()]);
+            }
+        }
+        self.run(&args)?;
+        Ok(())
+    }
+
+    /// The OS on an installation ISO, as far as VirtualBox can tell
+    pub fn detect_os(&self, iso: &str) -> VBoxResult<DetectedOs> {
+        let output = self.run(&["unattended", "detect", "--iso", iso, "--machine-readable"])?;
+        Ok(machinereadable::parse_detected_os(&output))
+    }
+
+    /// Prepare an unattended installation from `options.iso` on an existing
+    /// VM, booting it into the installer if `options.start` is set. Fails
+    /// before touching the VM if the ISO holds no OS VirtualBox can install.
+    pub fn install_os_unattended(&self, vm: &str, options: &UnattendedOptions) -> VBoxResult<DetectedOs> {
+        if options.user.is_empty() || options.password.is_empty() {
+            return Err(VBoxError::InvalidParameter("An unattended install needs a user name and password".to_string()));
+        }
+        let detected = self.detect_os(&options.iso)?;
+        if !detected.install_supported {
+            return Err(VBoxError::InvalidParameter(format!(
+                "VirtualBox cannot install the OS on '{}' unattended (detected {})",
+                options.iso,
+                detected.os_type.as_deref().unwrap_or("nothing")
+            )));
+        }
+
+        // Kept out of argv, where any local user could read it
+        let password = SecretFile::create(&options.password)?;
+        let password_path = password.0.to_string_lossy().into_owned();
+        let mut args = vec!["unattended", "install", vm, "--iso", &options.iso, "--user", &options.user, "--password-file", &password_path];
+        let settings = [
+            ("--full-user-name", &options.full_name),
+            ("--hostname", &options.hostname),
+            ("--locale", &options.locale),
+            ("--time-zone", &options.time_zone),
+            ("--post-install-command", &options.post_install_command),
+        ];
+        for (flag, value) in &settings {
+            if let Some(value) = value {
+                args.extend([*flag, value.as_str()]);
+            }
+        }
+        if options.install_additions {
+            args.push("--install-additions");
+        }
+        if options.start {
+            args.push("--start-vm=headless");
+        }
+        self.run(&args)?;
+        Ok(detected)
+    }
+
+    /// Write a cloud-init NoCloud seed ISO for a VM and insert it into a
+    /// DVD drive on `controller`, which is added first only if
+    /// `create_controller` is set. The image goes next to the VM's settings
+    /// file unless `path` is given; a seed already attached from the same
+    /// path keeps its slot, otherwise the first free port and device is used.
+    pub fn attach_cloud_init_seed(
+        &self,
+        vm: &str,
+        seed: &CloudInitSeed,
+        path: Option<&str>,
+        controller: &ControllerSpec,
+        create_controller: bool,
+    ) -> VBoxResult<StorageAttachment> {
+        seed.validate()?;
+        let info = self.vm_info(vm)?;
+        let path = match path {
+            Some(path) => path.to_string(),
+            None => {
+                let folder = info
+                    .config_file
+                    .as_deref()
+                    .and_then(|file| Path::new(file).parent())
+                    .ok_or_else(|| VBoxError::UnexpectedOutput(format!("VM '{}' reported no settings file", vm)))?;
+                folder.join(format!("{}-seed.iso", info.name.as_deref().unwrap_or(vm))).to_string_lossy().into_owned()
+            }
+        };
+
+        let existing = info.storage_controllers.iter().find(|c| c.name == controller.name);
+        if existing.is_none() && !create_controller {
+            return Err(VBoxError::InvalidParameter(format!("VM '{}' has no storage controller named '{}'", vm, controller.name)));
+        }
+        let bus = existing.and_then(|c| c.bus).unwrap_or(controller.bus);
+        let ports = existing.and_then(|c| c.port_count).or(controller.port_count).unwrap_or(bus.max_ports());
+        let on_controller: Vec<&StorageAttachment> = info.storage_attachments.iter().filter(|a| a.controller == controller.name).collect();
+        let (port, device) = match on_controller.iter().find(|a| a.medium.as_deref() == Some(path.as_str())) {
+            Some(seeded) => (seeded.port, seeded.device),
+            None => (0..ports)
+                .flat_map(|port| (0..bus.devices_per_port()).map(move |device| (port, device)))
+                .find(|&(port, device)| !on_controller.iter().any(|a| a.port == port && a.device == device))
+                .ok_or_else(|| VBoxError::InvalidParameter(format!("Storage controller '{}' has no free port", controller.name)))?,
+        };
+
+        seed.write_iso(Path::new(&path)).map_err(|e| VBoxError::Io {
+            path: path.clone(),
+            message: e.to_string(),
+        })?;
+        let options = AttachOptions {
+            device_type: DeviceType::DvdDrive,
+            device,
+            ..AttachOptions::default()
+        };
+        let attached = match existing {
+            None => self.add_storage_controller(vm, controller),
+            Some(_) => Ok(()),
+        }
+        .and_then(|()| self.attach_medium(vm, &controller.name, port, &path, &options));
+        attached.inspect_err(|error| {
+            // The seed holds keys and commands; don't leave it lying around
+            log::warn!("Attaching seed '{}' to VM '{}' failed, removing it: {}", path, vm, error);
+            if let Err(e) = std::fs::remove_file(&path) {
+                log::error!("Could not remove seed '{}': {}", path, e);
+            }
+        })?;
+        Ok(StorageAttachment {
+            controller: controller.name.clone(),
+            port,
+            device,
+            medium: Some(path),
+            medium_id: None,
+            device_type: Some(DeviceType::DvdDrive),
+            parent_id: None,
+            differencing: Some(false),
+        })
+    }
+
+    /// Changes needed to bring the VM named in `spec` in line with it,
+    /// without making any
+    pub fn plan_spec(&self, spec: &WorkerSpec) -> VBoxResult<SpecPlan> {
+        let media = if spec.disks.is_empty() { Vec::new() } else { self.list_media()? };
+        spec.validate(&media)?;
+        let current = match self.vm_info(&spec.name) {
+            Ok(vm) => Some(vm),
+            Err(e) if e.is_not_found() => None,
+            Err(e) => return Err(e),
+        };
+
+        let mut metadata = BTreeMap::new();
+        if current.is_some() {
+            for key in spec.metadata.keys() {
+                if let Some(value) = self.get_extradata(&spec.name, key)? {
+                    metadata.insert(key.clone(), value);
+                }
+            }
+        }
+        Ok(spec.plan(current.as_ref(), &metadata, &media))
+    }
+
+    /// Bring the VM named in `spec` in line with it, creating it if needed.
+    /// Changes run in order and stop at the first failure, whose error names
+    /// the change; nothing is rolled back, so applying again resumes.
+    pub fn apply_spec(&self, spec: &WorkerSpec) -> VBoxResult<SpecPlan> {
+        let plan = self.plan_spec(spec)?;
+        for change in &plan.changes {
+            log::info!("Applying spec change '{}' to VM '{}'", change.target, spec.name);
+            for command in &change.commands {
+                let args: Vec<&str> = command.iter().map(String::as_str).collect();
+                self.run(&args).map_err(|e| VBoxError::StepFailed {
+                    vm: spec.name.clone(),
+                    step: change.target.clone(),
+                    rolled_back: false,
+                    source: Box::new(e),
+                })?;
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Unregister a VM and delete its files and hard disks
+    pub fn delete_vm(&self, name: &str) -> VBoxResult<()> {
+        self.run(&["unregistervm", name, "--delete"])?;
+        Ok(())
+    }
+
+    /// Delete a VM, optionally powering it off first and detaching media
+    /// that must survive. Hard disks still attached are deleted with it.
+    pub fn delete_vm_with(&self, name: &str, options: &DeleteOptions) -> VBoxResult<DeleteReport> {
+        let vm = self.vm_info(name)?;
+
+        // Resolve every medium to keep before touching the VM
+        let mut keep = Vec::new();
+        for wanted in &options.keep_media {
+            let attachment = vm
+                .storage_attachments
+                .iter()
+                .find(|a| medium_matches(a, wanted))
+                .ok_or_else(|| VBoxError::InvalidParameter(format!("Medium '{}' is not attached to VM '{}'", wanted, name)))?;
+            keep.push(attachment.clone());
+        }
+
+        let mut report = DeleteReport::default();
+        let state = vm.state.clone().unwrap_or_else(|| VmState::Other("unknown".to_string()));
+        if !state.is_stopped() && options.force {
+            log::info!("Powering off VM '{}' ({}) before deleting it", name, state);
+            self.power_off(name)?;
+            report.powered_off = true;
+        }
+        if report.powered_off || is_session_locked(&vm) {
+            self.wait_for_unlock(name, options.unlock_timeout)?;
+        }
+
+        for attachment in &keep {
+            self.detach_device(name, &attachment.controller, attachment.port, attachment.device)?;
+            report
+                .kept_media
+                .push(attachment.medium.clone().unwrap_or_default());
+        }
+
+        let hard_disks = self.list_media()?;
+        let candidates = deletion_candidates(&vm, &hard_disks, &keep);
+
+        self.delete_vm(name)?;
+        // Only what is actually gone: VirtualBox skips files it cannot delete
+        report.deleted_files = candidates.into_iter().filter(|path| !Path::new(path).exists()).collect();
+        Ok(report)
+    }
+
+    // Poll until the VM is stopped and no session holds its lock
+    fn wait_for_unlock(&self, name: &str, timeout: Duration) -> VBoxResult<()> {
+        let started = Instant::now();
+        let mut history: Vec<StateTransition> = Vec::new();
+        loop {
+            let vm = self.vm_info(name)?;
+            let state = vm.state.clone().unwrap_or_else(|| VmState::Other("unknown".to_string()));
+            if state.is_stopped() && !is_session_locked(&vm) {
+                return Ok(());
+            }
+            if history.last().is_none_or(|last| last.state != state) {
+                history.push(StateTransition {
+                    state,
+                    run_level: None,
+                    elapsed_secs: started.elapsed().as_secs_f64(),
+                });
+            }
+
+            let remaining = timeout.saturating_sub(started.elapsed());
+            if remaining.is_zero() {
+                return Err(VBoxError::WaitTimedOut {
+                    vm: name.to_string(),
+                    target: WaitTarget::state(VmState::PowerOff),
+                    timeout,
+                    history,
+                });
+            }
+            self.sleep(self.poll_interval.min(remaining), name)?;
+        }
+    }
+
+    pub fn vm_info(&self, name: &str) -> VBoxResult<Vm> {
+        let output = self.run(&["showvminfo", name, "--machinereadable"])?;
+        Ok(machinereadable::parse_vm(&output))
+    }
+
+    /// Whether a VM is registered; errors other than "not found" are returned
+    pub fn vm_exists(&self, name: &str) -> VBoxResult<bool> {
+        exists(self.run(&["showvminfo", name, "--machinereadable"]))
+    }
+
+    /// Start a VM without a display window
+    pub fn start_vm(&self, name: &str) -> VBoxResult<()> {
+        self.start_vm_with(name, &StartOptions::default())
+    }
+
+    /// Start a VM with the given front end and process environment
+    pub fn start_vm_with(&self, name: &str, options: &StartOptions) -> VBoxResult<()> {
+        let env: Vec<String> = options.env.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+        let mut args = vec!["startvm", name, "--type", options.mode.as_str()];
+        for pair in &env {
+            args.extend(["--putenv", pair.as_str()]);
+        }
+        self.run(&args)?;
+        Ok(())
+    }
+
+    /// Start VMs group by group, at most `parallelism` at once within a
+    /// group. With `wait`, a VM counts as started once it meets the target,
+    /// so each group only starts after the previous one is up. A failure
+    /// leaves later groups unstarted; the reports follow the groups' order.
+    pub fn start_vms(&self, groups: &[StartGroup], options: &StartOptions, parallelism: usize, wait: Option<(&WaitTarget, Duration)>) -> Vec<StartReport> {
+        let mut reports = Vec::new();
+        let mut failed = false;
+        for (index, group) in groups.iter().enumerate() {
+            let report = |name: &String, result| StartReport {
+                name: name.clone(),
+                group: index,
+                result,
+            };
+            if failed {
+                reports.extend(group.workers.iter().map(|name| report(name, None)));
+                continue;
+            }
+            if index > 0 && group.delay_secs > 0 {
+                let delay = Duration::from_secs(group.delay_secs);
+                if let Err(e) = self.options.sleep(delay) {
+                    let err = VBoxError::from_exec(e, &format!("startvm {}", group.workers.join(" ")));
+                    reports.extend(group.workers.iter().map(|name| report(name, Some(Err(err.clone())))));
+                    failed = true;
+                    continue;
+                }
+            }
+
+            let start = |name: &str| -> VBoxResult<Vec<StateTransition>> {
+                self.start_vm_with(name, options)?;
+                match wait {
+                    Some((target, timeout)) => self.wait_for_state(name, target, timeout),
+                    None => Ok(Vec::new()),
+                }
+            };
+            let queue = Mutex::new(group.workers.iter().enumerate());
+            let results = Mutex::new(Vec::new());
+            std::thread::scope(|scope| {
+                for _ in 0..parallelism.clamp(1, group.workers.len().max(1)) {
+                    scope.spawn(|| {
+                        loop {
+                            let Some((i, name)) = queue.lock().unwrap().next() else {
+                                break;
+                            };
+                            let result = start(name);
+                            results.lock().unwrap().push((i, result));
+                        }
+                    });
+                }
+            });
+            let mut results = results.into_inner().unwrap();
+            results.sort_by_key(|(i, _)| *i);
+            for (i, result) in results {
+                failed |= result.is_err();
+                reports.push(report(&group.workers[i], Some(result)));
+            }
+        }
+        reports
+    }
+
+    /// Current power state of a VM
+    pub fn vm_state(&self, name: &str) -> VBoxResult<VmState> {
+        Ok(self
+            .vm_info(name)?
+            .state
+            .unwrap_or_else(|| VmState::Other("unknown".to_string())))
+    }
+
+    /// Poll a VM until it reaches `target`, recording every change of state
+    /// or Guest Additions run level. Fails with `VBoxError::WaitTimedOut`,
+    /// carrying the history, if `timeout` passes first.
+    pub fn wait_for_state(&self, name: &str, target: &WaitTarget, timeout: Duration) -> VBoxResult<Vec<StateTransition>> {
+        self.wait_until(name, target, timeout, |state, run_level| target.is_met(state, run_level))
+    }
+
+    /// Hard-reset a VM and wait for its guest to come back: the Guest
+    /// Additions run level must first drop below `min_run_level`, by default
+    /// the level before the reset, and then reach it again. The VM stays
+    /// `running` throughout, so a guest that reports no run level gives
+    /// nothing to wait on and is refused before it is reset.
+    pub fn reset_vm_and_wait(&self, name: &str, min_run_level: Option<u32>, timeout: Duration) -> VBoxResult<Vec<StateTransition>> {
+        let before = self.vm_info(name)?.guest.and_then(|g| g.additions_run_level).unwrap_or(0);
+        let level = min_run_level.unwrap_or(before);
+        if level == 0 {
+            return Err(VBoxError::InvalidParameter(format!(
+                "Cannot tell when VM '{}' has rebooted: its guest reports no Guest Additions run level; give guest_run_level to wait for",
+                name
+            )));
+        }
+
+        self.reset_vm(name)?;
+        let target = WaitTarget {
+            state: VmState::Running,
+            min_run_level: Some(level),
+        };
+        // Already below the target before the reset, so anything at the
+        // target afterwards comes from the rebooted guest
+        let mut restarted = before < level;
+        self.wait_until(name, &target, timeout, |state, run_level| {
+            restarted |= run_level.unwrap_or(0) < level;
+            restarted && target.is_met(state, run_level)
+        })
+    }
+
+    // Poll a VM until `met` holds; `target` describes it for the timeout error
+    fn wait_until(
+        &self,
+        name: &str,
+        target: &WaitTarget,
+        timeout: Duration,
+        mut met: impl FnMut(&VmState, Option<u32>) -> bool,
+    ) -> VBoxResult<Vec<StateTransition>> {
+        let started = Instant::now();
+        let mut history: Vec<StateTransition> = Vec::new();
+        loop {
+            let vm = self.vm_info(name)?;
+            let state = vm.state.unwrap_or_else(|| VmState::Other("unknown".to_string()));
+            let run_level = vm.guest.and_then(|g| g.additions_run_level);
+
+            if history.last().is_none_or(|last| last.state != state || last.run_level != run_level) {
+                log::debug!("VM '{}' is {} (guest run level {:?})", name, state, run_level);
+                history.push(StateTransition {
+                    state: state.clone(),
+                    run_level,
+                    elapsed_secs: started.elapsed().as_secs_f64(),
+                });
+            }
+            if met(&state, run_level) {
+                return Ok(history);
+            }
+
+            let remaining = timeout.saturating_sub(started.elapsed());
+            if remaining.is_zero() {
+                return Err(VBoxError::WaitTimedOut {
+                    vm: name.to_string(),
+                    target: target.clone(),
+                    timeout,
+                    history,
+                });
+            }
+            self.sleep(self.poll_interval.min(remaining), name)?;
+        }
+    }
+
+    /// Stop a VM: press the ACPI power button and give the guest `grace` to
+    /// shut down, then power it off hard. A zero grace, or a VM that is
+    /// paused or otherwise not running normally, is powered off right away.
+    pub fn stop_vm(&self, name: &str, grace: Duration) -> VBoxResult<StopOutcome> {
+        let state = self.vm_state(name)?;
+        if state.is_stopped() {
+            return Ok(StopOutcome {
+                method: StopMethod::AlreadyStopped,
+                waited: Duration::ZERO,
+            });
+        }
+
+        let started = Instant::now();
+        if state == VmState::Running && !grace.is_zero() {
+            self.acpi_shutdown(name)?;
+            loop {
+                if self.vm_state(name)?.is_stopped() {
+                    return Ok(StopOutcome {
+                        method: StopMethod::Acpi,
+                        waited: started.elapsed(),
+                    });
+                }
+                let remaining = grace.saturating_sub(started.elapsed());
+                if remaining.is_zero() {
+                    break;
+                }
+                self.sleep(self.poll_interval.min(remaining), name)?;
+            }
+            log::info!("VM '{}' ignored the ACPI power button for {:?}, powering off", name, grace);
+        }
+
+        if let Err(e) = self.power_off(name) {
+            // The guest may have finished shutting down on its own in the meantime
+            if self.vm_state(name)?.is_stopped() {
+                return Ok(StopOutcome {
+                    method: StopMethod::Acpi,
+                    waited: started.elapsed(),
+                });
+            }
+            return Err(e);
+        }
+        Ok(StopOutcome {
+            method: StopMethod::PowerOff,
+            waited: started.elapsed(),
+        })
+    }
+
+    /// Cut the power of a running or paused VM
+    pub fn power_off(&self, name: &str) -> VBoxResult<()> {
+        self.run(&["controlvm", name, "poweroff"])?;
+        Ok(())
+    }
+
+    /// Press the ACPI power button; returns before the guest shuts down
+    pub fn acpi_shutdown(&self, name: &str) -> VBoxResult<()> {
+        self.run(&["controlvm", name, "acpipowerbutton"])?;
+        Ok(())
+    }
+
+    pub fn pause_vm(&self, name: &str) -> VBoxResult<()> {
+        self.run(&["controlvm", name, "pause"])?;
+        Ok(())
+    }
+
+    pub fn resume_vm(&self, name: &str) -> VBoxResult<()> {
+        self.run(&["controlvm", name, "resume"])?;
+        Ok(())
+    }
+
+    /// Save the VM's memory to disk and stop it
+    pub fn save_state(&self, name: &str) -> VBoxResult<()> {
+        self.run(&["controlvm", name, "savestate"])?;
+        Ok(())
+    }
+
+    /// Throw away a saved state, leaving the VM powered off
+    pub fn discard_saved_state(&self, name: &str) -> VBoxResult<()> {
+        self.run(&["discardstate", name])?;
+        Ok(())
+    }
+
+    // Wait between polls of a VM, waking early if the call is cancelled
+    fn sleep(&self, duration: Duration, vm: &str) -> VBoxResult<()> {
+        self.options
+            .sleep(duration)
+            .map_err(|e| VBoxError::from_exec(e, &format!("showvminfo {} --machinereadable", vm)))
+    }
+
+    /// Hard-reset a running VM
+    pub fn reset_vm(&self, name: &str) -> VBoxResult<()> {
+        self.run(&["controlvm", name, "reset"])?;
+        Ok(())
+    }
+
+    /// Set the attachment type of one network adapter
+    pub fn set_nic(&self, vm: &str, nic: &Nic) -> VBoxResult<()> {
+        self.run(&["modifyvm", vm, &format!("--nic{}", nic.index), nic.nic_type.as_str()])?;
+        Ok(())
+    }
+
+    /// Store a key/value pair in the VM's extradata
+    pub fn set_extradata(&self, vm: &str, key: &str, value: &str) -> VBoxResult<()> {
+        self.run(&["setextradata", vm, key, value])?;
+        Ok(())
+    }
+
+    /// Read one extradata value; `None` when the key is not set
+    pub fn get_extradata(&self, vm: &str, key: &str) -> VBoxResult<Option<String>> {
+        let output = self.run(&["getextradata", vm, key])?;
+        Ok(output
+            .lines()
+            .find_map(|line| line.strip_prefix("Value: "))
+            .map(|value| value.to_string()))
+    }
+
+    /// All registered hard disks
+    pub fn list_media(&self) -> VBoxResult<Vec<Medium>> {
+        let output = self.run(&["list", "hdds"])?;
+        Ok(parse_medium_list(&output))
+    }
+
+    /// Whether a hard disk exists; errors other than "not found" are returned
+    pub fn medium_exists(&self, path: &str) -> VBoxResult<bool> {
+        exists(self.run(&["showmediuminfo", "disk", path]))
+    }
+
+    /// Create a dynamically allocated VDI disk
+    pub fn create_medium(&self, path: &str, size_mb: i64) -> VBoxResult<Medium> {
+        let output = self.run(&["createmedium", "disk", "--filename", path, "--size", &size_mb.to_string(), "--format", "VDI"])?;
+        Ok(Medium {
+            id: Some(parse_uuid(&output)),
+            path: Some(parse_field(&output, "Location:").unwrap_or_else(|| path.to_string())),
+            format: Some("VDI".to_string()),
+            size_mb: Some(size_mb),
+            ..Medium::default()
+        })
+    }
+
+    /// Unregister a hard disk and delete its file
+    pub fn delete_medium(&self, path: &str) -> VBoxResult<()> {
+        self.run(&["closemedium", "disk", path, "--delete"])?;
+        Ok(())
+    }
+
+    /// Copy a hard disk to a new file
+    pub fn clone_medium(&self, source: &str, target: &str) -> VBoxResult<Medium> {
+        let output = self.run(&["clonemedium", "disk", source, target])?;
+        Ok(Medium {
+            id: Some(parse_uuid(&output)),
+            path: Some(target.to_string()),
+            ..Medium::default()
+        })
+    }
+
+    /// Add a SATA controller to a VM
+    pub fn add_sata_controller(&self, vm: &str, controller: &str) -> VBoxResult<()> {
+        let spec = ControllerSpec {
+            port_count: Some(30),
+            ..ControllerSpec::new(controller, StorageBus::Sata)
+        };
+        self.add_storage_controller(vm, &spec)
+    }
+
+    /// Add a storage controller to a VM
+    pub fn add_storage_controller(&self, vm: &str, spec: &ControllerSpec) -> VBoxResult<()> {
+        spec.validate()?;
+        let args = spec.storagectl_args(vm);
+        self.run(&args.iter().map(String::as_str).collect::<Vec<_>>())?;
+        Ok(())
+    }
+
+    /// Add a storage controller unless the VM already has one by that name;
+    /// true if it was added
+    pub fn ensure_storage_controller(&self, vm: &str, spec: &ControllerSpec) -> VBoxResult<bool> {
+        if self.list_storage_controllers(vm)?.iter().any(|c| c.name == spec.name) {
+            return Ok(false);
+        }
+        self.add_storage_controller(vm, spec)?;
+        Ok(true)
+    }
+
+    /// Remove a storage controller along with everything attached to it
+    pub fn remove_storage_controller(&self, vm: &str, controller: &str) -> VBoxResult<()> {
+        self.run(&["storagectl", vm, "--name", controller, "--remove"])?;
+        Ok(())
+    }
+
+    /// Media on a VM's controller ports, empty drives included, with their
+    /// type and parent looked up in the medium registry
+    pub fn list_attachments(&self, vm: &str) -> VBoxResult<Vec<StorageAttachment>> {
+        let mut attachments = self.vm_info(vm)?.storage_attachments;
+        let mut registry = HashMap::new();
+        if attachments.iter().any(|a| a.medium_id.is_some()) {
+            for (list, device_type) in [("hdds", DeviceType::Hdd), ("dvds", DeviceType::DvdDrive), ("floppies", DeviceType::Fdd)] {
+                for medium in parse_medium_list(&self.run(&["list", list])?) {
+                    if let Some(id) = medium.id.clone() {
+                        registry.insert(id, (device_type, medium));
+                    }
+                }
+            }
+        }
+        for attachment in &mut attachments {
+            match attachment.medium_id.as_ref().map(|id| registry.get(id)) {
+                Some(Some((device_type, medium))) => {
+                    attachment.device_type = Some(*device_type);
+                    attachment.parent_id = medium.parent.clone().filter(|parent| parent != "base");
+                    attachment.differencing = Some(attachment.parent_id.is_some());
+                }
+                // Unregistered, e.g. detached meanwhile; leave it unknown
+                Some(None) => {}
+                // Empty and host drives
+                None => attachment.differencing = Some(false),
+            }
+        }
+        Ok(attachments)
+    }
+
+    /// Storage controllers of a VM
+    pub fn list_storage_controllers(&self, vm: &str) -> VBoxResult<Vec<StorageController>> {
+        Ok(self.vm_info(vm)?.storage_controllers)
+    }
+
+    /// Insert a medium into the DVD drive on a controller port
+    pub fn attach_dvd(&self, vm: &str, controller: &str, port: u32, medium: &str) -> VBoxResult<()> {
+        let options = AttachOptions {
+            device_type: DeviceType::DvdDrive,
+            ..AttachOptions::default()
+        };
+        self.attach_medium(vm, controller, port, medium, &options)
+    }
+
+    /// Attach a hard disk image to a controller port
+    pub fn attach_disk(&self, vm: &str, controller: &str, port: u32, medium: &str) -> VBoxResult<()> {
+        self.attach_medium(vm, controller, port, medium, &AttachOptions::default())
+    }
+
+    /// Attach a medium as the device `options` describes
+    pub fn attach_medium(&self, vm: &str, controller: &str, port: u32, medium: &str, options: &AttachOptions) -> VBoxResult<()> {
+        options.validate()?;
+        let (port, device) = (port.to_string(), options.device.to_string());
+        let mut args = vec![
+            "storageattach",
+            vm,
+            "--storagectl",
+            controller,
+            "--port",
+            &port,
+            "--device",
+            &device,
+            "--type",
+            options.device_type.as_str(),
+            "--medium",
+            medium,
+        ];
+        let flags = [
+            ("--nonrotational", options.nonrotational),
+            ("--discard", options.discard),
+            ("--hotpluggable", options.hotpluggable),
+        ];
+        for (flag, value) in flags {
+            if let Some(value) = value {
+                args.extend([flag, if value { "on" } else { "off" }]);
+            }
+        }
+        if let Some(mtype) = &options.mtype {
+            args.extend(["--mtype", mtype.as_str()]);
+        }
+        self.run(&args)?;
+        Ok(())
+    }
+
+    /// Remove whatever is attached to one device slot of a controller port
+    pub fn detach_device(&self, vm: &str, controller: &str, port: u32, device: u32) -> VBoxResult<()> {
+        self.run(&[
+            "storageattach",
+            vm,
+            "--storagectl",
+            controller,
+            "--port",
+            &port.to_string(),
+            "--device",
+            &device.to_string(),
+            "--medium",
+            "none",
+        ])?;
+        Ok(())
+    }
+
+    pub fn take_snapshot(&self, vm: &str, name: &str) -> VBoxResult<Snapshot> {
+        let output = self.run(&["snapshot", vm, "take", name])?;
+        Ok(Snapshot {
+            name: name.to_string(),
+            id: parse_uuid(&output),
+            description: None,
+            parent: None,
+        })
+    }
+
+    pub fn delete_snapshot(&self, vm: &str, name: &str) -> VBoxResult<()> {
+        self.run(&["snapshot", vm, "delete", name])?;
+        Ok(())
+    }
+
+    /// Snapshots of a VM, depth first; empty when it has none
+    pub fn list_snapshots(&self, vm: &str) -> VBoxResult<Vec<Snapshot>> {
+        match self.run(&["snapshot", vm, "list", "--machinereadable"]) {
+            Ok(output) => Ok(parse_snapshot_list(&output)),
+            // VBoxManage reports an empty snapshot tree as an error
+            Err(VBoxError::Command(failure)) if failure.stderr.contains("does not have any snapshots") => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// VBoxManage only prints SessionName while a session holds the machine lock
+fn is_session_locked(vm: &Vm) -> bool {
+    vm.properties.contains_key("SessionName")
+}
+
+// Match a medium by full path, UUID or file name
+fn medium_matches(attachment: &StorageAttachment, wanted: &str) -> bool {
+    let Some(path) = attachment.medium.as_deref() else {
+        return false;
+    };
+    path == wanted
+        || attachment.medium_id.as_deref() == Some(wanted)
+        || std::path::Path::new(path).file_name().is_some_and(|f| f == wanted)
+}
+
+// Map "not found" to false so existence checks don't fail on missing objects
+fn exists<T>(result: VBoxResult<T>) -> VBoxResult<bool> {
+    match result {
+        Ok(_) => Ok(true),
+        Err(e) if e.is_not_found() => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// Existing files `unregistervm --delete` may remove: the settings file with
+// its backup and NVRAM, the saved state, the hard disks of the VM and its
+// snapshots (whose differencing images and saved states live in the
+// snapshot folder), the VM's logs and anything else in the VM's folder
+fn deletion_candidates(vm: &Vm, hard_disks: &[Medium], keep: &[StorageAttachment]) -> Vec<String> {
+    let kept = |path: &str| keep.iter().any(|a| a.medium.as_deref() == Some(path));
+    let mut files = Vec::new();
+
+    if let Some(config) = &vm.config_file {
+        files.push(config.clone());
+        files.push(format!("{}-prev", config));
+        files.push(Path::new(config).with_extension("nvram").to_string_lossy().into_owned());
+    }
+    files.extend(vm.properties.get("VMStateFile").cloned());
+    for attachment in &vm.storage_attachments {
+        if keep.contains(attachment) {
+            continue;
+        }
+        let is_hard_disk = hard_disks.iter().any(|m| {
+            (m.id.is_some() && m.id == attachment.medium_id) || (m.path.is_some() && m.path == attachment.medium)
+        });
+        if is_hard_disk && let Some(path) = &attachment.medium {
+            files.push(path.clone());
+        }
+    }
+    if let Some(snapshots) = vm.properties.get("SnapFldr").map(Path::new) {
+        for path in hard_disks.iter().filter_map(|m| m.path.as_deref()) {
+            if Path::new(path).starts_with(snapshots) && !kept(path) {
+                files.push(path.to_string());
+            }
+        }
+        files.extend(folder_files(snapshots, |name| name.ends_with(".sav")));
+    }
+    if let Some(logs) = vm.properties.get("LogFldr") {
+        files.extend(folder_files(Path::new(logs), |name| name.starts_with("VBox") && name.contains(".log")));
+    }
+    if let Some(folder) = vm.config_file.as_deref().and_then(|config| Path::new(config).parent()) {
+        files.extend(folder_files(folder, |_| true).into_iter().filter(|path| !kept(path)));
+    }
+
+    let mut seen = HashSet::new();
+    files.retain(|f| Path::new(f).is_file() && seen.insert(f.clone()));
+    files
+}
+
+// Sorted paths of the files in `folder` whose name passes `wanted`; none if
+// the folder cannot be read
+fn folder_files(folder: &Path, wanted: impl Fn(&str) -> bool) -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(folder) else {
+        return Vec::new();
+    };
+    let mut files: Vec<String> = entries
+        .filter_map(Result::ok)
+        .filter(|e| e.file_name().to_str().is_some_and(&wanted))
+        .map(|e| e.path().to_string_lossy().into_owned())
+        .collect();
+    files.sort();
+    files
+}
+
+// A temporary file readable only by the current user, for secrets VBoxManage
+// can read from a file; removed when dropped
+struct SecretFile(PathBuf);
+
+impl SecretFile {
+    fn create(contents: &str) -> VBoxResult<Self> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "cpi-virtualbox-{}-{}.secret",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut open = OpenOptions::new();
+        open.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut open, 0o600);
+        let io_error = |e: std::io::Error| VBoxError::Io {
+            path: path.to_string_lossy().into_owned(),
+            message: e.to_string(),
+        };
+        let mut file = open.open(&path).map_err(io_error)?;
+        // Remove the file even if writing fails
+        let secret = SecretFile(path.clone());
+        file.write_all(contents.as_bytes()).map_err(io_error)?;
+        Ok(secret)
+    }
+}
+
+impl Drop for SecretFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            log::warn!("Could not remove '{}': {}", self.0.display(), e);
+        }
+    }
+}
+
+// "... UUID: <uuid>" as printed by createvm/createmedium/clonemedium/snapshot take
+fn parse_uuid(output: &str) -> String {
+    parse_field(output, "UUID:").unwrap_or_default()
+}
+
+// `modifyvm` flags for everything in `config` beyond name and OS type
+fn hardware_args(config: &VmConfig) -> Vec<String> {
+    let on_off = |flag: bool| if flag { "on" } else { "off" }.to_string();
+    let mut args = vec![
+        "--memory".to_string(),
+        config.memory_mb.to_string(),
+        "--cpus".to_string(),
+        config.cpu_count.to_string(),
+    ];
+    let mut push = |flag: &str, value: String| {
+        args.push(flag.to_string());
+        args.push(value);
+    };
+    if let Some(firmware) = &config.firmware {
+        push("--firmware", firmware.to_ascii_lowercase());
+    }
+    if let Some(chipset) = &config.chipset {
+        push("--chipset", chipset.to_ascii_lowercase());
+    }
+    if let Some(vram) = config.vram_mb {
+        push("--vram", vram.to_string());
+    }
+    if let Some(controller) = &config.graphics_controller {
+        push("--graphicscontroller", controller.to_ascii_lowercase());
+    }
+    if let Some(nested) = config.nested_virtualization {
+        push("--nested-hw-virt", on_off(nested));
+    }
+    if let Some(pae) = config.pae {
+        push("--pae", on_off(pae));
+    }
+    if let Some(provider) = &config.paravirt_provider {
+        push("--paravirt-provider", provider.to_ascii_lowercase());
+    }
+    if !config.boot_order.is_empty() {
+        for slot in 0..4 {
+            let device = config.boot_order.get(slot).map_or("none".to_string(), |d| d.to_ascii_lowercase());
+            push(&format!("--boot{}", slot + 1), device);
+        }
+    }
+    if let Some(description) = &config.description {
+        push("--description", description.clone());
+    }
+    if let Some(utc) = config.rtc_utc {
+        push("--rtc-use-utc", on_off(utc));
+    }
+    if let Some(audio) = config.audio {
+        push("--audio-enabled", on_off(audio));
+    }
+    args
+}
+
+fn parse_field(output: &str, label: &str) -> Option<String> {
+    output
+        .lines()
+        .find_map(|line| line.split_once(label))
+        .map(|(_, value)| value.trim().to_string())
+}
+
+/// Parse the virtual system description printed by `import --dry-run`:
+/// numbered entries under `Virtual system <N>:` headings, each followed by
+/// indented hints in parentheses
+pub fn parse_appliance(output: &str) -> Vec<ApplianceEntry> {
+    let mut entries = Vec::new();
+    let mut system = None;
+    for line in output.lines() {
+        let trimmed = line.trim();
+        if let Some(rest) = trimmed.strip_prefix("Virtual system ") {
+            system = rest.trim_end_matches(':').parse().ok();
+            continue;
+        }
+        let Some(system) = system else {
+            continue;
+        };
+        let Some((unit, text)) = trimmed.split_once(": ") else {
+            continue;
+        };
+        let Ok(unit) = unit.parse() else {
+            continue;
+        };
+        let (kind, value) = appliance_entry(text);
+        entries.push(ApplianceEntry {
+            system,
+            unit,
+            kind: kind.to_string(),
+            value,
+            text: text.to_string(),
+        });
+    }
+    entries
+}
+
+// Classify one description entry and pull out its suggested value
+fn appliance_entry(text: &str) -> (&'static str, Option<String>) {
+    let quoted = || {
+        let start = text.find('"')?;
+        let end = text.rfind('"').filter(|end| *end > start)?;
+        Some(text[start + 1..end].to_string())
+    };
+    let after_colon = || text.split_once(": ").map(|(_, value)| value.trim().to_string());
+
+    if text.starts_with("Suggested OS type") {
+        ("os_type", quoted())
+    } else if text.starts_with("Suggested VM name") {
+        ("name", quoted())
+    } else if text.starts_with("Suggested VM group") {
+        ("group", quoted())
+    } else if text.starts_with("Suggested VM settings file name") {
+        ("settings_file", quoted())
+    } else if text.starts_with("Suggested VM base folder") {
+        ("base_folder", quoted())
+    } else if text.starts_with("Number of CPUs") {
+        ("cpu_count", after_colon())
+    } else if text.starts_with("Guest memory") {
+        ("memory_mb", after_colon().map(|m| m.trim_end_matches(" MB").to_string()))
+    } else if text.starts_with("Network adapter") {
+        ("network_adapter", after_colon())
+    } else if text.starts_with("Hard disk image") {
+        let target = text
+            .split(", ")
+            .find_map(|part| part.strip_prefix("target path="))
+            .map(String::from);
+        ("hard_disk", target)
+    } else if text.contains("controller, type") {
+        ("controller", text.split_once(", type ").map(|(_, t)| t.to_string()))
+    } else if text.starts_with("Description") {
+        ("description", after_colon())
+    } else if text.starts_with("Product URL") {
+        ("product_url", after_colon())
+    } else if text.starts_with("Product") {
+        ("product", after_colon())
+    } else if text.starts_with("Vendor URL") {
+        ("vendor_url", after_colon())
+    } else if text.starts_with("Vendor") {
+        ("vendor", after_colon())
+    } else if text.starts_with("Version") {
+        ("version", after_colon())
+    } else if text.starts_with("End-user license agreement") || text.starts_with("License") {
+        ("license", None)
+    } else {
+        ("other", after_colon())
+    }
+}
+
+/// Parse `list vms` output: one `"name" {uuid}` per line
+pub fn parse_vm_list(output: &str) -> Vec<VmSummary> {
+    let mut vms = Vec::new();
+    for line in output.lines() {
+        if let (Some(first_quote), Some(last_quote)) = (line.find('"'), line.rfind('"'))
+            && first_quote < last_quote
+            && let (Some(open_brace), Some(close_brace)) = (line.rfind('{'), line.rfind('}'))
+            && last_quote < open_brace
+            && open_brace < close_brace
+        {
+            let name = line[first_quote + 1..last_quote].to_string();
+            let id = line[open_brace + 1..close_brace].to_string();
+            log::trace!("Parsed VM: name='{}', uuid='{}'", name, id);
+            vms.push(VmSummary { name, id });
+        }
+    }
+    vms
+}
+
+/// Parse `list -l vms` output: one human-readable `showvminfo` block per VM.
+/// Only the VM-level `Label:   value` lines are read; a block starts at a
+/// column-aligned `Name:` line (shared folders print `Name: 'x', ...`).
+pub fn parse_vm_long_list(output: &str) -> Vec<Vm> {
+    let mut vms: Vec<Vm> = Vec::new();
+    let mut seen: Vec<&str> = Vec::new();
+
+    for line in output.lines() {
+        let Some((label, value)) = line.split_once(':') else {
+            continue;
+        };
+        if label.starts_with(' ') {
+            continue;
+        }
+        if label == "Name" && (value.starts_with("  ") || vms.is_empty()) {
+            vms.push(Vm::default());
+            seen.clear();
+        }
+        // Labels such as "UUID" repeat for snapshots and media; the first one is the VM's
+        if seen.contains(&label) {
+            continue;
+        }
+        seen.push(label);
+        let Some(vm) = vms.last_mut() else {
+            continue;
+        };
+        let value = value.trim();
+        match label {
+            "Name" => vm.name = Some(value.to_string()),
+            "UUID" => vm.id = Some(value.to_string()),
+            "Guest OS" => vm.os_description = Some(value.to_string()),
+            "Groups" => vm.groups = value.split(',').filter(|g| !g.is_empty()).map(String::from).collect(),
+            "Memory size" => vm.memory_mb = value.trim_end_matches("MB").trim().parse().ok(),
+            "Number of CPUs" => vm.cpu_count = value.parse().ok(),
+            "State" => vm.state = Some(VmState::from_description(value)),
+            "Config file" => vm.config_file = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    vms
+}
+
+/// Parse `list hdds` output: blank-line separated `Label: value` blocks
+pub fn parse_medium_list(output: &str) -> Vec<Medium> {
+    let mut media = Vec::new();
+    for block in output.split("\n\n") {
+        let mut medium = Medium::default();
+        for line in block.lines() {
+            let Some((label, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim().to_string();
+            match label.trim() {
+                "UUID" => medium.id = Some(value),
+                "Parent UUID" => medium.parent = Some(value),
+                "State" => medium.state = Some(value),
+                "Type" => medium.medium_type = Some(value),
+                "Location" => medium.path = Some(value),
+                "Format" | "Storage format" => medium.format = Some(value),
+                "Capacity" => {
+                    if let Some(size) = value.strip_suffix(" MBytes") {
+                        medium.size_mb = size.trim().parse().ok();
+                    }
+                }
+                _ => {}
+            }
+        }
+        if medium != Medium::default() {
+            media.push(medium);
+        }
+    }
+    media
+}
+
+/// Parse `list ostypes` output into OS type ids keyed by description
+pub fn parse_os_types(output: &str) -> HashMap<String, String> {
+    let mut ids = HashMap::new();
+    for block in output.split("\n\n") {
+        let field = |label: &str| {
+            block
+                .lines()
+                .find_map(|line| line.split_once(':').filter(|(l, _)| l.trim() == label).map(|(_, v)| v.trim().to_string()))
+        };
+        if let (Some(id), Some(description)) = (field("ID"), field("Description")) {
+            ids.insert(description, id);
+        }
+    }
+    ids
+}
+
+/// Parse `snapshot list --machinereadable` output
+pub fn parse_snapshot_list(output: &str) -> Vec<Snapshot> {
+    machinereadable::snapshots(&MachineReadable::parse(output))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn os_types_are_keyed_by_description() {
+        let output = "ID:          Ubuntu_64\nDescription: Ubuntu (64-bit)\nFamily ID:   Linux\n\nID:          Other\nDescription: Other/Unknown\n";
+        let ids = parse_os_types(output);
+        assert_eq!(ids.len(), 2);
+        assert_eq!(ids["Ubuntu (64-bit)"], "Ubuntu_64");
+        assert_eq!(ids["Other/Unknown"], "Other");
+    }
+
+    #[test]
+    fn vm_list_handles_quotes_and_braces_in_names() {
+        let vms = parse_vm_list("\"web \"1\"\" {aaaa}\n\"db {x}\" {bbbb}\n\n");
+        assert_eq!(vms.len(), 2);
+        assert_eq!(vms[0].name, "web \"1\"");
+        assert_eq!(vms[1], VmSummary { name: "db {x}".to_string(), id: "bbbb".to_string() });
+    }
+
+    #[test]
+    fn long_vm_list_reads_each_block() {
+        let output = "Name:                        web-1\nGroups:                      /omni,/web\nGuest OS:                    Ubuntu (64-bit)\nUUID:                        u1\nMemory size:                 2048MB\nNumber of CPUs:              2\nState:                       running (since 2024-01-01T10:00:00.000000000)\nName: 'data', Host path: '/srv/data' (machine mapping), writable\nSnapshots:\n\n   Name: base (UUID: s1)\nUUID:                        s1\n\nName:                        db-1\nUUID:                        u2\nState:                       powered off (since 2024-01-01T10:00:00.000000000)\n";
+        let vms = parse_vm_long_list(output);
+        assert_eq!(vms.len(), 2);
+        assert_eq!(vms[0].name.as_deref(), Some("web-1"));
+        assert_eq!(vms[0].id.as_deref(), Some("u1"));
+        assert_eq!(vms[0].groups, vec!["/omni", "/web"]);
+        assert_eq!(vms[0].memory_mb, Some(2048));
+        assert_eq!(vms[0].state, Some(VmState::Running));
+        assert_eq!(vms[1].state, Some(VmState::PowerOff));
+    }
+
+    #[test]
+    fn appliance_description_is_classified() {
+        let output = r#"0%...10%...20%...30%...40%...50%...60%...70%...80%...90%...100%
+Interpreting /images/ubuntu.ova...
+OK.
+Disks:
+  vmdisk1	10737418240	-1	http://www.vmware.com/interfaces/specifications/vmdk.html#streamOptimized	ubuntu-disk001.vmdk	-1	-1
+
+Virtual system 0:
+ 0: Suggested OS type: "Ubuntu_64"
+    (change with "--vsys 0 --ostype <type>"; use "list ostypes" to list all possible values)
+ 1: Suggested VM name "ubuntu-template"
+    (change with "--vsys 0 --vmname <name>")
+ 2: Product (ignored): Golden Ubuntu
+ 3: Number of CPUs: 2
+    (change with "--vsys 0 --cpus <n>")
+ 4: Guest memory: 2048 MB
+    (change with "--vsys 0 --memory <MB>")
+ 5: Network adapter: orig NAT, config 3, extra slot=0;type=NAT
+ 6: SATA controller, type AHCI
+    (disable with "--vsys 0 --unit 6 --ignore")
+ 7: Hard disk image: source image=ubuntu-disk001.vmdk, target path=/vms/ubuntu-template/ubuntu-disk001.vmdk, controller=6;channel=0
+    (change target path with "--vsys 0 --unit 7 --disk path";
+    disable with "--vsys 0 --unit 7 --ignore")
+"#;
+        let entries = parse_appliance(output);
+        let kinds: Vec<_> = entries.iter().map(|e| (e.unit, e.kind.as_str(), e.value.as_deref())).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (0, "os_type", Some("Ubuntu_64")),
+                (1, "name", Some("ubuntu-template")),
+                (2, "product", Some("Golden Ubuntu")),
+                (3, "cpu_count", Some("2")),
+                (4, "memory_mb", Some("2048")),
+                (5, "network_adapter", Some("orig NAT, config 3, extra slot=0;type=NAT")),
+                (6, "controller", Some("AHCI")),
+                (7, "hard_disk", Some("/vms/ubuntu-template/ubuntu-disk001.vmdk")),
+            ]
+        );
+        assert!(entries.iter().all(|e| e.system == 0));
+    }
+
+    #[test]
+    fn snapshot_list_pairs_names_with_ids() {
+        let listing = "SnapshotName=\"base\"\nSnapshotUUID=\"u1\"\nSnapshotName-1=\"child\"\nSnapshotUUID-1=\"u2\"\nCurrentSnapshotName=\"child\"\nCurrentSnapshotUUID=\"u2\"\n";
+        let snapshots = parse_snapshot_list(listing);
+        assert_eq!(
+            snapshots,
+            vec![
+                Snapshot { name: "base".to_string(), id: "u1".to_string(), description: None, parent: None },
+                Snapshot { name: "child".to_string(), id: "u2".to_string(), description: None, parent: Some("base".to_string()) },
+            ]
+        );
+    }
+
+    #[test]
+    fn medium_list_reads_capacity_in_mbytes() {
+        let media = parse_medium_list("UUID:           u1\nLocation:       /vms/a.vdi\nStorage format: VDI\nCapacity:       512 MBytes\n");
+        assert_eq!(media[0].size_mb, Some(512));
+        assert_eq!(media[0].format.as_deref(), Some("VDI"));
+    }
+}
@@ -0,0 +1,165 @@
+// File: cpi_virtualbox/src/cloudinit.rs
+//! cloud-init NoCloud seeds: `user-data`, `meta-data` and `network-config`
+//! on an ISO9660 volume labelled `cidata`, which cloud-init finds on any
+//! attached drive at first boot. Each document is written as JSON, which
+//! YAML parsers read as it is.
+use crate::iso;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::io;
+use std::path::Path;
+
+/// Volume label cloud-init looks for
+pub const SEED_VOLUME_ID: &str = "cidata";
+
+/// A user to create in the guest, besides the distribution's default user
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CloudInitUser {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gecos: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    /// sudoers rule, e.g. `ALL=(ALL) NOPASSWD:ALL`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sudo: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shell: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ssh_authorized_keys: Vec<String>,
+}
+
+/// Contents of a NoCloud seed
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CloudInitSeed {
+    /// Changing it makes cloud-init run its per-instance modules again
+    pub instance_id: String,
+    pub hostname: String,
+    /// Keys for the default user
+    pub ssh_authorized_keys: Vec<String>,
+    pub users: Vec<CloudInitUser>,
+    /// Commands run once at the end of first boot
+    pub runcmd: Vec<String>,
+    /// Network configuration (version 1 or 2); DHCP on every `en*`
+    /// interface when `None`
+    pub network_config: Option<Value>,
+}
+
+impl CloudInitSeed {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.instance_id.is_empty() || self.hostname.is_empty() {
+            return Err("A seed needs an instance id and a hostname".to_string());
+        }
+        if let Some(user) = self.users.iter().find(|u| u.name.is_empty() || u.name == "default") {
+            return Err(format!("Invalid user name '{}'", user.name));
+        }
+        if let Some(config) = &self.network_config
+            && !config.get("version").is_some_and(Value::is_u64)
+        {
+            return Err("Network config must be an object with a 'version'".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn user_data(&self) -> String {
+        let mut config = Map::new();
+        config.insert("hostname".to_string(), json!(self.hostname));
+        if !self.ssh_authorized_keys.is_empty() {
+            config.insert("ssh_authorized_keys".to_string(), json!(self.ssh_authorized_keys));
+        }
+        if !self.users.is_empty() {
+            // Listing users replaces the default user unless it is kept explicitly
+            let mut users = vec![json!("default")];
+            users.extend(self.users.iter().map(|u| json!(u)));
+            config.insert("users".to_string(), Value::Array(users));
+        }
+        if !self.runcmd.is_empty() {
+            config.insert("runcmd".to_string(), json!(self.runcmd));
+        }
+        format!("#cloud-config\n{}\n", serde_json::to_string_pretty(&config).unwrap_or_default())
+    }
+
+    pub fn meta_data(&self) -> String {
+        let meta = json!({
+            "instance-id": self.instance_id,
+            "local-hostname": self.hostname
+        });
+        format!("{}\n", meta)
+    }
+
+    pub fn network_config(&self) -> String {
+        let config = self.network_config.clone().unwrap_or_else(|| {
+            json!({
+                "version": 2,
+                "ethernets": {
+                    "all-en": {
+                        "match": { "name": "en*" },
+                        "dhcp4": true
+                    }
+                }
+            })
+        });
+        format!("{}\n", serde_json::to_string_pretty(&config).unwrap_or_default())
+    }
+
+    /// Write the seed as an ISO image at `path`
+    pub fn write_iso(&self, path: &Path) -> io::Result<()> {
+        let (user_data, meta_data, network_config) = (self.user_data(), self.meta_data(), self.network_config());
+        iso::write_iso(
+            path,
+            SEED_VOLUME_ID,
+            &[
+                ("user-data", user_data.as_bytes()),
+                ("meta-data", meta_data.as_bytes()),
+                ("network-config", network_config.as_bytes()),
+            ],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_data_keeps_the_default_user() {
+        let seed = CloudInitSeed {
+            instance_id: "iid-web-1".to_string(),
+            hostname: "web-1".to_string(),
+            ssh_authorized_keys: vec!["ssh-ed25519 AAAA ops".to_string()],
+            users: vec![CloudInitUser {
+                name: "deploy".to_string(),
+                sudo: Some("ALL=(ALL) NOPASSWD:ALL".to_string()),
+                ..CloudInitUser::default()
+            }],
+            runcmd: vec!["systemctl enable --now nginx".to_string()],
+            network_config: None,
+        };
+        seed.validate().unwrap();
+
+        let user_data = seed.user_data();
+        let body = user_data.strip_prefix("#cloud-config\n").unwrap();
+        let config: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(config["users"][0], "default");
+        assert_eq!(config["users"][1]["name"], "deploy");
+        assert_eq!(config["ssh_authorized_keys"][0], "ssh-ed25519 AAAA ops");
+        assert_eq!(config["runcmd"][0], "systemctl enable --now nginx");
+
+        let meta: Value = serde_json::from_str(&seed.meta_data()).unwrap();
+        assert_eq!(meta["local-hostname"], "web-1");
+        let network: Value = serde_json::from_str(&seed.network_config()).unwrap();
+        assert_eq!(network["version"], 2);
+    }
+
+    #[test]
+    fn network_config_needs_a_version() {
+        let seed = CloudInitSeed {
+            instance_id: "iid-web-1".to_string(),
+            hostname: "web-1".to_string(),
+            network_config: Some(json!({ "ethernets": {} })),
+            ..CloudInitSeed::default()
+        };
+        assert!(seed.validate().is_err());
+    }
+}
@@ -0,0 +1,404 @@
+// File: cpi_virtualbox/src/error.rs
+use serde_json::{json, Value};
+use std::fmt;
+use std::time::Duration;
+
+use crate::executor::ExecError;
+use crate::types::{StateTransition, WaitTarget};
+
+/// Broad category of a VBoxManage failure, derived from its result code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// VM, medium, snapshot or controller does not exist
+    NotFound,
+    /// Object is locked by a session or another task
+    Locked,
+    /// Operation not valid in the object's or VM's current state
+    InvalidState,
+    /// Object is still in use, e.g. a medium attached to a VM
+    InUse,
+    AccessDenied,
+    FileError,
+    InvalidArgument,
+    NotSupported,
+    /// Anything VBoxManage reported that we don't classify further
+    Failed,
+}
+
+impl ErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::NotFound => "not_found",
+            ErrorKind::Locked => "locked",
+            ErrorKind::InvalidState => "invalid_state",
+            ErrorKind::InUse => "in_use",
+            ErrorKind::AccessDenied => "access_denied",
+            ErrorKind::FileError => "file_error",
+            ErrorKind::InvalidArgument => "invalid_argument",
+            ErrorKind::NotSupported => "not_supported",
+            ErrorKind::Failed => "command_failed",
+        }
+    }
+}
+
+/// A failure reported by VBoxManage on stderr, split into its parts
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct VBoxFailure {
+    /// Result code name, e.g. `VBOX_E_OBJECT_NOT_FOUND`
+    pub code: Option<String>,
+    /// Numeric result code as printed, e.g. `0x80bb0001`
+    pub hresult: Option<String>,
+    pub component: Option<String>,
+    pub interface: Option<String>,
+    pub callee: Option<String>,
+    /// The `Context:` line naming the failing API call
+    pub context: Option<String>,
+    /// Human-readable error lines, without the `VBoxManage: error:` prefix
+    pub messages: Vec<String>,
+    /// The command line that failed, without the binary name
+    pub command: String,
+    pub stderr: String,
+}
+
+impl VBoxFailure {
+    /// Parse VBoxManage stderr such as:
+    ///
+    /// ```text
+    /// VBoxManage: error: Could not find a registered machine named 'ghost'
+    /// VBoxManage: error: Details: code VBOX_E_OBJECT_NOT_FOUND (0x80bb0001), component VirtualBoxWrap, interface IVirtualBox, callee nsISupports
+    /// VBoxManage: error: Context: "FindMachine(...)" at line 2721 of file VBoxManageInfo.cpp
+    /// ```
+    pub fn parse(command: &str, stderr: &str) -> Self {
+        let mut failure = VBoxFailure {
+            command: command.to_string(),
+            stderr: stderr.to_string(),
+            ..Default::default()
+        };
+        let prefixed = stderr.lines().any(|l| l.starts_with("VBoxManage: error:"));
+
+        for line in stderr.lines() {
+            let line = match line.strip_prefix("VBoxManage: error:") {
+                Some(rest) => rest.trim(),
+                None if prefixed => continue,
+                None => line.trim(),
+            };
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(details) = line.strip_prefix("Details:") {
+                failure.parse_details(details);
+            } else if let Some(context) = line.strip_prefix("Context:") {
+                failure.context = Some(context.trim().to_string());
+            } else {
+                failure.messages.push(line.to_string());
+            }
+        }
+        failure
+    }
+
+    // "code VBOX_E_OBJECT_NOT_FOUND (0x80bb0001), component X, interface Y, callee Z"
+    fn parse_details(&mut self, details: &str) {
+        for part in details.split(',') {
+            let part = part.trim();
+            let (key, value) = match part.split_once(' ') {
+                Some((key, value)) => (key, value.trim()),
+                None => continue,
+            };
+            match key {
+                "code" => {
+                    let (name, hresult) = match value.split_once(' ') {
+                        Some((name, hex)) => (name, Some(hex.trim_matches(|c| c == '(' || c == ')'))),
+                        None => (value, None),
+                    };
+                    self.code = Some(name.to_string());
+                    self.hresult = hresult.map(|h| h.to_string());
+                }
+                "component" => self.component = Some(value.to_string()),
+                "interface" => self.interface = Some(value.to_string()),
+                "callee" => self.callee = Some(value.to_string()),
+                _ => {}
+            }
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        let message = self.messages.join(" ");
+        match self.code.as_deref() {
+            Some("VBOX_E_OBJECT_NOT_FOUND") => ErrorKind::NotFound,
+            Some("VBOX_E_INVALID_OBJECT_STATE") if message.contains("lock") => ErrorKind::Locked,
+            Some("VBOX_E_INVALID_OBJECT_STATE") | Some("VBOX_E_INVALID_VM_STATE") => ErrorKind::InvalidState,
+            Some("VBOX_E_OBJECT_IN_USE") => ErrorKind::InUse,
+            Some("E_ACCESSDENIED") => ErrorKind::AccessDenied,
+            Some("VBOX_E_FILE_ERROR") | Some("VBOX_E_IPRT_ERROR") if message.contains("Could not find") => {
+                ErrorKind::NotFound
+            }
+            Some("VBOX_E_FILE_ERROR") => ErrorKind::FileError,
+            Some("E_INVALIDARG") => ErrorKind::InvalidArgument,
+            Some("VBOX_E_NOT_SUPPORTED") | Some("E_NOTIMPL") => ErrorKind::NotSupported,
+            // Older releases and some subcommands print no Details line
+            _ if message.contains("Could not find") => ErrorKind::NotFound,
+            _ if message.contains("is already locked") => ErrorKind::Locked,
+            _ => ErrorKind::Failed,
+        }
+    }
+
+    /// The error lines as one sentence, or the raw stderr if none were found
+    pub fn message(&self) -> String {
+        if self.messages.is_empty() {
+            self.stderr.trim().to_string()
+        } else {
+            self.messages.join(" ")
+        }
+    }
+}
+
+/// Every way an action can fail
+#[derive(Debug, Clone, PartialEq)]
+pub enum VBoxError {
+    /// VBoxManage ran and exited non-zero
+    Command(Box<VBoxFailure>),
+    /// The VBoxManage binary does not exist at the resolved path
+    BinaryMissing(String),
+    /// VBoxManage could not be started for another reason
+    Spawn(String),
+    TimedOut { command: String, timeout: Duration },
+    Cancelled { command: String },
+    /// A VM did not reach the awaited state in time
+    WaitTimedOut {
+        vm: String,
+        target: WaitTarget,
+        timeout: Duration,
+        history: Vec<StateTransition>,
+    },
+    /// One step of a multi-step operation failed; `rolled_back` tells
+    /// whether what was already done has been undone
+    StepFailed {
+        vm: String,
+        step: String,
+        rolled_back: bool,
+        source: Box<VBoxError>,
+    },
+    /// VBoxManage succeeded but its output did not contain what was expected
+    UnexpectedOutput(String),
+    /// A file the extension writes itself could not be written
+    Io { path: String, message: String },
+    /// A parameter was missing, malformed or out of range
+    InvalidParameter(String),
+    UnknownAction(String),
+}
+
+pub type VBoxResult<T> = Result<T, VBoxError>;
+
+impl VBoxError {
+    pub fn from_exec(error: ExecError, command: &str) -> Self {
+        match error {
+            ExecError::BinaryMissing(message) => VBoxError::BinaryMissing(message),
+            ExecError::Spawn(message) => VBoxError::Spawn(message),
+            ExecError::TimedOut(timeout) => VBoxError::TimedOut {
+                command: command.to_string(),
+                timeout,
+            },
+            ExecError::Cancelled => VBoxError::Cancelled {
+                command: command.to_string(),
+            },
+        }
+    }
+
+    /// Machine-readable category, stable across releases
+    pub fn kind(&self) -> &'static str {
+        match self {
+            VBoxError::Command(failure) => failure.kind().as_str(),
+            VBoxError::BinaryMissing(_) => "binary_missing",
+            VBoxError::Spawn(_) => "spawn_failed",
+            VBoxError::TimedOut { .. } => "timeout",
+            VBoxError::Cancelled { .. } => "cancelled",
+            VBoxError::WaitTimedOut { .. } => "wait_timeout",
+            VBoxError::StepFailed { source, .. } => source.kind(),
+            VBoxError::UnexpectedOutput(_) => "unexpected_output",
+            VBoxError::Io { .. } => ErrorKind::FileError.as_str(),
+            VBoxError::InvalidParameter(_) => "invalid_parameter",
+            VBoxError::UnknownAction(_) => "unknown_action",
+        }
+    }
+
+    /// Whether VBoxManage reported the target object as missing
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, VBoxError::Command(f) if f.kind() == ErrorKind::NotFound)
+    }
+
+    /// JSON error payload returned through `ActionResult`
+    pub fn to_payload(&self) -> Value {
+        if let VBoxError::StepFailed { vm, step, rolled_back, source } = self {
+            let mut payload = source.to_payload();
+            let obj = payload.as_object_mut().unwrap();
+            obj.insert("message".to_string(), json!(self.to_string()));
+            obj.insert("vm".to_string(), json!(vm));
+            obj.insert("step".to_string(), json!(step));
+            obj.insert("rolled_back".to_string(), json!(rolled_back));
+            return payload;
+        }
+
+        let mut payload = json!({
+            "kind": self.kind(),
+            "message": self.to_string(),
+        });
+        let obj = payload.as_object_mut().unwrap();
+
+        match self {
+            VBoxError::Command(failure) => {
+                obj.insert("code".to_string(), json!(failure.code));
+                obj.insert("hresult".to_string(), json!(failure.hresult));
+                obj.insert("component".to_string(), json!(failure.component));
+                obj.insert("interface".to_string(), json!(failure.interface));
+                obj.insert("callee".to_string(), json!(failure.callee));
+                obj.insert("context".to_string(), json!(failure.context));
+                obj.insert("command".to_string(), json!(failure.command));
+                obj.insert("stderr".to_string(), json!(failure.stderr));
+            }
+            VBoxError::TimedOut { command, timeout } => {
+                obj.insert("command".to_string(), json!(command));
+                obj.insert("timeout_secs".to_string(), json!(timeout.as_secs_f64()));
+            }
+            VBoxError::Cancelled { command } => {
+                obj.insert("command".to_string(), json!(command));
+            }
+            VBoxError::WaitTimedOut { vm, target, timeout, history } => {
+                obj.insert("vm".to_string(), json!(vm));
+                obj.insert("target".to_string(), json!(target));
+                obj.insert("timeout_secs".to_string(), json!(timeout.as_secs_f64()));
+                obj.insert("history".to_string(), json!(history));
+            }
+            VBoxError::Io { path, .. } => {
+                obj.insert("path".to_string(), json!(path));
+            }
+            _ => {}
+        }
+        payload
+    }
+}
+
+impl fmt::Display for VBoxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VBoxError::Command(failure) => write!(f, "VBoxManage command failed: {}", failure.message()),
+            VBoxError::BinaryMissing(message) | VBoxError::Spawn(message) => write!(f, "{}", message),
+            VBoxError::TimedOut { command, timeout } => {
+                write!(f, "{}: VBoxManage {}", ExecError::TimedOut(*timeout), command)
+            }
+            VBoxError::Cancelled { command } => write!(f, "{}: VBoxManage {}", ExecError::Cancelled, command),
+            VBoxError::WaitTimedOut { vm, target, timeout, history } => {
+                write!(f, "VM '{}' did not reach state {} within {}s", vm, target, timeout.as_secs())?;
+                match history.last() {
+                    Some(last) => write!(f, " (last state: {})", last.state),
+                    None => Ok(()),
+                }
+            }
+            VBoxError::StepFailed { vm, step, rolled_back, source } => {
+                write!(f, "Step '{}' for VM '{}' failed: {}", step, vm, source)?;
+                if *rolled_back {
+                    write!(f, "; the VM was removed")?;
+                }
+                Ok(())
+            }
+            VBoxError::UnexpectedOutput(message) => write!(f, "{}", message),
+            VBoxError::Io { path, message } => write!(f, "Cannot write '{}': {}", path, message),
+            VBoxError::InvalidParameter(message) => write!(f, "{}", message),
+            VBoxError::UnknownAction(action) => write!(f, "Action '{}' not found", action),
+        }
+    }
+}
+
+impl std::error::Error for VBoxError {}
+
+/// Parameter validation in `lib_cpi` reports plain strings
+impl From<String> for VBoxError {
+    fn from(message: String) -> Self {
+        VBoxError::InvalidParameter(message)
+    }
+}
+
+impl From<VBoxError> for String {
+    fn from(error: VBoxError) -> Self {
+        error.to_payload().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOT_FOUND: &str = "VBoxManage: error: Could not find a registered machine named 'ghost'
+VBoxManage: error: Details: code VBOX_E_OBJECT_NOT_FOUND (0x80bb0001), component VirtualBoxWrap, interface IVirtualBox, callee nsISupports
+VBoxManage: error: Context: \"FindMachine(Bstr(a->argv[0]).raw(), machine.asOutParam())\" at line 2721 of file VBoxManageInfo.cpp
+";
+
+    #[test]
+    fn parses_details_and_context() {
+        let failure = VBoxFailure::parse("showvminfo ghost", NOT_FOUND);
+        assert_eq!(failure.code.as_deref(), Some("VBOX_E_OBJECT_NOT_FOUND"));
+        assert_eq!(failure.hresult.as_deref(), Some("0x80bb0001"));
+        assert_eq!(failure.component.as_deref(), Some("VirtualBoxWrap"));
+        assert_eq!(failure.interface.as_deref(), Some("IVirtualBox"));
+        assert_eq!(failure.callee.as_deref(), Some("nsISupports"));
+        assert!(failure.context.as_deref().unwrap().starts_with("\"FindMachine("));
+        assert_eq!(failure.messages, vec!["Could not find a registered machine named 'ghost'"]);
+        assert_eq!(failure.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn classifies_locks_and_state_errors() {
+        let locked = VBoxFailure::parse(
+            "modifyvm web-1",
+            "VBoxManage: error: The machine 'web-1' is already locked for a session (or being unlocked)\n\
+             VBoxManage: error: Details: code VBOX_E_INVALID_OBJECT_STATE (0x80bb0007), component MachineWrap, interface IMachine, callee nsISupports\n",
+        );
+        assert_eq!(locked.kind(), ErrorKind::Locked);
+
+        let not_running = VBoxFailure::parse(
+            "controlvm web-1 pause",
+            "VBoxManage: error: Machine 'web-1' is not currently running\n\
+             VBoxManage: error: Details: code VBOX_E_INVALID_VM_STATE (0x80bb0002), component ConsoleWrap, interface IConsole\n",
+        );
+        assert_eq!(not_running.kind(), ErrorKind::InvalidState);
+        assert_eq!(not_running.callee, None);
+
+        let denied = VBoxFailure::parse(
+            "createmedium disk",
+            "VBoxManage: error: Details: code E_ACCESSDENIED (0x80070005), component VirtualBoxWrap, interface IVirtualBox\n",
+        );
+        assert_eq!(denied.kind(), ErrorKind::AccessDenied);
+        assert_eq!(denied.message(), denied.stderr.trim());
+    }
+
+    #[test]
+    fn unprefixed_stderr_is_kept_as_message() {
+        let failure = VBoxFailure::parse("frobnicate", "Syntax error: Invalid command 'frobnicate'\n");
+        assert_eq!(failure.code, None);
+        assert_eq!(failure.kind(), ErrorKind::Failed);
+        assert_eq!(failure.message(), "Syntax error: Invalid command 'frobnicate'");
+    }
+
+    #[test]
+    fn payload_is_machine_readable() {
+        let error = VBoxError::Command(Box::new(VBoxFailure::parse("showvminfo ghost", NOT_FOUND)));
+        let payload: Value = serde_json::from_str(&String::from(error)).unwrap();
+        assert_eq!(payload["kind"], "not_found");
+        assert_eq!(payload["code"], "VBOX_E_OBJECT_NOT_FOUND");
+        assert_eq!(payload["interface"], "IVirtualBox");
+        assert_eq!(payload["command"], "showvminfo ghost");
+        assert_eq!(
+            payload["message"],
+            "VBoxManage command failed: Could not find a registered machine named 'ghost'"
+        );
+    }
+
+    #[test]
+    fn timeout_payload_carries_duration() {
+        let error = VBoxError::from_exec(ExecError::TimedOut(Duration::from_secs(30)), "startvm web-1");
+        let payload = error.to_payload();
+        assert_eq!(payload["kind"], "timeout");
+        assert_eq!(payload["timeout_secs"], 30.0);
+        assert_eq!(payload["message"], "VBoxManage command timed out after 30s: VBoxManage startvm web-1");
+    }
+}
@@ -0,0 +1,534 @@
+// File: cpi_virtualbox/src/executor.rs
+use std::ffi::OsString;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::retry::RetryPolicy;
+
+#[cfg(any(test, feature = "testing"))]
+mod fake;
+#[cfg(any(test, feature = "testing"))]
+pub use fake::FakeExecutor;
+
+/// How often a running VBoxManage is checked for exit, timeout and cancellation
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Raw result of a single VBoxManage invocation
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandOutput {
+    pub success: bool,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    pub fn ok(stdout: impl Into<String>) -> Self {
+        Self {
+            success: true,
+            stdout: stdout.into(),
+            stderr: String::new(),
+        }
+    }
+
+    pub fn failed(stderr: impl Into<String>) -> Self {
+        Self {
+            success: false,
+            stdout: String::new(),
+            stderr: stderr.into(),
+        }
+    }
+}
+
+/// Shared flag used to abort in-flight VBoxManage invocations
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Per-invocation limits. Timeout and cancellation are enforced by the
+/// executor; the retry policy is applied by the caller around it.
+#[derive(Debug, Clone, Default)]
+pub struct ExecOptions {
+    pub timeout: Option<Duration>,
+    pub cancel: Option<CancelToken>,
+    pub retry: RetryPolicy,
+}
+
+impl ExecOptions {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|c| c.is_cancelled())
+    }
+
+    /// Sleep for `duration`, waking early with `Cancelled` if the token fires
+    pub fn sleep(&self, duration: Duration) -> Result<(), ExecError> {
+        let deadline = Instant::now() + duration;
+        loop {
+            if self.is_cancelled() {
+                return Err(ExecError::Cancelled);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            thread::sleep((deadline - now).min(POLL_INTERVAL));
+        }
+    }
+}
+
+/// Reasons a VBoxManage invocation produced no exit status
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecError {
+    /// The binary does not exist at the configured path
+    BinaryMissing(String),
+    /// The binary could not be started or waited on
+    Spawn(String),
+    /// The process ran past its timeout and was killed
+    TimedOut(Duration),
+    /// The invocation was cancelled and the process was killed
+    Cancelled,
+}
+
+impl std::fmt::Display for ExecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExecError::BinaryMissing(message) | ExecError::Spawn(message) => write!(f, "{}", message),
+            ExecError::TimedOut(timeout) => write!(f, "VBoxManage command timed out after {}s", timeout.as_secs_f64()),
+            ExecError::Cancelled => write!(f, "VBoxManage command was cancelled"),
+        }
+    }
+}
+
+/// Runs VBoxManage with the given arguments.
+///
+/// `Err` is reserved for invocations that produced no exit status (spawn
+/// failure, timeout, cancellation); a non-zero exit is reported through
+/// `CommandOutput::success`.
+pub trait VBoxExecutor: Send + Sync {
+    fn execute(&self, args: &[&str], options: &ExecOptions) -> Result<CommandOutput, ExecError>;
+
+    /// The binary this executor runs, if it runs one
+    fn binary(&self) -> Option<&ResolvedBinary> {
+        None
+    }
+}
+
+#[cfg(target_os = "windows")]
+const BINARY_NAME: &str = "VBoxManage.exe";
+
+#[cfg(not(target_os = "windows"))]
+const BINARY_NAME: &str = "VBoxManage";
+
+/// Environment variable holding an explicit VBoxManage path
+pub const BINARY_ENV: &str = "CPI_VIRTUALBOX_VBOXMANAGE";
+
+/// Prefix of environment variables forwarded to VBoxManage with the prefix
+/// stripped, e.g. `CPI_VIRTUALBOX_ENV_VBOX_USER_HOME=/tenants/a`
+pub const FORWARD_ENV_PREFIX: &str = "CPI_VIRTUALBOX_ENV_";
+
+/// Installer-provided variables pointing at the VirtualBox install directory
+const INSTALL_DIR_ENVS: [&str; 2] = ["VBOX_MSI_INSTALL_PATH", "VBOX_INSTALL_PATH"];
+
+#[cfg(target_os = "windows")]
+const WELL_KNOWN_DIRS: &[&str] = &[
+    r"C:\Program Files\Oracle\VirtualBox",
+    r"C:\Program Files (x86)\Oracle\VirtualBox",
+];
+
+#[cfg(target_os = "macos")]
+const WELL_KNOWN_DIRS: &[&str] = &[
+    "/Applications/VirtualBox.app/Contents/MacOS",
+    "/usr/local/bin",
+    "/opt/homebrew/bin",
+];
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+const WELL_KNOWN_DIRS: &[&str] = &[
+    "/usr/bin",
+    "/usr/local/bin",
+    "/opt/VirtualBox",
+    "/usr/lib/virtualbox",
+];
+
+/// Where the VBoxManage binary path came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BinarySource {
+    /// Set explicitly by the caller or `CPI_VIRTUALBOX_VBOXMANAGE`
+    Explicit,
+    /// Found in the install directory named by an environment variable
+    EnvVar(&'static str),
+    /// Found on `PATH`
+    SearchPath,
+    /// Found in a standard install location
+    WellKnown,
+    /// Nothing found; the bare binary name is used and left to the OS
+    Fallback,
+}
+
+impl std::fmt::Display for BinarySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BinarySource::Explicit => write!(f, "explicit"),
+            BinarySource::EnvVar(name) => write!(f, "env:{}", name),
+            BinarySource::SearchPath => write!(f, "path"),
+            BinarySource::WellKnown => write!(f, "well_known"),
+            BinarySource::Fallback => write!(f, "fallback"),
+        }
+    }
+}
+
+/// A VBoxManage binary together with how it was located
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedBinary {
+    pub path: PathBuf,
+    pub source: BinarySource,
+}
+
+/// Locate VBoxManage using the process environment and filesystem.
+///
+/// Lookup order: `explicit`, `CPI_VIRTUALBOX_VBOXMANAGE`, the VirtualBox
+/// installer variables, `PATH`, then well-known install directories.
+pub fn resolve_vboxmanage(explicit: Option<&Path>) -> ResolvedBinary {
+    resolve_with(
+        explicit,
+        |name| std::env::var_os(name),
+        |path| path.is_file(),
+    )
+}
+
+fn resolve_with(
+    explicit: Option<&Path>,
+    env: impl Fn(&str) -> Option<OsString>,
+    exists: impl Fn(&Path) -> bool,
+) -> ResolvedBinary {
+    let found = |path: PathBuf, source| ResolvedBinary { path, source };
+
+    if let Some(path) = explicit {
+        return found(path.to_path_buf(), BinarySource::Explicit);
+    }
+    if let Some(path) = env(BINARY_ENV).filter(|p| !p.is_empty()) {
+        return found(PathBuf::from(path), BinarySource::Explicit);
+    }
+
+    for name in INSTALL_DIR_ENVS {
+        if let Some(dirs) = env(name) {
+            for dir in std::env::split_paths(&dirs) {
+                let candidate = dir.join(BINARY_NAME);
+                if exists(&candidate) {
+                    return found(candidate, BinarySource::EnvVar(name));
+                }
+            }
+        }
+    }
+
+    if let Some(dirs) = env("PATH") {
+        for dir in std::env::split_paths(&dirs) {
+            let candidate = dir.join(BINARY_NAME);
+            if exists(&candidate) {
+                return found(candidate, BinarySource::SearchPath);
+            }
+        }
+    }
+
+    for dir in WELL_KNOWN_DIRS {
+        let candidate = Path::new(dir).join(BINARY_NAME);
+        if exists(&candidate) {
+            return found(candidate, BinarySource::WellKnown);
+        }
+    }
+
+    found(PathBuf::from(BINARY_NAME), BinarySource::Fallback)
+}
+
+/// Executor that spawns the real VBoxManage binary
+pub struct ProcessExecutor {
+    binary: ResolvedBinary,
+    envs: Vec<(String, String)>,
+}
+
+impl ProcessExecutor {
+    /// Locate VBoxManage with `resolve_vboxmanage` and forward any
+    /// `CPI_VIRTUALBOX_ENV_*` variables from the host environment
+    pub fn new() -> Self {
+        let mut executor = Self {
+            binary: resolve_vboxmanage(None),
+            envs: Vec::new(),
+        };
+        for (key, value) in std::env::vars() {
+            if let Some(name) = key.strip_prefix(FORWARD_ENV_PREFIX)
+                && !name.is_empty()
+            {
+                executor = executor.env(name, value);
+            }
+        }
+        executor
+    }
+
+    /// Run a specific binary instead of resolving VBoxManage
+    pub fn with_program(program: impl Into<PathBuf>) -> Self {
+        Self {
+            binary: ResolvedBinary {
+                path: program.into(),
+                source: BinarySource::Explicit,
+            },
+            envs: Vec::new(),
+        }
+    }
+
+    /// Set an extra environment variable for every invocation, e.g.
+    /// `VBOX_USER_HOME` to give each tenant an isolated VirtualBox home
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.envs.push((key.into(), value.into()));
+        self
+    }
+}
+
+impl Default for ProcessExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Kills and reaps the child if it is still running when dropped
+struct ChildGuard(Child);
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        if let Ok(None) = self.0.try_wait() {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+}
+
+fn read_pipe(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<String> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buffer);
+        }
+        String::from_utf8_lossy(&buffer).to_string()
+    })
+}
+
+impl VBoxExecutor for ProcessExecutor {
+    fn execute(&self, args: &[&str], options: &ExecOptions) -> Result<CommandOutput, ExecError> {
+        let spawn_error = |e: std::io::Error| {
+            let message = format!("Failed to execute VBoxManage command '{}': {}", self.binary.path.display(), e);
+            if e.kind() == std::io::ErrorKind::NotFound {
+                ExecError::BinaryMissing(message)
+            } else {
+                ExecError::Spawn(message)
+            }
+        };
+
+        let mut child = Command::new(&self.binary.path)
+            .args(args)
+            .envs(self.envs.iter().map(|(k, v)| (k, v)))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(spawn_error)?;
+
+        // Drain both pipes concurrently so a chatty child can't block on a full pipe
+        let stdout = read_pipe(child.stdout.take());
+        let stderr = read_pipe(child.stderr.take());
+        let mut child = ChildGuard(child);
+        let started = Instant::now();
+
+        // On timeout or cancellation the guard kills the child; the reader
+        // threads are left to finish on their own once the pipes close
+        let status = loop {
+            if let Some(status) = child.0.try_wait().map_err(spawn_error)? {
+                break status;
+            }
+            if options.is_cancelled() {
+                return Err(ExecError::Cancelled);
+            }
+            if let Some(timeout) = options.timeout
+                && started.elapsed() >= timeout
+            {
+                return Err(ExecError::TimedOut(timeout));
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+
+        Ok(CommandOutput {
+            success: status.success(),
+            stdout: stdout.join().unwrap_or_default(),
+            stderr: stderr.join().unwrap_or_default(),
+        })
+    }
+
+    fn binary(&self) -> Option<&ResolvedBinary> {
+        Some(&self.binary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn resolve(
+        explicit: Option<&str>,
+        env: &[(&str, &str)],
+        files: &[PathBuf],
+    ) -> ResolvedBinary {
+        let env: HashMap<String, OsString> = env
+            .iter()
+            .map(|(k, v)| (k.to_string(), OsString::from(v)))
+            .collect();
+        resolve_with(
+            explicit.map(Path::new),
+            |name| env.get(name).cloned(),
+            |path| files.iter().any(|f| f == path),
+        )
+    }
+
+    fn join_dirs(dirs: &[&str]) -> String {
+        std::env::join_paths(dirs).unwrap().into_string().unwrap()
+    }
+
+    #[test]
+    fn explicit_path_wins() {
+        let install = PathBuf::from("/install").join(BINARY_NAME);
+        let resolved = resolve(
+            Some("/custom/VBoxManage"),
+            &[("VBOX_INSTALL_PATH", "/install")],
+            &[install],
+        );
+        assert_eq!(resolved.path, PathBuf::from("/custom/VBoxManage"));
+        assert_eq!(resolved.source, BinarySource::Explicit);
+    }
+
+    #[test]
+    fn binary_env_is_explicit() {
+        let resolved = resolve(None, &[(BINARY_ENV, "/tenant/VBoxManage")], &[]);
+        assert_eq!(resolved.path, PathBuf::from("/tenant/VBoxManage"));
+        assert_eq!(resolved.source, BinarySource::Explicit);
+    }
+
+    #[test]
+    fn msi_install_path_precedes_install_path_and_search_path() {
+        let msi = PathBuf::from("/msi").join(BINARY_NAME);
+        let install = PathBuf::from("/install").join(BINARY_NAME);
+        let on_path = PathBuf::from("/bin").join(BINARY_NAME);
+        let resolved = resolve(
+            None,
+            &[
+                ("VBOX_MSI_INSTALL_PATH", "/msi"),
+                ("VBOX_INSTALL_PATH", "/install"),
+                ("PATH", "/bin"),
+            ],
+            &[msi.clone(), install, on_path],
+        );
+        assert_eq!(resolved.path, msi);
+        assert_eq!(resolved.source, BinarySource::EnvVar("VBOX_MSI_INSTALL_PATH"));
+        assert_eq!(resolved.source.to_string(), "env:VBOX_MSI_INSTALL_PATH");
+    }
+
+    #[test]
+    fn install_dir_without_binary_is_skipped() {
+        let on_path = PathBuf::from("/second").join(BINARY_NAME);
+        let resolved = resolve(
+            None,
+            &[("VBOX_INSTALL_PATH", "/empty"), ("PATH", &join_dirs(&["/first", "/second"]))],
+            std::slice::from_ref(&on_path),
+        );
+        assert_eq!(resolved.path, on_path);
+        assert_eq!(resolved.source, BinarySource::SearchPath);
+    }
+
+    #[test]
+    fn falls_back_to_well_known_locations() {
+        let well_known = Path::new(WELL_KNOWN_DIRS[WELL_KNOWN_DIRS.len() - 1]).join(BINARY_NAME);
+        let resolved = resolve(None, &[("PATH", "/nowhere")], std::slice::from_ref(&well_known));
+        assert_eq!(resolved.path, well_known);
+        assert_eq!(resolved.source, BinarySource::WellKnown);
+    }
+
+    #[test]
+    fn bare_name_when_nothing_found() {
+        let resolved = resolve(None, &[], &[]);
+        assert_eq!(resolved.path, PathBuf::from(BINARY_NAME));
+        assert_eq!(resolved.source, BinarySource::Fallback);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hung_process_is_killed_on_timeout() {
+        let executor = ProcessExecutor::with_program("sleep");
+        let options = ExecOptions {
+            timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+
+        let started = Instant::now();
+        let result = executor.execute(&["30"], &options);
+        assert_eq!(result, Err(ExecError::TimedOut(Duration::from_millis(100))));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cancelled_process_is_killed() {
+        let executor = ProcessExecutor::with_program("sleep");
+        let token = CancelToken::new();
+        let options = ExecOptions {
+            cancel: Some(token.clone()),
+            ..Default::default()
+        };
+
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            token.cancel();
+        });
+        let started = Instant::now();
+        let result = executor.execute(&["30"], &options);
+        canceller.join().unwrap();
+        assert_eq!(result, Err(ExecError::Cancelled));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn output_is_captured_within_timeout() {
+        let executor = ProcessExecutor::with_program("sh");
+        let options = ExecOptions {
+            timeout: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+
+        let output = executor
+            .execute(&["-c", "echo out; echo err >&2; exit 3"], &options)
+            .unwrap();
+        assert!(!output.success);
+        assert_eq!(output.stdout, "out\n");
+        assert_eq!(output.stderr, "err\n");
+    }
+
+    #[test]
+    fn missing_binary_is_reported_as_such() {
+        let executor = ProcessExecutor::with_program("/nonexistent/VBoxManage");
+        let result = executor.execute(&["--version"], &ExecOptions::default());
+        assert!(matches!(result, Err(ExecError::BinaryMissing(_))), "{:?}", result);
+    }
+}
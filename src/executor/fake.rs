@@ -0,0 +1,108 @@
+// File: cpi_virtualbox/src/executor/fake.rs
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::{CommandOutput, ExecError, ExecOptions, VBoxExecutor};
+
+type Matcher = Box<dyn Fn(&[&str]) -> bool + Send>;
+
+enum Expected {
+    Args(Vec<String>),
+    /// Arguments that vary between runs, such as temporary file paths
+    Matching(Matcher),
+}
+
+type Expectation = (Expected, Result<CommandOutput, ExecError>);
+
+/// Scriptable executor that replays recorded VBoxManage output.
+///
+/// Each call must match the next queued expectation exactly, otherwise the
+/// executor panics so the offending argument vector shows up in the test.
+#[derive(Default)]
+pub struct FakeExecutor {
+    expectations: Mutex<VecDeque<Expectation>>,
+    timeouts: Mutex<Vec<Option<Duration>>>,
+}
+
+impl FakeExecutor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a call that succeeds with the given stdout
+    pub fn expect(&self, args: &[&str], stdout: &str) -> &Self {
+        self.push(args, Ok(CommandOutput::ok(stdout)))
+    }
+
+    /// Queue a call that exits non-zero with the given stderr
+    pub fn expect_failure(&self, args: &[&str], stderr: &str) -> &Self {
+        self.push(args, Ok(CommandOutput::failed(stderr)))
+    }
+
+    /// Queue a call where the binary could not be found
+    pub fn expect_missing_binary(&self, args: &[&str], message: &str) -> &Self {
+        self.push(args, Err(ExecError::BinaryMissing(message.to_string())))
+    }
+
+    /// Queue a call that ends without an exit status, e.g. a timeout
+    pub fn expect_error(&self, args: &[&str], error: ExecError) -> &Self {
+        self.push(args, Err(error))
+    }
+
+    /// Queue a call whose arguments pass `matcher`, succeeding with the
+    /// given stdout; the matcher runs during the call
+    pub fn expect_matching(&self, matcher: impl Fn(&[&str]) -> bool + Send + 'static, stdout: &str) -> &Self {
+        self.expectations
+            .lock()
+            .unwrap()
+            .push_back((Expected::Matching(Box::new(matcher)), Ok(CommandOutput::ok(stdout))));
+        self
+    }
+
+    fn push(&self, args: &[&str], result: Result<CommandOutput, ExecError>) -> &Self {
+        let args = args.iter().map(|a| a.to_string()).collect();
+        self.expectations.lock().unwrap().push_back((Expected::Args(args), result));
+        self
+    }
+
+    /// Number of queued calls that have not been made yet
+    pub fn pending(&self) -> usize {
+        self.expectations.lock().unwrap().len()
+    }
+
+    /// Timeout passed with each call made so far, in call order
+    pub fn timeouts(&self) -> Vec<Option<Duration>> {
+        self.timeouts.lock().unwrap().clone()
+    }
+
+    /// Panic if any queued call was never made
+    pub fn verify(&self) {
+        let remaining = self.expectations.lock().unwrap();
+        if let Some((expected, _)) = remaining.front() {
+            let next = match expected {
+                Expected::Args(args) => format!("{:?}", args),
+                Expected::Matching(_) => "a matched call".to_string(),
+            };
+            panic!("{} expected VBoxManage call(s) were never made, next: {}", remaining.len(), next);
+        }
+    }
+}
+
+impl VBoxExecutor for FakeExecutor {
+    fn execute(&self, args: &[&str], options: &ExecOptions) -> Result<CommandOutput, ExecError> {
+        let (expected, result) = self
+            .expectations
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| panic!("Unexpected VBoxManage call: {:?}", args));
+
+        match expected {
+            Expected::Args(expected) => assert_eq!(expected, args, "VBoxManage called with unexpected arguments"),
+            Expected::Matching(matcher) => assert!(matcher(args), "VBoxManage called with unexpected arguments: {:?}", args),
+        }
+        self.timeouts.lock().unwrap().push(options.timeout);
+        result
+    }
+}
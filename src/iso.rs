@@ -0,0 +1,388 @@
+// File: cpi_virtualbox/src/iso.rs
+//! Minimal ISO9660 image writer: a single root directory of small files,
+//! with a Joliet directory alongside so readers see the names unchanged
+//! (ISO9660 itself only allows upper-case letters, digits and `_`).
+//!
+//! Layout, in 2048-byte sectors: 16 empty system sectors, the primary and
+//! Joliet volume descriptors and the set terminator, the little- and
+//! big-endian path tables of each, the two root directories and then the
+//! file contents.
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECTOR: usize = 2048;
+
+/// Sector of the primary volume descriptor; the ones before are unused
+const FIRST_DESCRIPTOR: usize = 16;
+
+/// Largest root directory that fits the single sector reserved for it
+const MAX_FILES: usize = 16;
+
+/// Write an image holding `files` (name, contents) in its root directory
+pub fn write_iso(path: &Path, volume_id: &str, files: &[(&str, &[u8])]) -> io::Result<()> {
+    let image = build_iso(volume_id, files, SystemTime::now())?;
+    std::fs::write(path, image)
+}
+
+/// The bytes of an image holding `files`, all stamped with `modified`
+pub fn build_iso(volume_id: &str, files: &[(&str, &[u8])], modified: SystemTime) -> io::Result<Vec<u8>> {
+    if files.len() > MAX_FILES {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("At most {} files fit in the image", MAX_FILES)));
+    }
+    if let Some((name, _)) = files.iter().find(|(name, _)| name.is_empty() || name.len() > 64 || name.contains('/')) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid file name '{}'", name)));
+    }
+    let date = DateTime::from(modified);
+
+    // Sector numbers, in the order the layout above gives them
+    let primary_l_table = FIRST_DESCRIPTOR + 3;
+    let primary_m_table = primary_l_table + 1;
+    let joliet_l_table = primary_m_table + 1;
+    let joliet_m_table = joliet_l_table + 1;
+    let primary_root = joliet_m_table + 1;
+    let joliet_root = primary_root + 1;
+    let mut next = joliet_root + 1;
+    let mut extents = Vec::new();
+    for (_, data) in files {
+        extents.push(next);
+        next += sectors(data.len()).max(1);
+    }
+    let total = next;
+
+    // Each directory lists the files sorted by its own form of their names
+    let mut primary: Vec<(Vec<u8>, usize, usize)> = files
+        .iter()
+        .zip(&extents)
+        .map(|((name, data), &extent)| (primary_name(name), extent, data.len()))
+        .collect();
+    primary.sort();
+    // Names that differ only in characters ISO9660 can't hold would clash
+    if let Some(pair) = primary.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+        let clashing: Vec<&str> = files
+            .iter()
+            .zip(&extents)
+            .filter(|(_, extent)| **extent == pair[0].1 || **extent == pair[1].1)
+            .map(|((name, _), _)| *name)
+            .collect();
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Files '{}' share the ISO9660 name '{}'", clashing.join("' and '"), String::from_utf8_lossy(&pair[0].0)),
+        ));
+    }
+    let mut joliet: Vec<(Vec<u8>, usize, usize)> = files
+        .iter()
+        .zip(&extents)
+        .map(|((name, data), &extent)| (ucs2(name), extent, data.len()))
+        .collect();
+    joliet.sort();
+
+    let mut image = vec![0u8; total * SECTOR];
+    let root_path_table = path_table_entry(primary_root, true);
+    let joliet_path_table = path_table_entry(joliet_root, true);
+
+    let descriptor = |kind: u8, root: usize, l_table: usize, m_table: usize, text: &dyn Fn(&str, usize) -> Vec<u8>| {
+        let mut d = vec![0u8; SECTOR];
+        d[0] = kind;
+        d[1..6].copy_from_slice(b"CD001");
+        d[6] = 1;
+        d[8..40].copy_from_slice(&text("", 32));
+        d[40..72].copy_from_slice(&text(volume_id, 32));
+        both32(&mut d[80..88], total as u32);
+        both16(&mut d[120..124], 1);
+        both16(&mut d[124..128], 1);
+        both16(&mut d[128..132], SECTOR as u16);
+        both32(&mut d[132..140], root_path_table.len() as u32);
+        d[140..144].copy_from_slice(&(l_table as u32).to_le_bytes());
+        d[148..152].copy_from_slice(&(m_table as u32).to_be_bytes());
+        d[156..190].copy_from_slice(&directory_record(&[0], root, SECTOR, true, &date));
+        d[190..318].copy_from_slice(&text("", 128));
+        d[318..446].copy_from_slice(&text("", 128));
+        d[446..574].copy_from_slice(&text("", 128));
+        d[574..702].copy_from_slice(&text("", 128));
+        d[702..739].copy_from_slice(&text("", 37));
+        d[739..776].copy_from_slice(&text("", 37));
+        d[776..813].copy_from_slice(&text("", 37));
+        d[813..830].copy_from_slice(&date.long());
+        d[830..847].copy_from_slice(&date.long());
+        d[847..864].copy_from_slice(&DateTime::unset());
+        d[864..881].copy_from_slice(&date.long());
+        d[881] = 1;
+        d
+    };
+
+    let pvd = descriptor(1, primary_root, primary_l_table, primary_m_table, &padded_ascii);
+    let mut svd = descriptor(2, joliet_root, joliet_l_table, joliet_m_table, &padded_ucs2);
+    // UCS-2 level 3 escape sequence marks the Joliet descriptor
+    svd[88..91].copy_from_slice(b"%/E");
+    put(&mut image, FIRST_DESCRIPTOR, &pvd);
+    put(&mut image, FIRST_DESCRIPTOR + 1, &svd);
+    let mut terminator = vec![0u8; 7];
+    terminator[0] = 255;
+    terminator[1..6].copy_from_slice(b"CD001");
+    terminator[6] = 1;
+    put(&mut image, FIRST_DESCRIPTOR + 2, &terminator);
+
+    put(&mut image, primary_l_table, &root_path_table);
+    put(&mut image, primary_m_table, &path_table_entry(primary_root, false));
+    put(&mut image, joliet_l_table, &joliet_path_table);
+    put(&mut image, joliet_m_table, &path_table_entry(joliet_root, false));
+
+    for (root, entries) in [(primary_root, &primary), (joliet_root, &joliet)] {
+        let mut dir = Vec::new();
+        dir.extend(directory_record(&[0], root, SECTOR, true, &date));
+        dir.extend(directory_record(&[1], root, SECTOR, true, &date));
+        for (name, extent, len) in entries {
+            dir.extend(directory_record(name, *extent, *len, false, &date));
+        }
+        if dir.len() > SECTOR {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "File names do not fit in the root directory"));
+        }
+        put(&mut image, root, &dir);
+    }
+
+    for ((_, data), &extent) in files.iter().zip(&extents) {
+        put(&mut image, extent, data);
+    }
+    Ok(image)
+}
+
+fn sectors(len: usize) -> usize {
+    len.div_ceil(SECTOR)
+}
+
+fn put(image: &mut [u8], sector: usize, bytes: &[u8]) {
+    let start = sector * SECTOR;
+    image[start..start + bytes.len()].copy_from_slice(bytes);
+}
+
+// ISO9660 stores most numbers twice: little-endian, then big-endian
+fn both16(buf: &mut [u8], value: u16) {
+    buf[..2].copy_from_slice(&value.to_le_bytes());
+    buf[2..4].copy_from_slice(&value.to_be_bytes());
+}
+
+fn both32(buf: &mut [u8], value: u32) {
+    buf[..4].copy_from_slice(&value.to_le_bytes());
+    buf[4..8].copy_from_slice(&value.to_be_bytes());
+}
+
+fn directory_record(name: &[u8], extent: usize, len: usize, directory: bool, date: &DateTime) -> Vec<u8> {
+    // Records have an even length, so names of even length get a pad byte
+    let size = 33 + name.len() + (1 - name.len() % 2);
+    let mut record = vec![0u8; size];
+    record[0] = size as u8;
+    both32(&mut record[2..10], extent as u32);
+    both32(&mut record[10..18], len as u32);
+    record[18..25].copy_from_slice(&date.short());
+    record[25] = if directory { 2 } else { 0 };
+    both16(&mut record[28..32], 1);
+    record[32] = name.len() as u8;
+    record[33..33 + name.len()].copy_from_slice(name);
+    record
+}
+
+// The root directory's entry; there are no subdirectories
+fn path_table_entry(root: usize, little_endian: bool) -> Vec<u8> {
+    let mut entry = vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    if little_endian {
+        entry[2..6].copy_from_slice(&(root as u32).to_le_bytes());
+        entry[6..8].copy_from_slice(&1u16.to_le_bytes());
+    } else {
+        entry[2..6].copy_from_slice(&(root as u32).to_be_bytes());
+        entry[6..8].copy_from_slice(&1u16.to_be_bytes());
+    }
+    entry
+}
+
+/// `user-data` becomes `USER_DATA.;1`: d-characters, the separator before
+/// the extension (the part after the last dot) and the version. Name and
+/// extension together keep to the 30 characters ISO9660 allows.
+fn primary_name(name: &str) -> Vec<u8> {
+    let d_characters = |part: &str| -> String {
+        part.chars()
+            .map(|c| match c.to_ascii_uppercase() {
+                c @ ('A'..='Z' | '0'..='9' | '_') => c,
+                _ => '_',
+            })
+            .collect()
+    };
+    let (stem, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    let mut extension = d_characters(extension);
+    extension.truncate(30);
+    let mut stem = d_characters(stem);
+    stem.truncate(30 - extension.len());
+    format!("{}.{};1", stem, extension).into_bytes()
+}
+
+fn ucs2(text: &str) -> Vec<u8> {
+    text.encode_utf16().flat_map(u16::to_be_bytes).collect()
+}
+
+fn padded_ascii(text: &str, len: usize) -> Vec<u8> {
+    let mut bytes: Vec<u8> = text.bytes().take(len).collect();
+    bytes.resize(len, b' ');
+    bytes
+}
+
+fn padded_ucs2(text: &str, len: usize) -> Vec<u8> {
+    let mut bytes: Vec<u8> = ucs2(text).into_iter().take(len & !1).collect();
+    while bytes.len() + 1 < len {
+        bytes.extend([0, b' ']);
+    }
+    bytes.resize(len, 0);
+    bytes
+}
+
+/// A UTC timestamp in the two forms volume descriptors and directory
+/// records use
+struct DateTime {
+    year: u32,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+}
+
+impl From<SystemTime> for DateTime {
+    fn from(time: SystemTime) -> Self {
+        let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        // Civil date from days since 1970-01-01 (proleptic Gregorian)
+        let days = (secs / 86_400) as i64 + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days.rem_euclid(146_097);
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+        let time_of_day = secs % 86_400;
+        DateTime {
+            year: year as u32,
+            month: month as u32,
+            day: day as u32,
+            hour: (time_of_day / 3600) as u32,
+            minute: (time_of_day % 3600 / 60) as u32,
+            second: (time_of_day % 60) as u32,
+        }
+    }
+}
+
+impl DateTime {
+    /// Seven bytes: years since 1900, month, day, hour, minute, second and
+    /// the offset from UTC
+    fn short(&self) -> [u8; 7] {
+        [
+            self.year.saturating_sub(1900).min(255) as u8,
+            self.month as u8,
+            self.day as u8,
+            self.hour as u8,
+            self.minute as u8,
+            self.second as u8,
+            0,
+        ]
+    }
+
+    /// `YYYYMMDDHHMMSScc` digits and the offset from UTC
+    fn long(&self) -> [u8; 17] {
+        let digits = format!(
+            "{:04}{:02}{:02}{:02}{:02}{:02}00",
+            self.year.min(9999),
+            self.month,
+            self.day,
+            self.hour,
+            self.minute,
+            self.second
+        );
+        let mut bytes = [0u8; 17];
+        bytes[..16].copy_from_slice(digits.as_bytes());
+        bytes
+    }
+
+    fn unset() -> [u8; 17] {
+        let mut bytes = [b'0'; 17];
+        bytes[16] = 0;
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn read32(image: &[u8], offset: usize) -> usize {
+        u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap()) as usize
+    }
+
+    // Names and contents of the files listed in a root directory sector
+    fn list_root(image: &[u8], root: usize, joliet: bool) -> Vec<(String, Vec<u8>)> {
+        let dir = &image[root * SECTOR..(root + 1) * SECTOR];
+        let mut offset = 0;
+        let mut files = Vec::new();
+        while dir[offset] != 0 {
+            let record = &dir[offset..offset + dir[offset] as usize];
+            let name = &record[33..33 + record[32] as usize];
+            if record[25] & 2 == 0 {
+                let name = if joliet {
+                    let units: Vec<u16> = name.chunks(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+                    String::from_utf16(&units).unwrap()
+                } else {
+                    String::from_utf8(name.to_vec()).unwrap()
+                };
+                let (extent, len) = (read32(record, 2), read32(record, 10));
+                files.push((name, image[extent * SECTOR..extent * SECTOR + len].to_vec()));
+            }
+            offset += record.len();
+        }
+        files
+    }
+
+    #[test]
+    fn primary_and_joliet_directories_list_the_files() {
+        let modified = UNIX_EPOCH + Duration::from_secs(1_709_251_200); // 2024-03-01
+        let user_data = vec![b'x'; 3000];
+        let image = build_iso("cidata", &[("user-data", &user_data), ("meta-data", b"{}")], modified).unwrap();
+        assert_eq!(image.len() % SECTOR, 0);
+
+        let pvd = &image[FIRST_DESCRIPTOR * SECTOR..];
+        assert_eq!(&pvd[..6], b"\x01CD001");
+        assert_eq!(&pvd[40..46], b"cidata");
+        assert_eq!(read32(pvd, 80) * SECTOR, image.len());
+        assert_eq!(&pvd[813..823], b"2024030100");
+        let svd = &image[(FIRST_DESCRIPTOR + 1) * SECTOR..];
+        assert_eq!(&svd[..6], b"\x02CD001");
+        assert_eq!(&svd[88..91], b"%/E");
+        assert_eq!(&svd[40..52], &ucs2("cidata")[..]);
+        assert_eq!(image[(FIRST_DESCRIPTOR + 2) * SECTOR], 255);
+
+        let primary = list_root(&image, read32(pvd, 158), false);
+        let names: Vec<&str> = primary.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["META_DATA.;1", "USER_DATA.;1"]);
+        let joliet = list_root(&image, read32(svd, 158), true);
+        assert_eq!(joliet[0], ("meta-data".to_string(), b"{}".to_vec()));
+        assert_eq!(joliet[1], ("user-data".to_string(), user_data));
+    }
+
+    #[test]
+    fn rejects_names_that_cannot_be_stored() {
+        let err = build_iso("cidata", &[("dir/user-data", b"")], UNIX_EPOCH).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let err = build_iso("cidata", &[("a-b", b""), ("meta-data", b""), ("a_b", b"")], UNIX_EPOCH).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "Files 'a-b' and 'a_b' share the ISO9660 name 'A_B.;1'");
+    }
+
+    #[test]
+    fn primary_names_keep_only_the_last_dot() {
+        let name = |name: &str| String::from_utf8(primary_name(name)).unwrap();
+        assert_eq!(name("user-data"), "USER_DATA.;1");
+        assert_eq!(name("network-config.v2.yaml"), "NETWORK_CONFIG_V2.YAML;1");
+        assert_eq!(name(".hidden"), ".HIDDEN;1");
+        let long = name(&format!("{}.yaml", "x".repeat(40)));
+        assert_eq!(long, format!("{}.YAML;1", "X".repeat(26)));
+    }
+}
@@ -1,14 +1,81 @@
 // File: cpi_virtualbox/src/lib.rs
 use lib_cpi::{
-    ActionParameter, ActionDefinition, ActionResult, CpiExtension, ParamType,
-    action, param, validation
+    ActionDefinition, ActionResult, CpiExtension, ParamType,
+    param, validation
 };
 use serde_json::{json, Value};
-use std::collections::HashMap;
-use std::process::Command;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub mod client;
+pub mod cloudinit;
+pub mod error;
+pub mod executor;
+pub mod iso;
+pub mod logging;
+pub mod machinereadable;
+pub mod retry;
+pub mod settings;
+pub mod spec;
+pub mod types;
+
+#[cfg(test)]
+mod tests;
+
+pub use executor::{
+    BinarySource, CancelToken, CommandOutput, ExecError, ExecOptions,
+    ProcessExecutor, ResolvedBinary, VBoxExecutor, resolve_vboxmanage,
+};
+#[cfg(any(test, feature = "testing"))]
+pub use executor::FakeExecutor;
+pub use error::{ErrorKind, VBoxError, VBoxFailure, VBoxResult};
+pub use retry::RetryPolicy;
+pub use client::VirtualBoxClient;
+pub use cloudinit::{CloudInitSeed, CloudInitUser};
+pub use machinereadable::MachineReadable;
+pub use settings::DefaultSettings;
+pub use spec::{DiskAttachmentSpec, NicSpec, SpecChange, SpecPlan, WorkerSpec};
+pub use types::{
+    ApplianceEntry, AttachOptions, CloneOptions, ControllerSpec, DeleteOptions, DeleteReport, DetectedOs, DeviceType, DiskSpec, ExportOptions, GuestInfo,
+    ImportOptions, MacPolicy, Medium, NetworkAdapter, Nic, NicType, OvfVersion, PortForward, SharedFolder, Snapshot, StartGroup, StartMode,
+    StartOptions, StartReport, StopMethod, StopOutcome, StateTransition, StorageAttachment, StorageBus, StorageController, UnattendedOptions, UsbFilter,
+    UsbInfo, Vm, VmConfig, VmFilter, VmState, VmSummary, WaitTarget,
+};
+
+/// Timeout applied to each VBoxManage invocation unless configured otherwise
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// How long lifecycle actions wait for a state when asked to, and the
+/// default for `wait_for_worker_state`
+pub const DEFAULT_WAIT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How many VMs `start_worker` starts at once when given several
+pub const DEFAULT_START_PARALLELISM: usize = 4;
+
+/// How long `install_os_unattended` waits for the installed guest when
+/// asked to
+pub const DEFAULT_INSTALL_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// Actions that accept `wait`, each waiting for the state it leads to
+const LIFECYCLE_ACTIONS: &[&str] = &[
+    "start_worker",
+    "stop_worker",
+    "reboot_worker",
+    "pause_worker",
+    "resume_worker",
+    "save_worker_state",
+    "discard_saved_state",
+];
+
+/// How long `stop_worker` waits for an ACPI shutdown before powering off
+pub const DEFAULT_STOP_GRACE: Duration = Duration::from_secs(60);
 
 #[unsafe(no_mangle)]
+#[allow(improper_ctypes_definitions)]
 pub extern "C" fn get_extension() -> *mut dyn CpiExtension {
+    logging::init_from_env();
     Box::into_raw(Box::new(VirtualBoxExtension::new()))
 }
 
@@ -16,329 +83,453 @@ pub extern "C" fn get_extension() -> *mut dyn CpiExtension {
 pub struct VirtualBoxExtension {
     name: String,
     provider_type: String,
-    default_settings: HashMap<String, Value>,
+    default_settings: DefaultSettings,
+    executor: Arc<dyn VBoxExecutor>,
+    timeout: Option<Duration>,
+    action_timeouts: HashMap<String, Duration>,
+    retry: RetryPolicy,
+    action_retries: HashMap<String, RetryPolicy>,
+    poll_interval: Duration,
+    in_flight: Mutex<Vec<InFlight>>,
+    next_call_id: AtomicU64,
+}
+
+/// An action currently executing, tracked so it can be cancelled
+struct InFlight {
+    id: u64,
+    action: String,
+    cancel: CancelToken,
+}
+
+/// Removes an action from the in-flight list when it finishes
+struct InFlightGuard<'a> {
+    extension: &'a VirtualBoxExtension,
+    id: u64,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.extension
+            .in_flight
+            .lock()
+            .unwrap()
+            .retain(|call| call.id != self.id);
+    }
+}
+
+impl Default for VirtualBoxExtension {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VirtualBoxExtension {
+    /// Create the extension with defaults from `DefaultSettings::from_env`
     pub fn new() -> Self {
-        let mut default_settings = HashMap::new();
-        default_settings.insert("os_type".to_string(), json!("Ubuntu_64"));
-        default_settings.insert("memory_mb".to_string(), json!(2048));
-        default_settings.insert("cpu_count".to_string(), json!(2));
-        default_settings.insert("controller_name".to_string(), json!("SATA Controller"));
-        default_settings.insert("network_type".to_string(), json!("nat"));
-        default_settings.insert("username".to_string(), json!("vboxuser"));
-        default_settings.insert("password".to_string(), json!("password"));
+        Self::with_executor(Arc::new(ProcessExecutor::new())).with_default_settings(DefaultSettings::from_env())
+    }
 
+    /// Create the extension on top of a custom VBoxManage executor, with
+    /// the built-in defaults
+    pub fn with_executor(executor: Arc<dyn VBoxExecutor>) -> Self {
         Self {
             name: "virtualbox".to_string(),
             provider_type: "command".to_string(),
-            default_settings,
+            default_settings: DefaultSettings::default(),
+            executor,
+            timeout: Some(DEFAULT_TIMEOUT),
+            action_timeouts: HashMap::new(),
+            retry: RetryPolicy::default(),
+            action_retries: HashMap::new(),
+            poll_interval: client::DEFAULT_POLL_INTERVAL,
+            in_flight: Mutex::new(Vec::new()),
+            next_call_id: AtomicU64::new(0),
         }
     }
-    
-    // Helper method to run VBoxManage commands
-    fn run_vboxmanage(&self, args: &[&str]) -> Result<String, String> {
-        println!("Running VBoxManage command: {:?}", args);
-        
-        // Only add exe on windows
-        #[cfg(target_os = "windows")]
-        let output = Command::new("VBoxManage.exe")
-            .args(args)
-            .output()
-            .map_err(|e| format!("Failed to execute VBoxManage command: {}", e))?;
-            
-        #[cfg(not(target_os = "windows"))]
-        let output = Command::new("VBoxManage")
-            .args(args)
-            .output()
-            .map_err(|e| format!("Failed to execute VBoxManage command: {}", e))?;
-
-        if output.status.success() {
-            let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-            Ok(stdout)
-        } else {
-            let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-            Err(format!("VBoxManage command failed: {}", stderr))
-        }
+
+    /// Replace the defaults used for parameters an action was called without
+    pub fn with_default_settings(mut self, settings: DefaultSettings) -> Self {
+        self.default_settings = settings;
+        self
+    }
+
+    /// The defaults used for parameters an action was called without
+    pub fn default_settings(&self) -> &DefaultSettings {
+        &self.default_settings
+    }
+
+    /// Set the timeout for every VBoxManage invocation; `None` waits forever
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Override the timeout for the VBoxManage invocations of one action
+    pub fn with_action_timeout(mut self, action: &str, timeout: Duration) -> Self {
+        self.action_timeouts.insert(action.to_string(), timeout);
+        self
+    }
+
+    /// Set how transient VBoxManage failures are retried for every action
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Override the retry policy for one action
+    pub fn with_action_retry_policy(mut self, action: &str, policy: RetryPolicy) -> Self {
+        self.action_retries.insert(action.to_string(), policy);
+        self
+    }
+
+    /// Set how often actions that wait on a VM's state re-check it
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Cancel in-flight actions, optionally only those with the given name.
+    /// Their running VBoxManage processes are killed. Returns the names of
+    /// the cancelled actions.
+    pub fn cancel_actions(&self, action: Option<&str>) -> Vec<String> {
+        self.in_flight
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|call| action.is_none_or(|name| call.action == name))
+            .map(|call| {
+                call.cancel.cancel();
+                call.action.clone()
+            })
+            .collect()
+    }
+
+    // Register an action as in flight and build a client for its VBoxManage calls
+    fn begin_action(&self, action: &str, timeout: Option<Duration>) -> (VirtualBoxClient, InFlightGuard<'_>) {
+        let id = self.next_call_id.fetch_add(1, Ordering::SeqCst);
+        let cancel = CancelToken::new();
+        self.in_flight.lock().unwrap().push(InFlight {
+            id,
+            action: action.to_string(),
+            cancel: cancel.clone(),
+        });
+
+        let opts = ExecOptions {
+            timeout,
+            cancel: Some(cancel),
+            retry: self.action_retries.get(action).unwrap_or(&self.retry).clone(),
+        };
+        let client = VirtualBoxClient::with_executor(self.executor.clone())
+            .with_options(opts)
+            .with_poll_interval(self.poll_interval);
+        (client, InFlightGuard { extension: self, id })
     }
     
-    // Define all the methods without the #[action] attribute for now
+    // Each action maps its parameters onto the typed client and shapes the JSON result
     
-    fn test_install(&self) -> ActionResult {
-        let output = self.run_vboxmanage(&["--version"])?;
-        
-        // Parse the version from the output
-        let version = output.trim().to_string();
+    fn test_install(&self, client: &VirtualBoxClient) -> VBoxResult<Value> {
+        let version = client.version()?;
         
-        Ok(json!({
+        let mut result = json!({
             "success": true,
             "version": version
-        }))
-    }
-    
-    fn list_workers(&self) -> ActionResult {
-        let output = self.run_vboxmanage(&["list", "vms"])?;
-        
-        // Parse the output to get VM names and UUIDs
-        let mut workers = Vec::new();
+        });
         
-        for line in output.lines() {
-            if line.trim().is_empty() {
-                continue;
-            }
-            
-            // Each line is in format: "VM Name" {uuid}
-            if let (Some(first_quote), Some(last_quote)) = (line.find('"'), line.rfind('"')) {
-                if first_quote < last_quote {
-                    let name = line[first_quote+1..last_quote].to_string();
-                    
-                    // Find UUID between curly braces
-                    if let (Some(open_brace), Some(close_brace)) = (line.find('{'), line.rfind('}')) {
-                        if open_brace < close_brace {
-                            let uuid = line[open_brace+1..close_brace].to_string();
-                            
-                            workers.push(json!({
-                                "name": name,
-                                "uuid": uuid, // This field is not required by the CPI standard, but ID is. We return it in bolth places for convenience.
-                                "id": uuid,
-                                "state": "unknown"
-                            }));
-                            
-                            println!("Successfully parsed VM: name='{}', uuid='{}'", name, uuid);
-                        }
-                    }
-                }
-            }
+        // Report which VBoxManage was picked so misconfigured hosts are easy to spot
+        if let (Some(binary), Some(obj)) = (client.binary(), result.as_object_mut()) {
+            obj.insert("binary".to_string(), json!(binary.path.display().to_string()));
+            obj.insert("binary_source".to_string(), json!(binary.source.to_string()));
         }
         
+        Ok(result)
+    }
+    
+    fn list_workers(&self, client: &VirtualBoxClient, filter: VmFilter) -> VBoxResult<Value> {
+        let mut vms = client.find_vms(&filter)?;
+        client.resolve_os_types(&mut vms)?;
+        let workers: Vec<Value> = vms
+            .into_iter()
+            .map(|vm| {
+                json!({
+                    "name": vm.name,
+                    "uuid": vm.id, // This field is not required by the CPI standard, but ID is. We return it in bolth places for convenience.
+                    "id": vm.id,
+                    "state": vm.state.as_ref().map_or("unknown", |s| s.as_str()),
+                    "os_type": vm.os_type,
+                    "os_description": vm.os_description,
+                    "memory_mb": vm.memory_mb,
+                    "cpu_count": vm.cpu_count,
+                    "groups": vm.groups
+                })
+            })
+            .collect();
+        
         // Return just the content for the result object - the CPI wrapper will
         // handle adding the success/error fields
-        let result = json!({
+        Ok(json!({
             "workers": workers
-        });
+        }))
+    }
+    
+    fn create_worker(&self, client: &VirtualBoxClient, config: VmConfig) -> VBoxResult<Value> {
+        let vm = client.create_vm(&config)?;
         
-        println!("Final result JSON: {}", result.to_string());
+        Ok(json!({
+            "success": true,
+            "uuid": vm.id,
+            "name": vm.name
+        }))
+    }
+    
+    fn clone_worker(&self, client: &VirtualBoxClient, worker_name: String, options: CloneOptions) -> VBoxResult<Value> {
+        let vm = client.clone_vm(&worker_name, &options)?;
         
-        Ok(result)
+        Ok(json!({
+            "success": true,
+            "uuid": vm.id,
+            "name": vm.name,
+            "linked": options.linked
+        }))
     }
     
-    fn create_worker(&self, worker_name: String, os_type: String, memory_mb: i64, cpu_count: i64) -> ActionResult {
-        // Create the VM
-        let create_output = self.run_vboxmanage(&[
-            "createvm", 
-            "--name", &worker_name, 
-            "--ostype", &os_type, 
-            "--register"
-        ])?;
-        
-        // Extract the UUID
-        let mut uuid = String::new();
-        for line in create_output.lines() {
-            if line.contains("UUID") {
-                let parts: Vec<&str> = line.split(':').collect();
-                if parts.len() >= 2 {
-                    uuid = parts[1].trim().to_string();
-                    break;
-                }
-            }
+    fn import_appliance(&self, client: &VirtualBoxClient, appliance_path: String, dry_run: bool, options: ImportOptions) -> VBoxResult<Value> {
+        if dry_run {
+            let entries = client.inspect_appliance(&appliance_path)?;
+            return Ok(json!({
+                "success": true,
+                "dry_run": true,
+                "entries": entries
+            }));
         }
+        let vm = client.import_appliance(&appliance_path, &options)?;
         
-        // Configure memory and CPU
-        self.run_vboxmanage(&[
-            "modifyvm", 
-            &worker_name, 
-            "--memory", &memory_mb.to_string(), 
-            "--cpus", &cpu_count.to_string()
-        ])?;
-        
-        // Configure network
-        self.run_vboxmanage(&[
-            "modifyvm", 
-            &worker_name, 
-            "--nic1", "nat"
-        ])?;
+        Ok(json!({
+            "success": true,
+            "uuid": vm.id,
+            "name": vm.name
+        }))
+    }
+    
+    fn export_appliance(&self, client: &VirtualBoxClient, worker_name: String, options: ExportOptions) -> VBoxResult<Value> {
+        client.export_appliance(&worker_name, &options)?;
         
         Ok(json!({
             "success": true,
-            "uuid": uuid,
-            "name": worker_name
+            "path": options.output
         }))
     }
     
-    fn delete_worker(&self, worker_name: String) -> ActionResult {
-        self.run_vboxmanage(&[
-            "unregistervm", 
-            &worker_name, 
-            "--delete"
-        ])?;
+    fn apply_worker_spec(&self, client: &VirtualBoxClient, spec: WorkerSpec) -> VBoxResult<Value> {
+        let plan = client.apply_spec(&spec)?;
         
         Ok(json!({
-            "success": true
+            "success": true,
+            "created": !plan.exists,
+            "changes": plan.changes
         }))
     }
     
-    fn get_worker(&self, worker_name: String) -> ActionResult {
-        let output = self.run_vboxmanage(&[
-            "showvminfo", 
-            &worker_name, 
-            "--machinereadable"
-        ])?;
+    fn diff_worker_spec(&self, client: &VirtualBoxClient, spec: WorkerSpec) -> VBoxResult<Value> {
+        let plan = client.plan_spec(&spec)?;
         
-        let mut vm_info = json!({});
+        Ok(json!({
+            "success": true,
+            "exists": plan.exists,
+            "in_sync": plan.changes.is_empty(),
+            "changes": plan.changes
+        }))
+    }
+    
+    fn install_os_unattended(&self, client: &VirtualBoxClient, worker_name: String, options: UnattendedOptions, wait: Option<WaitSpec>) -> VBoxResult<Value> {
+        let detected = client.install_os_unattended(&worker_name, &options)?;
         
-        // Parse key properties
-        for line in output.lines() {
-            let parts: Vec<&str> = line.split('=').collect();
-            if parts.len() >= 2 {
-                let key = parts[0].trim();
-                let value = parts[1].trim().trim_matches('"');
-                
-                match key {
-                    "name" => {
-                        if let Some(obj) = vm_info.as_object_mut() {
-                            obj.insert("name".to_string(), json!(value));
-                        }
-                    },
-                    "UUID" => {
-                        if let Some(obj) = vm_info.as_object_mut() {
-                            obj.insert("id".to_string(), json!(value));
-                        }
-                    },
-                    "VMState" => {
-                        if let Some(obj) = vm_info.as_object_mut() {
-                            obj.insert("state".to_string(), json!(value));
-                        }
-                    },
-                    "memory" => {
-                        if let Some(obj) = vm_info.as_object_mut() {
-                            if let Ok(mem) = value.parse::<i64>() {
-                                obj.insert("memory_mb".to_string(), json!(mem));
-                            }
-                        }
-                    },
-                    "cpus" => {
-                        if let Some(obj) = vm_info.as_object_mut() {
-                            if let Ok(cpus) = value.parse::<i64>() {
-                                obj.insert("cpu_count".to_string(), json!(cpus));
-                            }
-                        }
-                    },
-                    "ostype" => {
-                        if let Some(obj) = vm_info.as_object_mut() {
-                            obj.insert("os_type".to_string(), json!(value));
-                        }
-                    },
-                    "firmware" => {
-                        if let Some(obj) = vm_info.as_object_mut() {
-                            obj.insert("firmware".to_string(), json!(value));
-                        }
-                    },
-                    "graphicscontroller" => {
-                        if let Some(obj) = vm_info.as_object_mut() {
-                            obj.insert("graphics_controller".to_string(), json!(value));
-                        }
-                    },
-                    _ => {}
-                }
-            }
+        let result = json!({
+            "success": true,
+            "os": detected,
+            "started": options.start
+        });
+        self.await_state(client, &worker_name, VmState::Running, wait, result)
+    }
+    
+    fn create_cloud_init_seed(&self, client: &VirtualBoxClient, worker_name: String, seed: CloudInitSeed, iso_path: Option<String>, controller: ControllerSpec, create_controller: bool) -> VBoxResult<Value> {
+        let attachment = client.attach_cloud_init_seed(&worker_name, &seed, iso_path.as_deref(), &controller, create_controller)?;
+        
+        Ok(json!({
+            "success": true,
+            "path": attachment.medium,
+            "controller": attachment.controller,
+            "port": attachment.port,
+            "device": attachment.device
+        }))
+    }
+    
+    fn delete_worker(&self, client: &VirtualBoxClient, worker_name: String, options: DeleteOptions) -> VBoxResult<Value> {
+        // A plain delete needs no inspection of the VM
+        if !options.force && options.keep_media.is_empty() {
+            client.delete_vm(&worker_name)?;
+            return Ok(json!({
+                "success": true
+            }));
         }
         
+        let report = client.delete_vm_with(&worker_name, &options)?;
+        
         Ok(json!({
             "success": true,
-            "vm": vm_info
+            "powered_off": report.powered_off,
+            "deleted_files": report.deleted_files,
+            "kept_media": report.kept_media
         }))
     }
     
-    fn has_worker(&self, worker_name: String) -> ActionResult {
-        let result = self.run_vboxmanage(&[
-            "showvminfo",
-            &worker_name,
-            "--machinereadable"
-        ]);
+    fn get_worker(&self, client: &VirtualBoxClient, worker_name: String) -> VBoxResult<Value> {
+        let mut vm = client.vm_info(&worker_name)?;
+        client.resolve_os_types(std::slice::from_mut(&mut vm))?;
         
-        match result {
-            Ok(_) => Ok(json!({
-                "success": true,
-                "exists": true
-            })),
-            Err(_) => Ok(json!({
-                "success": true,
-                "exists": false
-            }))
-        }
+        Ok(json!({
+            "success": true,
+            "vm": vm
+        }))
     }
     
-    fn start_worker(&self, worker_name: String) -> ActionResult {
-        let _output = self.run_vboxmanage(&[
-            "startvm",
-            &worker_name,
-            "--type",
-            "headless"
-        ])?;
+    fn has_worker(&self, client: &VirtualBoxClient, worker_name: String) -> VBoxResult<Value> {
+        let exists = client.vm_exists(&worker_name)?;
         
         Ok(json!({
             "success": true,
-            "started": worker_name
+            "exists": exists
         }))
     }
     
-    fn get_volumes(&self) -> ActionResult {
-        let output = self.run_vboxmanage(&["list", "hdds"])?;
+    fn start_worker(&self, client: &VirtualBoxClient, worker_name: String, options: StartOptions, wait: Option<WaitSpec>) -> VBoxResult<Value> {
+        client.start_vm_with(&worker_name, &options)?;
         
-        let blocks = output.split("\n\n").collect::<Vec<&str>>();
-        let mut volumes = Vec::new();
+        let result = json!({
+            "success": true,
+            "started": worker_name
+        });
+        self.await_state(client, &worker_name, VmState::Running, wait, result)
+    }
+    
+    fn start_workers(&self, client: &VirtualBoxClient, groups: Vec<StartGroup>, options: StartOptions, parallelism: usize, wait: Option<WaitSpec>) -> VBoxResult<Value> {
+        let target = wait.as_ref().map(|wait| {
+            let target = WaitTarget {
+                state: VmState::Running,
+                min_run_level: wait.run_level,
+            };
+            (target, wait.timeout)
+        });
+        let reports = client.start_vms(&groups, &options, parallelism, target.as_ref().map(|(target, timeout)| (target, *timeout)));
         
-        for block in blocks {
-            if block.trim().is_empty() {
-                continue;
-            }
-            
-            let mut volume = json!({});
-            let lines = block.lines().collect::<Vec<&str>>();
-            
-            for line in lines {
-                if line.starts_with("UUID:") {
-                    if let Some(obj) = volume.as_object_mut() {
-                        obj.insert("id".to_string(), json!(line.trim_start_matches("UUID:").trim()));
-                    }
-                } else if line.starts_with("Location:") {
-                    if let Some(obj) = volume.as_object_mut() {
-                        obj.insert("path".to_string(), json!(line.trim_start_matches("Location:").trim()));
-                    }
-                } else if line.starts_with("Capacity:") {
-                    let size_str = line.trim_start_matches("Capacity:").trim();
-                    let size_parts: Vec<&str> = size_str.split_whitespace().collect();
-                    if size_parts.len() >= 2 && size_parts[1] == "MBytes" {
-                        if let Ok(size) = size_parts[0].parse::<i64>() {
-                            if let Some(obj) = volume.as_object_mut() {
-                                obj.insert("size_mb".to_string(), json!(size));
-                            }
+        let workers: Vec<Value> = reports
+            .iter()
+            .map(|report| {
+                let mut worker = json!({
+                    "name": report.name,
+                    "group": report.group
+                });
+                let obj = worker.as_object_mut().unwrap();
+                match &report.result {
+                    Some(Ok(history)) => {
+                        obj.insert("status".to_string(), json!("started"));
+                        if let Some(last) = history.last() {
+                            obj.insert("state".to_string(), json!(last.state));
+                            obj.insert("history".to_string(), json!(history));
                         }
                     }
-                } else if line.starts_with("Format:") {
-                    if let Some(obj) = volume.as_object_mut() {
-                        obj.insert("format".to_string(), json!(line.trim_start_matches("Format:").trim()));
-                    }
-                } else if line.starts_with("Type:") {
-                    if let Some(obj) = volume.as_object_mut() {
-                        obj.insert("type".to_string(), json!(line.trim_start_matches("Type:").trim()));
-                    }
-                } else if line.starts_with("Parent UUID:") {
-                    if let Some(obj) = volume.as_object_mut() {
-                        obj.insert("parent".to_string(), json!(line.trim_start_matches("Parent UUID:").trim()));
+                    Some(Err(e)) => {
+                        obj.insert("status".to_string(), json!("failed"));
+                        obj.insert("error".to_string(), e.to_payload());
                     }
-                } else if line.starts_with("State:") {
-                    if let Some(obj) = volume.as_object_mut() {
-                        obj.insert("state".to_string(), json!(line.trim_start_matches("State:").trim()));
+                    None => {
+                        obj.insert("status".to_string(), json!("skipped"));
                     }
                 }
-            }
-            
-            if !volume.as_object().unwrap().is_empty() {
-                volumes.push(volume);
-            }
+                worker
+            })
+            .collect();
+        Ok(json!({
+            "success": reports.iter().all(|r| matches!(r.result, Some(Ok(_)))),
+            "workers": workers
+        }))
+    }
+    
+    fn stop_worker(&self, client: &VirtualBoxClient, worker_name: String, grace: Duration, wait: Option<WaitSpec>) -> VBoxResult<Value> {
+        let outcome = client.stop_vm(&worker_name, grace)?;
+        
+        let result = json!({
+            "success": true,
+            "method": outcome.method,
+            "waited_secs": outcome.waited.as_secs_f64()
+        });
+        self.await_state(client, &worker_name, VmState::PowerOff, wait, result)
+    }
+    
+    fn wait_for_worker_state(&self, client: &VirtualBoxClient, worker_name: String, target: WaitTarget, timeout: Duration) -> VBoxResult<Value> {
+        let history = client.wait_for_state(&worker_name, &target, timeout)?;
+        
+        Ok(json!({
+            "success": true,
+            "state": history.last().map(|t| &t.state),
+            "history": history
+        }))
+    }
+    
+    // Block until a lifecycle action's effect is visible, if the caller asked to
+    fn await_state(&self, client: &VirtualBoxClient, worker_name: &str, state: VmState, wait: Option<WaitSpec>, mut result: Value) -> VBoxResult<Value> {
+        let Some(wait) = wait else {
+            return Ok(result);
+        };
+        let target = WaitTarget {
+            state,
+            min_run_level: wait.run_level,
+        };
+        let history = client.wait_for_state(worker_name, &target, wait.timeout)?;
+        if let Some(obj) = result.as_object_mut() {
+            obj.insert("state".to_string(), json!(history.last().map(|t| &t.state)));
+            obj.insert("history".to_string(), json!(history));
         }
+        Ok(result)
+    }
+    
+    fn pause_worker(&self, client: &VirtualBoxClient, worker_name: String, wait: Option<WaitSpec>) -> VBoxResult<Value> {
+        client.pause_vm(&worker_name)?;
+        
+        let result = json!({
+            "success": true
+        });
+        self.await_state(client, &worker_name, VmState::Paused, wait, result)
+    }
+    
+    fn resume_worker(&self, client: &VirtualBoxClient, worker_name: String, wait: Option<WaitSpec>) -> VBoxResult<Value> {
+        client.resume_vm(&worker_name)?;
+        
+        let result = json!({
+            "success": true
+        });
+        self.await_state(client, &worker_name, VmState::Running, wait, result)
+    }
+    
+    fn save_worker_state(&self, client: &VirtualBoxClient, worker_name: String, wait: Option<WaitSpec>) -> VBoxResult<Value> {
+        client.save_state(&worker_name)?;
+        
+        let result = json!({
+            "success": true
+        });
+        self.await_state(client, &worker_name, VmState::Saved, wait, result)
+    }
+    
+    fn discard_saved_state(&self, client: &VirtualBoxClient, worker_name: String, wait: Option<WaitSpec>) -> VBoxResult<Value> {
+        client.discard_saved_state(&worker_name)?;
+        
+        let result = json!({
+            "success": true
+        });
+        self.await_state(client, &worker_name, VmState::PowerOff, wait, result)
+    }
+    
+    fn get_volumes(&self, client: &VirtualBoxClient) -> VBoxResult<Value> {
+        let volumes = client.list_media()?;
         
         Ok(json!({
             "success": true,
@@ -346,186 +537,108 @@ impl VirtualBoxExtension {
         }))
     }
     
-    fn has_volume(&self, disk_path: String) -> ActionResult {
-        let result = self.run_vboxmanage(&[
-            "showmediuminfo",
-            "disk",
-            &disk_path
-        ]);
+    fn has_volume(&self, client: &VirtualBoxClient, disk_path: String) -> VBoxResult<Value> {
+        let exists = client.medium_exists(&disk_path)?;
         
-        match result {
-            Ok(_) => Ok(json!({
-                "success": true,
-                "exists": true
-            })),
-            Err(_) => Ok(json!({
-                "success": true,
-                "exists": false
-            }))
-        }
+        Ok(json!({
+            "success": true,
+            "exists": exists
+        }))
     }
     
-    fn create_volume(&self, disk_path: String, size_mb: i64) -> ActionResult {
-        let output = self.run_vboxmanage(&[
-            "createmedium",
-            "disk",
-            "--filename",
-            &disk_path,
-            "--size",
-            &size_mb.to_string(),
-            "--format",
-            "VDI"
-        ])?;
-        
-        let mut uuid = String::new();
-        let mut path = String::new();
-        
-        for line in output.lines() {
-            if line.contains("UUID:") {
-                let parts: Vec<&str> = line.split(':').collect();
-                if parts.len() >= 2 {
-                    uuid = parts[1].trim().to_string();
-                }
-            } else if line.contains("Location:") {
-                let parts: Vec<&str> = line.split(':').collect();
-                if parts.len() >= 2 {
-                    path = parts[1].trim().to_string();
-                }
-            }
-        }
+    fn create_volume(&self, client: &VirtualBoxClient, disk_path: String, size_mb: i64) -> VBoxResult<Value> {
+        let medium = client.create_medium(&disk_path, size_mb)?;
         
         Ok(json!({
             "success": true,
-            "uuid": uuid,
-            "path": path
+            "uuid": medium.id,
+            "path": medium.path
         }))
     }
     
-    fn delete_volume(&self, disk_path: String) -> ActionResult {
-        self.run_vboxmanage(&[
-            "closemedium",
-            "disk",
-            &disk_path,
-            "--delete"
-        ])?;
+    fn delete_volume(&self, client: &VirtualBoxClient, disk_path: String) -> VBoxResult<Value> {
+        client.delete_medium(&disk_path)?;
         
         Ok(json!({
             "success": true
         }))
     }
     
-    fn attach_volume(&self, worker_name: String, controller_name: String, port: i64, disk_path: String) -> ActionResult {
-        // Create the storage controller first
-        let _ = self.run_vboxmanage(&[
-            "storagectl",
-            &worker_name,
-            "--name",
-            &controller_name,
-            "--add",
-            "sata",
-            "--controller",
-            "IntelAhci",
-            "--portcount",
-            "30"
-        ]);
-        
-        // Now attach the disk
-        self.run_vboxmanage(&[
-            "storageattach",
-            &worker_name,
-            "--storagectl",
-            &controller_name,
-            "--port",
-            &port.to_string(),
-            "--device",
-            "0",
-            "--type",
-            "dvddrive",
-            "--medium",
-            &disk_path
-        ])?;
+    fn attach_volume(&self, client: &VirtualBoxClient, worker_name: String, controller_name: String, port: u32, disk_path: String, options: AttachOptions) -> VBoxResult<Value> {
+        client.attach_medium(&worker_name, &controller_name, port, &disk_path, &options)?;
         
         Ok(json!({
-            "success": true
+            "success": true,
+            "device_type": options.device_type
         }))
     }
     
-    fn detach_volume(&self, worker_name: String, controller_name: String, port: i64) -> ActionResult {
-        self.run_vboxmanage(&[
-            "storageattach",
-            &worker_name,
-            "--storagectl",
-            &controller_name,
-            "--port",
-            &port.to_string(),
-            "--device",
-            "0",
-            "--type",
-            "hdd",
-            "--medium",
-            "none"
-        ])?;
+    fn list_worker_attachments(&self, client: &VirtualBoxClient, worker_name: String) -> VBoxResult<Value> {
+        let attachments = client.list_attachments(&worker_name)?;
         
         Ok(json!({
-            "success": true
+            "success": true,
+            "attachments": attachments
         }))
     }
     
-    fn create_snapshot(&self, worker_name: String, snapshot_name: String) -> ActionResult {
-        let output = self.run_vboxmanage(&[
-            "snapshot",
-            &worker_name,
-            "take",
-            &snapshot_name
-        ])?;
+    fn add_storage_controller(&self, client: &VirtualBoxClient, worker_name: String, controller: ControllerSpec) -> VBoxResult<Value> {
+        client.add_storage_controller(&worker_name, &controller)?;
         
-        let mut uuid = String::new();
+        Ok(json!({
+            "success": true,
+            "bus": controller.bus,
+            "chipset": controller.chipset.as_deref().unwrap_or(controller.bus.chipsets()[0])
+        }))
+    }
+    
+    fn remove_storage_controller(&self, client: &VirtualBoxClient, worker_name: String, controller_name: String) -> VBoxResult<Value> {
+        client.remove_storage_controller(&worker_name, &controller_name)?;
         
-        for line in output.lines() {
-            if line.contains("taken as") {
-                let parts: Vec<&str> = line.split("taken as").collect();
-                if parts.len() >= 2 {
-                    uuid = parts[1].trim().to_string();
-                    break;
-                }
-            }
-        }
+        Ok(json!({
+            "success": true
+        }))
+    }
+    
+    fn list_storage_controllers(&self, client: &VirtualBoxClient, worker_name: String) -> VBoxResult<Value> {
+        let controllers = client.list_storage_controllers(&worker_name)?;
         
         Ok(json!({
             "success": true,
-            "uuid": uuid
+            "controllers": controllers
         }))
     }
     
-    fn delete_snapshot(&self, worker_name: String, snapshot_name: String) -> ActionResult {
-        self.run_vboxmanage(&[
-            "snapshot",
-            &worker_name,
-            "delete",
-            &snapshot_name
-        ])?;
+    fn detach_volume(&self, client: &VirtualBoxClient, worker_name: String, controller_name: String, port: u32, device: u32) -> VBoxResult<Value> {
+        client.detach_device(&worker_name, &controller_name, port, device)?;
         
         Ok(json!({
             "success": true
         }))
     }
     
-    fn has_snapshot(&self, worker_name: String, snapshot_name: String) -> ActionResult {
-        let output = self.run_vboxmanage(&[
-            "snapshot",
-            &worker_name,
-            "list",
-            "--machinereadable"
-        ])?;
+    fn create_snapshot(&self, client: &VirtualBoxClient, worker_name: String, snapshot_name: String) -> VBoxResult<Value> {
+        let snapshot = client.take_snapshot(&worker_name, &snapshot_name)?;
         
-        let mut exists = false;
+        Ok(json!({
+            "success": true,
+            "uuid": snapshot.id
+        }))
+    }
+    
+    fn delete_snapshot(&self, client: &VirtualBoxClient, worker_name: String, snapshot_name: String) -> VBoxResult<Value> {
+        client.delete_snapshot(&worker_name, &snapshot_name)?;
         
-        for line in output.lines() {
-            if line.contains(&snapshot_name) {
-                exists = true;
-                break;
-            }
-        }
+        Ok(json!({
+            "success": true
+        }))
+    }
+    
+    fn has_snapshot(&self, client: &VirtualBoxClient, worker_name: String, snapshot_name: String) -> VBoxResult<Value> {
+        let exists = client
+            .list_snapshots(&worker_name)?
+            .iter()
+            .any(|s| s.name == snapshot_name || s.id == snapshot_name);
         
         Ok(json!({
             "success": true,
@@ -533,67 +646,46 @@ impl VirtualBoxExtension {
         }))
     }
     
-    fn reboot_worker(&self, worker_name: String) -> ActionResult {
-        self.run_vboxmanage(&[
-            "controlvm",
-            &worker_name,
-            "reset"
-        ])?;
+    fn reboot_worker(&self, client: &VirtualBoxClient, worker_name: String, wait: Option<WaitSpec>) -> VBoxResult<Value> {
+        let Some(wait) = wait else {
+            client.reset_vm(&worker_name)?;
+            return Ok(json!({
+                "success": true
+            }));
+        };
+        // The VM is `running` before and after a reset; only the guest's
+        // run level shows that it went down and came back
+        let history = client.reset_vm_and_wait(&worker_name, wait.run_level, wait.timeout)?;
         
         Ok(json!({
-            "success": true
+            "success": true,
+            "state": history.last().map(|t| &t.state),
+            "history": history
         }))
     }
     
-    fn configure_networks(&self, worker_name: String, network_index: i64, network_type: String) -> ActionResult {
-        self.run_vboxmanage(&[
-            "modifyvm",
-            &worker_name,
-            &format!("--nic{}", network_index),
-            &network_type
-        ])?;
+    fn configure_networks(&self, client: &VirtualBoxClient, worker_name: String, nic: Nic) -> VBoxResult<Value> {
+        client.set_nic(&worker_name, &nic)?;
         
         Ok(json!({
             "success": true
         }))
     }
     
-    fn set_worker_metadata(&self, worker_name: String, key: String, value: String) -> ActionResult {
-        self.run_vboxmanage(&[
-            "setextradata",
-            &worker_name,
-            &key,
-            &value
-        ])?;
+    fn set_worker_metadata(&self, client: &VirtualBoxClient, worker_name: String, key: String, value: String) -> VBoxResult<Value> {
+        client.set_extradata(&worker_name, &key, &value)?;
         
         Ok(json!({
             "success": true
         }))
     }
     
-    fn snapshot_volume(&self, source_volume_path: String, target_volume_path: String) -> ActionResult {
-        let output = self.run_vboxmanage(&[
-            "clonemedium",
-            "disk",
-            &source_volume_path,
-            &target_volume_path
-        ])?;
-        
-        let mut uuid = String::new();
-        
-        for line in output.lines() {
-            if line.contains("UUID:") {
-                let parts: Vec<&str> = line.split(':').collect();
-                if parts.len() >= 2 {
-                    uuid = parts[1].trim().to_string();
-                    break;
-                }
-            }
-        }
+    fn snapshot_volume(&self, client: &VirtualBoxClient, source_volume_path: String, target_volume_path: String) -> VBoxResult<Value> {
+        let medium = client.clone_medium(&source_volume_path, &target_volume_path)?;
         
         Ok(json!({
             "success": true,
-            "uuid": uuid
+            "uuid": medium.id
         }))
     }
 }
@@ -612,28 +704,57 @@ impl CpiExtension for VirtualBoxExtension {
             "test_install".to_string(),
             "list_workers".to_string(),
             "create_worker".to_string(),
+            "clone_worker".to_string(),
+            "import_appliance".to_string(),
+            "export_appliance".to_string(),
+            "apply_worker_spec".to_string(),
+            "diff_worker_spec".to_string(),
+            "install_os_unattended".to_string(),
+            "create_cloud_init_seed".to_string(),
             "delete_worker".to_string(),
             "get_worker".to_string(),
             "has_worker".to_string(),
             "start_worker".to_string(),
+            "stop_worker".to_string(),
+            "pause_worker".to_string(),
+            "resume_worker".to_string(),
+            "save_worker_state".to_string(),
+            "discard_saved_state".to_string(),
+            "wait_for_worker_state".to_string(),
             "get_volumes".to_string(),
             "has_volume".to_string(),
             "create_volume".to_string(),
             "delete_volume".to_string(),
             "attach_volume".to_string(),
             "detach_volume".to_string(),
+            "list_worker_attachments".to_string(),
+            "add_storage_controller".to_string(),
+            "remove_storage_controller".to_string(),
+            "list_storage_controllers".to_string(),
             "create_snapshot".to_string(),
             "delete_snapshot".to_string(),
             "has_snapshot".to_string(),
             "reboot_worker".to_string(),
             "configure_networks".to_string(),
             "set_worker_metadata".to_string(),
-            "snapshot_volume".to_string()
+            "snapshot_volume".to_string(),
+            "cancel_actions".to_string()
         ]
     }
     
     fn get_action_definition(&self, action: &str) -> Option<ActionDefinition> {
-        match action {
+        if action == "cancel_actions" {
+            return Some(ActionDefinition {
+                name: "cancel_actions".to_string(),
+                description: "Cancel in-flight actions and kill their VBoxManage processes".to_string(),
+                parameters: vec![
+                    param!("action", "Only cancel actions with this name", ParamType::String, optional),
+                ],
+            });
+        }
+
+        let defaults = &self.default_settings;
+        let mut definition = match action {
             "test_install" => Some(ActionDefinition {
                 name: "test_install".to_string(),
                 description: "Test if VirtualBox is properly installed".to_string(),
@@ -641,17 +762,135 @@ impl CpiExtension for VirtualBoxExtension {
             }),
             "list_workers" => Some(ActionDefinition {
                 name: "list_workers".to_string(),
-                description: "List all virtual machines".to_string(),
-                parameters: vec![],
+                description: "List virtual machines with their state and hardware".to_string(),
+                parameters: vec![
+                    param!("state", "Only VMs in one of these comma-separated states, e.g. running,paused", ParamType::String, optional),
+                    param!("group", "Only VMs in this group or its subgroups", ParamType::String, optional),
+                    param!("name_pattern", "Only VMs whose name matches this glob", ParamType::String, optional),
+                    param!("tag", "Only VMs with this extradata key, or key=value", ParamType::String, optional),
+                ],
             }),
             "create_worker" => Some(ActionDefinition {
                 name: "create_worker".to_string(),
                 description: "Create a new virtual machine".to_string(),
                 parameters: vec![
                     param!("worker_name", "Name of the VM to create", ParamType::String, required),
-                    param!("os_type", "Operating system type", ParamType::String, optional, json!("Ubuntu_64")),
-                    param!("memory_mb", "Memory in MB", ParamType::Integer, optional, json!(2048)),
-                    param!("cpu_count", "Number of CPUs", ParamType::Integer, optional, json!(2)),
+                    param!("os_type", "Operating system type", ParamType::String, optional, json!(defaults.os_type)),
+                    param!("memory_mb", "Memory in MB", ParamType::Integer, optional, json!(defaults.memory_mb)),
+                    param!("cpu_count", "Number of CPUs", ParamType::Integer, optional, json!(defaults.cpu_count)),
+                    param!("firmware", "Firmware: bios, efi, efi32 or efi64", ParamType::String, optional),
+                    param!("chipset", "Chipset: piix3 or ich9", ParamType::String, optional),
+                    param!("vram_mb", "Video memory in MB", ParamType::Integer, optional),
+                    param!("graphics_controller", "Graphics controller: none, vboxvga, vmsvga or vboxsvga", ParamType::String, optional),
+                    param!("nested_virtualization", "Expose hardware virtualization to the guest", ParamType::Boolean, optional),
+                    param!("pae", "Enable PAE/NX", ParamType::Boolean, optional),
+                    param!("paravirt_provider", "Paravirtualization interface: none, default, legacy, minimal, hyperv or kvm", ParamType::String, optional),
+                    param!("boot_order", "Comma-separated boot devices (none, floppy, dvd, disk, net), at most 4", ParamType::String, optional),
+                    param!("description", "VM description", ParamType::String, optional),
+                    param!("groups", "Comma-separated group paths, e.g. /web/prod", ParamType::String, optional),
+                    param!("base_folder", "Directory to create the VM folder in", ParamType::String, optional),
+                    param!("rtc_utc", "Keep the hardware clock in UTC", ParamType::Boolean, optional),
+                    param!("audio", "Enable audio; false turns it off", ParamType::Boolean, optional),
+                    param!("disks", "Hard disks to create and attach, e.g. [{\"size_mb\": 20480, \"path\": \"/vms/web-1.vdi\"}]", ParamType::Json, optional),
+                    param!("controller_name", "Storage controller to add for the disks", ParamType::String, optional, json!(defaults.controller_name)),
+                    param!("nics", "Network adapters, e.g. [{\"index\": 1, \"type\": \"nat\"}]; NIC 1 uses the default network type when omitted", ParamType::Json, optional),
+                ],
+            }),
+            "clone_worker" => Some(ActionDefinition {
+                name: "clone_worker".to_string(),
+                description: "Clone a VM into a new one, as a full or linked clone".to_string(),
+                parameters: vec![
+                    param!("worker_name", "Name of the VM to clone", ParamType::String, required),
+                    param!("new_name", "Name of the new VM", ParamType::String, required),
+                    param!("linked", "Share the source's disks through differencing images", ParamType::Boolean, optional, json!(false)),
+                    param!("snapshot", "Snapshot to clone from, taken first if missing", ParamType::String, optional),
+                    param!("mac_policy", "MAC addresses to keep: regenerate, keep_nat or keep_all", ParamType::String, optional, json!(MacPolicy::default().as_str())),
+                    param!("uuid", "UUID for the new VM", ParamType::String, optional),
+                    param!("groups", "Comma-separated group paths, e.g. /web/prod", ParamType::String, optional),
+                    param!("base_folder", "Directory to create the VM folder in", ParamType::String, optional),
+                ],
+            }),
+            "import_appliance" => Some(ActionDefinition {
+                name: "import_appliance".to_string(),
+                description: "Import a VM from an OVF/OVA appliance, or list its contents".to_string(),
+                parameters: vec![
+                    param!("appliance_path", "Path of the .ova or .ovf file", ParamType::String, required),
+                    param!("dry_run", "Only list the appliance's virtual system description", ParamType::Boolean, optional, json!(false)),
+                    param!("new_name", "Name of the imported VM instead of the suggested one", ParamType::String, optional),
+                    param!("memory_mb", "Memory in MB instead of the suggested amount", ParamType::Integer, optional),
+                    param!("cpu_count", "Number of CPUs instead of the suggested number", ParamType::Integer, optional),
+                    param!("disk_paths", "Comma-separated target paths for the appliance's hard disks, in order", ParamType::String, optional),
+                    param!("mac_policy", "MAC addresses to keep: regenerate, keep_nat or keep_all", ParamType::String, optional, json!(MacPolicy::default().as_str())),
+                    param!("accept_eula", "Accept the appliance's license agreement", ParamType::Boolean, optional, json!(false)),
+                ],
+            }),
+            "export_appliance" => Some(ActionDefinition {
+                name: "export_appliance".to_string(),
+                description: "Export a VM as an OVF/OVA appliance".to_string(),
+                parameters: vec![
+                    param!("worker_name", "Name of the VM to export", ParamType::String, required),
+                    param!("output_path", "File to write; .ova for a single archive, .ovf for separate files", ParamType::String, required),
+                    param!("ovf_version", "OVF standard version: 1.0 or 2.0", ParamType::String, optional, json!("1.0")),
+                    param!("manifest", "Write a manifest of checksums", ParamType::Boolean, optional, json!(true)),
+                    param!("product", "Product name", ParamType::String, optional),
+                    param!("product_url", "Product URL", ParamType::String, optional),
+                    param!("vendor", "Vendor name", ParamType::String, optional),
+                    param!("vendor_url", "Vendor URL", ParamType::String, optional),
+                    param!("version", "Product version", ParamType::String, optional),
+                    param!("description", "Appliance description", ParamType::String, optional),
+                ],
+            }),
+            "apply_worker_spec" => Some(ActionDefinition {
+                name: "apply_worker_spec".to_string(),
+                description: "Create or update a VM to match a declarative spec, changing only what differs".to_string(),
+                parameters: vec![
+                    param!("spec", "Spec as a JSON object, or JSON or TOML text", ParamType::Json, optional),
+                    param!("spec_file", "Path of a JSON or TOML spec file, used when 'spec' is not given", ParamType::String, optional),
+                ],
+            }),
+            "diff_worker_spec" => Some(ActionDefinition {
+                name: "diff_worker_spec".to_string(),
+                description: "List the changes apply_worker_spec would make, without making them".to_string(),
+                parameters: vec![
+                    param!("spec", "Spec as a JSON object, or JSON or TOML text", ParamType::Json, optional),
+                    param!("spec_file", "Path of a JSON or TOML spec file, used when 'spec' is not given", ParamType::String, optional),
+                ],
+            }),
+            "install_os_unattended" => Some(ActionDefinition {
+                name: "install_os_unattended".to_string(),
+                description: "Install a guest OS from an ISO unattended, optionally booting and waiting for it".to_string(),
+                parameters: vec![
+                    param!("worker_name", "Name of the VM to install", ParamType::String, required),
+                    param!("iso_path", "Installation ISO", ParamType::String, required),
+                    param!("username", "Login of the user to create", ParamType::String, optional, json!(defaults.username)),
+                    param!("password", "Password of the user to create; defaults to the configured password", ParamType::String, optional),
+                    param!("full_name", "Full name of the user to create", ParamType::String, optional),
+                    param!("hostname", "Fully qualified host name, e.g. web-1.example.com", ParamType::String, optional),
+                    param!("locale", "Locale, e.g. en_US", ParamType::String, optional),
+                    param!("time_zone", "Time zone, e.g. UTC or Europe/Berlin", ParamType::String, optional),
+                    param!("install_additions", "Install the Guest Additions", ParamType::Boolean, optional, json!(false)),
+                    param!("post_install_command", "Command run in the guest after installation", ParamType::String, optional),
+                    param!("start", "Boot the VM into the installer", ParamType::Boolean, optional, json!(false)),
+                    param!("wait", "Wait for the installed guest; needs 'start', and 'install_additions' or 'guest_run_level'", ParamType::Boolean, optional, json!(false)),
+                    param!("wait_timeout_secs", "Seconds to wait for the installation when 'wait' is set", ParamType::Integer, optional, json!(DEFAULT_INSTALL_TIMEOUT.as_secs())),
+                    param!("guest_run_level", "Guest Additions run level marking the installation done; 2 (userland) when installing them", ParamType::Integer, optional),
+                ],
+            }),
+            "create_cloud_init_seed" => Some(ActionDefinition {
+                name: "create_cloud_init_seed".to_string(),
+                description: "Build a cloud-init NoCloud seed ISO and insert it into the VM's DVD drive".to_string(),
+                parameters: vec![
+                    param!("worker_name", "Name of the VM to seed", ParamType::String, required),
+                    param!("iso_path", "Where to write the ISO; next to the VM's settings file by default", ParamType::String, optional),
+                    param!("hostname", "Guest host name; the worker name by default", ParamType::String, optional),
+                    param!("instance_id", "cloud-init instance id; iid-<worker name> by default", ParamType::String, optional),
+                    param!("ssh_authorized_keys", "SSH public keys for the default user, e.g. [\"ssh-ed25519 AAAA...\"]", ParamType::Json, optional),
+                    param!("users", "Extra users, e.g. [{\"name\": \"deploy\", \"sudo\": \"ALL=(ALL) NOPASSWD:ALL\", \"ssh_authorized_keys\": [...]}]", ParamType::Json, optional),
+                    param!("runcmd", "Commands to run at the end of first boot, e.g. [\"apt-get update\"]", ParamType::Json, optional),
+                    param!("network_config", "cloud-init network config object; DHCP on every en* interface by default", ParamType::Json, optional),
+                    param!("controller_name", "Storage controller for the DVD drive", ParamType::String, optional, json!(defaults.controller_name)),
+                    param!("create_controller", "Add the controller first if the VM does not have it", ParamType::Boolean, optional, json!(false)),
+                    param!("controller_bus", "Bus of a controller created by create_controller", ParamType::String, optional, json!("sata")),
                 ],
             }),
             "delete_worker" => Some(ActionDefinition {
@@ -659,6 +898,9 @@ impl CpiExtension for VirtualBoxExtension {
                 description: "Delete a virtual machine".to_string(),
                 parameters: vec![
                     param!("worker_name", "Name of the VM to delete", ParamType::String, required),
+                    param!("force", "Power the VM off and wait for its session to unlock first", ParamType::Boolean, optional, json!(false)),
+                    param!("keep_disks", "Comma-separated paths, file names or UUIDs of attached media to detach and keep", ParamType::String, optional),
+                    param!("unlock_timeout_secs", "Seconds to wait for the session to unlock after powering off", ParamType::Integer, optional, json!(DeleteOptions::default().unlock_timeout.as_secs())),
                 ],
             }),
             "get_worker" => Some(ActionDefinition {
@@ -677,9 +919,60 @@ impl CpiExtension for VirtualBoxExtension {
             }),
             "start_worker" => Some(ActionDefinition {
                 name: "start_worker".to_string(),
-                description: "Start a virtual machine".to_string(),
+                description: "Start a virtual machine, or several in dependency order; with several, 'success' is false if any failed".to_string(),
+                parameters: vec![
+                    param!("worker_name", "Name of the VM to start", ParamType::String, optional),
+                    param!("worker_names", "Comma-separated VMs to start together instead of 'worker_name'", ParamType::String, optional),
+                    param!("start_order", "Groups started one after another instead of 'worker_name', e.g. [{\"workers\": [\"db-1\"]}, {\"workers\": [\"web-1\", \"web-2\"], \"delay_secs\": 30}]", ParamType::Json, optional),
+                    param!("parallelism", "How many VMs of a group to start at once", ParamType::Integer, optional, json!(DEFAULT_START_PARALLELISM)),
+                    param!("start_type", "Front end: headless, gui, sdl or separate", ParamType::String, optional, json!(StartMode::default().as_str())),
+                    param!("env", "Environment for the VM process, e.g. {\"DISPLAY\": \":1\"}", ParamType::Json, optional),
+                ],
+            }),
+            "stop_worker" => Some(ActionDefinition {
+                name: "stop_worker".to_string(),
+                description: "Shut a VM down via ACPI, powering it off if it does not stop in time".to_string(),
+                parameters: vec![
+                    param!("worker_name", "Name of the VM to stop", ParamType::String, required),
+                    param!("grace_secs", "Seconds to wait for an ACPI shutdown before powering off; 0 powers off immediately", ParamType::Integer, optional, json!(DEFAULT_STOP_GRACE.as_secs())),
+                ],
+            }),
+            "pause_worker" => Some(ActionDefinition {
+                name: "pause_worker".to_string(),
+                description: "Pause a running VM".to_string(),
+                parameters: vec![
+                    param!("worker_name", "Name of the VM to pause", ParamType::String, required),
+                ],
+            }),
+            "resume_worker" => Some(ActionDefinition {
+                name: "resume_worker".to_string(),
+                description: "Resume a paused VM".to_string(),
                 parameters: vec![
-                    param!("worker_name", "Name of the VM to start", ParamType::String, required),
+                    param!("worker_name", "Name of the VM to resume", ParamType::String, required),
+                ],
+            }),
+            "save_worker_state" => Some(ActionDefinition {
+                name: "save_worker_state".to_string(),
+                description: "Save a VM's state to disk and stop it".to_string(),
+                parameters: vec![
+                    param!("worker_name", "Name of the VM", ParamType::String, required),
+                ],
+            }),
+            "discard_saved_state" => Some(ActionDefinition {
+                name: "discard_saved_state".to_string(),
+                description: "Discard a VM's saved state, leaving it powered off".to_string(),
+                parameters: vec![
+                    param!("worker_name", "Name of the VM", ParamType::String, required),
+                ],
+            }),
+            "wait_for_worker_state" => Some(ActionDefinition {
+                name: "wait_for_worker_state".to_string(),
+                description: "Wait until a VM reaches a state and report the transitions observed".to_string(),
+                parameters: vec![
+                    param!("worker_name", "Name of the VM", ParamType::String, required),
+                    param!("state", "State to wait for, e.g. running, poweroff, paused, saved", ParamType::String, required),
+                    param!("guest_run_level", "Also wait for this Guest Additions run level (1 system, 2 userland, 3 desktop)", ParamType::Integer, optional),
+                    param!("wait_timeout_secs", "Seconds to wait before giving up", ParamType::Integer, optional, json!(DEFAULT_WAIT_TIMEOUT.as_secs())),
                 ],
             }),
             "get_volumes" => Some(ActionDefinition {
@@ -711,12 +1004,20 @@ impl CpiExtension for VirtualBoxExtension {
             }),
             "attach_volume" => Some(ActionDefinition {
                 name: "attach_volume".to_string(),
-                description: "Create a storage controller and attach a disk to a VM".to_string(),
+                description: "Attach a disk, DVD or floppy image to a VM".to_string(),
                 parameters: vec![
                     param!("worker_name", "Name of the VM", ParamType::String, required),
-                    param!("controller_name", "Name of the storage controller", ParamType::String, optional, json!("SATA Controller")),
+                    param!("controller_name", "Name of the storage controller", ParamType::String, optional, json!(defaults.controller_name)),
+                    param!("create_controller", "Add the controller first if the VM does not have it", ParamType::Boolean, optional, json!(false)),
+                    param!("controller_bus", "Bus of a controller created by create_controller", ParamType::String, optional, json!("sata")),
                     param!("port", "Port number", ParamType::Integer, required),
+                    param!("device", "Device slot on the port; only IDE ports have two", ParamType::Integer, optional, json!(0)),
                     param!("disk_path", "Path to the disk", ParamType::String, required),
+                    param!("device_type", "hdd, dvddrive or fdd; inferred from the file extension, hdd if it does not tell", ParamType::String, optional),
+                    param!("nonrotational", "Report the disk to the guest as an SSD", ParamType::Boolean, optional),
+                    param!("discard", "Pass the guest's TRIM requests on to shrink the image", ParamType::Boolean, optional),
+                    param!("hotpluggable", "Allow attaching and detaching while the VM runs", ParamType::Boolean, optional),
+                    param!("mtype", "Medium type: normal, writethrough, immutable, shareable, readonly or multiattach", ParamType::String, optional),
                 ],
             }),
             "detach_volume" => Some(ActionDefinition {
@@ -724,8 +1025,44 @@ impl CpiExtension for VirtualBoxExtension {
                 description: "Detach a disk from a VM".to_string(),
                 parameters: vec![
                     param!("worker_name", "Name of the VM", ParamType::String, required),
-                    param!("controller_name", "Name of the storage controller", ParamType::String, optional, json!("SATA Controller")),
+                    param!("controller_name", "Name of the storage controller", ParamType::String, optional, json!(defaults.controller_name)),
                     param!("port", "Port number", ParamType::Integer, required),
+                    param!("device", "Device slot on the port", ParamType::Integer, optional, json!(0)),
+                ],
+            }),
+            "list_worker_attachments" => Some(ActionDefinition {
+                name: "list_worker_attachments".to_string(),
+                description: "List the media on each controller port of a VM, with their type and whether they are differencing images".to_string(),
+                parameters: vec![
+                    param!("worker_name", "Name of the VM", ParamType::String, required),
+                ],
+            }),
+            "add_storage_controller" => Some(ActionDefinition {
+                name: "add_storage_controller".to_string(),
+                description: "Add a storage controller to a VM".to_string(),
+                parameters: vec![
+                    param!("worker_name", "Name of the VM", ParamType::String, required),
+                    param!("controller_name", "Name of the new controller", ParamType::String, optional, json!(defaults.controller_name)),
+                    param!("bus", "ide, sata, scsi, sas, nvme, virtio_scsi or floppy", ParamType::String, optional, json!("sata")),
+                    param!("chipset", "Emulated chipset, e.g. PIIX4 or ICH6 for IDE, BusLogic for SCSI; the bus's usual one by default", ParamType::String, optional),
+                    param!("port_count", "Number of ports", ParamType::Integer, optional),
+                    param!("host_io_cache", "Use the host's I/O cache for attached media", ParamType::Boolean, optional),
+                    param!("bootable", "Let the firmware boot from this controller", ParamType::Boolean, optional),
+                ],
+            }),
+            "remove_storage_controller" => Some(ActionDefinition {
+                name: "remove_storage_controller".to_string(),
+                description: "Remove a storage controller and detach everything on it".to_string(),
+                parameters: vec![
+                    param!("worker_name", "Name of the VM", ParamType::String, required),
+                    param!("controller_name", "Name of the controller", ParamType::String, required),
+                ],
+            }),
+            "list_storage_controllers" => Some(ActionDefinition {
+                name: "list_storage_controllers".to_string(),
+                description: "List the storage controllers of a VM".to_string(),
+                parameters: vec![
+                    param!("worker_name", "Name of the VM", ParamType::String, required),
                 ],
             }),
             "create_snapshot" => Some(ActionDefinition {
@@ -765,7 +1102,7 @@ impl CpiExtension for VirtualBoxExtension {
                 parameters: vec![
                     param!("worker_name", "Name of the VM", ParamType::String, required),
                     param!("network_index", "Network adapter index", ParamType::Integer, required),
-                    param!("network_type", "Network type", ParamType::String, optional, json!("nat")),
+                    param!("network_type", "Network type", ParamType::String, optional, json!(defaults.network_type.as_str())),
                 ],
             }),
             "set_worker_metadata" => Some(ActionDefinition {
@@ -786,105 +1123,567 @@ impl CpiExtension for VirtualBoxExtension {
                 ],
             }),
             _ => None,
+        }?;
+
+        if LIFECYCLE_ACTIONS.contains(&action) {
+            definition.parameters.push(param!("wait", "Wait until the VM reaches the resulting state", ParamType::Boolean, optional, json!(false)));
+            definition.parameters.push(param!("wait_timeout_secs", "Seconds to wait for the state when 'wait' is set", ParamType::Integer, optional, json!(DEFAULT_WAIT_TIMEOUT.as_secs())));
+            if matches!(action, "start_worker" | "reboot_worker" | "resume_worker") {
+                definition.parameters.push(param!("guest_run_level", "Also wait for this Guest Additions run level (1 system, 2 userland, 3 desktop)", ParamType::Integer, optional));
+            }
         }
+
+        // Every VBoxManage-backed action accepts a per-call timeout
+        definition.parameters.push(param!(
+            "timeout_secs",
+            "Timeout in seconds for each VBoxManage invocation",
+            ParamType::Integer,
+            optional,
+            json!(self.action_timeouts.get(action).copied().or(self.timeout).map(|t| t.as_secs()))
+        ));
+        Some(definition)
     }
     
     fn execute_action(&self, action: &str, params: &HashMap<String, Value>) -> ActionResult {
+        log::debug!("Executing action '{}'", action);
+        let result = self.dispatch(action, params);
+        if let Err(e) = &result {
+            log::info!("Action '{}' failed: {}", action, e);
+        }
+        result.map_err(String::from)
+    }
+}
+
+impl VirtualBoxExtension {
+    fn dispatch(&self, action: &str, params: &HashMap<String, Value>) -> VBoxResult<Value> {
+        if action == "cancel_actions" {
+            let filter = validation::extract_string_opt(params, "action")?;
+            let cancelled = self.cancel_actions(filter.as_deref());
+            return Ok(json!({
+                "success": true,
+                "cancelled": cancelled
+            }));
+        }
+
+        let timeout = match validation::extract_int_opt(params, "timeout_secs")? {
+            Some(secs) if secs <= 0 => {
+                return Err(VBoxError::InvalidParameter("Parameter 'timeout_secs' must be positive".to_string()));
+            }
+            Some(secs) => Some(Duration::from_secs(secs as u64)),
+            None => self.action_timeouts.get(action).copied().or(self.timeout),
+        };
+        let (client, _guard) = self.begin_action(action, timeout);
+        let client = &client;
+
         match action {
-            "test_install" => self.test_install(),
-            "list_workers" => self.list_workers(),
+            "test_install" => self.test_install(client),
+            "list_workers" => {
+                let states = validation::extract_string_opt(params, "state")?
+                    .map(|states| {
+                        states
+                            .split(',')
+                            .map(str::trim)
+                            .filter(|s| !s.is_empty())
+                            .map(str::parse)
+                            .collect::<Result<_, _>>()
+                    })
+                    .transpose()?
+                    .unwrap_or_default();
+                let tag = validation::extract_string_opt(params, "tag")?.map(|tag| match tag.split_once('=') {
+                    Some((key, value)) => (key.to_string(), Some(value.to_string())),
+                    None => (tag, None),
+                });
+                let filter = VmFilter {
+                    states,
+                    group: validation::extract_string_opt(params, "group")?,
+                    name_pattern: validation::extract_string_opt(params, "name_pattern")?,
+                    tag,
+                };
+                
+                self.list_workers(client, filter)
+            },
             "create_worker" => {
+                let defaults = &self.default_settings;
+                let mut config = VmConfig {
+                    name: validation::extract_string(params, "worker_name")?,
+                    os_type: validation::extract_string_opt(params, "os_type")?.unwrap_or_else(|| defaults.os_type.clone()),
+                    memory_mb: validation::extract_int_opt(params, "memory_mb")?.unwrap_or(defaults.memory_mb),
+                    cpu_count: validation::extract_int_opt(params, "cpu_count")?.unwrap_or(defaults.cpu_count),
+                    firmware: validation::extract_string_opt(params, "firmware")?,
+                    chipset: validation::extract_string_opt(params, "chipset")?,
+                    vram_mb: validation::extract_int_opt(params, "vram_mb")?,
+                    graphics_controller: validation::extract_string_opt(params, "graphics_controller")?,
+                    nested_virtualization: bool_param(params, "nested_virtualization")?,
+                    pae: bool_param(params, "pae")?,
+                    paravirt_provider: validation::extract_string_opt(params, "paravirt_provider")?,
+                    boot_order: list_param(params, "boot_order")?,
+                    description: validation::extract_string_opt(params, "description")?,
+                    groups: list_param(params, "groups")?,
+                    base_folder: validation::extract_string_opt(params, "base_folder")?,
+                    rtc_utc: bool_param(params, "rtc_utc")?,
+                    audio: bool_param(params, "audio")?,
+                    disks: json_param(params, "disks")?,
+                    controller_name: validation::extract_string_opt(params, "controller_name")?
+                        .unwrap_or_else(|| defaults.controller_name.clone()),
+                    nics: json_param(params, "nics")?,
+                };
+                if config.nics.is_empty() {
+                    config.nics.push(Nic {
+                        index: 1,
+                        nic_type: defaults.network_type.clone(),
+                    });
+                }
+                
+                self.create_worker(client, config)
+            },
+            "clone_worker" => {
                 let worker_name = validation::extract_string(params, "worker_name")?;
-                let os_type = validation::extract_string_opt(params, "os_type")?.unwrap_or_else(|| "Ubuntu_64".to_string());
-                let memory_mb = validation::extract_int_opt(params, "memory_mb")?.unwrap_or(2048);
-                let cpu_count = validation::extract_int_opt(params, "cpu_count")?.unwrap_or(2);
+                let options = CloneOptions {
+                    name: validation::extract_string(params, "new_name")?,
+                    snapshot: validation::extract_string_opt(params, "snapshot")?,
+                    linked: bool_param(params, "linked")?.unwrap_or(false),
+                    mac_policy: match validation::extract_string_opt(params, "mac_policy")? {
+                        Some(policy) => policy.parse()?,
+                        None => MacPolicy::default(),
+                    },
+                    uuid: validation::extract_string_opt(params, "uuid")?,
+                    groups: list_param(params, "groups")?,
+                    base_folder: validation::extract_string_opt(params, "base_folder")?,
+                };
+                
+                self.clone_worker(client, worker_name, options)
+            },
+            "import_appliance" => {
+                let appliance_path = validation::extract_string(params, "appliance_path")?;
+                let dry_run = bool_param(params, "dry_run")?.unwrap_or(false);
+                let options = ImportOptions {
+                    name: validation::extract_string_opt(params, "new_name")?,
+                    memory_mb: validation::extract_int_opt(params, "memory_mb")?,
+                    cpu_count: validation::extract_int_opt(params, "cpu_count")?,
+                    disk_paths: list_param(params, "disk_paths")?,
+                    mac_policy: match validation::extract_string_opt(params, "mac_policy")? {
+                        Some(policy) => policy.parse()?,
+                        None => MacPolicy::default(),
+                    },
+                    accept_eula: bool_param(params, "accept_eula")?.unwrap_or(false),
+                };
+                if options.memory_mb.is_some_and(|m| m <= 0) || options.cpu_count.is_some_and(|c| c <= 0) {
+                    return Err(VBoxError::InvalidParameter("Parameters 'memory_mb' and 'cpu_count' must be positive".to_string()));
+                }
                 
-                self.create_worker(worker_name, os_type, memory_mb, cpu_count)
+                self.import_appliance(client, appliance_path, dry_run, options)
+            },
+            "export_appliance" => {
+                let worker_name = validation::extract_string(params, "worker_name")?;
+                let options = ExportOptions {
+                    output: validation::extract_string(params, "output_path")?,
+                    ovf_version: match validation::extract_string_opt(params, "ovf_version")? {
+                        Some(version) => version.parse()?,
+                        None => OvfVersion::default(),
+                    },
+                    manifest: bool_param(params, "manifest")?.unwrap_or(true),
+                    product: validation::extract_string_opt(params, "product")?,
+                    product_url: validation::extract_string_opt(params, "product_url")?,
+                    vendor: validation::extract_string_opt(params, "vendor")?,
+                    vendor_url: validation::extract_string_opt(params, "vendor_url")?,
+                    version: validation::extract_string_opt(params, "version")?,
+                    description: validation::extract_string_opt(params, "description")?,
+                };
+                
+                self.export_appliance(client, worker_name, options)
+            },
+            "apply_worker_spec" => {
+                let spec = spec_param(params)?.with_defaults(&self.default_settings);
+                self.apply_worker_spec(client, spec)
+            },
+            "diff_worker_spec" => {
+                let spec = spec_param(params)?.with_defaults(&self.default_settings);
+                self.diff_worker_spec(client, spec)
+            },
+            "install_os_unattended" => {
+                let defaults = &self.default_settings;
+                let worker_name = validation::extract_string(params, "worker_name")?;
+                let options = UnattendedOptions {
+                    iso: validation::extract_string(params, "iso_path")?,
+                    user: validation::extract_string_opt(params, "username")?.unwrap_or_else(|| defaults.username.clone()),
+                    password: validation::extract_string_opt(params, "password")?.unwrap_or_else(|| defaults.password.clone()),
+                    full_name: validation::extract_string_opt(params, "full_name")?,
+                    hostname: validation::extract_string_opt(params, "hostname")?,
+                    locale: validation::extract_string_opt(params, "locale")?,
+                    time_zone: validation::extract_string_opt(params, "time_zone")?,
+                    install_additions: bool_param(params, "install_additions")?.unwrap_or(false),
+                    post_install_command: validation::extract_string_opt(params, "post_install_command")?,
+                    start: bool_param(params, "start")?.unwrap_or(false),
+                };
+                let wait = match bool_param(params, "wait")? {
+                    Some(true) if !options.start => {
+                        return Err(VBoxError::InvalidParameter("Parameter 'wait' needs 'start'".to_string()));
+                    }
+                    Some(true) => {
+                        // The VM is running as soon as the installer boots; only
+                        // the Guest Additions show the installed guest is up
+                        let run_level = run_level_param(params)?.or(options.install_additions.then_some(2)).ok_or_else(|| {
+                            VBoxError::InvalidParameter("Parameter 'wait' needs 'install_additions' or 'guest_run_level'".to_string())
+                        })?;
+                        Some(WaitSpec {
+                            timeout: wait_timeout_param(params, DEFAULT_INSTALL_TIMEOUT)?,
+                            run_level: Some(run_level),
+                        })
+                    }
+                    _ => None,
+                };
+                
+                self.install_os_unattended(client, worker_name, options, wait)
+            },
+            "create_cloud_init_seed" => {
+                let worker_name = validation::extract_string(params, "worker_name")?;
+                let network_config = match params.get("network_config") {
+                    None | Some(Value::Null) => None,
+                    Some(config @ Value::Object(_)) => Some(config.clone()),
+                    Some(_) => return Err(VBoxError::InvalidParameter("Parameter 'network_config' must be an object".to_string())),
+                };
+                let seed = CloudInitSeed {
+                    instance_id: validation::extract_string_opt(params, "instance_id")?.unwrap_or_else(|| format!("iid-{}", worker_name)),
+                    hostname: validation::extract_string_opt(params, "hostname")?.unwrap_or_else(|| worker_name.clone()),
+                    ssh_authorized_keys: json_param(params, "ssh_authorized_keys")?,
+                    users: json_param(params, "users")?,
+                    runcmd: json_param(params, "runcmd")?,
+                    network_config,
+                };
+                let iso_path = validation::extract_string_opt(params, "iso_path")?;
+                let controller_name = validation::extract_string_opt(params, "controller_name")?.unwrap_or_else(|| self.default_settings.controller_name.clone());
+                let bus = validation::extract_string_opt(params, "controller_bus")?.as_deref().unwrap_or("sata").parse()?;
+                let create_controller = bool_param(params, "create_controller")?.unwrap_or(false);
+                
+                self.create_cloud_init_seed(client, worker_name, seed, iso_path, ControllerSpec::new(&controller_name, bus), create_controller)
             },
             "delete_worker" => {
                 let worker_name = validation::extract_string(params, "worker_name")?;
-                self.delete_worker(worker_name)
+                let mut options = DeleteOptions {
+                    force: bool_param(params, "force")?.unwrap_or(false),
+                    keep_media: validation::extract_string_opt(params, "keep_disks")?
+                        .map(|disks| disks.split(',').map(str::trim).filter(|d| !d.is_empty()).map(String::from).collect())
+                        .unwrap_or_default(),
+                    ..DeleteOptions::default()
+                };
+                match validation::extract_int_opt(params, "unlock_timeout_secs")? {
+                    Some(secs) if secs <= 0 => {
+                        return Err(VBoxError::InvalidParameter("Parameter 'unlock_timeout_secs' must be positive".to_string()));
+                    }
+                    Some(secs) => options.unlock_timeout = Duration::from_secs(secs as u64),
+                    None => {}
+                }
+                
+                self.delete_worker(client, worker_name, options)
             },
             "get_worker" => {
                 let worker_name = validation::extract_string(params, "worker_name")?;
-                self.get_worker(worker_name)
+                self.get_worker(client, worker_name)
             },
             "has_worker" => {
                 let worker_name = validation::extract_string(params, "worker_name")?;
-                self.has_worker(worker_name)
+                self.has_worker(client, worker_name)
             },
             "start_worker" => {
+                let options = StartOptions {
+                    mode: match validation::extract_string_opt(params, "start_type")? {
+                        Some(mode) => mode.parse()?,
+                        None => StartMode::default(),
+                    },
+                    env: env_param(params)?,
+                };
+                let wait = wait_spec(params)?;
+                let worker_name = validation::extract_string_opt(params, "worker_name")?;
+                let worker_names = list_param(params, "worker_names")?;
+                let start_order: Vec<StartGroup> = json_param(params, "start_order")?;
+                let groups = match (worker_name, worker_names.is_empty(), start_order.is_empty()) {
+                    (Some(worker_name), true, true) => return self.start_worker(client, worker_name, options, wait),
+                    (None, false, true) => vec![StartGroup {
+                        workers: worker_names,
+                        delay_secs: 0,
+                    }],
+                    (None, true, false) => start_order,
+                    _ => {
+                        return Err(VBoxError::InvalidParameter(
+                            "Give exactly one of 'worker_name', 'worker_names' or 'start_order'".to_string(),
+                        ));
+                    }
+                };
+                let mut seen = HashSet::new();
+                if let Some(name) = groups.iter().flat_map(|g| &g.workers).find(|name| !seen.insert(name.as_str())) {
+                    return Err(VBoxError::InvalidParameter(format!("Worker '{}' is listed more than once", name)));
+                }
+                if groups.iter().any(|g| g.workers.is_empty()) {
+                    return Err(VBoxError::InvalidParameter("Every start group needs at least one worker".to_string()));
+                }
+                let parallelism = match validation::extract_int_opt(params, "parallelism")? {
+                    Some(n) if n <= 0 => return Err(VBoxError::InvalidParameter("Parameter 'parallelism' must be positive".to_string())),
+                    Some(n) => n as usize,
+                    None => DEFAULT_START_PARALLELISM,
+                };
+                
+                self.start_workers(client, groups, options, parallelism, wait)
+            },
+            "stop_worker" => {
+                let worker_name = validation::extract_string(params, "worker_name")?;
+                let grace = match validation::extract_int_opt(params, "grace_secs")? {
+                    Some(secs) if secs < 0 => {
+                        return Err(VBoxError::InvalidParameter("Parameter 'grace_secs' must not be negative".to_string()));
+                    }
+                    Some(secs) => Duration::from_secs(secs as u64),
+                    None => DEFAULT_STOP_GRACE,
+                };
+                let wait = wait_spec(params)?;
+                self.stop_worker(client, worker_name, grace, wait)
+            },
+            "wait_for_worker_state" => {
+                let worker_name = validation::extract_string(params, "worker_name")?;
+                let target = WaitTarget {
+                    state: validation::extract_string(params, "state")?.parse()?,
+                    min_run_level: run_level_param(params)?,
+                };
+                let timeout = wait_timeout_param(params, DEFAULT_WAIT_TIMEOUT)?;
+                self.wait_for_worker_state(client, worker_name, target, timeout)
+            },
+            "pause_worker" => {
                 let worker_name = validation::extract_string(params, "worker_name")?;
-                self.start_worker(worker_name)
+                let wait = wait_spec(params)?;
+                self.pause_worker(client, worker_name, wait)
             },
-            "get_volumes" => self.get_volumes(),
+            "resume_worker" => {
+                let worker_name = validation::extract_string(params, "worker_name")?;
+                let wait = wait_spec(params)?;
+                self.resume_worker(client, worker_name, wait)
+            },
+            "save_worker_state" => {
+                let worker_name = validation::extract_string(params, "worker_name")?;
+                let wait = wait_spec(params)?;
+                self.save_worker_state(client, worker_name, wait)
+            },
+            "discard_saved_state" => {
+                let worker_name = validation::extract_string(params, "worker_name")?;
+                let wait = wait_spec(params)?;
+                self.discard_saved_state(client, worker_name, wait)
+            },
+            "get_volumes" => self.get_volumes(client),
             "has_volume" => {
                 let disk_path = validation::extract_string(params, "disk_path")?;
-                self.has_volume(disk_path)
+                self.has_volume(client, disk_path)
             },
             "create_volume" => {
                 let disk_path = validation::extract_string(params, "disk_path")?;
                 let size_mb = validation::extract_int(params, "size_mb")?;
-                self.create_volume(disk_path, size_mb)
+                self.create_volume(client, disk_path, size_mb)
             },
             "delete_volume" => {
                 let disk_path = validation::extract_string(params, "disk_path")?;
-                self.delete_volume(disk_path)
+                self.delete_volume(client, disk_path)
             },
             "attach_volume" => {
                 let worker_name = validation::extract_string(params, "worker_name")?;
-                let controller_name = validation::extract_string_opt(params, "controller_name")?.unwrap_or_else(|| "SATA Controller".to_string());
-                let port = validation::extract_int(params, "port")?;
+                let controller_name = validation::extract_string_opt(params, "controller_name")?.unwrap_or_else(|| self.default_settings.controller_name.clone());
+                let port = slot("port", validation::extract_int(params, "port")?)?;
                 let disk_path = validation::extract_string(params, "disk_path")?;
+                let device_type = match validation::extract_string_opt(params, "device_type")? {
+                    Some(device_type) => Some(device_type.parse()?),
+                    None => None,
+                };
+                let options = AttachOptions {
+                    device: slot("device", validation::extract_int_opt(params, "device")?.unwrap_or(0))?,
+                    nonrotational: bool_param(params, "nonrotational")?,
+                    discard: bool_param(params, "discard")?,
+                    hotpluggable: bool_param(params, "hotpluggable")?,
+                    mtype: validation::extract_string_opt(params, "mtype")?,
+                    ..AttachOptions::for_path(&disk_path, device_type)?
+                };
+                let bus = validation::extract_string_opt(params, "controller_bus")?.as_deref().unwrap_or("sata").parse()?;
                 
-                self.attach_volume(worker_name, controller_name, port, disk_path)
+                // Refuse bad options before touching the controllers
+                options.validate()?;
+                if bool_param(params, "create_controller")?.unwrap_or(false) {
+                    client.ensure_storage_controller(&worker_name, &ControllerSpec::new(&controller_name, bus))?;
+                }
+                self.attach_volume(client, worker_name, controller_name, port, disk_path, options)
             },
             "detach_volume" => {
                 let worker_name = validation::extract_string(params, "worker_name")?;
-                let controller_name = validation::extract_string_opt(params, "controller_name")?.unwrap_or_else(|| "SATA Controller".to_string());
-                let port = validation::extract_int(params, "port")?;
-                self.detach_volume(worker_name, controller_name, port)
+                let controller_name = validation::extract_string_opt(params, "controller_name")?.unwrap_or_else(|| self.default_settings.controller_name.clone());
+                let port = slot("port", validation::extract_int(params, "port")?)?;
+                let device = slot("device", validation::extract_int_opt(params, "device")?.unwrap_or(0))?;
+                self.detach_volume(client, worker_name, controller_name, port, device)
+            },
+            "list_worker_attachments" => {
+                let worker_name = validation::extract_string(params, "worker_name")?;
+                self.list_worker_attachments(client, worker_name)
+            },
+            "add_storage_controller" => {
+                let worker_name = validation::extract_string(params, "worker_name")?;
+                let controller_name = validation::extract_string_opt(params, "controller_name")?.unwrap_or_else(|| self.default_settings.controller_name.clone());
+                let bus = validation::extract_string_opt(params, "bus")?.as_deref().unwrap_or("sata").parse()?;
+                let port_count = match validation::extract_int_opt(params, "port_count")? {
+                    Some(ports) => Some(slot("port_count", ports)?),
+                    None => None,
+                };
+                let controller = ControllerSpec {
+                    chipset: validation::extract_string_opt(params, "chipset")?,
+                    port_count,
+                    host_io_cache: bool_param(params, "host_io_cache")?,
+                    bootable: bool_param(params, "bootable")?,
+                    ..ControllerSpec::new(&controller_name, bus)
+                };
+                self.add_storage_controller(client, worker_name, controller)
+            },
+            "remove_storage_controller" => {
+                let worker_name = validation::extract_string(params, "worker_name")?;
+                let controller_name = validation::extract_string(params, "controller_name")?;
+                self.remove_storage_controller(client, worker_name, controller_name)
+            },
+            "list_storage_controllers" => {
+                let worker_name = validation::extract_string(params, "worker_name")?;
+                self.list_storage_controllers(client, worker_name)
             },
             "create_snapshot" => {
                 let worker_name = validation::extract_string(params, "worker_name")?;
                 let snapshot_name = validation::extract_string(params, "snapshot_name")?;
-                self.create_snapshot(worker_name, snapshot_name)
+                self.create_snapshot(client, worker_name, snapshot_name)
             },
             "delete_snapshot" => {
                 let worker_name = validation::extract_string(params, "worker_name")?;
                 let snapshot_name = validation::extract_string(params, "snapshot_name")?;
-                self.delete_snapshot(worker_name, snapshot_name)
+                self.delete_snapshot(client, worker_name, snapshot_name)
             },
             "has_snapshot" => {
                 let worker_name = validation::extract_string(params, "worker_name")?;
                 let snapshot_name = validation::extract_string(params, "snapshot_name")?;
-                self.has_snapshot(worker_name, snapshot_name)
+                self.has_snapshot(client, worker_name, snapshot_name)
             },
             "reboot_worker" => {
                 let worker_name = validation::extract_string(params, "worker_name")?;
-                self.reboot_worker(worker_name)
+                let wait = wait_spec(params)?;
+                self.reboot_worker(client, worker_name, wait)
             },
             "configure_networks" => {
                 let worker_name = validation::extract_string(params, "worker_name")?;
                 let network_index = validation::extract_int(params, "network_index")?;
-                let network_type = validation::extract_string_opt(params, "network_type")?.unwrap_or_else(|| "nat".to_string());
+                let network_type = validation::extract_string_opt(params, "network_type")?;
+                let nic = Nic {
+                    index: u32::try_from(network_index)
+                        .ok()
+                        .filter(|index| (1..=8).contains(index))
+                        .ok_or_else(|| VBoxError::InvalidParameter("Parameter 'network_index' must be between 1 and 8".to_string()))?,
+                    nic_type: match network_type {
+                        Some(network_type) => network_type.parse::<NicType>()?,
+                        None => self.default_settings.network_type.clone(),
+                    },
+                };
                 
-                self.configure_networks(worker_name, network_index, network_type)
+                self.configure_networks(client, worker_name, nic)
             },
             "set_worker_metadata" => {
                 let worker_name = validation::extract_string(params, "worker_name")?;
                 let key = validation::extract_string(params, "key")?;
                 let value = validation::extract_string(params, "value")?;
                 
-                self.set_worker_metadata(worker_name, key, value)
+                self.set_worker_metadata(client, worker_name, key, value)
             },
             "snapshot_volume" => {
                 let source_volume_path = validation::extract_string(params, "source_volume_path")?;
                 let target_volume_path = validation::extract_string(params, "target_volume_path")?;
                 
-                self.snapshot_volume(source_volume_path, target_volume_path)
+                self.snapshot_volume(client, source_volume_path, target_volume_path)
             },
-            _ => Err(format!("Action '{}' not found", action)),
+            _ => Err(VBoxError::UnknownAction(action.to_string())),
         }
     }
 }
+
+/// Requested wait after a lifecycle action
+struct WaitSpec {
+    timeout: Duration,
+    run_level: Option<u32>,
+}
+
+fn wait_spec(params: &HashMap<String, Value>) -> VBoxResult<Option<WaitSpec>> {
+    if !bool_param(params, "wait")?.unwrap_or(false) {
+        return Ok(None);
+    }
+    Ok(Some(WaitSpec {
+        timeout: wait_timeout_param(params, DEFAULT_WAIT_TIMEOUT)?,
+        run_level: run_level_param(params)?,
+    }))
+}
+
+fn wait_timeout_param(params: &HashMap<String, Value>, default: Duration) -> VBoxResult<Duration> {
+    match validation::extract_int_opt(params, "wait_timeout_secs")? {
+        Some(secs) if secs <= 0 => Err(VBoxError::InvalidParameter("Parameter 'wait_timeout_secs' must be positive".to_string())),
+        Some(secs) => Ok(Duration::from_secs(secs as u64)),
+        None => Ok(default),
+    }
+}
+
+fn run_level_param(params: &HashMap<String, Value>) -> VBoxResult<Option<u32>> {
+    match validation::extract_int_opt(params, "guest_run_level")? {
+        Some(level) if !(0..=3).contains(&level) => {
+            Err(VBoxError::InvalidParameter("Parameter 'guest_run_level' must be between 0 and 3".to_string()))
+        }
+        level => Ok(level.map(|l| l as u32)),
+    }
+}
+
+// A controller port or device number
+fn slot(name: &str, value: i64) -> VBoxResult<u32> {
+    u32::try_from(value).map_err(|_| VBoxError::InvalidParameter(format!("Parameter '{}' must not be negative", name)))
+}
+
+fn bool_param(params: &HashMap<String, Value>, name: &str) -> VBoxResult<Option<bool>> {
+    if !params.contains_key(name) {
+        return Ok(None);
+    }
+    Ok(Some(validation::extract_bool(params, name)?))
+}
+
+// A comma-separated string parameter; empty entries are dropped
+fn list_param(params: &HashMap<String, Value>, name: &str) -> VBoxResult<Vec<String>> {
+    Ok(validation::extract_string_opt(params, name)?
+        .map(|list| list.split(',').map(str::trim).filter(|s| !s.is_empty()).map(String::from).collect())
+        .unwrap_or_default())
+}
+
+// A JSON object of string values, e.g. process environment
+fn env_param(params: &HashMap<String, Value>) -> VBoxResult<BTreeMap<String, String>> {
+    let env: BTreeMap<String, String> = match params.get("env") {
+        None | Some(Value::Null) => return Ok(BTreeMap::new()),
+        Some(value) => serde_json::from_value(value.clone())
+            .map_err(|_| VBoxError::InvalidParameter("Parameter 'env' must be an object of strings".to_string()))?,
+    };
+    if let Some(key) = env.keys().find(|key| key.is_empty() || key.contains('=')) {
+        return Err(VBoxError::InvalidParameter(format!("Invalid environment variable name '{}'", key)));
+    }
+    Ok(env)
+}
+
+// A JSON array parameter deserialized into `T`; missing means empty
+fn json_param<T: serde::de::DeserializeOwned>(params: &HashMap<String, Value>, name: &str) -> VBoxResult<Vec<T>> {
+    match params.get(name) {
+        None | Some(Value::Null) => Ok(Vec::new()),
+        Some(value) => serde_json::from_value(value.clone())
+            .map_err(|e| VBoxError::InvalidParameter(format!("Parameter '{}' is invalid: {}", name, e))),
+    }
+}
+
+// The spec given inline as `spec` or read from `spec_file`
+fn spec_param(params: &HashMap<String, Value>) -> VBoxResult<WorkerSpec> {
+    let spec = match (params.get("spec"), validation::extract_string_opt(params, "spec_file")?) {
+        (Some(Value::String(text)), _) => WorkerSpec::parse(text),
+        (Some(value @ Value::Object(_)), _) => {
+            serde_json::from_value(value.clone()).map_err(|e| format!("Invalid JSON spec: {}", e))
+        }
+        (Some(_), _) => Err("Parameter 'spec' must be an object or a string".to_string()),
+        (None, Some(path)) => {
+            let text = std::fs::read_to_string(&path).map_err(|e| format!("Cannot read spec file '{}': {}", path, e))?;
+            WorkerSpec::parse(&text)
+        }
+        (None, None) => Err("Parameter 'spec' or 'spec_file' is required".to_string()),
+    };
+    Ok(spec?)
+}
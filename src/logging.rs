@@ -0,0 +1,228 @@
+// File: cpi_virtualbox/src/logging.rs
+//! Logging goes through the `log` facade. When the crate is linked as an
+//! rlib the host's logger receives everything; when it is loaded as a
+//! cdylib the library has its own copy of the facade, so `get_extension`
+//! installs `ExtensionLogger`, configured from the environment. It never
+//! writes to stdout, which belongs to the host.
+use log::{LevelFilter, Log, Metadata, Record};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Maximum level to log: `off`, `error`, `warn`, `info`, `debug` or `trace`
+pub const LOG_LEVEL_ENV: &str = "CPI_VIRTUALBOX_LOG";
+
+/// File to append log lines to instead of stderr
+pub const LOG_FILE_ENV: &str = "CPI_VIRTUALBOX_LOG_FILE";
+
+/// Placeholder written in place of secret argument values
+pub const REDACTED: &str = "******";
+
+/// Flags whose following argument is a secret
+const SECRET_FLAGS: &[&str] = &[
+    "--password",
+    "--user-password",
+    "--admin-password",
+    "--new-password",
+    "--old-password",
+    "--key-password",
+];
+
+/// Key fragments marking an extradata/guest property/env value as secret
+const SECRET_KEYS: &[&str] = &["password", "passwd", "secret", "token", "apikey", "api_key", "private"];
+
+pub(crate) fn is_secret_key(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    SECRET_KEYS.iter().any(|s| key.contains(s))
+}
+
+/// Copy of a VBoxManage argument vector that is safe to log or report:
+/// values of password flags, `setextradata`/`guestproperty set` values
+/// under secret-looking keys and secret `KEY=VALUE` pairs are masked.
+pub fn redact_args(args: &[&str]) -> Vec<String> {
+    let mut redacted: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+
+    for i in 0..args.len() {
+        let arg = args[i];
+        if SECRET_FLAGS.contains(&arg) && i + 1 < args.len() {
+            redacted[i + 1] = REDACTED.to_string();
+        } else if let Some((flag, _)) = arg.split_once('=')
+            && SECRET_FLAGS.contains(&flag)
+        {
+            redacted[i] = format!("{}={}", flag, REDACTED);
+        } else if let Some((key, _)) = arg.split_once('=')
+            && !arg.starts_with('-')
+            && is_secret_key(key)
+        {
+            redacted[i] = format!("{}={}", key, REDACTED);
+        }
+    }
+
+    // setextradata <vm> <key> <value> / guestproperty set <vm> <key> <value>
+    let value_index = match args {
+        ["setextradata", _, key, ..] if is_secret_key(key) => Some(3),
+        ["guestproperty", "set", _, key, ..] if is_secret_key(key) => Some(4),
+        _ => None,
+    };
+    if let Some(index) = value_index
+        && index < redacted.len()
+    {
+        redacted[index] = REDACTED.to_string();
+    }
+    redacted
+}
+
+/// Minimal logger used when the extension is loaded as a dynamic library
+pub struct ExtensionLogger {
+    level: LevelFilter,
+    sink: Mutex<Box<dyn Write + Send>>,
+}
+
+impl ExtensionLogger {
+    pub fn new(level: LevelFilter, sink: Box<dyn Write + Send>) -> Self {
+        Self {
+            level,
+            sink: Mutex::new(sink),
+        }
+    }
+
+    /// Build a logger from `CPI_VIRTUALBOX_LOG` (default `warn`) and
+    /// `CPI_VIRTUALBOX_LOG_FILE` (default stderr)
+    pub fn from_env() -> Self {
+        let level = std::env::var(LOG_LEVEL_ENV)
+            .ok()
+            .and_then(|l| l.trim().parse().ok())
+            .unwrap_or(LevelFilter::Warn);
+
+        let file = std::env::var_os(LOG_FILE_ENV)
+            .and_then(|path| OpenOptions::new().create(true).append(true).open(path).ok());
+        let sink: Box<dyn Write + Send> = match file {
+            Some(file) => Box::new(file),
+            None => Box::new(std::io::stderr()),
+        };
+        Self::new(level, sink)
+    }
+
+    pub fn level(&self) -> LevelFilter {
+        self.level
+    }
+}
+
+impl Log for ExtensionLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        let mut sink = self.sink.lock().unwrap();
+        let _ = writeln!(
+            sink,
+            "{:.3} {:<5} {}: {}",
+            timestamp,
+            record.level(),
+            record.target(),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {
+        let _ = self.sink.lock().unwrap().flush();
+    }
+}
+
+/// Install `ExtensionLogger::from_env` unless a logger is already set
+pub fn init_from_env() {
+    let logger = ExtensionLogger::from_env();
+    let level = logger.level();
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn password_flags_are_redacted() {
+        let args = ["unattended", "install", "web-1", "--user", "ops", "--password", "hunter2", "--admin-password=s3cret"];
+        assert_eq!(
+            redact_args(&args),
+            vec!["unattended", "install", "web-1", "--user", "ops", "--password", REDACTED, "--admin-password=******"]
+        );
+    }
+
+    #[test]
+    fn secret_extradata_and_env_values_are_redacted() {
+        assert_eq!(
+            redact_args(&["setextradata", "web-1", "omni/db_password", "hunter2"]),
+            vec!["setextradata", "web-1", "omni/db_password", REDACTED]
+        );
+        assert_eq!(
+            redact_args(&["setextradata", "web-1", "omni/role", "worker"]),
+            vec!["setextradata", "web-1", "omni/role", "worker"]
+        );
+        assert_eq!(
+            redact_args(&["startvm", "web-1", "--putenv", "API_TOKEN=abc", "--putenv", "MODE=prod"]),
+            vec!["startvm", "web-1", "--putenv", "API_TOKEN=******", "--putenv", "MODE=prod"]
+        );
+        assert_eq!(
+            redact_args(&["guestproperty", "set", "web-1", "/Secret/Key", "abc"]),
+            vec!["guestproperty", "set", "web-1", "/Secret/Key", REDACTED]
+        );
+    }
+
+    /// Sink shared between the logger and the test
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(data)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn logger_honours_level_and_sink() {
+        let buffer = Buffer::default();
+        let logger = ExtensionLogger::new(LevelFilter::Info, Box::new(buffer.clone()));
+
+        logger.log(
+            &Record::builder()
+                .level(log::Level::Info)
+                .target("cpi_virtualbox")
+                .args(format_args!("started web-1"))
+                .build(),
+        );
+        logger.log(
+            &Record::builder()
+                .level(log::Level::Debug)
+                .target("cpi_virtualbox")
+                .args(format_args!("noisy"))
+                .build(),
+        );
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("INFO  cpi_virtualbox: started web-1"), "{}", output);
+        assert!(!output.contains("noisy"));
+    }
+
+    #[test]
+    fn off_level_silences_everything() {
+        let logger = ExtensionLogger::new(LevelFilter::Off, Box::new(Buffer::default()));
+        assert!(!logger.enabled(&Metadata::builder().level(log::Level::Error).build()));
+    }
+}
@@ -0,0 +1,569 @@
+// File: cpi_virtualbox/src/machinereadable.rs
+//! Parser for the `key=value` output of `--machinereadable` commands.
+//!
+//! Keys are bare (`memory`, `Forwarding(0)`) or quoted when they contain
+//! spaces (`"SATA Controller-0-0"`). Values are bare numbers or quoted
+//! strings in which `\"`, `\\` and `\n` are escaped; older VBoxManage
+//! versions print raw newlines inside quoted values instead.
+use crate::types::{
+    DetectedOs, DeviceType, GuestInfo, NetworkAdapter, NicType, PortForward, SharedFolder, Snapshot, StorageAttachment,
+    StorageBus, StorageController, UsbFilter, UsbInfo, Vm, VmState,
+};
+use std::collections::{BTreeMap, HashMap};
+
+/// Parsed output, keeping every key in its original order
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MachineReadable {
+    pairs: Vec<(String, String)>,
+    index: HashMap<String, usize>,
+}
+
+impl MachineReadable {
+    pub fn parse(output: &str) -> Self {
+        let mut parsed = Self::default();
+        let mut chars = output.chars().peekable();
+
+        while chars.peek().is_some() {
+            // Key: quoted, or everything up to '=' on the current line
+            let key = if chars.peek() == Some(&'"') {
+                chars.next();
+                let key = read_quoted(&mut chars);
+                if chars.peek() != Some(&'=') {
+                    skip_line(&mut chars);
+                    continue;
+                }
+                key
+            } else {
+                let mut key = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c == '=' || c == '\n' {
+                        break;
+                    }
+                    key.push(c);
+                    chars.next();
+                }
+                if chars.peek() != Some(&'=') {
+                    // Not a key/value line (blank or a stray message)
+                    chars.next();
+                    continue;
+                }
+                key.trim().to_string()
+            };
+            chars.next(); // '='
+
+            let value = if chars.peek() == Some(&'"') {
+                chars.next();
+                let value = read_quoted(&mut chars);
+                skip_line(&mut chars);
+                value
+            } else {
+                let mut value = String::new();
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                    value.push(c);
+                }
+                value.trim().to_string()
+            };
+
+            parsed.insert(key, value);
+        }
+        parsed
+    }
+
+    fn insert(&mut self, key: String, value: String) {
+        match self.index.get(&key) {
+            Some(&i) => self.pairs[i].1 = value,
+            None => {
+                self.index.insert(key.clone(), self.pairs.len());
+                self.pairs.push((key, value));
+            }
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.index.get(key).map(|&i| self.pairs[i].1.as_str())
+    }
+
+    pub fn get_parsed<T: std::str::FromStr>(&self, key: &str) -> Option<T> {
+        self.get(key).and_then(|v| v.parse().ok())
+    }
+
+    /// `on`/`off` flags
+    pub fn get_flag(&self, key: &str) -> Option<bool> {
+        self.get(key).map(|v| v == "on")
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+}
+
+// Read up to the closing quote, resolving escapes; the opening quote is consumed
+fn read_quoted(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) -> String {
+    let mut value = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('r') => value.push('\r'),
+                Some('t') => value.push('\t'),
+                Some(other) => value.push(other),
+                None => value.push('\\'),
+            },
+            c => value.push(c),
+        }
+    }
+    value
+}
+
+fn skip_line(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) {
+    for c in chars.by_ref() {
+        if c == '\n' {
+            break;
+        }
+    }
+}
+
+/// Split `prefix<N>` into N, e.g. `nic3` -> 3
+fn indexed(key: &str, prefix: &str) -> Option<u32> {
+    key.strip_prefix(prefix)?.parse().ok()
+}
+
+/// Parse `showvminfo --machinereadable` output into a `Vm`
+pub fn parse_vm(output: &str) -> Vm {
+    let info = MachineReadable::parse(output);
+
+    let storage_controllers = storage_controllers(&info);
+    Vm {
+        name: info.get("name").map(String::from),
+        id: info.get("UUID").map(String::from),
+        state: info.get("VMState").map(VmState::from),
+        state_changed_at: info.get("VMStateChangeTime").map(String::from),
+        memory_mb: info.get_parsed("memory"),
+        vram_mb: info.get_parsed("vram"),
+        cpu_count: info.get_parsed("cpus"),
+        // Looked up by VirtualBoxClient::resolve_os_types
+        os_type: None,
+        // Despite the key, this is the description rather than the id
+        os_description: info.get("ostype").map(String::from),
+        firmware: info.get("firmware").map(String::from),
+        graphics_controller: info.get("graphicscontroller").map(String::from),
+        description: info.get("description").map(String::from),
+        groups: info
+            .get("groups")
+            .map(|g| g.split(',').filter(|g| !g.is_empty()).map(String::from).collect())
+            .unwrap_or_default(),
+        config_file: info.get("CfgFile").map(String::from),
+        storage_attachments: storage_attachments(&info, &storage_controllers),
+        storage_controllers,
+        network_adapters: network_adapters(&info),
+        snapshots: snapshots(&info),
+        current_snapshot: info.get("CurrentSnapshotName").map(String::from),
+        shared_folders: shared_folders(&info),
+        usb: usb(&info),
+        guest: guest(&info),
+        properties: info.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+    }
+}
+
+fn storage_controllers(info: &MachineReadable) -> Vec<StorageController> {
+    info.iter()
+        .filter_map(|(key, name)| {
+            let i = indexed(key, "storagecontrollername")?;
+            Some(StorageController {
+                name: name.to_string(),
+                bus: info.get(&format!("storagecontrollertype{}", i)).and_then(StorageBus::for_chipset),
+                controller_type: info.get(&format!("storagecontrollertype{}", i)).map(String::from),
+                instance: info.get_parsed(&format!("storagecontrollerinstance{}", i)),
+                max_port_count: info.get_parsed(&format!("storagecontrollermaxportcount{}", i)),
+                port_count: info.get_parsed(&format!("storagecontrollerportcount{}", i)),
+                bootable: info.get_flag(&format!("storagecontrollerbootable{}", i)),
+            })
+        })
+        .collect()
+}
+
+// Attachments are keyed "<controller>-<port>-<device>"; controller names may
+// themselves contain dashes, so match against the known controllers
+fn storage_attachments(info: &MachineReadable, controllers: &[StorageController]) -> Vec<StorageAttachment> {
+    let mut attachments = Vec::new();
+    for (key, value) in info.iter() {
+        // "SATA-2-0-0" is port 0 of "SATA-2", not port 2 of "SATA"
+        let Some((controller, port, device)) = controllers.iter().find_map(|c| {
+            let (port, device) = key.strip_prefix(c.name.as_str())?.strip_prefix('-')?.split_once('-')?;
+            Some((c, port.parse::<u32>().ok()?, device.parse::<u32>().ok()?))
+        }) else {
+            continue;
+        };
+        if value == "none" {
+            continue;
+        }
+        let medium = (value != "emptydrive").then(|| value.to_string());
+        // Only DVD and floppy drives can be empty or pass a host drive through;
+        // what an image is attached as is not in this output
+        let device_type = match (controller.bus, medium.as_deref()) {
+            (Some(StorageBus::Floppy), _) => Some(DeviceType::Fdd),
+            (_, None) => Some(DeviceType::DvdDrive),
+            (_, Some(path)) if path.starts_with("host:") => Some(DeviceType::DvdDrive),
+            _ => None,
+        };
+        attachments.push(StorageAttachment {
+            controller: controller.name.clone(),
+            port,
+            device,
+            medium,
+            medium_id: info
+                .get(&format!("{}-ImageUUID-{}-{}", controller.name, port, device))
+                .map(String::from),
+            device_type,
+            parent_id: None,
+            differencing: None,
+        });
+    }
+    attachments
+}
+
+fn network_adapters(info: &MachineReadable) -> Vec<NetworkAdapter> {
+    let mut adapters: Vec<NetworkAdapter> = Vec::new();
+    // Port forwarding rules follow the NAT adapter they belong to
+    let mut current: Option<usize> = None;
+
+    for (key, value) in info.iter() {
+        if let Some(index) = indexed(key, "nic") {
+            current = None;
+            let Ok(nic_type) = value.parse::<NicType>() else {
+                log::trace!("Skipping nic{} with unknown type '{}'", index, value);
+                continue;
+            };
+            if nic_type == NicType::None {
+                continue;
+            }
+            let network = match nic_type {
+                NicType::Bridged => info.get(&format!("bridgeadapter{}", index)),
+                NicType::HostOnly => info.get(&format!("hostonlyadapter{}", index)),
+                NicType::HostOnlyNet => info.get(&format!("hostonly-network{}", index)),
+                NicType::Intnet => info.get(&format!("intnet{}", index)),
+                NicType::NatNetwork => info.get(&format!("nat-network{}", index)),
+                NicType::Generic => info.get(&format!("generic{}", index)),
+                _ => None,
+            };
+            current = Some(adapters.len());
+            adapters.push(NetworkAdapter {
+                index,
+                nic_type,
+                adapter_type: info.get(&format!("nictype{}", index)).map(String::from),
+                mac_address: info.get(&format!("macaddress{}", index)).map(String::from),
+                cable_connected: info.get_flag(&format!("cableconnected{}", index)),
+                network: network.map(String::from),
+                port_forwards: Vec::new(),
+            });
+        } else if key.starts_with("Forwarding(")
+            && let Some(adapter) = current.and_then(|i| adapters.get_mut(i))
+            && let Some(rule) = PortForward::parse(value)
+        {
+            adapter.port_forwards.push(rule);
+        }
+    }
+    adapters
+}
+
+/// Snapshot keys are `SnapshotName<path>`, where the path is empty for the
+/// root and `-1`, `-1-2`, ... for descendants
+pub fn snapshots(info: &MachineReadable) -> Vec<Snapshot> {
+    let mut by_path: BTreeMap<&str, &str> = BTreeMap::new();
+    let mut snapshots = Vec::new();
+    for (key, name) in info.iter() {
+        let Some(path) = key.strip_prefix("SnapshotName") else {
+            continue;
+        };
+        if !(path.is_empty() || path.starts_with('-')) {
+            continue;
+        }
+        by_path.insert(path, name);
+        let parent = path.rfind('-').and_then(|i| by_path.get(&path[..i])).map(|p| p.to_string());
+        snapshots.push(Snapshot {
+            name: name.to_string(),
+            id: info.get(&format!("SnapshotUUID{}", path)).unwrap_or_default().to_string(),
+            description: info
+                .get(&format!("SnapshotDescription{}", path))
+                .filter(|d| !d.is_empty())
+                .map(String::from),
+            parent,
+        });
+    }
+    snapshots
+}
+
+/// Parse `unattended detect --machine-readable` output
+pub fn parse_detected_os(output: &str) -> DetectedOs {
+    let info = MachineReadable::parse(output);
+    let text = |key: &str| info.get(key).filter(|v| !v.is_empty()).map(String::from);
+    DetectedOs {
+        os_type: text("OSTypeId"),
+        version: text("OSVersion"),
+        flavor: text("OSFlavor"),
+        languages: info
+            .get("OSLanguages")
+            .unwrap_or_default()
+            .split([',', ' '])
+            .filter(|l| !l.is_empty())
+            .map(String::from)
+            .collect(),
+        install_supported: info.get("IsInstallSupported") == Some("true"),
+    }
+}
+
+fn shared_folders(info: &MachineReadable) -> Vec<SharedFolder> {
+    let mut folders = Vec::new();
+    for (key, name) in info.iter() {
+        for (kind, transient) in [("Machine", false), ("Transient", true)] {
+            if let Some(i) = indexed(key, &format!("SharedFolderName{}Mapping", kind)) {
+                folders.push(SharedFolder {
+                    name: name.to_string(),
+                    host_path: info
+                        .get(&format!("SharedFolderPath{}Mapping{}", kind, i))
+                        .unwrap_or_default()
+                        .to_string(),
+                    transient,
+                });
+            }
+        }
+    }
+    folders
+}
+
+fn usb(info: &MachineReadable) -> Option<UsbInfo> {
+    let filters: Vec<UsbFilter> = info
+        .iter()
+        .filter_map(|(key, name)| {
+            let i = indexed(key, "USBFilterName")?;
+            Some(UsbFilter {
+                name: name.to_string(),
+                active: info.get_flag(&format!("USBFilterActive{}", i)).unwrap_or(false),
+                vendor_id: info.get(&format!("USBFilterVendorId{}", i)).filter(|v| !v.is_empty()).map(String::from),
+                product_id: info.get(&format!("USBFilterProductId{}", i)).filter(|v| !v.is_empty()).map(String::from),
+            })
+        })
+        .collect();
+
+    let usb = UsbInfo {
+        ohci: info.get_flag("usb"),
+        ehci: info.get_flag("ehci"),
+        xhci: info.get_flag("xhci"),
+        filters,
+    };
+    (usb != UsbInfo::default()).then_some(usb)
+}
+
+fn guest(info: &MachineReadable) -> Option<GuestInfo> {
+    let facilities: BTreeMap<String, String> = info
+        .iter()
+        .filter_map(|(key, value)| {
+            let name = key.strip_prefix("GuestAdditionsFacility_")?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect();
+
+    let guest = GuestInfo {
+        os_type: info.get("GuestOSType").map(String::from),
+        additions_version: info.get("GuestAdditionsVersion").map(String::from),
+        additions_run_level: info.get_parsed("GuestAdditionsRunLevel"),
+        memory_balloon_mb: info.get_parsed("GuestMemoryBalloon"),
+        facilities,
+    };
+    (guest != GuestInfo::default()).then_some(guest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FULL: &str = r#"name="web \"prod\" 1"
+groups="/omni,/web"
+ostype="Ubuntu (64-bit)"
+UUID="2b4f6c1e-8d2a-4f7e-9c3b-1a2b3c4d5e6f"
+CfgFile="/vms/web-1/web-1.vbox"
+description="line one
+line two=with equals"
+memory=2048
+vram=16
+cpus=2
+VMState="running"
+storagecontrollername0="SATA-Controller"
+storagecontrollertype0="IntelAhci"
+storagecontrollerinstance0="0"
+storagecontrollermaxportcount0="30"
+storagecontrollerportcount0="2"
+storagecontrollerbootable0="on"
+"SATA-Controller-0-0"="/vms/web-1/disk=1.vdi"
+"SATA-Controller-ImageUUID-0-0"="6a1b2c3d-0000-4000-8000-000000000001"
+"SATA-Controller-1-0"="emptydrive"
+"SATA-Controller-IsEjected"="off"
+natnet1="nat"
+macaddress1="080027AABBCC"
+cableconnected1="on"
+nic1="nat"
+nictype1="82540EM"
+Forwarding(0)="ssh,tcp,,2222,,22"
+Forwarding(1)="web,tcp,127.0.0.1,8080,,80"
+bridgeadapter2="eth0"
+macaddress2="080027DDEEFF"
+cableconnected2="off"
+nic2="bridged"
+nic3="none"
+usb="off"
+ehci="off"
+xhci="on"
+USBFilterActive1="on"
+USBFilterName1="token"
+USBFilterVendorId1="1050"
+USBFilterProductId1=""
+SharedFolderNameMachineMapping1="data"
+SharedFolderPathMachineMapping1="/srv/data"
+SharedFolderNameTransientMapping1="tmp"
+SharedFolderPathTransientMapping1="/tmp/share"
+SnapshotName="base"
+SnapshotUUID="u0"
+SnapshotName-1="patched"
+SnapshotUUID-1="u1"
+SnapshotDescription-1="after \\ patches"
+SnapshotName-1-1="configured"
+SnapshotUUID-1-1="u2"
+SnapshotName-2="experiment"
+SnapshotUUID-2="u3"
+CurrentSnapshotName="configured"
+CurrentSnapshotUUID="u2"
+CurrentSnapshotNode="SnapshotName-1-1"
+GuestMemoryBalloon=0
+GuestOSType="Ubuntu_64"
+GuestAdditionsRunLevel=2
+GuestAdditionsVersion="7.0.14 r161095"
+GuestAdditionsFacility_VirtualBox Base Driver=50,1700000000000
+"#;
+
+    #[test]
+    fn quoted_keys_escapes_and_multiline_values() {
+        let info = MachineReadable::parse(FULL);
+        assert_eq!(info.get("name"), Some("web \"prod\" 1"));
+        assert_eq!(info.get("description"), Some("line one\nline two=with equals"));
+        assert_eq!(info.get("SATA-Controller-0-0"), Some("/vms/web-1/disk=1.vdi"));
+        assert_eq!(info.get("Forwarding(1)"), Some("web,tcp,127.0.0.1,8080,,80"));
+        assert_eq!(info.get("GuestAdditionsFacility_VirtualBox Base Driver"), Some("50,1700000000000"));
+        assert_eq!(info.get("SnapshotDescription-1"), Some("after \\ patches"));
+        assert_eq!(info.get_parsed::<i64>("memory"), Some(2048));
+    }
+
+    #[test]
+    fn vm_sections() {
+        let vm = parse_vm(FULL);
+        assert_eq!(vm.state, Some(VmState::Running));
+        assert_eq!(vm.groups, vec!["/omni", "/web"]);
+
+        assert_eq!(vm.storage_controllers.len(), 1);
+        assert_eq!(vm.storage_controllers[0].port_count, Some(2));
+        assert_eq!(vm.storage_controllers[0].bootable, Some(true));
+        assert_eq!(
+            vm.storage_attachments,
+            vec![
+                StorageAttachment {
+                    controller: "SATA-Controller".to_string(),
+                    port: 0,
+                    device: 0,
+                    medium: Some("/vms/web-1/disk=1.vdi".to_string()),
+                    medium_id: Some("6a1b2c3d-0000-4000-8000-000000000001".to_string()),
+                    device_type: None,
+                    parent_id: None,
+                    differencing: None,
+                },
+                StorageAttachment {
+                    controller: "SATA-Controller".to_string(),
+                    port: 1,
+                    device: 0,
+                    medium: None,
+                    medium_id: None,
+                    device_type: Some(DeviceType::DvdDrive),
+                    parent_id: None,
+                    differencing: None,
+                },
+            ]
+        );
+
+        assert_eq!(vm.network_adapters.len(), 2);
+        let nat = &vm.network_adapters[0];
+        assert_eq!(nat.mac_address.as_deref(), Some("080027AABBCC"));
+        assert_eq!(nat.port_forwards.len(), 2);
+        assert_eq!(nat.port_forwards[1].host_ip.as_deref(), Some("127.0.0.1"));
+        assert_eq!(nat.port_forwards[1].guest_port, 80);
+        let bridged = &vm.network_adapters[1];
+        assert_eq!(bridged.nic_type, NicType::Bridged);
+        assert_eq!(bridged.network.as_deref(), Some("eth0"));
+        assert_eq!(bridged.cable_connected, Some(false));
+        assert!(bridged.port_forwards.is_empty());
+
+        let names: Vec<_> = vm.snapshots.iter().map(|s| (s.name.as_str(), s.parent.as_deref())).collect();
+        assert_eq!(
+            names,
+            vec![("base", None), ("patched", Some("base")), ("configured", Some("patched")), ("experiment", Some("base"))]
+        );
+        assert_eq!(vm.current_snapshot.as_deref(), Some("configured"));
+
+        assert_eq!(vm.shared_folders.len(), 2);
+        assert!(vm.shared_folders[1].transient);
+
+        let usb = vm.usb.unwrap();
+        assert_eq!(usb.xhci, Some(true));
+        assert_eq!(usb.filters[0].vendor_id.as_deref(), Some("1050"));
+        assert_eq!(usb.filters[0].product_id, None);
+
+        let guest = vm.guest.unwrap();
+        assert_eq!(guest.additions_run_level, Some(2));
+        assert_eq!(guest.facilities["VirtualBox Base Driver"], "50,1700000000000");
+    }
+
+    #[test]
+    fn controller_names_sharing_a_prefix() {
+        let vm = parse_vm(
+            "storagecontrollername0=\"SATA\"\nstoragecontrollername1=\"SATA-2\"\n\
+             \"SATA-0-0\"=\"/vms/os.vdi\"\n\"SATA-2-0-0\"=\"/vms/data.vdi\"\n\"SATA-2-ImageUUID-0-0\"=\"6a1b2c3d-0000-4000-8000-000000000002\"\n",
+        );
+        let slots: Vec<(&str, u32, Option<&str>)> =
+            vm.storage_attachments.iter().map(|a| (a.controller.as_str(), a.port, a.medium_id.as_deref())).collect();
+        assert_eq!(slots, vec![("SATA", 0, None), ("SATA-2", 0, Some("6a1b2c3d-0000-4000-8000-000000000002"))]);
+    }
+
+    #[test]
+    fn attachment_types_known_from_showvminfo() {
+        let vm = parse_vm(
+            "storagecontrollername0=\"IDE\"\nstoragecontrollertype0=\"PIIX4\"\nstoragecontrollername1=\"Floppy\"\nstoragecontrollertype1=\"I82078\"\n\
+             \"IDE-0-0\"=\"/isos/tools.img\"\n\"IDE-1-0\"=\"host:/dev/sr0\"\n\"IDE-1-1\"=\"emptydrive\"\n\"Floppy-0-0\"=\"emptydrive\"\n",
+        );
+        let kinds: Vec<Option<DeviceType>> = vm.storage_attachments.iter().map(|a| a.device_type).collect();
+        assert_eq!(kinds, vec![None, Some(DeviceType::DvdDrive), Some(DeviceType::DvdDrive), Some(DeviceType::Fdd)]);
+        assert_eq!(serde_json::to_value(&vm.storage_attachments[0]).unwrap()["type"], "unknown");
+        assert_eq!(vm.storage_controllers[1].bus, Some(StorageBus::Floppy));
+    }
+
+    #[test]
+    fn parses_unattended_detect_output() {
+        let os = parse_detected_os(
+            "OSTypeId=\"Ubuntu_64\"\nOSVersion=\"22.04.3 LTS\"\nOSFlavor=\"\"\nOSLanguages=\"en-US,de-DE\"\nOSHints=\"\"\nIsInstallSupported=\"true\"\n",
+        );
+        assert_eq!(os.os_type.as_deref(), Some("Ubuntu_64"));
+        assert_eq!(os.version.as_deref(), Some("22.04.3 LTS"));
+        assert_eq!(os.flavor, None);
+        assert_eq!(os.languages, vec!["en-US", "de-DE"]);
+        assert!(os.install_supported);
+        assert!(!parse_detected_os("OSTypeId=\"Other\"\n").install_supported);
+    }
+}
@@ -0,0 +1,118 @@
+// File: cpi_virtualbox/src/retry.rs
+use std::time::Duration;
+
+use crate::error::{ErrorKind, VBoxFailure};
+
+/// Result codes that will not go away by retrying
+const PERMANENT_MARKERS: &[&str] = &[
+    "VBOX_E_OBJECT_NOT_FOUND",
+    "VBOX_E_FILE_ERROR",
+    "VBOX_E_NOT_SUPPORTED",
+    "E_ACCESSDENIED",
+    "E_INVALIDARG",
+    "Could not find a registered machine",
+];
+
+/// Session locks and busy states that usually clear within a few seconds,
+/// e.g. right after a VM powers off or while another `modifyvm` runs. These
+/// are matched by message: VBOX_E_INVALID_OBJECT_STATE alone also covers
+/// states that retrying won't change, such as a VM that isn't running
+const TRANSIENT_MARKERS: &[&str] = &[
+    "is already locked",
+    "locked for a session",
+    "being unlocked",
+    "session is busy",
+];
+
+/// Whether a failed VBoxManage invocation is worth retrying, judged from its stderr
+pub fn is_transient(stderr: &str) -> bool {
+    if PERMANENT_MARKERS.iter().any(|m| stderr.contains(m)) {
+        return false;
+    }
+    VBoxFailure::parse("", stderr).kind() == ErrorKind::Locked || TRANSIENT_MARKERS.iter().any(|m| stderr.contains(m))
+}
+
+/// How often and how patiently transient VBoxManage failures are retried
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Total attempts including the first; 1 disables retrying
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+}
+
+impl RetryPolicy {
+    /// Run each invocation exactly once
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// Delay before retry number `retry` (1 for the first retry)
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = self.multiplier.powi(retry.saturating_sub(1) as i32);
+        self.initial_backoff.mul_f64(factor).min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(4),
+            multiplier: 2.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_locks_are_transient() {
+        assert!(is_transient(
+            "VBoxManage: error: The machine 'web-1' is already locked for a session (or being unlocked)\n\
+             VBoxManage: error: Details: code VBOX_E_INVALID_OBJECT_STATE (0x80bb0007), component MachineWrap, interface IMachine, callee nsISupports\n"
+        ));
+        assert!(is_transient("VBoxManage: error: The machine 'web-1' is already locked by a session (or being locked or unlocked)"));
+        assert!(is_transient(
+            "VBoxManage: error: Cannot unregister the machine 'web-1' while it is locked\n\
+             VBoxManage: error: Details: code VBOX_E_INVALID_OBJECT_STATE (0x80bb0007), component MachineWrap, interface IMachine, callee nsISupports\n"
+        ));
+    }
+
+    #[test]
+    fn invalid_states_other_than_locks_are_permanent() {
+        assert!(!is_transient(
+            "VBoxManage: error: Machine 'web-1' is not currently running\n\
+             VBoxManage: error: Details: code VBOX_E_INVALID_OBJECT_STATE (0x80bb0007), component ConsoleWrap, interface IConsole, callee nsISupports\n"
+        ));
+        assert!(!is_transient(
+            "VBoxManage: error: The machine 'web-1' is already running\n\
+             VBoxManage: error: Details: code VBOX_E_INVALID_OBJECT_STATE (0x80bb0007), component MachineWrap, interface IMachine, callee nsISupports\n"
+        ));
+    }
+
+    #[test]
+    fn missing_objects_are_permanent() {
+        assert!(!is_transient(
+            "VBoxManage: error: Could not find a registered machine named 'ghost'\n\
+             VBoxManage: error: Details: code VBOX_E_OBJECT_NOT_FOUND (0x80bb0001), component VirtualBoxWrap, interface IVirtualBox, callee nsISupports\n"
+        ));
+        assert!(!is_transient("VBoxManage: error: Machine settings file '/vms/web-1/web-1.vbox' already exists"));
+    }
+
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff(1), Duration::from_millis(250));
+        assert_eq!(policy.backoff(2), Duration::from_millis(500));
+        assert_eq!(policy.backoff(3), Duration::from_secs(1));
+        assert_eq!(policy.backoff(10), Duration::from_secs(4));
+    }
+}
@@ -0,0 +1,187 @@
+// File: cpi_virtualbox/src/settings.rs
+//! Defaults the extension falls back to when an action leaves a parameter
+//! out. They start from built-in values, then a JSON config file and then
+//! environment variables override them when the extension is loaded.
+use crate::logging::REDACTED;
+use crate::types::NicType;
+use serde_json::Value;
+use std::fmt;
+use std::path::Path;
+
+/// JSON file of default overrides, e.g. `{"memory_mb": 4096}`
+pub const CONFIG_FILE_ENV: &str = "CPI_VIRTUALBOX_CONFIG";
+
+/// Prefix of environment variables overriding one default, with the key in
+/// upper case, e.g. `CPI_VIRTUALBOX_DEFAULT_MEMORY_MB=4096`
+pub const DEFAULT_ENV_PREFIX: &str = "CPI_VIRTUALBOX_DEFAULT_";
+
+/// Values used for parameters an action was called without
+#[derive(Clone, PartialEq)]
+pub struct DefaultSettings {
+    pub os_type: String,
+    pub memory_mb: i64,
+    pub cpu_count: i64,
+    pub controller_name: String,
+    pub network_type: NicType,
+    pub username: String,
+    pub password: String,
+}
+
+impl Default for DefaultSettings {
+    fn default() -> Self {
+        DefaultSettings {
+            os_type: "Ubuntu_64".to_string(),
+            memory_mb: 2048,
+            cpu_count: 2,
+            controller_name: "SATA Controller".to_string(),
+            network_type: NicType::Nat,
+            username: "vboxuser".to_string(),
+            password: "password".to_string(),
+        }
+    }
+}
+
+// The password must not end up in logs
+impl fmt::Debug for DefaultSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DefaultSettings")
+            .field("os_type", &self.os_type)
+            .field("memory_mb", &self.memory_mb)
+            .field("cpu_count", &self.cpu_count)
+            .field("controller_name", &self.controller_name)
+            .field("network_type", &self.network_type)
+            .field("username", &self.username)
+            .field("password", &REDACTED)
+            .finish()
+    }
+}
+
+impl DefaultSettings {
+    /// Built-in defaults overridden by the file named in
+    /// `CPI_VIRTUALBOX_CONFIG`, then by `CPI_VIRTUALBOX_DEFAULT_*`
+    /// variables. Invalid entries are logged and skipped.
+    pub fn from_env() -> Self {
+        let file = std::env::var_os(CONFIG_FILE_ENV).filter(|p| !p.is_empty());
+        Self::from_sources(file.as_deref().map(Path::new), std::env::vars())
+    }
+
+    fn from_sources(file: Option<&Path>, vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        let mut settings = Self::default();
+        if let Some(path) = file
+            && let Err(e) = settings.apply_file(path)
+        {
+            log::warn!("Ignoring config file '{}': {}", path.display(), e);
+        }
+        for (key, value) in vars {
+            let Some(name) = key.strip_prefix(DEFAULT_ENV_PREFIX) else {
+                continue;
+            };
+            if let Err(e) = settings.set(&name.to_ascii_lowercase(), &Value::String(value)) {
+                log::warn!("Ignoring {}: {}", key, e);
+            }
+        }
+        settings
+    }
+
+    /// Apply every key of a JSON object file; nothing is changed if the
+    /// file cannot be read or any entry is invalid
+    pub fn apply_file(&mut self, path: &Path) -> Result<(), String> {
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let value: Value = serde_json::from_str(&text).map_err(|e| format!("Invalid JSON: {}", e))?;
+        let Value::Object(entries) = value else {
+            return Err("Expected a JSON object".to_string());
+        };
+        let mut updated = self.clone();
+        for (key, value) in &entries {
+            updated.set(key, value)?;
+        }
+        *self = updated;
+        Ok(())
+    }
+
+    /// Override one default. Numbers may also be given as strings, as they
+    /// are when read from the environment.
+    pub fn set(&mut self, key: &str, value: &Value) -> Result<(), String> {
+        match key {
+            "os_type" => self.os_type = string(key, value)?,
+            "memory_mb" => self.memory_mb = positive(key, value)?,
+            "cpu_count" => self.cpu_count = positive(key, value)?,
+            "controller_name" => self.controller_name = string(key, value)?,
+            "network_type" => self.network_type = string(key, value)?.parse()?,
+            "username" => self.username = string(key, value)?,
+            "password" => self.password = string(key, value)?,
+            other => return Err(format!("Unknown setting '{}'", other)),
+        }
+        Ok(())
+    }
+}
+
+fn string(key: &str, value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) if !s.is_empty() => Ok(s.clone()),
+        _ => Err(format!("Setting '{}' must be a non-empty string", key)),
+    }
+}
+
+fn positive(key: &str, value: &Value) -> Result<i64, String> {
+    let number = match value {
+        Value::Number(n) => n.as_i64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    };
+    number
+        .filter(|n| *n > 0)
+        .ok_or_else(|| format!("Setting '{}' must be a positive integer", key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn environment_overrides_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("virtualbox.json");
+        std::fs::write(&file, r#"{"memory_mb": 4096, "cpu_count": 4, "network_type": "bridged"}"#).unwrap();
+
+        let settings = DefaultSettings::from_sources(
+            Some(&file),
+            vars(&[("CPI_VIRTUALBOX_DEFAULT_CPU_COUNT", "8"), ("CPI_VIRTUALBOX_DEFAULT_OS_TYPE", "Debian_64"), ("PATH", "/usr/bin")]),
+        );
+        assert_eq!(settings.memory_mb, 4096);
+        assert_eq!(settings.cpu_count, 8);
+        assert_eq!(settings.os_type, "Debian_64");
+        assert_eq!(settings.network_type, NicType::Bridged);
+        assert_eq!(settings.controller_name, "SATA Controller");
+    }
+
+    #[test]
+    fn invalid_overrides_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("virtualbox.json");
+        // One bad entry rejects the whole file
+        std::fs::write(&file, r#"{"memory_mb": 4096, "cpu_count": 0}"#).unwrap();
+
+        let settings = DefaultSettings::from_sources(
+            Some(&file),
+            vars(&[("CPI_VIRTUALBOX_DEFAULT_MEMORY_MB", "lots"), ("CPI_VIRTUALBOX_DEFAULT_NETWORK_TYPE", "wifi")]),
+        );
+        assert_eq!(settings, DefaultSettings::default());
+        assert_eq!(
+            DefaultSettings::default().set("colour", &json!("blue")).unwrap_err(),
+            "Unknown setting 'colour'"
+        );
+    }
+
+    #[test]
+    fn debug_output_hides_the_password() {
+        let debug = format!("{:?}", DefaultSettings::default());
+        assert!(debug.contains(REDACTED));
+        assert!(!debug.contains("\"password\""));
+    }
+}
@@ -0,0 +1,610 @@
+// File: cpi_virtualbox/src/spec.rs
+//! Declarative worker specs. A `WorkerSpec` describes a VM's hardware,
+//! disks, NICs, port forwards and metadata; `WorkerSpec::plan` compares it
+//! with the VM's current `showvminfo` state and lists the VBoxManage calls
+//! that bring the VM in line. Settings a spec leaves out are never touched,
+//! and neither are disks, NICs, rules or metadata keys it does not list.
+use crate::logging::{self, REDACTED};
+use crate::settings::DefaultSettings;
+use crate::types::{ControllerSpec, Medium, NicType, PortForward, StorageBus, Vm, VmConfig};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Desired state of one VM, read from JSON or TOML:
+///
+/// ```toml
+/// name = "web-1"
+/// memory_mb = 4096
+///
+/// [[disks]]
+/// path = "/vms/web-1/data.vdi"
+/// port = 1
+/// size_mb = 10240
+///
+/// [[nics]]
+/// index = 1
+/// type = "nat"
+/// port_forwards = [{ name = "ssh", protocol = "tcp", host_port = 2222, guest_port = 22 }]
+///
+/// [metadata]
+/// role = "web"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkerSpec {
+    pub name: String,
+    /// Only used when the VM has to be created
+    pub os_type: Option<String>,
+    /// Only used when the VM has to be created
+    pub base_folder: Option<String>,
+    pub groups: Option<Vec<String>>,
+    pub memory_mb: Option<i64>,
+    pub cpu_count: Option<i64>,
+    pub vram_mb: Option<i64>,
+    pub firmware: Option<String>,
+    pub chipset: Option<String>,
+    pub graphics_controller: Option<String>,
+    pub nested_virtualization: Option<bool>,
+    pub pae: Option<bool>,
+    pub paravirt_provider: Option<String>,
+    pub boot_order: Option<Vec<String>>,
+    pub description: Option<String>,
+    pub rtc_utc: Option<bool>,
+    pub audio: Option<bool>,
+    pub disks: Vec<DiskAttachmentSpec>,
+    pub nics: Vec<NicSpec>,
+    /// Extradata keys and values
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// A hard disk attached to a controller slot
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiskAttachmentSpec {
+    pub path: String,
+    /// Controller, added if missing; defaults to the default controller
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub controller: Option<String>,
+    /// Bus of the controller when it has to be added; SATA by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub controller_bus: Option<StorageBus>,
+    #[serde(default)]
+    pub port: u32,
+    #[serde(default)]
+    pub device: u32,
+    /// Create the disk with this size when it is not registered yet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size_mb: Option<i64>,
+}
+
+/// A network adapter and the NAT rules on it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NicSpec {
+    pub index: u32,
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub nic_type: Option<NicType>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub port_forwards: Vec<PortForward>,
+}
+
+/// One difference between a spec and the VM, and how to resolve it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SpecChange {
+    /// What changes, e.g. `memory_mb`, `disk SATA Controller/1/0`, `nic2`,
+    /// `port_forward nic1/ssh` or `metadata role`
+    pub target: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    pub to: String,
+    /// VBoxManage invocations making the change; secrets are masked when
+    /// serialized
+    #[serde(serialize_with = "redacted_commands")]
+    pub commands: Vec<Vec<String>>,
+}
+
+/// Changes planned (or applied) for a spec
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SpecPlan {
+    /// Whether the VM existed before; if not the first change creates it
+    pub exists: bool,
+    pub changes: Vec<SpecChange>,
+}
+
+fn redacted_commands<S: serde::Serializer>(commands: &[Vec<String>], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(commands.iter().map(|args| {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        logging::redact_args(&args)
+    }))
+}
+
+impl WorkerSpec {
+    /// Parse a spec from JSON (when it starts with `{`) or TOML text
+    pub fn parse(text: &str) -> Result<Self, String> {
+        if text.trim_start().starts_with('{') {
+            serde_json::from_str(text).map_err(|e| format!("Invalid JSON spec: {}", e))
+        } else {
+            toml::from_str(text).map_err(|e| format!("Invalid TOML spec: {}", e))
+        }
+    }
+
+    /// Fill in what the spec leaves to the extension's defaults
+    pub fn with_defaults(mut self, defaults: &DefaultSettings) -> Self {
+        self.os_type.get_or_insert_with(|| defaults.os_type.clone());
+        for disk in &mut self.disks {
+            disk.controller.get_or_insert_with(|| defaults.controller_name.clone());
+        }
+        self
+    }
+
+    /// Reject specs VBoxManage would fail on, before anything is changed;
+    /// `media` holds the registered disks
+    pub fn validate(&self, media: &[Medium]) -> Result<(), String> {
+        // Hardware values share the rules of a new VM
+        let config = VmConfig {
+            name: self.name.clone(),
+            memory_mb: self.memory_mb.unwrap_or(1),
+            cpu_count: self.cpu_count.unwrap_or(1),
+            firmware: self.firmware.clone(),
+            chipset: self.chipset.clone(),
+            vram_mb: self.vram_mb,
+            graphics_controller: self.graphics_controller.clone(),
+            paravirt_provider: self.paravirt_provider.clone(),
+            boot_order: self.boot_order.clone().unwrap_or_default(),
+            groups: self.groups.clone().unwrap_or_default(),
+            ..VmConfig::default()
+        };
+        config.validate()?;
+
+        let mut slots = Vec::new();
+        for disk in &self.disks {
+            if disk.path.trim().is_empty() {
+                return Err("Disk path must not be empty".to_string());
+            }
+            if disk.size_mb.is_some_and(|size| size <= 0) {
+                return Err(format!("Size of disk '{}' must be positive", disk.path));
+            }
+            if disk.size_mb.is_none() && !media.iter().any(|m| m.path.as_deref() == Some(disk.path.as_str())) {
+                return Err(format!("Disk '{}' is not registered; give size_mb to create it", disk.path));
+            }
+            if let Some(bus) = disk.controller_bus
+                && let Some(other) = self.disks.iter().find(|d| d.controller == disk.controller && d.controller_bus.is_some_and(|b| b != bus))
+            {
+                return Err(format!("Disks '{}' and '{}' ask for different buses on one controller", disk.path, other.path));
+            }
+            let slot = (disk.controller.clone(), disk.port, disk.device);
+            if slots.contains(&slot) {
+                return Err(format!("Disk '{}' uses a controller slot that is already taken", disk.path));
+            }
+            slots.push(slot);
+        }
+
+        let mut seen = Vec::new();
+        for nic in &self.nics {
+            if !(1..=8).contains(&nic.index) {
+                return Err(format!("Network adapter index {} must be between 1 and 8", nic.index));
+            }
+            if seen.contains(&nic.index) {
+                return Err(format!("Network adapter {} is given more than once", nic.index));
+            }
+            seen.push(nic.index);
+            if !nic.port_forwards.is_empty() && nic.nic_type.as_ref().is_some_and(|t| *t != NicType::Nat) {
+                return Err(format!("Port forwards need NIC {} to be NAT", nic.index));
+            }
+            for rule in &nic.port_forwards {
+                if rule.name.is_empty() || rule.name.contains(',') {
+                    return Err(format!("Port forward name '{}' must be non-empty and without commas", rule.name));
+                }
+                if !matches!(rule.protocol.to_ascii_lowercase().as_str(), "tcp" | "udp") {
+                    return Err(format!("Port forward '{}' must use tcp or udp", rule.name));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes that bring `current` in line with the spec. `current` is
+    /// `None` for a VM that does not exist yet; `metadata` holds the current
+    /// values of the spec's metadata keys and `media` the registered disks.
+    pub fn plan(&self, current: Option<&Vm>, metadata: &BTreeMap<String, String>, media: &[Medium]) -> SpecPlan {
+        let empty = Vm::default();
+        let vm = current.unwrap_or(&empty);
+        let name = self.name.as_str();
+        let mut changes = Vec::new();
+
+        if current.is_none() {
+            let mut args = vec!["createvm".to_string(), "--name".to_string(), self.name.clone(), "--register".to_string()];
+            if let Some(os_type) = &self.os_type {
+                args.extend(["--ostype".to_string(), os_type.clone()]);
+            }
+            if let Some(folder) = &self.base_folder {
+                args.extend(["--basefolder".to_string(), folder.clone()]);
+            }
+            changes.push(SpecChange {
+                target: "vm".to_string(),
+                from: None,
+                to: self.name.clone(),
+                commands: vec![args],
+            });
+        }
+
+        // Hardware: (field, showvminfo key, desired value, modifyvm flag)
+        let on_off = |flag: bool| if flag { "on" } else { "off" }.to_string();
+        let settings: Vec<(&str, &str, Option<String>, &str)> = vec![
+            ("groups", "groups", self.groups.as_ref().map(|g| g.join(",")), "--groups"),
+            ("memory_mb", "memory", self.memory_mb.map(|m| m.to_string()), "--memory"),
+            ("cpu_count", "cpus", self.cpu_count.map(|c| c.to_string()), "--cpus"),
+            ("vram_mb", "vram", self.vram_mb.map(|v| v.to_string()), "--vram"),
+            ("firmware", "firmware", lower(&self.firmware), "--firmware"),
+            ("chipset", "chipset", lower(&self.chipset), "--chipset"),
+            ("graphics_controller", "graphicscontroller", lower(&self.graphics_controller), "--graphicscontroller"),
+            ("nested_virtualization", "nested-hw-virt", self.nested_virtualization.map(on_off), "--nested-hw-virt"),
+            ("pae", "pae", self.pae.map(on_off), "--pae"),
+            ("paravirt_provider", "paravirtprovider", lower(&self.paravirt_provider), "--paravirt-provider"),
+            ("description", "description", self.description.clone(), "--description"),
+            ("rtc_utc", "rtcuseutc", self.rtc_utc.map(on_off), "--rtc-use-utc"),
+        ];
+        for (field, key, desired, flag) in settings {
+            let Some(desired) = desired else {
+                continue;
+            };
+            let from = vm.properties.get(key).cloned();
+            if from.as_deref().is_some_and(|from| from.eq_ignore_ascii_case(&desired)) {
+                continue;
+            }
+            changes.push(SpecChange {
+                target: field.to_string(),
+                from,
+                commands: vec![modifyvm(name, &[flag, &desired])],
+                to: desired,
+            });
+        }
+
+        // VirtualBox reports a disabled audio adapter as `none`
+        if let Some(audio) = self.audio {
+            let from = vm.properties.get("audio").map(|driver| driver != "none");
+            if from != Some(audio) {
+                changes.push(SpecChange {
+                    target: "audio".to_string(),
+                    from: from.map(on_off),
+                    to: on_off(audio),
+                    commands: vec![modifyvm(name, &["--audio-enabled", &on_off(audio)])],
+                });
+            }
+        }
+
+        if let Some(order) = &self.boot_order {
+            let desired: Vec<String> = (0..4)
+                .map(|slot| order.get(slot).map_or("none".to_string(), |d| d.to_ascii_lowercase()))
+                .collect();
+            let existing: Vec<String> = (1..=4)
+                .map(|slot| vm.properties.get(&format!("boot{}", slot)).map_or("none".to_string(), |d| d.to_ascii_lowercase()))
+                .collect();
+            if desired != existing {
+                let mut args = vec!["modifyvm".to_string(), self.name.clone()];
+                for (slot, device) in desired.iter().enumerate() {
+                    args.extend([format!("--boot{}", slot + 1), device.clone()]);
+                }
+                changes.push(SpecChange {
+                    target: "boot_order".to_string(),
+                    from: current.map(|_| existing.join(",")),
+                    to: desired.join(","),
+                    commands: vec![args],
+                });
+            }
+        }
+
+        let mut added_controllers: Vec<&str> = Vec::new();
+        for disk in &self.disks {
+            let controller = disk.controller.as_deref().unwrap_or_default();
+            if !vm.storage_controllers.iter().any(|c| c.name == controller) && !added_controllers.contains(&controller) {
+                added_controllers.push(controller);
+                let bus = self
+                    .disks
+                    .iter()
+                    .filter(|d| d.controller == disk.controller)
+                    .find_map(|d| d.controller_bus)
+                    .unwrap_or(StorageBus::Sata);
+                changes.push(SpecChange {
+                    target: format!("controller {}", controller),
+                    from: None,
+                    to: bus.to_string(),
+                    commands: vec![ControllerSpec::new(controller, bus).storagectl_args(name)],
+                });
+            }
+
+            let attached = vm
+                .storage_attachments
+                .iter()
+                .find(|a| a.controller == controller && a.port == disk.port && a.device == disk.device)
+                .and_then(|a| a.medium.clone());
+            if attached.as_deref() == Some(disk.path.as_str()) {
+                continue;
+            }
+            let mut commands = Vec::new();
+            let registered = media.iter().any(|m| m.path.as_deref() == Some(disk.path.as_str()));
+            if let (false, Some(size)) = (registered, disk.size_mb) {
+                commands.push(strings(&["createmedium", "disk", "--filename", &disk.path, "--size", &size.to_string(), "--format", "VDI"]));
+            }
+            commands.push(strings(&[
+                "storageattach",
+                name,
+                "--storagectl",
+                controller,
+                "--port",
+                &disk.port.to_string(),
+                "--device",
+                &disk.device.to_string(),
+                "--type",
+                "hdd",
+                "--medium",
+                &disk.path,
+            ]));
+            changes.push(SpecChange {
+                target: format!("disk {}/{}/{}", controller, disk.port, disk.device),
+                from: attached,
+                to: disk.path.clone(),
+                commands,
+            });
+        }
+
+        for nic in &self.nics {
+            let adapter = vm.network_adapters.iter().find(|a| a.index == nic.index);
+            if let Some(nic_type) = &nic.nic_type
+                && adapter.map(|a| &a.nic_type) != Some(nic_type)
+            {
+                changes.push(SpecChange {
+                    target: format!("nic{}", nic.index),
+                    from: adapter.map(|a| a.nic_type.to_string()).or_else(|| current.map(|_| NicType::None.to_string())),
+                    to: nic_type.to_string(),
+                    commands: vec![modifyvm(name, &[&format!("--nic{}", nic.index), nic_type.as_str()])],
+                });
+            }
+            let flag = format!("--natpf{}", nic.index);
+            for rule in &nic.port_forwards {
+                let existing = adapter.and_then(|a| a.port_forwards.iter().find(|r| r.name == rule.name));
+                if existing.is_some_and(|existing| rule_string(existing) == rule_string(rule)) {
+                    continue;
+                }
+                let mut commands = Vec::new();
+                if existing.is_some() {
+                    commands.push(strings(&["modifyvm", name, &flag, "delete", &rule.name]));
+                }
+                commands.push(modifyvm(name, &[&flag, &rule_string(rule)]));
+                changes.push(SpecChange {
+                    target: format!("port_forward nic{}/{}", nic.index, rule.name),
+                    from: existing.map(rule_string),
+                    to: rule_string(rule),
+                    commands,
+                });
+            }
+        }
+
+        for (key, value) in &self.metadata {
+            let from = metadata.get(key);
+            if from == Some(value) {
+                continue;
+            }
+            let secret = logging::is_secret_key(key);
+            let shown = |v: &String| if secret { REDACTED.to_string() } else { v.clone() };
+            changes.push(SpecChange {
+                target: format!("metadata {}", key),
+                from: from.map(shown),
+                to: shown(value),
+                commands: vec![strings(&["setextradata", name, key, value])],
+            });
+        }
+
+        SpecPlan {
+            exists: current.is_some(),
+            changes,
+        }
+    }
+}
+
+fn strings(args: &[&str]) -> Vec<String> {
+    args.iter().map(|a| a.to_string()).collect()
+}
+
+fn modifyvm(name: &str, args: &[&str]) -> Vec<String> {
+    let mut command = strings(&["modifyvm", name]);
+    command.extend(args.iter().map(|a| a.to_string()));
+    command
+}
+
+fn rule_string(rule: &PortForward) -> String {
+    format!(
+        "{},{},{},{},{},{}",
+        rule.name,
+        rule.protocol.to_ascii_lowercase(),
+        rule.host_ip.as_deref().unwrap_or_default(),
+        rule.host_port,
+        rule.guest_ip.as_deref().unwrap_or_default(),
+        rule.guest_port
+    )
+}
+
+// Enumerated settings are case-insensitive; compare and pass them lowercased
+fn lower(value: &Option<String>) -> Option<String> {
+    value.as_ref().map(|v| v.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machinereadable::parse_vm;
+
+    const WEB_1: &str = r#"name="web-1"
+groups="/"
+memory=1024
+cpus=2
+firmware="BIOS"
+boot1="floppy"
+boot2="dvd"
+boot3="disk"
+boot4="none"
+storagecontrollername0="SATA Controller"
+storagecontrollertype0="IntelAhci"
+"SATA Controller-0-0"="/vms/web-1/os.vdi"
+"SATA Controller-1-0"="none"
+nic1="nat"
+Forwarding(0)="ssh,tcp,,2222,,22"
+nic2="none"
+"#;
+
+    fn spec(text: &str) -> WorkerSpec {
+        WorkerSpec::parse(text).unwrap().with_defaults(&DefaultSettings::default())
+    }
+
+    fn targets(plan: &SpecPlan) -> Vec<&str> {
+        plan.changes.iter().map(|c| c.target.as_str()).collect()
+    }
+
+    #[test]
+    fn toml_and_json_specs_parse_alike() {
+        let toml = WorkerSpec::parse(
+            r#"
+name = "web-1"
+memory_mb = 2048
+
+[[nics]]
+index = 1
+type = "nat"
+port_forwards = [{ name = "ssh", protocol = "tcp", host_port = 2222, guest_port = 22 }]
+
+[metadata]
+role = "web"
+"#,
+        )
+        .unwrap();
+        let json = WorkerSpec::parse(
+            r#"{"name": "web-1", "memory_mb": 2048, "metadata": {"role": "web"},
+                "nics": [{"index": 1, "type": "nat", "port_forwards": [{"name": "ssh", "protocol": "tcp", "host_port": 2222, "guest_port": 22}]}]}"#,
+        )
+        .unwrap();
+        assert_eq!(toml, json);
+        assert!(WorkerSpec::parse("name = \"web-1\"\nmemroy_mb = 1").unwrap_err().contains("unknown field"));
+    }
+
+    #[test]
+    fn plan_only_lists_differences() {
+        let vm = parse_vm(WEB_1);
+        let spec = spec(
+            r#"
+name = "web-1"
+memory_mb = 2048
+cpu_count = 2
+firmware = "bios"
+boot_order = ["disk", "dvd"]
+
+[[disks]]
+path = "/vms/web-1/os.vdi"
+
+[[disks]]
+path = "/vms/web-1/data.vdi"
+port = 1
+size_mb = 1024
+
+[[nics]]
+index = 1
+type = "nat"
+port_forwards = [
+    { name = "ssh", protocol = "tcp", host_port = 2222, guest_port = 22 },
+    { name = "web", protocol = "tcp", host_port = 8080, guest_port = 80 },
+]
+
+[[nics]]
+index = 2
+type = "hostonly"
+"#,
+        );
+        let media = [Medium {
+            path: Some("/vms/web-1/os.vdi".to_string()),
+            ..Medium::default()
+        }];
+        spec.validate(&media).unwrap();
+
+        let plan = spec.plan(Some(&vm), &BTreeMap::new(), &media);
+        assert!(plan.exists);
+        assert_eq!(
+            targets(&plan),
+            vec!["memory_mb", "boot_order", "disk SATA Controller/1/0", "port_forward nic1/web", "nic2"]
+        );
+        assert_eq!(plan.changes[0].from.as_deref(), Some("1024"));
+        assert_eq!(plan.changes[1].commands[0][2..], ["--boot1", "disk", "--boot2", "dvd", "--boot3", "none", "--boot4", "none"]);
+        // Unregistered disks with a size are created before attaching
+        assert_eq!(plan.changes[2].commands[0][0], "createmedium");
+        assert_eq!(plan.changes[2].commands[1][0], "storageattach");
+        assert_eq!(plan.changes[3].commands[0], ["modifyvm", "web-1", "--natpf1", "web,tcp,,8080,,80"]);
+    }
+
+    #[test]
+    fn plan_for_missing_vm_creates_it_first() {
+        let spec = spec(
+            r#"
+name = "web-1"
+memory_mb = 2048
+
+[[disks]]
+path = "/vms/web-1/os.vdi"
+
+[[disks]]
+path = "/vms/web-1/data.vdi"
+controller = "NVMe"
+controller_bus = "nvme"
+size_mb = 1024
+
+[metadata]
+role = "web"
+db_password = "hunter2"
+"#,
+        );
+        let media = [Medium {
+            path: Some("/vms/web-1/os.vdi".to_string()),
+            ..Medium::default()
+        }];
+
+        spec.validate(&media).unwrap();
+
+        let plan = spec.plan(None, &BTreeMap::new(), &media);
+        assert!(!plan.exists);
+        assert_eq!(
+            targets(&plan),
+            vec![
+                "vm",
+                "memory_mb",
+                "controller SATA Controller",
+                "disk SATA Controller/0/0",
+                "controller NVMe",
+                "disk NVMe/0/0",
+                "metadata db_password",
+                "metadata role"
+            ]
+        );
+        assert_eq!(plan.changes[0].commands[0], ["createvm", "--name", "web-1", "--register", "--ostype", "Ubuntu_64"]);
+        assert_eq!(plan.changes[2].commands[0], ["storagectl", "web-1", "--name", "SATA Controller", "--add", "sata", "--controller", "IntelAhci"]);
+        assert_eq!(plan.changes[4].commands[0], ["storagectl", "web-1", "--name", "NVMe", "--add", "pcie", "--controller", "NVMe"]);
+        // Registered disks are attached as they are
+        assert_eq!(plan.changes[3].commands.len(), 1);
+
+        let secret = serde_json::to_value(&plan.changes[6]).unwrap();
+        assert_eq!(secret["to"], REDACTED);
+        assert_eq!(secret["commands"][0][3], REDACTED);
+    }
+
+    #[test]
+    fn invalid_specs_are_rejected() {
+        for text in [
+            "name = \"\"",
+            "name = \"web-1\"\nfirmware = \"uefi\"",
+            "name = \"web-1\"\n[[nics]]\nindex = 9",
+            "name = \"web-1\"\n[[nics]]\nindex = 1\ntype = \"bridged\"\nport_forwards = [{ name = \"ssh\", protocol = \"tcp\", host_port = 2222, guest_port = 22 }]",
+            "name = \"web-1\"\n[[disks]]\npath = \"/a.vdi\"\nsize_mb = 1\n[[disks]]\npath = \"/b.vdi\"\nsize_mb = 1",
+            // Neither registered nor to be created
+            "name = \"web-1\"\n[[disks]]\npath = \"/missing.vdi\"",
+            "name = \"web-1\"\n[[disks]]\npath = \"/a.vdi\"\nsize_mb = 1\ncontroller_bus = \"sata\"\n[[disks]]\npath = \"/b.vdi\"\nsize_mb = 1\nport = 1\ncontroller_bus = \"scsi\"",
+        ] {
+            assert!(spec(text).validate(&[]).is_err(), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,1538 @@
+// File: cpi_virtualbox/src/tests.rs
+use super::*;
+
+const SHOWVMINFO: &str = r#"name="web-1"
+groups="/"
+ostype="Ubuntu (64-bit)"
+UUID="2b4f6c1e-8d2a-4f7e-9c3b-1a2b3c4d5e6f"
+CfgFile="/home/vbox/VirtualBox VMs/web-1/web-1.vbox"
+memory=2048
+vram=16
+cpus=2
+firmware="BIOS"
+graphicscontroller="vmsvga"
+VMState="poweroff"
+VMStateChangeTime="2024-01-01T10:00:00.000000000"
+"#;
+
+const LIST_LONG_VMS: &str = "Name:                        web-1
+Encryption:                  disabled
+Groups:                      /omni/web
+Guest OS:                    Ubuntu (64-bit)
+UUID:                        2b4f6c1e-8d2a-4f7e-9c3b-1a2b3c4d5e6f
+Memory size:                 2048MB
+Number of CPUs:              2
+State:                       running (since 2024-01-01T10:00:00.000000000)
+
+Name:                        db \"primary\"
+Groups:                      /
+Guest OS:                    Debian (64-bit)
+UUID:                        9f8e7d6c-0000-4000-8000-000000000000
+Memory size:                 4096MB
+Number of CPUs:              4
+State:                       powered off (since 2024-01-01T10:00:00.000000000)
+";
+
+const LIST_OSTYPES: &str = "ID:          Debian_64
+Description: Debian (64-bit)
+Family ID:   Linux
+Family Desc: Linux
+64 bit:      true
+
+ID:          Ubuntu_64
+Description: Ubuntu (64-bit)
+Family ID:   Linux
+Family Desc: Linux
+64 bit:      true
+";
+
+const LIST_HDDS: &str = "UUID:           6a1b2c3d-0000-4000-8000-000000000001
+Parent UUID:    base
+State:          created
+Type:           normal (base)
+Location:       /vms/data-1.vdi
+Storage format: VDI
+Format variant: dynamic default
+Capacity:       10240 MBytes
+Encryption:     disabled
+
+UUID:           6a1b2c3d-0000-4000-8000-000000000002
+Parent UUID:    base
+State:          inaccessible
+Type:           normal (base)
+Location:       /vms/data-2.vdi
+Storage format: VDI
+Format variant: dynamic default
+Capacity:       512 MBytes
+Encryption:     disabled
+";
+
+fn setup() -> (Arc<FakeExecutor>, VirtualBoxExtension) {
+    let fake = Arc::new(FakeExecutor::new());
+    let extension = VirtualBoxExtension::with_executor(fake.clone());
+    (fake, extension)
+}
+
+/// Decode the JSON error payload returned through `ActionResult`
+fn error_payload(err: String) -> Value {
+    serde_json::from_str(&err).unwrap_or_else(|_| panic!("error is not a JSON payload: {}", err))
+}
+
+fn params(value: Value) -> HashMap<String, Value> {
+    value
+        .as_object()
+        .expect("params must be a JSON object")
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+}
+
+#[test]
+fn every_listed_action_has_a_definition() {
+    let (_, extension) = setup();
+    for action in extension.list_actions() {
+        let definition = extension
+            .get_action_definition(&action)
+            .unwrap_or_else(|| panic!("no definition for '{}'", action));
+        assert_eq!(definition.name, action);
+    }
+}
+
+#[test]
+fn unknown_action_is_rejected() {
+    let (fake, extension) = setup();
+    let err = error_payload(extension.execute_action("explode", &HashMap::new()).unwrap_err());
+    assert_eq!(err["kind"], "unknown_action");
+    assert_eq!(err["message"], "Action 'explode' not found");
+    fake.verify();
+}
+
+#[test]
+fn missing_required_parameter_makes_no_calls() {
+    let (fake, extension) = setup();
+    let err = error_payload(extension.execute_action("stop_worker", &HashMap::new()).unwrap_err());
+    assert_eq!(err["kind"], "invalid_parameter");
+    assert_eq!(err["message"], "Required parameter 'worker_name' not provided");
+    fake.verify();
+}
+
+#[test]
+fn test_install_reports_version() {
+    let (fake, extension) = setup();
+    fake.expect(&["--version"], "7.0.14r161095\n");
+
+    let result = extension.execute_action("test_install", &HashMap::new()).unwrap();
+    assert_eq!(result["version"], "7.0.14r161095");
+    fake.verify();
+}
+
+#[test]
+fn test_install_fails_when_binary_missing() {
+    let (fake, extension) = setup();
+    fake.expect_missing_binary(&["--version"], "Failed to execute VBoxManage command 'VBoxManage': not found");
+
+    let err = error_payload(extension.execute_action("test_install", &HashMap::new()).unwrap_err());
+    assert_eq!(err["kind"], "binary_missing");
+    assert_eq!(err["message"], "Failed to execute VBoxManage command 'VBoxManage': not found");
+    fake.verify();
+}
+
+#[test]
+fn list_workers_reports_state_and_hardware() {
+    let (fake, extension) = setup();
+    fake.expect(&["list", "-l", "vms"], LIST_LONG_VMS).expect(&["list", "ostypes"], LIST_OSTYPES);
+
+    let result = extension.execute_action("list_workers", &HashMap::new()).unwrap();
+    let workers = result["workers"].as_array().unwrap();
+    assert_eq!(workers.len(), 2);
+    assert_eq!(workers[0]["name"], "web-1");
+    assert_eq!(workers[0]["id"], "2b4f6c1e-8d2a-4f7e-9c3b-1a2b3c4d5e6f");
+    assert_eq!(workers[0]["uuid"], workers[0]["id"]);
+    assert_eq!(workers[0]["state"], "running");
+    assert_eq!(workers[0]["os_type"], "Ubuntu_64");
+    assert_eq!(workers[0]["os_description"], "Ubuntu (64-bit)");
+    assert_eq!(workers[0]["memory_mb"], 2048);
+    assert_eq!(workers[0]["cpu_count"], 2);
+    assert_eq!(workers[0]["groups"], json!(["/omni/web"]));
+    assert_eq!(workers[1]["name"], "db \"primary\"");
+    assert_eq!(workers[1]["state"], "poweroff");
+    fake.verify();
+}
+
+#[test]
+fn list_workers_filters_by_state_group_name_and_tag() {
+    let (fake, extension) = setup();
+    fake.expect(&["list", "-l", "vms"], LIST_LONG_VMS)
+        .expect(&["list", "ostypes"], LIST_OSTYPES)
+        .expect(&["list", "-l", "vms"], LIST_LONG_VMS)
+        .expect(&["list", "ostypes"], LIST_OSTYPES)
+        .expect(&["list", "-l", "vms"], LIST_LONG_VMS)
+        .expect(&["getextradata", "2b4f6c1e-8d2a-4f7e-9c3b-1a2b3c4d5e6f", "omni/role"], "Value: worker\n")
+        .expect(&["getextradata", "9f8e7d6c-0000-4000-8000-000000000000", "omni/role"], "No value set!\n")
+        .expect(&["list", "ostypes"], LIST_OSTYPES);
+
+    let names = |result: Value| -> Vec<String> {
+        result["workers"].as_array().unwrap().iter().map(|w| w["name"].as_str().unwrap().to_string()).collect()
+    };
+    let running = extension
+        .execute_action("list_workers", &params(json!({ "state": "running,paused", "group": "/omni" })))
+        .unwrap();
+    assert_eq!(names(running), vec!["web-1"]);
+    let by_name = extension
+        .execute_action("list_workers", &params(json!({ "name_pattern": "db*" })))
+        .unwrap();
+    assert_eq!(names(by_name), vec!["db \"primary\""]);
+    let tagged = extension
+        .execute_action("list_workers", &params(json!({ "tag": "omni/role=worker" })))
+        .unwrap();
+    assert_eq!(names(tagged), vec!["web-1"]);
+    let err = error_payload(
+        extension
+            .execute_action("list_workers", &params(json!({ "state": "running,stopped" })))
+            .unwrap_err(),
+    );
+    assert_eq!(err["kind"], "invalid_parameter");
+    fake.verify();
+}
+
+#[test]
+fn create_worker_uses_defaults() {
+    let (fake, extension) = setup();
+    fake.expect(
+        &["createvm", "--name", "web-1", "--ostype", "Ubuntu_64", "--register"],
+        "Virtual machine 'web-1' is created and registered.\nUUID: 2b4f6c1e-8d2a-4f7e-9c3b-1a2b3c4d5e6f\nSettings file: '/vms/web-1/web-1.vbox'\n",
+    )
+    .expect(&["modifyvm", "web-1", "--memory", "2048", "--cpus", "2"], "")
+    .expect(&["modifyvm", "web-1", "--nic1", "nat"], "");
+
+    let result = extension
+        .execute_action("create_worker", &params(json!({ "worker_name": "web-1" })))
+        .unwrap();
+    assert_eq!(result["uuid"], "2b4f6c1e-8d2a-4f7e-9c3b-1a2b3c4d5e6f");
+    assert_eq!(result["name"], "web-1");
+    fake.verify();
+}
+
+#[test]
+fn create_worker_passes_explicit_hardware() {
+    let (fake, extension) = setup();
+    fake.expect(
+        &["createvm", "--name", "db", "--ostype", "Debian_64", "--register"],
+        "UUID: 11111111-2222-3333-4444-555555555555\n",
+    )
+    .expect(&["modifyvm", "db", "--memory", "4096", "--cpus", "4"], "")
+    .expect(&["modifyvm", "db", "--nic1", "nat"], "");
+
+    extension
+        .execute_action(
+            "create_worker",
+            &params(json!({ "worker_name": "db", "os_type": "Debian_64", "memory_mb": 4096, "cpu_count": 4 })),
+        )
+        .unwrap();
+    fake.verify();
+}
+
+#[test]
+fn create_worker_stops_on_failure() {
+    let (fake, extension) = setup();
+    fake.expect_failure(
+        &["createvm", "--name", "web-1", "--ostype", "Ubuntu_64", "--register"],
+        "VBoxManage: error: Machine settings file '/vms/web-1/web-1.vbox' already exists\n",
+    );
+
+    let err = extension
+        .execute_action("create_worker", &params(json!({ "worker_name": "web-1" })))
+        .unwrap_err();
+    assert!(err.contains("already exists"));
+    fake.verify();
+}
+
+#[test]
+fn create_worker_rolls_back_when_a_step_fails() {
+    let (fake, extension) = setup();
+    fake.expect(
+        &["createvm", "--name", "web-1", "--ostype", "Ubuntu_64", "--register"],
+        "Virtual machine 'web-1' is created and registered.\nUUID: 2b4f6c1e-8d2a-4f7e-9c3b-1a2b3c4d5e6f\nSettings file: '/vms/web-1/web-1.vbox'\n",
+    )
+    .expect(&["modifyvm", "web-1", "--memory", "2048", "--cpus", "2"], "")
+    .expect_failure(
+        &["modifyvm", "web-1", "--nic1", "nat"],
+        "VBoxManage: error: Invalid NIC type\nVBoxManage: error: Details: code E_INVALIDARG (0x80070057), component NetworkAdapterWrap, interface INetworkAdapter, callee nsISupports\n",
+    )
+    .expect(&["unregistervm", "2b4f6c1e-8d2a-4f7e-9c3b-1a2b3c4d5e6f", "--delete"], "");
+
+    let err = error_payload(
+        extension
+            .execute_action("create_worker", &params(json!({ "worker_name": "web-1" })))
+            .unwrap_err(),
+    );
+    assert_eq!(err["kind"], "invalid_argument");
+    assert_eq!(err["step"], "configure network");
+    assert_eq!(err["rolled_back"], true);
+    assert_eq!(err["code"], "E_INVALIDARG");
+    assert!(err["message"].as_str().unwrap().contains("the VM was removed"));
+    fake.verify();
+}
+
+#[test]
+fn create_worker_applies_full_hardware_spec() {
+    let (fake, extension) = setup();
+    fake.expect(
+        &["createvm", "--name", "web-1", "--ostype", "Ubuntu_64", "--register", "--groups", "/web,/prod", "--basefolder", "/vms"],
+        "Virtual machine 'web-1' is created and registered.\nUUID: 2b4f6c1e-8d2a-4f7e-9c3b-1a2b3c4d5e6f\nSettings file: '/vms/web-1/web-1.vbox'\n",
+    )
+    .expect(
+        &[
+            "modifyvm", "web-1", "--memory", "4096", "--cpus", "4", "--firmware", "efi", "--chipset", "ich9", "--vram", "16",
+            "--graphicscontroller", "vmsvga", "--nested-hw-virt", "on", "--pae", "off", "--paravirt-provider", "kvm",
+            "--boot1", "disk", "--boot2", "net", "--boot3", "none", "--boot4", "none", "--description", "Web frontend",
+            "--rtc-use-utc", "on", "--audio-enabled", "off",
+        ],
+        "",
+    )
+    .expect(&["storagectl", "web-1", "--name", "SATA Controller", "--add", "sata", "--controller", "IntelAhci", "--portcount", "30"], "")
+    .expect(
+        &["createmedium", "disk", "--filename", "/vms/web-1/web-1-disk1.vdi", "--size", "20480", "--format", "VDI"],
+        "Medium created. UUID: 0c4d5e6f-1a2b-4c3d-8e9f-7a6b5c4d3e2f\n",
+    )
+    .expect(
+        &["storageattach", "web-1", "--storagectl", "SATA Controller", "--port", "0", "--device", "0", "--type", "hdd", "--medium", "/vms/web-1/web-1-disk1.vdi"],
+        "",
+    )
+    .expect(
+        &["createmedium", "disk", "--filename", "/data/web-1-data.vdi", "--size", "1024", "--format", "VDI"],
+        "Medium created. UUID: 1d5e6f70-2b3c-4d5e-9f80-8b7c6d5e4f30\n",
+    )
+    .expect(
+        &["storageattach", "web-1", "--storagectl", "SATA Controller", "--port", "1", "--device", "0", "--type", "hdd", "--medium", "/data/web-1-data.vdi"],
+        "",
+    )
+    .expect(&["modifyvm", "web-1", "--nic1", "bridged", "--nic2", "hostonly"], "");
+
+    let result = extension
+        .execute_action(
+            "create_worker",
+            &params(json!({
+                "worker_name": "web-1",
+                "memory_mb": 4096,
+                "cpu_count": 4,
+                "firmware": "EFI",
+                "chipset": "ich9",
+                "vram_mb": 16,
+                "graphics_controller": "vmsvga",
+                "nested_virtualization": true,
+                "pae": false,
+                "paravirt_provider": "kvm",
+                "boot_order": "disk,net",
+                "description": "Web frontend",
+                "groups": "/web,/prod",
+                "base_folder": "/vms",
+                "rtc_utc": true,
+                "audio": false,
+                "disks": [{ "size_mb": 20480 }, { "size_mb": 1024, "path": "/data/web-1-data.vdi" }],
+                "nics": [{ "index": 1, "type": "bridged" }, { "index": 2, "type": "hostonly" }],
+            })),
+        )
+        .unwrap();
+    assert_eq!(result["uuid"], "2b4f6c1e-8d2a-4f7e-9c3b-1a2b3c4d5e6f");
+    fake.verify();
+}
+
+#[test]
+fn create_worker_rejects_invalid_spec_before_creating() {
+    let (fake, extension) = setup();
+
+    for spec in [
+        json!({ "worker_name": "web-1", "firmware": "uefi" }),
+        json!({ "worker_name": "web-1", "boot_order": "disk,dvd,net,floppy,disk" }),
+        json!({ "worker_name": "web-1", "groups": "web" }),
+        json!({ "worker_name": "web-1", "disks": [{ "size_mb": 0 }] }),
+        json!({ "worker_name": "web-1", "nics": [{ "index": 1, "type": "nat" }, { "index": 1, "type": "bridged" }] }),
+        json!({ "worker_name": "web-1", "nics": [{ "index": 1, "type": "wifi" }] }),
+    ] {
+        let err = error_payload(extension.execute_action("create_worker", &params(spec)).unwrap_err());
+        assert_eq!(err["kind"], "invalid_parameter");
+    }
+    fake.verify();
+}
+
+#[test]
+fn default_settings_drive_and_advertise_parameter_defaults() {
+    let (fake, extension) = setup();
+    let extension = extension.with_default_settings(DefaultSettings {
+        os_type: "Debian_64".to_string(),
+        memory_mb: 4096,
+        cpu_count: 4,
+        network_type: NicType::Bridged,
+        ..DefaultSettings::default()
+    });
+    fake.expect(
+        &["createvm", "--name", "web-1", "--ostype", "Debian_64", "--register"],
+        "Virtual machine 'web-1' is created and registered.\nUUID: 2b4f6c1e-8d2a-4f7e-9c3b-1a2b3c4d5e6f\nSettings file: '/vms/web-1/web-1.vbox'\n",
+    )
+    .expect(&["modifyvm", "web-1", "--memory", "4096", "--cpus", "4"], "")
+    .expect(&["modifyvm", "web-1", "--nic1", "bridged"], "")
+    .expect(&["modifyvm", "web-1", "--nic2", "bridged"], "");
+
+    extension
+        .execute_action("create_worker", &params(json!({ "worker_name": "web-1" })))
+        .unwrap();
+    extension
+        .execute_action("configure_networks", &params(json!({ "worker_name": "web-1", "network_index": 2 })))
+        .unwrap();
+
+    let default_of = |action: &str, name: &str| {
+        let definition = extension.get_action_definition(action).unwrap();
+        definition.parameters.into_iter().find(|p| p.name == name).unwrap().default_value
+    };
+    assert_eq!(default_of("create_worker", "os_type"), Some(json!("Debian_64")));
+    assert_eq!(default_of("create_worker", "memory_mb"), Some(json!(4096)));
+    assert_eq!(default_of("configure_networks", "network_type"), Some(json!("bridged")));
+    assert_eq!(default_of("attach_volume", "controller_name"), Some(json!("SATA Controller")));
+    fake.verify();
+}
+
+#[test]
+fn clone_worker_snapshots_source_for_linked_clone_and_finds_new_uuid() {
+    let (fake, extension) = setup();
+    fake.expect_failure(
+        &["snapshot", "template", "list", "--machinereadable"],
+        "VBoxManage: error: This machine does not have any snapshots\n",
+    )
+    .expect(
+        &["snapshot", "template", "take", "linked-clone-base"],
+        "Snapshot taken. UUID: 7d1e2f30-4a5b-4c6d-8e7f-901a2b3c4d5e\n",
+    )
+    .expect(&["list", "vms"], "\"template\" {1a2b3c4d-0000-4000-8000-000000000001}\n\"web-1\" {1a2b3c4d-0000-4000-8000-000000000002}\n")
+    .expect(
+        &[
+            "clonevm", "template", "--name", "web-1", "--mode", "machine", "--register", "--snapshot", "linked-clone-base",
+            "--options", "Link,KeepNATMACs", "--groups", "/web",
+        ],
+        "0%...10%...20%...30%...40%...50%...60%...70%...80%...90%...100%\nMachine has been successfully cloned as \"web-1\"\n",
+    )
+    .expect(
+        &["list", "vms"],
+        "\"template\" {1a2b3c4d-0000-4000-8000-000000000001}\n\"web-1\" {1a2b3c4d-0000-4000-8000-000000000002}\n\"web-1\" {1a2b3c4d-0000-4000-8000-000000000003}\n",
+    );
+
+    let result = extension
+        .execute_action(
+            "clone_worker",
+            &params(json!({ "worker_name": "template", "new_name": "web-1", "linked": true, "mac_policy": "keep_nat", "groups": "/web" })),
+        )
+        .unwrap();
+    // The pre-existing VM of the same name is not mistaken for the clone
+    assert_eq!(result["uuid"], "1a2b3c4d-0000-4000-8000-000000000003");
+    assert_eq!(result["linked"], true);
+    fake.verify();
+
+    let err = error_payload(
+        extension
+            .execute_action("clone_worker", &params(json!({ "worker_name": "template", "new_name": "web-2", "mac_policy": "random" })))
+            .unwrap_err(),
+    );
+    assert_eq!(err["kind"], "invalid_parameter");
+}
+
+#[test]
+fn failed_linked_clone_deletes_the_snapshot_it_took() {
+    let (fake, extension) = setup();
+    let vms = "\"template\" {1a2b3c4d-0000-4000-8000-000000000001}\n";
+    fake.expect_failure(
+        &["snapshot", "template", "list", "--machinereadable"],
+        "VBoxManage: error: This machine does not have any snapshots\n",
+    )
+    .expect(
+        &["snapshot", "template", "take", "linked-clone-base"],
+        "Snapshot taken. UUID: 7d1e2f30-4a5b-4c6d-8e7f-901a2b3c4d5e\n",
+    )
+    .expect(&["list", "vms"], vms)
+    .expect_failure(
+        &["clonevm", "template", "--name", "web-1", "--mode", "machine", "--register", "--snapshot", "linked-clone-base", "--options", "Link"],
+        "VBoxManage: error: Could not create the clone medium\n",
+    )
+    .expect(&["snapshot", "template", "delete", "7d1e2f30-4a5b-4c6d-8e7f-901a2b3c4d5e"], "")
+    // A snapshot that was already there is left alone
+    .expect(
+        &["snapshot", "template", "list", "--machinereadable"],
+        "SnapshotName=\"linked-clone-base\"\nSnapshotUUID=\"7d1e2f30-4a5b-4c6d-8e7f-901a2b3c4d5e\"\n",
+    )
+    .expect(&["list", "vms"], vms)
+    .expect_failure(
+        &["clonevm", "template", "--name", "web-1", "--mode", "machine", "--register", "--snapshot", "linked-clone-base", "--options", "Link"],
+        "VBoxManage: error: Could not create the clone medium\n",
+    );
+
+    let clone = params(json!({ "worker_name": "template", "new_name": "web-1", "linked": true }));
+    assert!(extension.execute_action("clone_worker", &clone).is_err());
+    assert!(extension.execute_action("clone_worker", &clone).is_err());
+    fake.verify();
+}
+
+#[test]
+fn delete_worker_unregisters_and_deletes() {
+    let (fake, extension) = setup();
+    fake.expect(&["unregistervm", "web-1", "--delete"], "");
+
+    let result = extension
+        .execute_action("delete_worker", &params(json!({ "worker_name": "web-1" })))
+        .unwrap();
+    assert_eq!(result["success"], true);
+    fake.verify();
+}
+
+#[test]
+fn get_worker_parses_machinereadable_output() {
+    let (fake, extension) = setup();
+    fake.expect(&["showvminfo", "web-1", "--machinereadable"], SHOWVMINFO)
+        .expect(&["list", "ostypes"], LIST_OSTYPES);
+
+    let result = extension
+        .execute_action("get_worker", &params(json!({ "worker_name": "web-1" })))
+        .unwrap();
+    let vm = &result["vm"];
+    assert_eq!(vm["name"], "web-1");
+    assert_eq!(vm["id"], "2b4f6c1e-8d2a-4f7e-9c3b-1a2b3c4d5e6f");
+    assert_eq!(vm["state"], "poweroff");
+    assert_eq!(vm["memory_mb"], 2048);
+    assert_eq!(vm["cpu_count"], 2);
+    assert_eq!(vm["os_type"], "Ubuntu_64");
+    assert_eq!(vm["os_description"], "Ubuntu (64-bit)");
+    assert_eq!(vm["firmware"], "BIOS");
+    assert_eq!(vm["graphics_controller"], "vmsvga");
+    fake.verify();
+}
+
+#[test]
+fn has_worker_reflects_showvminfo_status() {
+    let (fake, extension) = setup();
+    fake.expect(&["showvminfo", "web-1", "--machinereadable"], SHOWVMINFO)
+        .expect_failure(
+            &["showvminfo", "ghost", "--machinereadable"],
+            "VBoxManage: error: Could not find a registered machine named 'ghost'\n",
+        );
+
+    let found = extension
+        .execute_action("has_worker", &params(json!({ "worker_name": "web-1" })))
+        .unwrap();
+    let missing = extension
+        .execute_action("has_worker", &params(json!({ "worker_name": "ghost" })))
+        .unwrap();
+    assert_eq!(found["exists"], true);
+    assert_eq!(missing["exists"], false);
+    fake.verify();
+}
+
+#[test]
+fn start_worker_starts_headless() {
+    let (fake, extension) = setup();
+    fake.expect(
+        &["startvm", "web-1", "--type", "headless"],
+        "Waiting for VM \"web-1\" to power on...\nVM \"web-1\" has been successfully started.\n",
+    );
+
+    let result = extension
+        .execute_action("start_worker", &params(json!({ "worker_name": "web-1" })))
+        .unwrap();
+    assert_eq!(result["started"], "web-1");
+    fake.verify();
+}
+
+#[test]
+fn get_volumes_parses_each_block() {
+    let (fake, extension) = setup();
+    fake.expect(&["list", "hdds"], LIST_HDDS);
+
+    let result = extension.execute_action("get_volumes", &HashMap::new()).unwrap();
+    let volumes = result["volumes"].as_array().unwrap();
+    assert_eq!(volumes.len(), 2);
+    assert_eq!(volumes[0]["id"], "6a1b2c3d-0000-4000-8000-000000000001");
+    assert_eq!(volumes[0]["path"], "/vms/data-1.vdi");
+    assert_eq!(volumes[0]["size_mb"], 10240);
+    assert_eq!(volumes[0]["state"], "created");
+    assert_eq!(volumes[0]["type"], "normal (base)");
+    assert_eq!(volumes[1]["state"], "inaccessible");
+    assert_eq!(volumes[1]["size_mb"], 512);
+    fake.verify();
+}
+
+#[test]
+fn has_volume_reflects_showmediuminfo_status() {
+    let (fake, extension) = setup();
+    fake.expect(&["showmediuminfo", "disk", "/vms/data-1.vdi"], "UUID: 6a1b2c3d\n")
+        .expect_failure(
+            &["showmediuminfo", "disk", "/vms/missing.vdi"],
+            "VBoxManage: error: Could not find file for the medium '/vms/missing.vdi'\n",
+        );
+
+    let found = extension
+        .execute_action("has_volume", &params(json!({ "disk_path": "/vms/data-1.vdi" })))
+        .unwrap();
+    let missing = extension
+        .execute_action("has_volume", &params(json!({ "disk_path": "/vms/missing.vdi" })))
+        .unwrap();
+    assert_eq!(found["exists"], true);
+    assert_eq!(missing["exists"], false);
+    fake.verify();
+}
+
+#[test]
+fn create_volume_returns_uuid() {
+    let (fake, extension) = setup();
+    fake.expect(
+        &["createmedium", "disk", "--filename", "/vms/data-1.vdi", "--size", "10240", "--format", "VDI"],
+        "0%...10%...20%...30%...40%...50%...60%...70%...80%...90%...100%\nMedium created. UUID: 6a1b2c3d-0000-4000-8000-000000000001\n",
+    );
+
+    let result = extension
+        .execute_action("create_volume", &params(json!({ "disk_path": "/vms/data-1.vdi", "size_mb": 10240 })))
+        .unwrap();
+    assert_eq!(result["uuid"], "6a1b2c3d-0000-4000-8000-000000000001");
+    fake.verify();
+}
+
+#[test]
+fn delete_volume_closes_and_deletes_medium() {
+    let (fake, extension) = setup();
+    fake.expect(&["closemedium", "disk", "/vms/data-1.vdi", "--delete"], "");
+
+    extension
+        .execute_action("delete_volume", &params(json!({ "disk_path": "/vms/data-1.vdi" })))
+        .unwrap();
+    fake.verify();
+}
+
+#[test]
+fn attach_volume_creates_missing_controller_only_when_asked() {
+    let (fake, extension) = setup();
+    fake.expect(
+        &["storageattach", "web-1", "--storagectl", "SATA Controller", "--port", "1", "--device", "0", "--type", "dvddrive", "--medium", "/isos/seed.iso"],
+        "",
+    )
+    .expect(SHOWVMINFO_ARGS, SHOWVMINFO)
+    .expect(&["storagectl", "web-1", "--name", "NVMe", "--add", "pcie", "--controller", "NVMe"], "")
+    .expect(
+        &["storageattach", "web-1", "--storagectl", "NVMe", "--port", "0", "--device", "0", "--type", "hdd", "--medium", "/vms/data-1.vdi"],
+        "",
+    );
+
+    extension
+        .execute_action("attach_volume", &params(json!({ "worker_name": "web-1", "port": 1, "disk_path": "/isos/seed.iso" })))
+        .unwrap();
+    extension
+        .execute_action(
+            "attach_volume",
+            &params(json!({
+                "worker_name": "web-1",
+                "controller_name": "NVMe",
+                "create_controller": true,
+                "controller_bus": "nvme",
+                "port": 0,
+                "disk_path": "/vms/data-1.vdi"
+            })),
+        )
+        .unwrap();
+    fake.verify();
+}
+
+#[test]
+fn add_storage_controller_passes_bus_options() {
+    let (fake, extension) = setup();
+    fake.expect(
+        &[
+            "storagectl", "web-1", "--name", "IDE", "--add", "ide", "--controller", "ICH6", "--portcount", "2", "--hostiocache", "on", "--bootable", "off",
+        ],
+        "",
+    )
+    .expect(&["storagectl", "web-1", "--name", "IDE", "--remove"], "");
+
+    let result = extension
+        .execute_action(
+            "add_storage_controller",
+            &params(json!({
+                "worker_name": "web-1",
+                "controller_name": "IDE",
+                "bus": "ide",
+                "chipset": "ICH6",
+                "port_count": 2,
+                "host_io_cache": true,
+                "bootable": false
+            })),
+        )
+        .unwrap();
+    assert_eq!(result["bus"], "ide");
+    assert_eq!(result["chipset"], "ICH6");
+
+    // A chipset from another bus or too many ports is refused without a call
+    let err = error_payload(
+        extension
+            .execute_action("add_storage_controller", &params(json!({ "worker_name": "web-1", "bus": "scsi", "chipset": "IntelAhci" })))
+            .unwrap_err(),
+    );
+    assert_eq!(err["kind"], "invalid_parameter");
+    let err = error_payload(
+        extension
+            .execute_action("add_storage_controller", &params(json!({ "worker_name": "web-1", "bus": "ide", "port_count": 4 })))
+            .unwrap_err(),
+    );
+    assert_eq!(err["message"], "Port count must be between 1 and 2 on ide, not 4");
+
+    extension
+        .execute_action("remove_storage_controller", &params(json!({ "worker_name": "web-1", "controller_name": "IDE" })))
+        .unwrap();
+    fake.verify();
+}
+
+#[test]
+fn attach_volume_attaches_disk_images_as_hard_disks() {
+    let (fake, extension) = setup();
+    fake.expect(
+        &[
+            "storageattach", "web-1", "--storagectl", "SATA Controller", "--port", "2", "--device", "0", "--type", "hdd", "--medium", "/vms/data-1.vdi",
+            "--nonrotational", "on", "--discard", "on", "--hotpluggable", "off", "--mtype", "writethrough",
+        ],
+        "",
+    );
+
+    let result = extension
+        .execute_action(
+            "attach_volume",
+            &params(json!({
+                "worker_name": "web-1",
+                "port": 2,
+                "disk_path": "/vms/data-1.vdi",
+                "nonrotational": true,
+                "discard": true,
+                "hotpluggable": false,
+                "mtype": "writethrough"
+            })),
+        )
+        .unwrap();
+    assert_eq!(result["device_type"], "hdd");
+
+    // Mismatches are refused before anything is attached
+    let err = error_payload(
+        extension
+            .execute_action("attach_volume", &params(json!({ "worker_name": "web-1", "port": 1, "disk_path": "/isos/seed.iso", "device_type": "hdd" })))
+            .unwrap_err(),
+    );
+    assert_eq!(err["kind"], "invalid_parameter");
+    assert_eq!(err["message"], "'/isos/seed.iso' is a dvddrive image and cannot be attached as hdd");
+    let err = error_payload(
+        extension
+            .execute_action("attach_volume", &params(json!({ "worker_name": "web-1", "port": 1, "disk_path": "/isos/seed.iso", "discard": true })))
+            .unwrap_err(),
+    );
+    assert_eq!(err["kind"], "invalid_parameter");
+    fake.verify();
+}
+
+#[test]
+fn detach_volume_empties_port() {
+    let (fake, extension) = setup();
+    fake.expect(&["storageattach", "web-1", "--storagectl", "NVMe", "--port", "2", "--device", "0", "--medium", "none"], "");
+
+    extension
+        .execute_action("detach_volume", &params(json!({ "worker_name": "web-1", "controller_name": "NVMe", "port": 2 })))
+        .unwrap();
+    fake.verify();
+}
+
+#[test]
+fn create_snapshot_returns_uuid() {
+    let (fake, extension) = setup();
+    fake.expect(
+        &["snapshot", "web-1", "take", "before-upgrade"],
+        "0%...10%...20%...30%...40%...50%...60%...70%...80%...90%...100%\nSnapshot taken. UUID: 0c0ffee0-0000-4000-8000-000000000000\n",
+    );
+
+    let result = extension
+        .execute_action("create_snapshot", &params(json!({ "worker_name": "web-1", "snapshot_name": "before-upgrade" })))
+        .unwrap();
+    assert_eq!(result["success"], true);
+    fake.verify();
+}
+
+#[test]
+fn delete_snapshot_deletes_by_name() {
+    let (fake, extension) = setup();
+    fake.expect(&["snapshot", "web-1", "delete", "before-upgrade"], "");
+
+    extension
+        .execute_action("delete_snapshot", &params(json!({ "worker_name": "web-1", "snapshot_name": "before-upgrade" })))
+        .unwrap();
+    fake.verify();
+}
+
+#[test]
+fn has_snapshot_searches_snapshot_list() {
+    let (fake, extension) = setup();
+    let listing = "SnapshotName=\"before-upgrade\"\nSnapshotUUID=\"0c0ffee0-0000-4000-8000-000000000000\"\nCurrentSnapshotName=\"before-upgrade\"\n";
+    fake.expect(&["snapshot", "web-1", "list", "--machinereadable"], listing)
+        .expect(&["snapshot", "web-1", "list", "--machinereadable"], listing);
+
+    let found = extension
+        .execute_action("has_snapshot", &params(json!({ "worker_name": "web-1", "snapshot_name": "before-upgrade" })))
+        .unwrap();
+    let missing = extension
+        .execute_action("has_snapshot", &params(json!({ "worker_name": "web-1", "snapshot_name": "nightly" })))
+        .unwrap();
+    assert_eq!(found["exists"], true);
+    assert_eq!(missing["exists"], false);
+    fake.verify();
+}
+
+fn vm_in_state(state: &str) -> String {
+    SHOWVMINFO.replace("VMState=\"poweroff\"", &format!("VMState=\"{}\"", state))
+}
+
+const SHOWVMINFO_ARGS: &[&str] = &["showvminfo", "web-1", "--machinereadable"];
+
+#[test]
+fn stop_worker_uses_acpi_when_guest_complies() {
+    let (fake, extension) = setup();
+    let extension = extension.with_poll_interval(Duration::from_millis(1));
+    fake.expect(SHOWVMINFO_ARGS, &vm_in_state("running"))
+        .expect(&["controlvm", "web-1", "acpipowerbutton"], "")
+        .expect(SHOWVMINFO_ARGS, &vm_in_state("running"))
+        .expect(SHOWVMINFO_ARGS, &vm_in_state("poweroff"));
+
+    let result = extension
+        .execute_action("stop_worker", &params(json!({ "worker_name": "web-1" })))
+        .unwrap();
+    assert_eq!(result["method"], "acpi");
+    fake.verify();
+}
+
+#[test]
+fn stop_worker_powers_off_after_grace() {
+    let (fake, extension) = setup();
+    // One poll right after the button press, one when the grace period ends
+    let extension = extension.with_poll_interval(Duration::from_secs(5));
+    fake.expect(SHOWVMINFO_ARGS, &vm_in_state("running"))
+        .expect(&["controlvm", "web-1", "acpipowerbutton"], "")
+        .expect(SHOWVMINFO_ARGS, &vm_in_state("running"))
+        .expect(SHOWVMINFO_ARGS, &vm_in_state("running"))
+        .expect(&["controlvm", "web-1", "poweroff"], "");
+
+    let result = extension
+        .execute_action("stop_worker", &params(json!({ "worker_name": "web-1", "grace_secs": 1 })))
+        .unwrap();
+    assert_eq!(result["method"], "power_off");
+    assert!(result["waited_secs"].as_f64().unwrap() >= 1.0);
+    fake.verify();
+}
+
+#[test]
+fn stop_worker_skips_acpi_when_paused_or_stopped() {
+    let (fake, extension) = setup();
+    fake.expect(SHOWVMINFO_ARGS, &vm_in_state("paused"))
+        .expect(&["controlvm", "web-1", "poweroff"], "")
+        .expect(SHOWVMINFO_ARGS, &vm_in_state("saved"));
+
+    let paused = extension
+        .execute_action("stop_worker", &params(json!({ "worker_name": "web-1" })))
+        .unwrap();
+    assert_eq!(paused["method"], "power_off");
+    let saved = extension
+        .execute_action("stop_worker", &params(json!({ "worker_name": "web-1" })))
+        .unwrap();
+    assert_eq!(saved["method"], "already_stopped");
+    fake.verify();
+}
+
+#[test]
+fn pause_resume_and_saved_state_actions() {
+    let (fake, extension) = setup();
+    fake.expect(&["controlvm", "web-1", "pause"], "")
+        .expect(&["controlvm", "web-1", "resume"], "")
+        .expect(&["controlvm", "web-1", "savestate"], "")
+        .expect(&["discardstate", "web-1"], "");
+
+    for action in ["pause_worker", "resume_worker", "save_worker_state", "discard_saved_state"] {
+        extension
+            .execute_action(action, &params(json!({ "worker_name": "web-1" })))
+            .unwrap();
+        assert!(extension.get_action_definition(action).is_some());
+    }
+    fake.verify();
+}
+
+#[test]
+fn wait_for_worker_state_records_transitions() {
+    let (fake, extension) = setup();
+    let extension = extension.with_poll_interval(Duration::from_millis(1));
+    let booted = |level: u32| format!("{}GuestAdditionsRunLevel={}\n", vm_in_state("running"), level);
+    fake.expect(SHOWVMINFO_ARGS, &vm_in_state("starting"))
+        .expect(SHOWVMINFO_ARGS, &vm_in_state("starting"))
+        .expect(SHOWVMINFO_ARGS, &booted(1))
+        .expect(SHOWVMINFO_ARGS, &booted(2));
+
+    let result = extension
+        .execute_action(
+            "wait_for_worker_state",
+            &params(json!({ "worker_name": "web-1", "state": "running", "guest_run_level": 2 })),
+        )
+        .unwrap();
+    assert_eq!(result["state"], "running");
+    let history: Vec<(Value, Value)> = result["history"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|t| (t["state"].clone(), t["run_level"].clone()))
+        .collect();
+    assert_eq!(
+        history,
+        vec![
+            (json!("starting"), Value::Null),
+            (json!("running"), json!(1)),
+            (json!("running"), json!(2)),
+        ]
+    );
+    fake.verify();
+}
+
+#[test]
+fn wait_for_unknown_state_is_rejected() {
+    let (fake, extension) = setup();
+    let err = error_payload(
+        extension
+            .execute_action("wait_for_worker_state", &params(json!({ "worker_name": "web-1", "state": "powered_off" })))
+            .unwrap_err(),
+    );
+    assert_eq!(err["kind"], "invalid_parameter");
+    assert!(err["message"].as_str().unwrap().contains("Unknown VM state 'powered_off'"), "{}", err);
+    fake.verify();
+}
+
+#[test]
+fn wait_timeout_reports_history() {
+    let (fake, extension) = setup();
+    let extension = extension.with_poll_interval(Duration::from_secs(5));
+    fake.expect(SHOWVMINFO_ARGS, &vm_in_state("stopping"))
+        .expect(SHOWVMINFO_ARGS, &vm_in_state("stopping"));
+
+    let err = error_payload(
+        extension
+            .execute_action(
+                "wait_for_worker_state",
+                &params(json!({ "worker_name": "web-1", "state": "poweroff", "wait_timeout_secs": 1 })),
+            )
+            .unwrap_err(),
+    );
+    assert_eq!(err["kind"], "wait_timeout");
+    assert_eq!(err["target"]["state"], "poweroff");
+    assert_eq!(err["history"][0]["state"], "stopping");
+    assert!(err["message"].as_str().unwrap().contains("last state: stopping"));
+    fake.verify();
+}
+
+#[test]
+fn lifecycle_actions_wait_when_asked() {
+    let (fake, extension) = setup();
+    let extension = extension.with_poll_interval(Duration::from_millis(1));
+    fake.expect(&["startvm", "web-1", "--type", "headless"], "")
+        .expect(SHOWVMINFO_ARGS, &vm_in_state("starting"))
+        .expect(SHOWVMINFO_ARGS, &vm_in_state("running"))
+        .expect(&["controlvm", "web-1", "pause"], "");
+
+    let started = extension
+        .execute_action("start_worker", &params(json!({ "worker_name": "web-1", "wait": true })))
+        .unwrap();
+    assert_eq!(started["state"], "running");
+    assert_eq!(started["history"].as_array().unwrap().len(), 2);
+    let paused = extension
+        .execute_action("pause_worker", &params(json!({ "worker_name": "web-1", "wait": false })))
+        .unwrap();
+    assert!(paused.get("history").is_none());
+
+    let definition = extension.get_action_definition("start_worker").unwrap();
+    assert!(definition.parameters.iter().any(|p| p.name == "guest_run_level"));
+    fake.verify();
+}
+
+#[test]
+fn force_delete_powers_off_waits_for_unlock_and_keeps_disks() {
+    let (fake, extension) = setup();
+    let extension = extension.with_poll_interval(Duration::from_millis(1));
+    let home = tempfile::tempdir().unwrap();
+    let path = |file: &str| home.path().join(file).to_str().unwrap().to_string();
+    std::fs::create_dir_all(home.path().join("web-1")).unwrap();
+    for file in ["web-1/web-1.vbox", "web-1/web-1.nvram", "web-1/os.vdi", "data-1.vdi"] {
+        std::fs::write(path(file), b"").unwrap();
+    }
+    let storage = format!(
+        "storagecontrollername0=\"SATA\"\n\"SATA-0-0\"=\"{}\"\n\"SATA-ImageUUID-0-0\"=\"6a1b2c3d-0000-4000-8000-000000000009\"\n\"SATA-1-0\"=\"{}\"\n\"SATA-ImageUUID-1-0\"=\"6a1b2c3d-0000-4000-8000-000000000001\"\n\"SATA-2-0\"=\"/isos/seed.iso\"\n",
+        path("web-1/os.vdi"),
+        path("data-1.vdi")
+    );
+    let in_home = |info: String| info.replace("/home/vbox/VirtualBox VMs/web-1/web-1.vbox", &path("web-1/web-1.vbox"));
+    let running = in_home(format!("{}{}", vm_in_state("running"), storage));
+    let unlocking = format!("{}SessionName=\"headless\"\n", vm_in_state("poweroff"));
+    let hdds = format!("{}\nUUID:           6a1b2c3d-0000-4000-8000-000000000009\nLocation:       {}\n", LIST_HDDS, path("web-1/os.vdi"));
+    let (config, disk) = (path("web-1/web-1.vbox"), path("web-1/os.vdi"));
+    fake.expect(SHOWVMINFO_ARGS, &running)
+        .expect(&["controlvm", "web-1", "poweroff"], "")
+        .expect(SHOWVMINFO_ARGS, &unlocking)
+        .expect(SHOWVMINFO_ARGS, &vm_in_state("poweroff"))
+        .expect(&["storageattach", "web-1", "--storagectl", "SATA", "--port", "1", "--device", "0", "--medium", "none"], "")
+        .expect(&["list", "hdds"], &hdds)
+        // Deletes the settings file and disk but, as if it failed to, not the NVRAM file
+        .expect_matching(
+            move |args| {
+                std::fs::remove_file(&config).unwrap();
+                std::fs::remove_file(&disk).unwrap();
+                args == ["unregistervm", "web-1", "--delete"]
+            },
+            "",
+        );
+
+    let result = extension
+        .execute_action("delete_worker", &params(json!({ "worker_name": "web-1", "force": true, "keep_disks": "data-1.vdi" })))
+        .unwrap();
+    assert_eq!(result["powered_off"], true);
+    assert_eq!(result["kept_media"], json!([path("data-1.vdi")]));
+    assert_eq!(result["deleted_files"], json!([path("web-1/web-1.vbox"), path("web-1/os.vdi")]));
+    fake.verify();
+}
+
+#[test]
+fn delete_rejects_keeping_unattached_disk() {
+    let (fake, extension) = setup();
+    fake.expect(SHOWVMINFO_ARGS, SHOWVMINFO);
+
+    let err = error_payload(
+        extension
+            .execute_action("delete_worker", &params(json!({ "worker_name": "web-1", "keep_disks": "/vms/other.vdi" })))
+            .unwrap_err(),
+    );
+    assert_eq!(err["kind"], "invalid_parameter");
+    fake.verify();
+}
+
+#[test]
+fn reboot_worker_resets_vm() {
+    let (fake, extension) = setup();
+    fake.expect(&["controlvm", "web-1", "reset"], "");
+
+    extension
+        .execute_action("reboot_worker", &params(json!({ "worker_name": "web-1" })))
+        .unwrap();
+    fake.verify();
+}
+
+#[test]
+fn reboot_worker_waits_for_the_guest_to_go_down_and_come_back() {
+    let (fake, extension) = setup();
+    let extension = extension.with_poll_interval(Duration::from_millis(1));
+    let booted = |level: u32| format!("{}GuestAdditionsRunLevel={}\n", vm_in_state("running"), level);
+    fake.expect(SHOWVMINFO_ARGS, &booted(2))
+        .expect(&["controlvm", "web-1", "reset"], "")
+        // Still the run level from before the reset
+        .expect(SHOWVMINFO_ARGS, &booted(2))
+        .expect(SHOWVMINFO_ARGS, &vm_in_state("running"))
+        .expect(SHOWVMINFO_ARGS, &booted(1))
+        .expect(SHOWVMINFO_ARGS, &booted(2));
+
+    let result = extension
+        .execute_action("reboot_worker", &params(json!({ "worker_name": "web-1", "wait": true })))
+        .unwrap();
+    assert_eq!(result["state"], "running");
+    let levels: Vec<Value> = result["history"].as_array().unwrap().iter().map(|t| t["run_level"].clone()).collect();
+    assert_eq!(levels, vec![json!(2), Value::Null, json!(1), json!(2)]);
+    fake.verify();
+}
+
+#[test]
+fn reboot_worker_refuses_to_wait_without_a_run_level() {
+    let (fake, extension) = setup();
+    fake.expect(SHOWVMINFO_ARGS, &vm_in_state("running"))
+        .expect(SHOWVMINFO_ARGS, &vm_in_state("running"))
+        .expect(&["controlvm", "web-1", "reset"], "")
+        .expect(SHOWVMINFO_ARGS, &format!("{}GuestAdditionsRunLevel=1\n", vm_in_state("running")));
+
+    // Nothing shows that a guest without Guest Additions came back
+    let err = error_payload(
+        extension
+            .execute_action("reboot_worker", &params(json!({ "worker_name": "web-1", "wait": true })))
+            .unwrap_err(),
+    );
+    assert_eq!(err["kind"], "invalid_parameter");
+    assert_eq!(fake.pending(), 3);
+
+    // A requested level the guest is below is only reached after the reset
+    let result = extension
+        .execute_action("reboot_worker", &params(json!({ "worker_name": "web-1", "wait": true, "guest_run_level": 1 })))
+        .unwrap();
+    assert_eq!(result["history"][0]["run_level"], 1);
+    fake.verify();
+}
+
+#[test]
+fn configure_networks_sets_nic_type() {
+    let (fake, extension) = setup();
+    fake.expect(&["modifyvm", "web-1", "--nic2", "nat"], "")
+        .expect(&["modifyvm", "web-1", "--nic3", "hostonly"], "");
+
+    extension
+        .execute_action("configure_networks", &params(json!({ "worker_name": "web-1", "network_index": 2 })))
+        .unwrap();
+    extension
+        .execute_action(
+            "configure_networks",
+            &params(json!({ "worker_name": "web-1", "network_index": 3, "network_type": "hostonly" })),
+        )
+        .unwrap();
+    fake.verify();
+}
+
+#[test]
+fn configure_networks_rejects_unknown_type_and_slot() {
+    let (fake, extension) = setup();
+
+    let err = extension
+        .execute_action("configure_networks", &params(json!({ "worker_name": "web-1", "network_index": 1, "network_type": "wifi" })))
+        .unwrap_err();
+    assert_eq!(error_payload(err)["kind"], "invalid_parameter");
+    let err = extension
+        .execute_action("configure_networks", &params(json!({ "worker_name": "web-1", "network_index": 9 })))
+        .unwrap_err();
+    assert_eq!(error_payload(err)["kind"], "invalid_parameter");
+    fake.verify();
+}
+
+#[test]
+fn set_worker_metadata_writes_extradata() {
+    let (fake, extension) = setup();
+    fake.expect(&["setextradata", "web-1", "omni/role", "worker"], "");
+
+    extension
+        .execute_action(
+            "set_worker_metadata",
+            &params(json!({ "worker_name": "web-1", "key": "omni/role", "value": "worker" })),
+        )
+        .unwrap();
+    fake.verify();
+}
+
+#[test]
+fn snapshot_volume_clones_medium() {
+    let (fake, extension) = setup();
+    fake.expect(
+        &["clonemedium", "disk", "/vms/data-1.vdi", "/vms/data-1-copy.vdi"],
+        "0%...10%...20%...30%...40%...50%...60%...70%...80%...90%...100%\nClone medium created in format 'VDI'. UUID: 7e7e7e7e-0000-4000-8000-000000000000\n",
+    );
+
+    let result = extension
+        .execute_action(
+            "snapshot_volume",
+            &params(json!({ "source_volume_path": "/vms/data-1.vdi", "target_volume_path": "/vms/data-1-copy.vdi" })),
+        )
+        .unwrap();
+    assert_eq!(result["uuid"], "7e7e7e7e-0000-4000-8000-000000000000");
+    fake.verify();
+}
+
+#[test]
+#[should_panic(expected = "unexpected arguments")]
+fn fake_executor_rejects_mismatched_arguments() {
+    let (fake, extension) = setup();
+    fake.expect(&["controlvm", "web-1", "poweroff"], "");
+
+    let _ = extension.execute_action("reboot_worker", &params(json!({ "worker_name": "web-1" })));
+}
+
+#[test]
+#[should_panic(expected = "never made")]
+fn fake_executor_verify_reports_unused_expectations() {
+    let (fake, _) = setup();
+    fake.expect(&["list", "vms"], "");
+    fake.verify();
+}
+
+#[test]
+fn timeout_resolution_prefers_param_then_action_then_global() {
+    let fake = Arc::new(FakeExecutor::new());
+    let extension = VirtualBoxExtension::with_executor(fake.clone())
+        .with_timeout(Some(Duration::from_secs(30)))
+        .with_action_timeout("start_worker", Duration::from_secs(120));
+    fake.expect(&["--version"], "7.0.14r161095\n")
+        .expect(&["startvm", "web-1", "--type", "headless"], "")
+        .expect(&["startvm", "web-1", "--type", "headless"], "");
+
+    extension.execute_action("test_install", &HashMap::new()).unwrap();
+    extension
+        .execute_action("start_worker", &params(json!({ "worker_name": "web-1" })))
+        .unwrap();
+    extension
+        .execute_action("start_worker", &params(json!({ "worker_name": "web-1", "timeout_secs": 5 })))
+        .unwrap();
+
+    assert_eq!(
+        fake.timeouts(),
+        vec![
+            Some(Duration::from_secs(30)),
+            Some(Duration::from_secs(120)),
+            Some(Duration::from_secs(5)),
+        ]
+    );
+    fake.verify();
+}
+
+#[test]
+fn default_timeout_is_applied_and_advertised() {
+    let (fake, extension) = setup();
+    fake.expect(&["list", "-l", "vms"], "");
+
+    extension.execute_action("list_workers", &HashMap::new()).unwrap();
+    assert_eq!(fake.timeouts(), vec![Some(DEFAULT_TIMEOUT)]);
+
+    let definition = extension.get_action_definition("list_workers").unwrap();
+    let timeout = definition.parameters.iter().find(|p| p.name == "timeout_secs").unwrap();
+    assert_eq!(timeout.default_value, Some(json!(DEFAULT_TIMEOUT.as_secs())));
+    fake.verify();
+}
+
+#[test]
+fn non_positive_timeout_is_rejected() {
+    let (fake, extension) = setup();
+    let err = error_payload(
+        extension
+            .execute_action("list_workers", &params(json!({ "timeout_secs": 0 })))
+            .unwrap_err(),
+    );
+    assert_eq!(err["message"], "Parameter 'timeout_secs' must be positive");
+    fake.verify();
+}
+
+#[test]
+fn timed_out_invocation_reports_distinct_error() {
+    let (fake, extension) = setup();
+    fake.expect_error(
+        &["snapshot", "web-1", "take", "nightly"],
+        ExecError::TimedOut(Duration::from_secs(30)),
+    );
+
+    let err = error_payload(
+        extension
+            .execute_action("create_snapshot", &params(json!({ "worker_name": "web-1", "snapshot_name": "nightly" })))
+            .unwrap_err(),
+    );
+    assert_eq!(err["kind"], "timeout");
+    assert_eq!(err["command"], "snapshot web-1 take nightly");
+    assert_eq!(err["message"], "VBoxManage command timed out after 30s: VBoxManage snapshot web-1 take nightly");
+    fake.verify();
+}
+
+/// Executor that blocks every call until it is cancelled
+struct BlockingExecutor;
+
+impl VBoxExecutor for BlockingExecutor {
+    fn execute(&self, _args: &[&str], options: &ExecOptions) -> Result<CommandOutput, ExecError> {
+        while !options.is_cancelled() {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        Err(ExecError::Cancelled)
+    }
+}
+
+#[test]
+fn in_flight_action_can_be_cancelled() {
+    let extension = Arc::new(VirtualBoxExtension::with_executor(Arc::new(BlockingExecutor)));
+
+    let worker = {
+        let extension = extension.clone();
+        std::thread::spawn(move || {
+            extension.execute_action("start_worker", &params(json!({ "worker_name": "web-1" })))
+        })
+    };
+
+    let cancelled = loop {
+        let result = extension
+            .execute_action("cancel_actions", &params(json!({ "action": "start_worker" })))
+            .unwrap();
+        if !result["cancelled"].as_array().unwrap().is_empty() {
+            break result;
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    };
+    assert_eq!(cancelled["cancelled"], json!(["start_worker"]));
+
+    let err = error_payload(worker.join().unwrap().unwrap_err());
+    assert_eq!(err["kind"], "cancelled");
+    assert!(extension.cancel_actions(None).is_empty());
+}
+
+#[test]
+fn cancel_filter_leaves_other_actions_running() {
+    let (_, extension) = setup();
+    let (_opts, _guard) = extension.begin_action("list_workers", None);
+
+    assert!(extension.cancel_actions(Some("start_worker")).is_empty());
+    assert_eq!(extension.cancel_actions(None), vec!["list_workers".to_string()]);
+}
+
+const LOCKED: &str = "VBoxManage: error: The machine 'web-1' is already locked for a session (or being unlocked)
+VBoxManage: error: Details: code VBOX_E_INVALID_OBJECT_STATE (0x80bb0007), component MachineWrap, interface IMachine, callee nsISupports
+";
+
+fn fast_retries(max_attempts: u32) -> RetryPolicy {
+    RetryPolicy {
+        max_attempts,
+        initial_backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(1),
+        multiplier: 1.0,
+    }
+}
+
+#[test]
+fn session_lock_is_retried_until_success() {
+    let fake = Arc::new(FakeExecutor::new());
+    let extension = VirtualBoxExtension::with_executor(fake.clone()).with_retry_policy(fast_retries(3));
+    fake.expect_failure(&["modifyvm", "web-1", "--nic2", "nat"], LOCKED)
+        .expect_failure(&["modifyvm", "web-1", "--nic2", "nat"], LOCKED)
+        .expect(&["modifyvm", "web-1", "--nic2", "nat"], "");
+
+    extension
+        .execute_action("configure_networks", &params(json!({ "worker_name": "web-1", "network_index": 2 })))
+        .unwrap();
+    fake.verify();
+}
+
+#[test]
+fn retries_give_up_after_max_attempts() {
+    let fake = Arc::new(FakeExecutor::new());
+    let extension = VirtualBoxExtension::with_executor(fake.clone()).with_retry_policy(fast_retries(2));
+    fake.expect_failure(&["controlvm", "web-1", "reset"], LOCKED)
+        .expect_failure(&["controlvm", "web-1", "reset"], LOCKED);
+
+    let err = extension
+        .execute_action("reboot_worker", &params(json!({ "worker_name": "web-1" })))
+        .unwrap_err();
+    assert!(err.contains("VBOX_E_INVALID_OBJECT_STATE"), "{}", err);
+    fake.verify();
+}
+
+#[test]
+fn permanent_errors_are_not_retried() {
+    let fake = Arc::new(FakeExecutor::new());
+    let extension = VirtualBoxExtension::with_executor(fake.clone()).with_retry_policy(fast_retries(5));
+    fake.expect_failure(
+        &["unregistervm", "ghost", "--delete"],
+        "VBoxManage: error: Could not find a registered machine named 'ghost'\n\
+         VBoxManage: error: Details: code VBOX_E_OBJECT_NOT_FOUND (0x80bb0001), component VirtualBoxWrap, interface IVirtualBox, callee nsISupports\n",
+    );
+
+    let err = extension
+        .execute_action("delete_worker", &params(json!({ "worker_name": "ghost" })))
+        .unwrap_err();
+    assert!(err.contains("VBOX_E_OBJECT_NOT_FOUND"), "{}", err);
+    fake.verify();
+}
+
+#[test]
+fn retry_policy_can_be_overridden_per_action() {
+    let fake = Arc::new(FakeExecutor::new());
+    let extension = VirtualBoxExtension::with_executor(fake.clone())
+        .with_retry_policy(fast_retries(5))
+        .with_action_retry_policy("start_worker", RetryPolicy::none());
+    fake.expect_failure(&["startvm", "web-1", "--type", "headless"], LOCKED);
+
+    extension
+        .execute_action("start_worker", &params(json!({ "worker_name": "web-1" })))
+        .unwrap_err();
+    fake.verify();
+}
+
+#[test]
+fn cancellation_interrupts_retry_backoff() {
+    let fake = Arc::new(FakeExecutor::new());
+    let slow = RetryPolicy {
+        initial_backoff: Duration::from_secs(60),
+        ..RetryPolicy::default()
+    };
+    let extension = Arc::new(VirtualBoxExtension::with_executor(fake.clone()).with_retry_policy(slow));
+    fake.expect_failure(&["controlvm", "web-1", "reset"], LOCKED);
+
+    let worker = {
+        let extension = extension.clone();
+        std::thread::spawn(move || extension.execute_action("reboot_worker", &params(json!({ "worker_name": "web-1" }))))
+    };
+    while extension.cancel_actions(Some("reboot_worker")).is_empty() {
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    let err = error_payload(worker.join().unwrap().unwrap_err());
+    assert_eq!(err["kind"], "cancelled");
+    fake.verify();
+}
+
+#[test]
+fn vboxmanage_failures_carry_result_codes() {
+    let (fake, extension) = setup();
+    fake.expect_failure(
+        &["snapshot", "web-1", "delete", "nightly"],
+        "VBoxManage: error: Could not find a snapshot named 'nightly'\n\
+         VBoxManage: error: Details: code VBOX_E_OBJECT_NOT_FOUND (0x80bb0001), component MachineWrap, interface IMachine, callee nsISupports\n\
+         VBoxManage: error: Context: \"FindSnapshot(Bstr(pszName).raw(), pSnapshot.asOutParam())\" at line 630 of file VBoxManageSnapshot.cpp\n",
+    );
+
+    let err = error_payload(
+        extension
+            .execute_action("delete_snapshot", &params(json!({ "worker_name": "web-1", "snapshot_name": "nightly" })))
+            .unwrap_err(),
+    );
+    assert_eq!(err["kind"], "not_found");
+    assert_eq!(err["code"], "VBOX_E_OBJECT_NOT_FOUND");
+    assert_eq!(err["hresult"], "0x80bb0001");
+    assert_eq!(err["component"], "MachineWrap");
+    assert_eq!(err["interface"], "IMachine");
+    assert_eq!(err["callee"], "nsISupports");
+    assert_eq!(err["command"], "snapshot web-1 delete nightly");
+    assert_eq!(err["message"], "VBoxManage command failed: Could not find a snapshot named 'nightly'");
+    fake.verify();
+}
+
+#[test]
+fn has_worker_propagates_errors_other_than_not_found() {
+    let (fake, extension) = setup();
+    fake.expect_error(
+        &["showvminfo", "web-1", "--machinereadable"],
+        ExecError::TimedOut(Duration::from_secs(10)),
+    );
+
+    let err = error_payload(
+        extension
+            .execute_action("has_worker", &params(json!({ "worker_name": "web-1" })))
+            .unwrap_err(),
+    );
+    assert_eq!(err["kind"], "timeout");
+    fake.verify();
+}
+
+#[test]
+fn secrets_are_redacted_from_error_payloads() {
+    let (fake, extension) = setup();
+    fake.expect_failure(
+        &["setextradata", "web-1", "omni/db_password", "hunter2"],
+        "VBoxManage: error: Could not find a registered machine named 'web-1'\n",
+    );
+
+    let err = extension
+        .execute_action(
+            "set_worker_metadata",
+            &params(json!({ "worker_name": "web-1", "key": "omni/db_password", "value": "hunter2" })),
+        )
+        .unwrap_err();
+    assert!(!err.contains("hunter2"), "{}", err);
+    assert_eq!(error_payload(err)["command"], "setextradata web-1 omni/db_password ******");
+    fake.verify();
+}
+
+#[test]
+fn unattended_install_uses_configured_credentials_without_advertising_the_password() {
+    let (fake, extension) = setup();
+    let extension = extension.with_default_settings(DefaultSettings {
+        username: "ops".to_string(),
+        password: "s3cret".to_string(),
+        ..DefaultSettings::default()
+    });
+    let detect = ["unattended", "detect", "--iso", "/isos/ubuntu.iso", "--machine-readable"];
+    // The password reaches VBoxManage through a private file, never argv
+    let password_file = Arc::new(Mutex::new(String::new()));
+    let seen = password_file.clone();
+    fake.expect(&detect, "OSTypeId=\"Ubuntu_64\"\nOSVersion=\"22.04\"\nIsInstallSupported=\"true\"\n")
+        .expect_matching(
+            move |args| {
+                if args.len() != 12 {
+                    return false;
+                }
+                let path = args[8].to_string();
+                let readable = std::fs::read_to_string(&path).is_ok_and(|password| password == "s3cret");
+                #[cfg(unix)]
+                let readable = readable && {
+                    use std::os::unix::fs::PermissionsExt;
+                    std::fs::metadata(&path).unwrap().permissions().mode() & 0o777 == 0o600
+                };
+                *seen.lock().unwrap() = path;
+                readable
+                    && args[..8] == ["unattended", "install", "web-1", "--iso", "/isos/ubuntu.iso", "--user", "ops", "--password-file"]
+                    && args[9..] == ["--hostname", "web-1.example.com", "--install-additions"]
+            },
+            "",
+        )
+        .expect(&["unattended", "detect", "--iso", "/isos/other.iso", "--machine-readable"], "OSTypeId=\"Other\"\nIsInstallSupported=\"false\"\n");
+
+    let result = extension
+        .execute_action(
+            "install_os_unattended",
+            &params(json!({ "worker_name": "web-1", "iso_path": "/isos/ubuntu.iso", "hostname": "web-1.example.com", "install_additions": true })),
+        )
+        .unwrap();
+    assert_eq!(result["os"]["os_type"], "Ubuntu_64");
+    assert_eq!(result["started"], false);
+    assert!(!std::path::Path::new(password_file.lock().unwrap().as_str()).exists());
+
+    let err = extension
+        .execute_action("install_os_unattended", &params(json!({ "worker_name": "web-1", "iso_path": "/isos/other.iso" })))
+        .unwrap_err();
+    assert!(err.contains("invalid_parameter"), "{}", err);
+    let err = extension
+        .execute_action("install_os_unattended", &params(json!({ "worker_name": "web-1", "iso_path": "/isos/ubuntu.iso", "wait": true })))
+        .unwrap_err();
+    assert!(err.contains("needs 'start'"), "{}", err);
+    let err = extension
+        .execute_action(
+            "install_os_unattended",
+            &params(json!({ "worker_name": "web-1", "iso_path": "/isos/ubuntu.iso", "start": true, "wait": true })),
+        )
+        .unwrap_err();
+    assert!(err.contains("needs 'install_additions' or 'guest_run_level'"), "{}", err);
+
+    let definition = extension.get_action_definition("install_os_unattended").unwrap();
+    let default_of = |name: &str| definition.parameters.iter().find(|p| p.name == name).unwrap().default_value.clone();
+    assert_eq!(default_of("username"), Some(json!("ops")));
+    assert_eq!(default_of("password"), None);
+    fake.verify();
+}
+
+#[test]
+fn start_order_groups_stop_after_a_failed_group() {
+    let (fake, extension) = setup();
+    let start = |name: &'static str| ["startvm", name, "--type", "gui", "--putenv", "DISPLAY=:1", "--putenv", "LANG=C"];
+    fake.expect(&start("db-1"), "")
+        .expect_failure(&start("web-1"), "VBoxManage: error: Could not find a registered machine named 'web-1'\nVBoxManage: error: Details: code VBOX_E_OBJECT_NOT_FOUND (0x80bb0001), component VirtualBoxWrap, interface IVirtualBox, callee nsISupports\n")
+        .expect(&start("web-2"), "");
+
+    let result = extension
+        .execute_action(
+            "start_worker",
+            &params(json!({
+                "start_order": [{ "workers": ["db-1"] }, { "workers": ["web-1", "web-2"] }, { "workers": ["lb-1"], "delay_secs": 600 }],
+                "parallelism": 1,
+                "start_type": "gui",
+                "env": { "LANG": "C", "DISPLAY": ":1" }
+            })),
+        )
+        .unwrap();
+    assert_eq!(result["success"], false);
+    let statuses: Vec<(&str, &str)> = result["workers"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|w| (w["name"].as_str().unwrap(), w["status"].as_str().unwrap()))
+        .collect();
+    assert_eq!(statuses, vec![("db-1", "started"), ("web-1", "failed"), ("web-2", "started"), ("lb-1", "skipped")]);
+    assert_eq!(result["workers"][1]["error"]["kind"], "not_found");
+    fake.verify();
+
+    let err = extension
+        .execute_action("start_worker", &params(json!({ "worker_name": "db-1", "worker_names": "web-1" })))
+        .unwrap_err();
+    assert!(err.contains("exactly one"), "{}", err);
+    let err = extension
+        .execute_action("start_worker", &params(json!({ "worker_names": "web-1,web-1" })))
+        .unwrap_err();
+    assert!(err.contains("more than once"), "{}", err);
+}
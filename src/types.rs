@@ -0,0 +1,1332 @@
+// File: cpi_virtualbox/src/types.rs
+use crate::error::VBoxResult;
+use crate::settings::DefaultSettings;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Power state as reported in the `VMState` key of `showvminfo`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum VmState {
+    PowerOff,
+    Running,
+    Paused,
+    Saved,
+    Aborted,
+    Starting,
+    Stopping,
+    Saving,
+    Restoring,
+    Stuck,
+    /// Any state this crate does not model, kept verbatim
+    Other(String),
+}
+
+impl VmState {
+    /// Whether the VM has no running process (powered off, saved or aborted)
+    pub fn is_stopped(&self) -> bool {
+        matches!(self, VmState::PowerOff | VmState::Saved | VmState::Aborted)
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            VmState::PowerOff => "poweroff",
+            VmState::Running => "running",
+            VmState::Paused => "paused",
+            VmState::Saved => "saved",
+            VmState::Aborted => "aborted",
+            VmState::Starting => "starting",
+            VmState::Stopping => "stopping",
+            VmState::Saving => "saving",
+            VmState::Restoring => "restoring",
+            VmState::Stuck => "gurumeditation",
+            VmState::Other(state) => state,
+        }
+    }
+}
+
+impl VmState {
+    /// Parse the human-readable state printed by `list -l vms`, e.g.
+    /// `powered off (since 2024-01-01T10:00:00.000000000)`
+    pub fn from_description(description: &str) -> Self {
+        let state = description.split(" (since").next().unwrap_or_default();
+        match state.trim().replace(' ', "").as_str() {
+            "poweredoff" => VmState::PowerOff,
+            other => VmState::from(other),
+        }
+    }
+}
+
+impl From<&str> for VmState {
+    fn from(state: &str) -> Self {
+        match state {
+            "poweroff" => VmState::PowerOff,
+            "running" => VmState::Running,
+            "paused" => VmState::Paused,
+            "saved" => VmState::Saved,
+            "aborted" => VmState::Aborted,
+            "starting" => VmState::Starting,
+            "stopping" => VmState::Stopping,
+            "saving" => VmState::Saving,
+            "restoring" => VmState::Restoring,
+            "gurumeditation" => VmState::Stuck,
+            other => VmState::Other(other.to_string()),
+        }
+    }
+}
+
+/// Strict counterpart of `From<&str>` for states given by callers, which
+/// rejects anything this crate does not model instead of keeping it verbatim
+impl FromStr for VmState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match VmState::from(s) {
+            VmState::Other(other) => Err(format!(
+                "Unknown VM state '{}', expected poweroff, running, paused, saved, aborted, starting, stopping, saving, restoring or gurumeditation",
+                other
+            )),
+            state => Ok(state),
+        }
+    }
+}
+
+impl fmt::Display for VmState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for VmState {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for VmState {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let state = String::deserialize(deserializer)?;
+        Ok(VmState::from(state.as_str()))
+    }
+}
+
+/// A registered VM as listed by `list vms`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VmSummary {
+    pub name: String,
+    pub id: String,
+}
+
+/// Details of a single VM from `showvminfo --machinereadable`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Vm {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<VmState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_changed_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_mb: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vram_mb: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_count: Option<i64>,
+    /// OS type id as `createvm --ostype` takes it, e.g. `Ubuntu_64`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os_type: Option<String>,
+    /// What VBoxManage shows for the OS type, e.g. `Ubuntu (64-bit)`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os_description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub firmware: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub graphics_controller: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config_file: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub storage_controllers: Vec<StorageController>,
+    /// Occupied controller ports; empty ports are left out
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub storage_attachments: Vec<StorageAttachment>,
+    /// Enabled network adapters; `none` slots are left out
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub network_adapters: Vec<NetworkAdapter>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub snapshots: Vec<Snapshot>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_snapshot: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shared_folders: Vec<SharedFolder>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usb: Option<UsbInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guest: Option<GuestInfo>,
+    /// Every key/value pair VBoxManage reported, including unmodelled ones
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<String, String>,
+}
+
+/// A storage controller (`storagecontroller*<N>` keys)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StorageController {
+    pub name: String,
+    /// Judged from the chipset, which showvminfo reports instead of the bus
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bus: Option<StorageBus>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub controller_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_port_count: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port_count: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bootable: Option<bool>,
+}
+
+/// Bus a storage controller sits on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBus {
+    Ide,
+    Sata,
+    Scsi,
+    Sas,
+    Nvme,
+    VirtioScsi,
+    Floppy,
+}
+
+impl StorageBus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StorageBus::Ide => "ide",
+            StorageBus::Sata => "sata",
+            StorageBus::Scsi => "scsi",
+            StorageBus::Sas => "sas",
+            StorageBus::Nvme => "nvme",
+            StorageBus::VirtioScsi => "virtio_scsi",
+            StorageBus::Floppy => "floppy",
+        }
+    }
+
+    /// Value of `storagectl --add`
+    pub fn storagectl_arg(&self) -> &'static str {
+        match self {
+            StorageBus::Nvme => "pcie",
+            StorageBus::VirtioScsi => "virtio",
+            other => other.as_str(),
+        }
+    }
+
+    /// Chipsets VirtualBox emulates on this bus; the first is the default
+    pub fn chipsets(&self) -> &'static [&'static str] {
+        match self {
+            StorageBus::Ide => &["PIIX4", "PIIX3", "ICH6"],
+            StorageBus::Sata => &["IntelAhci"],
+            StorageBus::Scsi => &["LsiLogic", "BusLogic"],
+            StorageBus::Sas => &["LsiLogicSas"],
+            StorageBus::Nvme => &["NVMe"],
+            StorageBus::VirtioScsi => &["VirtIO"],
+            StorageBus::Floppy => &["I82078"],
+        }
+    }
+
+    /// Master and slave on IDE, one device elsewhere
+    pub fn devices_per_port(&self) -> u32 {
+        match self {
+            StorageBus::Ide => 2,
+            _ => 1,
+        }
+    }
+
+    pub fn max_ports(&self) -> u32 {
+        match self {
+            StorageBus::Ide => 2,
+            StorageBus::Sata => 30,
+            StorageBus::Scsi => 16,
+            StorageBus::Sas | StorageBus::Nvme => 255,
+            StorageBus::VirtioScsi => 256,
+            StorageBus::Floppy => 1,
+        }
+    }
+
+    /// The bus a chipset belongs to, e.g. `IntelAhci` -> SATA
+    pub fn for_chipset(chipset: &str) -> Option<Self> {
+        [
+            StorageBus::Ide,
+            StorageBus::Sata,
+            StorageBus::Scsi,
+            StorageBus::Sas,
+            StorageBus::Nvme,
+            StorageBus::VirtioScsi,
+            StorageBus::Floppy,
+        ]
+        .into_iter()
+        .find(|bus| bus.chipsets().iter().any(|c| c.eq_ignore_ascii_case(chipset)))
+    }
+}
+
+impl FromStr for StorageBus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().replace('-', "_").as_str() {
+            "ide" => Ok(StorageBus::Ide),
+            "sata" => Ok(StorageBus::Sata),
+            "scsi" => Ok(StorageBus::Scsi),
+            "sas" => Ok(StorageBus::Sas),
+            "nvme" | "pcie" => Ok(StorageBus::Nvme),
+            "virtio_scsi" | "virtio" => Ok(StorageBus::VirtioScsi),
+            "floppy" => Ok(StorageBus::Floppy),
+            other => Err(format!(
+                "Unknown storage bus '{}', expected ide, sata, scsi, sas, nvme, virtio_scsi or floppy",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for StorageBus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A storage controller to add with `VirtualBoxClient::add_storage_controller`;
+/// `None` leaves VirtualBox's default
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControllerSpec {
+    pub name: String,
+    pub bus: StorageBus,
+    /// One of `bus.chipsets()`; the bus's default when `None`
+    pub chipset: Option<String>,
+    pub port_count: Option<u32>,
+    pub host_io_cache: Option<bool>,
+    pub bootable: Option<bool>,
+}
+
+impl ControllerSpec {
+    pub fn new(name: &str, bus: StorageBus) -> Self {
+        ControllerSpec {
+            name: name.to_string(),
+            bus,
+            chipset: None,
+            port_count: None,
+            host_io_cache: None,
+            bootable: None,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() {
+            return Err("A storage controller needs a name".to_string());
+        }
+        if let Some(chipset) = &self.chipset
+            && !self.bus.chipsets().iter().any(|c| c.eq_ignore_ascii_case(chipset))
+        {
+            return Err(format!(
+                "Chipset '{}' does not fit a {} controller, expected one of: {}",
+                chipset,
+                self.bus,
+                self.bus.chipsets().join(", ")
+            ));
+        }
+        if let Some(ports) = self.port_count
+            && !(1..=self.bus.max_ports()).contains(&ports)
+        {
+            return Err(format!("Port count must be between 1 and {} on {}, not {}", self.bus.max_ports(), self.bus, ports));
+        }
+        Ok(())
+    }
+
+    /// `storagectl` invocation adding the controller to `vm`
+    pub fn storagectl_args(&self, vm: &str) -> Vec<String> {
+        let chipset = self.chipset.as_deref().unwrap_or(self.bus.chipsets()[0]);
+        let mut args: Vec<String> = ["storagectl", vm, "--name", &self.name, "--add", self.bus.storagectl_arg(), "--controller", chipset]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        let on_off = |flag: bool| if flag { "on" } else { "off" }.to_string();
+        if let Some(ports) = self.port_count {
+            args.extend(["--portcount".to_string(), ports.to_string()]);
+        }
+        if let Some(cache) = self.host_io_cache {
+            args.extend(["--hostiocache".to_string(), on_off(cache)]);
+        }
+        if let Some(bootable) = self.bootable {
+            args.extend(["--bootable".to_string(), on_off(bootable)]);
+        }
+        args
+    }
+}
+
+/// A device on a controller port (`"<controller>-<port>-<device>"` keys)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StorageAttachment {
+    pub controller: String,
+    pub port: u32,
+    pub device: u32,
+    /// Path of the attached medium; `None` for an empty drive
+    pub medium: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub medium_id: Option<String>,
+    /// `None` ("unknown") when showvminfo alone does not tell, which is the
+    /// case for images until `VirtualBoxClient::list_attachments` looks
+    /// them up in the medium registry
+    #[serde(rename = "type", serialize_with = "device_type_or_unknown", deserialize_with = "unknown_or_device_type")]
+    pub device_type: Option<DeviceType>,
+    /// Medium a differencing image (made for a snapshot or linked clone) is
+    /// based on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+    /// `None` until the medium registry was consulted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub differencing: Option<bool>,
+}
+
+fn device_type_or_unknown<S: serde::Serializer>(device_type: &Option<DeviceType>, serializer: S) -> Result<S::Ok, S::Error> {
+    match device_type {
+        Some(device_type) => device_type.serialize(serializer),
+        None => serializer.serialize_str("unknown"),
+    }
+}
+
+fn unknown_or_device_type<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<DeviceType>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    match value.as_str() {
+        "unknown" => Ok(None),
+        other => other.parse().map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+/// An enabled network adapter (`nic<N>`, `macaddress<N>`, ... keys)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetworkAdapter {
+    pub index: u32,
+    #[serde(rename = "type")]
+    pub nic_type: NicType,
+    /// Emulated hardware, e.g. `82540EM` or `virtio`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adapter_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mac_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cable_connected: Option<bool>,
+    /// Bridged interface, host-only adapter, internal network or NAT network name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub port_forwards: Vec<PortForward>,
+}
+
+/// A NAT port forwarding rule (`Forwarding(<N>)` keys)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PortForward {
+    pub name: String,
+    pub protocol: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host_ip: Option<String>,
+    pub host_port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guest_ip: Option<String>,
+    pub guest_port: u16,
+}
+
+impl PortForward {
+    /// Parse `name,protocol,host_ip,host_port,guest_ip,guest_port`
+    pub fn parse(rule: &str) -> Option<Self> {
+        let parts: Vec<&str> = rule.split(',').collect();
+        let [name, protocol, host_ip, host_port, guest_ip, guest_port] = parts[..] else {
+            return None;
+        };
+        let ip = |ip: &str| (!ip.is_empty()).then(|| ip.to_string());
+        Some(Self {
+            name: name.to_string(),
+            protocol: protocol.to_string(),
+            host_ip: ip(host_ip),
+            host_port: host_port.parse().ok()?,
+            guest_ip: ip(guest_ip),
+            guest_port: guest_port.parse().ok()?,
+        })
+    }
+}
+
+/// A shared folder (`SharedFolder{Name,Path}{Machine,Transient}Mapping<N>` keys)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SharedFolder {
+    pub name: String,
+    pub host_path: String,
+    pub transient: bool,
+}
+
+/// USB controllers and device filters
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UsbInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ohci: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ehci: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub xhci: Option<bool>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub filters: Vec<UsbFilter>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UsbFilter {
+    pub name: String,
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vendor_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub product_id: Option<String>,
+}
+
+/// What the running guest reports through the Guest Additions
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GuestInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub additions_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub additions_run_level: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_balloon_mb: Option<i64>,
+    /// Facility name to `<status>,<timestamp>`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub facilities: BTreeMap<String, String>,
+}
+
+/// Front end `startvm` runs a VM with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StartMode {
+    /// No window
+    #[default]
+    Headless,
+    /// The VirtualBox Manager window
+    Gui,
+    /// The minimal SDL window
+    Sdl,
+    /// Headless VM process with a separate, detachable window
+    Separate,
+}
+
+impl StartMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StartMode::Headless => "headless",
+            StartMode::Gui => "gui",
+            StartMode::Sdl => "sdl",
+            StartMode::Separate => "separate",
+        }
+    }
+}
+
+impl FromStr for StartMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "headless" => Ok(StartMode::Headless),
+            "gui" => Ok(StartMode::Gui),
+            "sdl" => Ok(StartMode::Sdl),
+            "separate" => Ok(StartMode::Separate),
+            other => Err(format!("Unknown start type '{}', expected headless, gui, sdl or separate", other)),
+        }
+    }
+}
+
+/// How `VirtualBoxClient::start_vm_with` starts a VM
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StartOptions {
+    pub mode: StartMode,
+    /// Environment variables for the VM process (`--putenv`)
+    pub env: BTreeMap<String, String>,
+}
+
+/// VMs `VirtualBoxClient::start_vms` starts together, once the previous
+/// group has started and `delay_secs` more have passed
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StartGroup {
+    pub workers: Vec<String>,
+    #[serde(default)]
+    pub delay_secs: u64,
+}
+
+/// What happened to one VM of `VirtualBoxClient::start_vms`
+#[derive(Debug, Clone, PartialEq)]
+pub struct StartReport {
+    pub name: String,
+    /// Index of the VM's start group
+    pub group: usize,
+    /// The states seen while waiting, empty without a wait; `None` if the
+    /// VM was not started because an earlier group failed
+    pub result: Option<VBoxResult<Vec<StateTransition>>>,
+}
+
+/// How `VirtualBoxClient::stop_vm` got the VM to stop
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopMethod {
+    /// The guest shut down after the ACPI power button within the grace period
+    Acpi,
+    /// The VM was powered off hard
+    PowerOff,
+    /// The VM was not running
+    AlreadyStopped,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StopOutcome {
+    pub method: StopMethod,
+    /// Time spent waiting for the guest to shut down
+    pub waited: std::time::Duration,
+}
+
+/// State to wait for, optionally with a minimum Guest Additions run level
+/// (1 system, 2 userland, 3 desktop) so a wait can cover the guest booting
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WaitTarget {
+    pub state: VmState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_run_level: Option<u32>,
+}
+
+impl WaitTarget {
+    pub fn state(state: VmState) -> Self {
+        Self {
+            state,
+            min_run_level: None,
+        }
+    }
+
+    pub fn is_met(&self, state: &VmState, run_level: Option<u32>) -> bool {
+        *state == self.state && self.min_run_level.is_none_or(|min| run_level.unwrap_or(0) >= min)
+    }
+}
+
+impl fmt::Display for WaitTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.min_run_level {
+            Some(level) => write!(f, "{} with guest run level {}", self.state, level),
+            None => write!(f, "{}", self.state),
+        }
+    }
+}
+
+/// A change observed while waiting on a VM; the first entry is the state
+/// at the start of the wait
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateTransition {
+    pub state: VmState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_level: Option<u32>,
+    /// Seconds since the wait started
+    pub elapsed_secs: f64,
+}
+
+/// How `VirtualBoxClient::delete_vm_with` removes a VM
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeleteOptions {
+    /// Power the VM off first if it is running, paused or stuck
+    pub force: bool,
+    /// Attached media to detach and keep, by path, file name or UUID
+    pub keep_media: Vec<String>,
+    /// How long to wait for the VM's session to unlock after powering off
+    pub unlock_timeout: std::time::Duration,
+}
+
+impl Default for DeleteOptions {
+    fn default() -> Self {
+        Self {
+            force: false,
+            keep_media: Vec::new(),
+            unlock_timeout: std::time::Duration::from_secs(60),
+        }
+    }
+}
+
+/// What deleting a VM removed and what it left behind
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeleteReport {
+    /// Whether the VM had to be powered off first
+    pub powered_off: bool,
+    /// Files that existed before `unregistervm --delete` and are gone after
+    /// it: settings file, NVRAM, saved states, hard disks including snapshot
+    /// differencing images, and logs
+    pub deleted_files: Vec<String>,
+    /// Paths of media detached and preserved
+    pub kept_media: Vec<String>,
+}
+
+/// Which MAC addresses a clone keeps from its source
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MacPolicy {
+    /// Every NIC gets a new MAC address
+    #[default]
+    Regenerate,
+    /// NAT NICs keep their MAC address, all others get a new one
+    KeepNat,
+    /// Every NIC keeps its MAC address
+    KeepAll,
+}
+
+impl MacPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MacPolicy::Regenerate => "regenerate",
+            MacPolicy::KeepNat => "keep_nat",
+            MacPolicy::KeepAll => "keep_all",
+        }
+    }
+}
+
+impl FromStr for MacPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "regenerate" => Ok(MacPolicy::Regenerate),
+            "keep_nat" => Ok(MacPolicy::KeepNat),
+            "keep_all" => Ok(MacPolicy::KeepAll),
+            other => Err(format!("Unknown MAC policy '{}', expected regenerate, keep_nat or keep_all", other)),
+        }
+    }
+}
+
+/// How `VirtualBoxClient::clone_vm` copies a VM
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CloneOptions {
+    /// Name of the new VM
+    pub name: String,
+    /// Snapshot to clone from, taken first if the source does not have it.
+    /// Linked clones always need one.
+    pub snapshot: Option<String>,
+    /// Share the source's disks through differencing images instead of
+    /// copying them
+    pub linked: bool,
+    pub mac_policy: MacPolicy,
+    /// UUID for the new VM; VirtualBox picks one when `None`
+    pub uuid: Option<String>,
+    /// Group paths such as `/web/prod`; the source's groups when empty
+    pub groups: Vec<String>,
+    /// Directory the VM folder is created in
+    pub base_folder: Option<String>,
+}
+
+/// Snapshot linked clones are made from when none is named
+pub const LINKED_CLONE_SNAPSHOT: &str = "linked-clone-base";
+
+/// One line of an appliance's virtual system description, as listed by
+/// `import --dry-run`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApplianceEntry {
+    /// Index of the virtual system (VM) in the appliance
+    pub system: u32,
+    /// Index of the entry, as passed to `--unit`
+    pub unit: u32,
+    /// `os_type`, `name`, `group`, `settings_file`, `base_folder`,
+    /// `cpu_count`, `memory_mb`, `network_adapter`, `controller`,
+    /// `hard_disk`, `description`, `product`, `product_url`, `vendor`,
+    /// `vendor_url`, `version`, `license` or `other`
+    pub kind: String,
+    /// The suggested value; the target path for hard disks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    /// The entry as VBoxManage printed it
+    pub text: String,
+}
+
+/// Overrides for `VirtualBoxClient::import_appliance`; `None` keeps what
+/// the appliance suggests. Only the first virtual system is changed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportOptions {
+    pub name: Option<String>,
+    pub memory_mb: Option<i64>,
+    pub cpu_count: Option<i64>,
+    /// Target paths for the appliance's hard disks, in the order listed
+    pub disk_paths: Vec<String>,
+    pub mac_policy: MacPolicy,
+    /// Accept the appliance's license, which VBoxManage otherwise refuses
+    pub accept_eula: bool,
+}
+
+/// OVF standard version written by `export_appliance`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OvfVersion {
+    #[default]
+    Ovf10,
+    Ovf20,
+}
+
+impl FromStr for OvfVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().trim_start_matches("ovf") {
+            "1.0" | "10" => Ok(OvfVersion::Ovf10),
+            "2.0" | "20" => Ok(OvfVersion::Ovf20),
+            other => Err(format!("Unknown OVF version '{}', expected 1.0 or 2.0", other)),
+        }
+    }
+}
+
+/// How `VirtualBoxClient::export_appliance` writes an appliance. The
+/// output's extension picks the packaging: `.ova` for a single archive,
+/// `.ovf` for a descriptor next to separate disk files.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExportOptions {
+    pub output: String,
+    pub ovf_version: OvfVersion,
+    /// Write a manifest of checksums alongside the appliance
+    pub manifest: bool,
+    pub product: Option<String>,
+    pub product_url: Option<String>,
+    pub vendor: Option<String>,
+    pub vendor_url: Option<String>,
+    pub version: Option<String>,
+    pub description: Option<String>,
+}
+
+/// What `unattended detect` found on an installation ISO
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DetectedOs {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flavor: Option<String>,
+    pub languages: Vec<String>,
+    /// Whether VirtualBox can install this OS unattended
+    pub install_supported: bool,
+}
+
+/// Settings for `VirtualBoxClient::install_os_unattended`
+#[derive(Clone, Default, PartialEq)]
+pub struct UnattendedOptions {
+    pub iso: String,
+    pub user: String,
+    pub password: String,
+    pub full_name: Option<String>,
+    /// Fully qualified, e.g. `web-1.example.com`
+    pub hostname: Option<String>,
+    /// e.g. `en_US`
+    pub locale: Option<String>,
+    /// e.g. `UTC` or `Europe/Berlin`
+    pub time_zone: Option<String>,
+    pub install_additions: bool,
+    /// Shell command run in the guest once installation finishes
+    pub post_install_command: Option<String>,
+    /// Boot the VM headless into the installer straight away
+    pub start: bool,
+}
+
+// The password must not end up in logs
+impl fmt::Debug for UnattendedOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UnattendedOptions")
+            .field("iso", &self.iso)
+            .field("user", &self.user)
+            .field("password", &crate::logging::REDACTED)
+            .field("full_name", &self.full_name)
+            .field("hostname", &self.hostname)
+            .field("locale", &self.locale)
+            .field("time_zone", &self.time_zone)
+            .field("install_additions", &self.install_additions)
+            .field("post_install_command", &self.post_install_command)
+            .field("start", &self.start)
+            .finish()
+    }
+}
+
+/// Criteria for selecting VMs; empty criteria match everything
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VmFilter {
+    /// Match any of these states
+    pub states: Vec<VmState>,
+    /// Match VMs in this group or one of its subgroups, e.g. `/omni`
+    pub group: Option<String>,
+    /// Glob on the VM name; `*` and `?` are wildcards
+    pub name_pattern: Option<String>,
+    /// Extradata key the VM must have, optionally with this exact value
+    pub tag: Option<(String, Option<String>)>,
+}
+
+impl VmFilter {
+    /// Whether a VM passes the state, group and name criteria. The tag
+    /// needs an extradata lookup and is checked by `VirtualBoxClient::find_vms`.
+    pub fn matches(&self, vm: &Vm) -> bool {
+        if !self.states.is_empty() && !vm.state.as_ref().is_some_and(|s| self.states.contains(s)) {
+            return false;
+        }
+        if let Some(group) = &self.group {
+            let group = group.trim_end_matches('/');
+            let in_group = vm.groups.iter().any(|g| g == group || g.starts_with(&format!("{}/", group)));
+            if !in_group {
+                return false;
+            }
+        }
+        if let Some(pattern) = &self.name_pattern {
+            let name = vm.name.as_deref().unwrap_or_default();
+            if !glob_match(pattern.as_bytes(), name.as_bytes()) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// Linear-time `*`/`?` matching: on a mismatch, only the most recent `*`
+// is widened, since any earlier one could not match more than it already does
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text it currently ends at
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Hardware for a new VM. Optional settings left as `None` keep
+/// VirtualBox's defaults for the OS type; flag names follow VirtualBox 7.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VmConfig {
+    pub name: String,
+    pub os_type: String,
+    pub memory_mb: i64,
+    pub cpu_count: i64,
+    /// `bios`, `efi`, `efi32` or `efi64`
+    pub firmware: Option<String>,
+    /// `piix3` or `ich9`
+    pub chipset: Option<String>,
+    pub vram_mb: Option<i64>,
+    /// `none`, `vboxvga`, `vmsvga` or `vboxsvga`
+    pub graphics_controller: Option<String>,
+    pub nested_virtualization: Option<bool>,
+    pub pae: Option<bool>,
+    /// `none`, `default`, `legacy`, `minimal`, `hyperv` or `kvm`
+    pub paravirt_provider: Option<String>,
+    /// Up to four of `none`, `floppy`, `dvd`, `disk` and `net`; slots not
+    /// listed are set to `none`
+    pub boot_order: Vec<String>,
+    pub description: Option<String>,
+    /// Group paths such as `/web/prod`
+    pub groups: Vec<String>,
+    /// Directory the VM folder is created in
+    pub base_folder: Option<String>,
+    pub rtc_utc: Option<bool>,
+    pub audio: Option<bool>,
+    /// Hard disks to create, attached in order from port 0
+    pub disks: Vec<DiskSpec>,
+    /// SATA controller added for `disks`
+    pub controller_name: String,
+    /// Network adapters; NIC 1 is NAT when empty
+    pub nics: Vec<Nic>,
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        let defaults = DefaultSettings::default();
+        VmConfig {
+            name: String::new(),
+            os_type: defaults.os_type,
+            memory_mb: defaults.memory_mb,
+            cpu_count: defaults.cpu_count,
+            firmware: None,
+            chipset: None,
+            vram_mb: None,
+            graphics_controller: None,
+            nested_virtualization: None,
+            pae: None,
+            paravirt_provider: None,
+            boot_order: Vec::new(),
+            description: None,
+            groups: Vec::new(),
+            base_folder: None,
+            rtc_utc: None,
+            audio: None,
+            disks: Vec::new(),
+            controller_name: defaults.controller_name,
+            nics: Vec::new(),
+        }
+    }
+}
+
+impl VmConfig {
+    /// Reject values VBoxManage would fail on, before anything is created
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("VM name must not be empty".to_string());
+        }
+        if self.memory_mb <= 0 {
+            return Err("Memory must be positive".to_string());
+        }
+        if self.cpu_count <= 0 {
+            return Err("CPU count must be positive".to_string());
+        }
+        one_of("firmware", self.firmware.as_deref(), &["bios", "efi", "efi32", "efi64"])?;
+        one_of("chipset", self.chipset.as_deref(), &["piix3", "ich9"])?;
+        one_of("graphics controller", self.graphics_controller.as_deref(), &["none", "vboxvga", "vmsvga", "vboxsvga"])?;
+        one_of(
+            "paravirtualization provider",
+            self.paravirt_provider.as_deref(),
+            &["none", "default", "legacy", "minimal", "hyperv", "kvm"],
+        )?;
+        if let Some(vram) = self.vram_mb
+            && !(0..=256).contains(&vram)
+        {
+            return Err("Video memory must be between 0 and 256 MB".to_string());
+        }
+        if self.boot_order.len() > 4 {
+            return Err("At most 4 boot devices can be given".to_string());
+        }
+        for device in &self.boot_order {
+            one_of("boot device", Some(device), &["none", "floppy", "dvd", "disk", "net"])?;
+        }
+        if let Some(group) = self.groups.iter().find(|g| !g.starts_with('/')) {
+            return Err(format!("Group '{}' must start with '/'", group));
+        }
+        if !self.disks.is_empty() && self.controller_name.trim().is_empty() {
+            return Err("Controller name must not be empty".to_string());
+        }
+        for disk in &self.disks {
+            if disk.size_mb <= 0 {
+                return Err("Disk size must be positive".to_string());
+            }
+        }
+        let mut seen = Vec::new();
+        for nic in &self.nics {
+            if !(1..=8).contains(&nic.index) {
+                return Err(format!("Network adapter index {} must be between 1 and 8", nic.index));
+            }
+            if seen.contains(&nic.index) {
+                return Err(format!("Network adapter {} is given more than once", nic.index));
+            }
+            seen.push(nic.index);
+        }
+        Ok(())
+    }
+}
+
+fn one_of(what: &str, value: Option<&str>, allowed: &[&str]) -> Result<(), String> {
+    match value {
+        Some(value) if !allowed.contains(&value.to_ascii_lowercase().as_str()) => {
+            Err(format!("Unknown {} '{}', expected one of: {}", what, value, allowed.join(", ")))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// A hard disk created along with a new VM
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DiskSpec {
+    pub size_mb: i64,
+    /// File to create; defaults to `<name>-disk<N>.vdi` in the VM's folder
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
+/// Kind of drive a medium is attached as (`storageattach --type`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceType {
+    #[default]
+    Hdd,
+    #[serde(rename = "dvddrive")]
+    DvdDrive,
+    Fdd,
+}
+
+impl DeviceType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceType::Hdd => "hdd",
+            DeviceType::DvdDrive => "dvddrive",
+            DeviceType::Fdd => "fdd",
+        }
+    }
+
+    /// The device a medium file belongs in, judging by its extension;
+    /// `None` when the extension does not tell (`.img` is used for both
+    /// floppy and raw disk images)
+    pub fn for_path(path: &str) -> Option<Self> {
+        let extension = std::path::Path::new(path).extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "vdi" | "vmdk" | "vhd" | "vhdx" | "hdd" | "qcow" | "qcow2" | "qed" | "parallels" => Some(DeviceType::Hdd),
+            "iso" | "cdr" | "dmg" => Some(DeviceType::DvdDrive),
+            "flp" | "vfd" | "ima" => Some(DeviceType::Fdd),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for DeviceType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "hdd" | "disk" => Ok(DeviceType::Hdd),
+            "dvddrive" | "dvd" => Ok(DeviceType::DvdDrive),
+            "fdd" | "floppy" => Ok(DeviceType::Fdd),
+            other => Err(format!("Unknown device type '{}', expected hdd, dvddrive or fdd", other)),
+        }
+    }
+}
+
+impl fmt::Display for DeviceType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// How `VirtualBoxClient::attach_medium` attaches a medium; `None` leaves
+/// VirtualBox's default
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AttachOptions {
+    pub device_type: DeviceType,
+    /// Device slot on the port; only IDE ports have two
+    pub device: u32,
+    /// Report the disk to the guest as an SSD
+    pub nonrotational: Option<bool>,
+    /// Pass the guest's TRIM requests on to shrink the image
+    pub discard: Option<bool>,
+    pub hotpluggable: Option<bool>,
+    /// Medium type: normal, writethrough, immutable, shareable, readonly
+    /// or multiattach
+    pub mtype: Option<String>,
+}
+
+impl AttachOptions {
+    /// Options for `path`, with the device type taken from its extension
+    /// unless one is given. Fails if the two disagree, e.g. an ISO as `hdd`.
+    pub fn for_path(path: &str, device_type: Option<DeviceType>) -> Result<Self, String> {
+        let inferred = DeviceType::for_path(path);
+        if let (Some(given), Some(inferred)) = (device_type, inferred)
+            && given != inferred
+        {
+            return Err(format!("'{}' is a {} image and cannot be attached as {}", path, inferred, given));
+        }
+        Ok(AttachOptions {
+            device_type: device_type.or(inferred).unwrap_or_default(),
+            ..AttachOptions::default()
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        one_of("medium type", self.mtype.as_deref(), &["normal", "writethrough", "immutable", "shareable", "readonly", "multiattach"])?;
+        if self.device_type != DeviceType::Hdd && (self.nonrotational.is_some() || self.discard.is_some() || self.mtype.is_some()) {
+            return Err(format!("nonrotational, discard and mtype only apply to hard disks, not {}", self.device_type));
+        }
+        Ok(())
+    }
+}
+
+/// A virtual disk as listed by `list hdds`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Medium {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub medium_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size_mb: Option<i64>,
+}
+
+/// A VM snapshot
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub name: String,
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Name of the parent snapshot; `None` for the root
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+}
+
+/// Attachment type of a network adapter, as accepted by `modifyvm --nic<N>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NicType {
+    None,
+    Null,
+    Nat,
+    NatNetwork,
+    Bridged,
+    Intnet,
+    HostOnly,
+    HostOnlyNet,
+    Generic,
+}
+
+impl NicType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NicType::None => "none",
+            NicType::Null => "null",
+            NicType::Nat => "nat",
+            NicType::NatNetwork => "natnetwork",
+            NicType::Bridged => "bridged",
+            NicType::Intnet => "intnet",
+            NicType::HostOnly => "hostonly",
+            NicType::HostOnlyNet => "hostonlynet",
+            NicType::Generic => "generic",
+        }
+    }
+}
+
+impl FromStr for NicType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(NicType::None),
+            "null" => Ok(NicType::Null),
+            "nat" => Ok(NicType::Nat),
+            "natnetwork" => Ok(NicType::NatNetwork),
+            "bridged" => Ok(NicType::Bridged),
+            "intnet" => Ok(NicType::Intnet),
+            "hostonly" => Ok(NicType::HostOnly),
+            "hostonlynet" => Ok(NicType::HostOnlyNet),
+            "generic" => Ok(NicType::Generic),
+            other => Err(format!("Unknown network type '{}'", other)),
+        }
+    }
+}
+
+impl fmt::Display for NicType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for NicType {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for NicType {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let nic = String::deserialize(deserializer)?;
+        nic.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A network adapter slot (1-based, as in `--nic1`)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Nic {
+    pub index: u32,
+    #[serde(rename = "type")]
+    pub nic_type: NicType,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vm(name: &str, state: VmState, groups: &[&str]) -> Vm {
+        Vm {
+            name: Some(name.to_string()),
+            state: Some(state),
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+            ..Vm::default()
+        }
+    }
+
+    #[test]
+    fn human_readable_states() {
+        assert_eq!(VmState::from_description("powered off (since 2024-01-01T10:00:00.000000000)"), VmState::PowerOff);
+        assert_eq!(VmState::from_description("running (since 2024-01-01T10:00:00.000000000)"), VmState::Running);
+        assert_eq!(VmState::from_description("guru meditation"), VmState::Stuck);
+    }
+
+    #[test]
+    fn filter_by_state_group_and_name() {
+        let web = vm("web-1", VmState::Running, &["/omni/web"]);
+        let db = vm("db-1", VmState::PowerOff, &["/omni"]);
+        let other = vm("web-2", VmState::Running, &["/omnibus"]);
+
+        let filter = VmFilter {
+            states: vec![VmState::Running],
+            group: Some("/omni".to_string()),
+            ..VmFilter::default()
+        };
+        assert!(filter.matches(&web));
+        assert!(!filter.matches(&db));
+        assert!(!filter.matches(&other));
+
+        let filter = VmFilter {
+            name_pattern: Some("web-?".to_string()),
+            ..VmFilter::default()
+        };
+        assert!(filter.matches(&web) && filter.matches(&other) && !filter.matches(&db));
+        assert!(VmFilter { name_pattern: Some("*-1".to_string()), ..VmFilter::default() }.matches(&db));
+    }
+
+    #[test]
+    fn glob_patterns() {
+        let matches = |pattern: &str, text: &str| glob_match(pattern.as_bytes(), text.as_bytes());
+        assert!(matches("", ""));
+        assert!(matches("*", ""));
+        assert!(matches("web-*", "web-"));
+        assert!(matches("*web*", "my-web-1"));
+        assert!(matches("w?b-*1", "web-11"));
+        assert!(matches("a*b*c", "aXbYbZc"));
+        assert!(!matches("a*b*c", "aXbYbZ"));
+        assert!(!matches("web-?", "web-"));
+        assert!(!matches("", "web"));
+        // Would take exponential time with naive backtracking
+        let text = "a".repeat(64);
+        assert!(!matches(&format!("{}b", "a*".repeat(32)), &text));
+    }
+
+    #[test]
+    fn unknown_states_are_rejected_when_parsed() {
+        assert_eq!("poweroff".parse::<VmState>(), Ok(VmState::PowerOff));
+        assert_eq!("gurumeditation".parse::<VmState>(), Ok(VmState::Stuck));
+        assert!("Running".parse::<VmState>().is_err());
+        assert_eq!(VmState::from("Running"), VmState::Other("Running".to_string()));
+    }
+}
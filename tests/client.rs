@@ -0,0 +1,133 @@
+// File: cpi_virtualbox/tests/client.rs
+//! The typed `VirtualBoxClient` API driven against the `vboxmanage-sim` binary.
+use cpi_virtualbox::{DiskSpec, ErrorKind, ExecOptions, Nic, NicType, ProcessExecutor, RetryPolicy, VirtualBoxClient, VmConfig, VmState};
+use std::sync::Arc;
+use tempfile::TempDir;
+
+fn simulator() -> (TempDir, VirtualBoxClient) {
+    let home = tempfile::tempdir().unwrap();
+    let executor = ProcessExecutor::with_program(env!("CARGO_BIN_EXE_vboxmanage-sim"))
+        .env("VBOX_USER_HOME", home.path().to_str().unwrap());
+    // The simulator never releases a lock on its own, so don't wait on it
+    let options = ExecOptions {
+        retry: RetryPolicy::none(),
+        ..ExecOptions::default()
+    };
+    (home, VirtualBoxClient::with_executor(Arc::new(executor)).with_options(options))
+}
+
+fn web_1() -> VmConfig {
+    VmConfig {
+        name: "web-1".to_string(),
+        os_type: "Ubuntu_64".to_string(),
+        memory_mb: 1024,
+        cpu_count: 2,
+        ..VmConfig::default()
+    }
+}
+
+#[test]
+fn vm_lifecycle_is_typed() {
+    let (_home, client) = simulator();
+
+    let created = client.create_vm(&web_1()).unwrap();
+    assert_eq!(client.list_vms().unwrap(), vec![created.clone()]);
+
+    let vm = client.vm_info("web-1").unwrap();
+    assert_eq!(vm.id, Some(created.id));
+    assert_eq!(vm.state, Some(VmState::PowerOff));
+    assert_eq!(vm.memory_mb, Some(1024));
+
+    client
+        .set_nic("web-1", &Nic { index: 2, nic_type: NicType::HostOnly })
+        .unwrap();
+    client.start_vm("web-1").unwrap();
+    assert_eq!(client.vm_info("web-1").unwrap().state, Some(VmState::Running));
+
+    // The simulator keeps the VM locked while it runs
+    let err = client.delete_vm("web-1").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Locked.as_str());
+    assert!(client.vm_exists("web-1").unwrap());
+    assert!(!client.vm_exists("ghost").unwrap());
+}
+
+#[test]
+fn media_and_snapshots_are_typed() {
+    let (home, client) = simulator();
+    let disk = home.path().join("data.vdi");
+    let disk = disk.to_str().unwrap();
+
+    client.create_vm(&web_1()).unwrap();
+    let medium = client.create_medium(disk, 512).unwrap();
+    assert_eq!(medium.path.as_deref(), Some(disk));
+
+    let listed = client.list_media().unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].id, medium.id);
+    assert_eq!(listed[0].size_mb, Some(512));
+
+    assert!(client.list_snapshots("web-1").unwrap().is_empty());
+    let snapshot = client.take_snapshot("web-1", "base").unwrap();
+    assert!(!snapshot.id.is_empty());
+    assert_eq!(client.list_snapshots("web-1").unwrap(), vec![snapshot]);
+}
+
+#[test]
+fn vm_info_reports_storage_network_and_snapshot_sections() {
+    let (home, client) = simulator();
+    let disk = home.path().join("data \"1\".vdi");
+    let disk = disk.to_str().unwrap();
+
+    client.create_vm(&web_1()).unwrap();
+    client.create_medium(disk, 64).unwrap();
+    client.add_sata_controller("web-1", "SATA Controller").unwrap();
+    client.run(&["storageattach", "web-1", "--storagectl", "SATA Controller", "--port", "1", "--device", "0", "--type", "hdd", "--medium", disk]).unwrap();
+    client.take_snapshot("web-1", "base").unwrap();
+    client.take_snapshot("web-1", "patched").unwrap();
+
+    let vm = client.vm_info("web-1").unwrap();
+    assert_eq!(vm.storage_controllers[0].name, "SATA Controller");
+    assert_eq!(vm.storage_attachments.len(), 1);
+    assert_eq!(vm.storage_attachments[0].port, 1);
+    assert_eq!(vm.storage_attachments[0].medium.as_deref(), Some(disk));
+
+    assert_eq!(vm.network_adapters.len(), 1);
+    assert_eq!(vm.network_adapters[0].nic_type, NicType::Nat);
+    assert!(vm.network_adapters[0].mac_address.is_some());
+
+    assert_eq!(vm.snapshots[1].parent.as_deref(), Some("base"));
+    assert_eq!(vm.current_snapshot.as_deref(), Some("patched"));
+}
+
+#[test]
+fn create_vm_applies_hardware_disks_and_nics() {
+    let (home, client) = simulator();
+    let base = home.path().to_str().unwrap();
+
+    let config = VmConfig {
+        firmware: Some("efi".to_string()),
+        vram_mb: Some(32),
+        graphics_controller: Some("vmsvga".to_string()),
+        description: Some("Web frontend".to_string()),
+        groups: vec!["/web".to_string()],
+        base_folder: Some(base.to_string()),
+        boot_order: vec!["disk".to_string()],
+        disks: vec![DiskSpec { size_mb: 2048, path: None }],
+        nics: vec![Nic { index: 1, nic_type: NicType::HostOnly }, Nic { index: 2, nic_type: NicType::Nat }],
+        ..web_1()
+    };
+    client.create_vm(&config).unwrap();
+
+    let vm = client.vm_info("web-1").unwrap();
+    assert_eq!(vm.firmware.as_deref(), Some("EFI"));
+    assert_eq!(vm.vram_mb, Some(32));
+    assert_eq!(vm.graphics_controller.as_deref(), Some("vmsvga"));
+    assert_eq!(vm.description.as_deref(), Some("Web frontend"));
+    assert_eq!(vm.groups, vec!["/web"]);
+    assert_eq!(vm.properties["boot1"], "disk");
+    let disk = format!("{}/web-1/web-1-disk1.vdi", base);
+    assert_eq!(vm.storage_attachments.len(), 1);
+    assert_eq!(vm.storage_attachments[0].medium.as_deref(), Some(disk.as_str()));
+    let nics: Vec<_> = vm.network_adapters.iter().map(|n| n.nic_type.clone()).collect();
+    assert_eq!(nics, vec![NicType::HostOnly, NicType::Nat]);
+}
@@ -0,0 +1,674 @@
+// File: cpi_virtualbox/tests/simulator.rs
+//! End-to-end workflows run through `execute_action` against the
+//! `vboxmanage-sim` binary, each test with its own registry directory.
+use cpi_virtualbox::{ProcessExecutor, RetryPolicy, VirtualBoxExtension};
+use lib_cpi::CpiExtension;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Keep lock retries short; the simulator never releases a lock on its own
+fn quick_retries() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: 2,
+        initial_backoff: Duration::from_millis(10),
+        ..RetryPolicy::default()
+    }
+}
+
+fn simulator() -> (TempDir, VirtualBoxExtension) {
+    let home = tempfile::tempdir().unwrap();
+    let executor = ProcessExecutor::with_program(env!("CARGO_BIN_EXE_vboxmanage-sim"))
+        .env("VBOX_USER_HOME", home.path().to_str().unwrap());
+    let extension = VirtualBoxExtension::with_executor(Arc::new(executor)).with_retry_policy(quick_retries());
+    (home, extension)
+}
+
+/// Run an action that is expected to fail and decode its JSON error payload
+fn run_err(extension: &VirtualBoxExtension, action: &str, params: Value) -> Value {
+    let err = run(extension, action, params).unwrap_err();
+    serde_json::from_str(&err).unwrap()
+}
+
+fn run(extension: &VirtualBoxExtension, action: &str, params: Value) -> Result<Value, String> {
+    let params: HashMap<String, Value> = params
+        .as_object()
+        .unwrap()
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    extension.execute_action(action, &params)
+}
+
+#[test]
+fn reports_simulator_version() {
+    let (_home, extension) = simulator();
+    let result = run(&extension, "test_install", json!({})).unwrap();
+    assert!(result["version"].as_str().unwrap().contains("SIM"));
+    assert_eq!(result["binary"], env!("CARGO_BIN_EXE_vboxmanage-sim"));
+    assert_eq!(result["binary_source"], "explicit");
+}
+
+#[test]
+fn create_inspect_and_delete_worker() {
+    let (_home, extension) = simulator();
+
+    let created = run(&extension, "create_worker", json!({ "worker_name": "web-1", "memory_mb": 1024 })).unwrap();
+    let uuid = created["uuid"].as_str().unwrap().to_string();
+    assert!(!uuid.is_empty());
+
+    let listed = run(&extension, "list_workers", json!({})).unwrap();
+    assert_eq!(listed["workers"][0]["id"], uuid.as_str());
+    assert_eq!(listed["workers"][0]["state"], "poweroff");
+    assert_eq!(listed["workers"][0]["memory_mb"], 1024);
+    assert_eq!(listed["workers"][0]["os_type"], "Ubuntu_64");
+    let running = run(&extension, "list_workers", json!({ "state": "running" })).unwrap();
+    assert_eq!(running["workers"], json!([]));
+
+    let info = run(&extension, "get_worker", json!({ "worker_name": "web-1" })).unwrap();
+    assert_eq!(info["vm"]["id"], uuid.as_str());
+    assert_eq!(info["vm"]["memory_mb"], 1024);
+    assert_eq!(info["vm"]["cpu_count"], 2);
+    assert_eq!(info["vm"]["state"], "poweroff");
+    assert_eq!(info["vm"]["os_type"], "Ubuntu_64");
+    assert_eq!(info["vm"]["os_description"], "Ubuntu (64-bit)");
+
+    run(&extension, "delete_worker", json!({ "worker_name": "web-1" })).unwrap();
+    let exists = run(&extension, "has_worker", json!({ "worker_name": "web-1" })).unwrap();
+    assert_eq!(exists["exists"], false);
+}
+
+#[test]
+fn duplicate_worker_is_rejected() {
+    let (_home, extension) = simulator();
+    run(&extension, "create_worker", json!({ "worker_name": "web-1" })).unwrap();
+
+    let err = run_err(&extension, "create_worker", json!({ "worker_name": "web-1" }));
+    assert_eq!(err["kind"], "file_error");
+    assert!(err["message"].as_str().unwrap().contains("already exists"), "{}", err);
+}
+
+#[test]
+fn failed_create_leaves_nothing_registered() {
+    let (home, extension) = simulator();
+    let taken = home.path().join("taken.vdi");
+    let taken = taken.to_str().unwrap();
+    run(&extension, "create_volume", json!({ "disk_path": taken, "size_mb": 64 })).unwrap();
+
+    // The first disk is created and attached, the second clashes
+    let err = run_err(
+        &extension,
+        "create_worker",
+        json!({ "worker_name": "web-1", "disks": [{ "size_mb": 64 }, { "size_mb": 64, "path": taken }] }),
+    );
+    assert_eq!(err["step"], "configure storage");
+    assert_eq!(err["rolled_back"], true);
+
+    let listed = run(&extension, "list_workers", json!({})).unwrap();
+    assert_eq!(listed["workers"], json!([]));
+    let volumes = run(&extension, "get_volumes", json!({})).unwrap();
+    assert_eq!(volumes["volumes"].as_array().unwrap().len(), 1);
+    assert_eq!(volumes["volumes"][0]["path"], taken);
+    run(&extension, "create_worker", json!({ "worker_name": "web-1" })).unwrap();
+}
+
+#[test]
+fn volume_attach_and_snapshot_workflow() {
+    let (home, extension) = simulator();
+    let data = home.path().join("db-data.vdi").to_str().unwrap().to_string();
+    run(&extension, "create_worker", json!({ "worker_name": "db" })).unwrap();
+
+    let volume = run(&extension, "create_volume", json!({ "disk_path": data, "size_mb": 4096 })).unwrap();
+    assert!(!volume["uuid"].as_str().unwrap().is_empty());
+    let volumes = run(&extension, "get_volumes", json!({})).unwrap();
+    assert_eq!(volumes["volumes"][0]["size_mb"], 4096);
+
+    // A worker created without disks has no controller until asked for one
+    let err = run_err(&extension, "attach_volume", json!({ "worker_name": "db", "port": 1, "disk_path": data }));
+    assert_eq!(err["kind"], "not_found");
+    let attached = run(
+        &extension,
+        "attach_volume",
+        json!({ "worker_name": "db", "port": 1, "disk_path": data, "create_controller": true }),
+    )
+    .unwrap();
+    assert_eq!(attached["device_type"], "hdd");
+    let err = run_err(&extension, "delete_volume", json!({ "disk_path": data }));
+    assert_eq!(err["kind"], "in_use");
+    assert_eq!(err["code"], "VBOX_E_OBJECT_IN_USE");
+    run(&extension, "detach_volume", json!({ "worker_name": "db", "port": 1 })).unwrap();
+    run(&extension, "delete_volume", json!({ "disk_path": data })).unwrap();
+
+    run(&extension, "create_snapshot", json!({ "worker_name": "db", "snapshot_name": "clean" })).unwrap();
+    let found = run(&extension, "has_snapshot", json!({ "worker_name": "db", "snapshot_name": "clean" })).unwrap();
+    assert_eq!(found["exists"], true);
+    run(&extension, "delete_snapshot", json!({ "worker_name": "db", "snapshot_name": "clean" })).unwrap();
+
+    run(&extension, "delete_worker", json!({ "worker_name": "db" })).unwrap();
+}
+
+#[test]
+fn storage_controllers_are_added_listed_and_removed() {
+    let (_home, extension) = simulator();
+    run(&extension, "create_worker", json!({ "worker_name": "web-1" })).unwrap();
+
+    run(&extension, "add_storage_controller", json!({ "worker_name": "web-1", "controller_name": "IDE", "bus": "ide", "bootable": true })).unwrap();
+    run(
+        &extension,
+        "add_storage_controller",
+        json!({ "worker_name": "web-1", "controller_name": "NVMe", "bus": "nvme", "port_count": 4, "host_io_cache": false }),
+    )
+    .unwrap();
+    let err = run_err(&extension, "add_storage_controller", json!({ "worker_name": "web-1", "controller_name": "IDE", "bus": "ide" }));
+    assert_eq!(err["kind"], "in_use");
+
+    let listed = run(&extension, "list_storage_controllers", json!({ "worker_name": "web-1" })).unwrap();
+    let controllers = listed["controllers"].as_array().unwrap();
+    assert_eq!(controllers.len(), 2);
+    assert_eq!(controllers[0]["name"], "IDE");
+    assert_eq!(controllers[0]["bus"], "ide");
+    assert_eq!(controllers[0]["type"], "PIIX4");
+    assert_eq!(controllers[1]["bus"], "nvme");
+    assert_eq!(controllers[1]["port_count"], 4);
+
+    run(&extension, "remove_storage_controller", json!({ "worker_name": "web-1", "controller_name": "IDE" })).unwrap();
+    let err = run_err(&extension, "remove_storage_controller", json!({ "worker_name": "web-1", "controller_name": "IDE" }));
+    assert_eq!(err["kind"], "not_found");
+    let listed = run(&extension, "list_storage_controllers", json!({ "worker_name": "web-1" })).unwrap();
+    assert_eq!(listed["controllers"].as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn running_worker_cannot_be_modified_or_deleted() {
+    let (_home, extension) = simulator();
+    run(&extension, "create_worker", json!({ "worker_name": "web-1" })).unwrap();
+    run(&extension, "start_worker", json!({ "worker_name": "web-1" })).unwrap();
+    run(&extension, "reboot_worker", json!({ "worker_name": "web-1" })).unwrap();
+
+    let info = run(&extension, "get_worker", json!({ "worker_name": "web-1" })).unwrap();
+    assert_eq!(info["vm"]["state"], "running");
+
+    let err = run_err(&extension, "configure_networks", json!({ "worker_name": "web-1", "network_index": 2 }));
+    assert_eq!(err["kind"], "locked");
+    assert_eq!(err["code"], "VBOX_E_INVALID_OBJECT_STATE");
+    let err = run_err(&extension, "delete_worker", json!({ "worker_name": "web-1" }));
+    assert_eq!(err["kind"], "locked");
+}
+
+#[test]
+fn missing_worker_reports_vbox_error() {
+    let (_home, extension) = simulator();
+    let err = run_err(&extension, "get_worker", json!({ "worker_name": "ghost" }));
+    assert_eq!(err["kind"], "not_found");
+    assert_eq!(err["code"], "VBOX_E_OBJECT_NOT_FOUND");
+    assert_eq!(err["interface"], "IVirtualBox");
+}
+
+#[test]
+fn hung_vboxmanage_is_killed_after_timeout() {
+    let home = tempfile::tempdir().unwrap();
+    let executor = ProcessExecutor::with_program(env!("CARGO_BIN_EXE_vboxmanage-sim"))
+        .env("VBOX_USER_HOME", home.path().to_str().unwrap())
+        .env("VBOXSIM_HANG_ON", "startvm");
+    let extension = VirtualBoxExtension::with_executor(Arc::new(executor));
+    run(&extension, "create_worker", json!({ "worker_name": "web-1" })).unwrap();
+
+    let started = std::time::Instant::now();
+    let err = run_err(&extension, "start_worker", json!({ "worker_name": "web-1", "timeout_secs": 1 }));
+    assert_eq!(err["kind"], "timeout");
+    assert_eq!(err["timeout_secs"], 1.0);
+    assert!(started.elapsed() < std::time::Duration::from_secs(30));
+
+    let info = run(&extension, "get_worker", json!({ "worker_name": "web-1" })).unwrap();
+    assert_eq!(info["vm"]["state"], "poweroff");
+}
+
+#[test]
+fn missing_binary_is_reported_distinctly() {
+    let extension = VirtualBoxExtension::with_executor(Arc::new(ProcessExecutor::with_program("/nonexistent/VBoxManage")));
+    let err = run_err(&extension, "test_install", json!({}));
+    assert_eq!(err["kind"], "binary_missing");
+}
+
+#[test]
+fn stop_pause_and_save_state_workflow() {
+    let (_home, extension) = simulator();
+    let extension = extension.with_poll_interval(Duration::from_millis(50));
+    let web = json!({ "worker_name": "web-1" });
+    let state = |extension: &VirtualBoxExtension| run(extension, "get_worker", json!({ "worker_name": "web-1" })).unwrap()["vm"]["state"].clone();
+
+    run(&extension, "create_worker", web.clone()).unwrap();
+    let started = run(&extension, "start_worker", json!({ "worker_name": "web-1", "wait": true })).unwrap();
+    assert_eq!(started["state"], "running");
+    let stopped = run(&extension, "stop_worker", web.clone()).unwrap();
+    assert_eq!(stopped["method"], "acpi");
+    assert_eq!(state(&extension), "poweroff");
+
+    run(&extension, "start_worker", web.clone()).unwrap();
+    run(&extension, "pause_worker", web.clone()).unwrap();
+    assert_eq!(state(&extension), "paused");
+    run(&extension, "resume_worker", web.clone()).unwrap();
+    run(&extension, "save_worker_state", web.clone()).unwrap();
+    assert_eq!(state(&extension), "saved");
+    run(&extension, "discard_saved_state", web.clone()).unwrap();
+    assert_eq!(state(&extension), "poweroff");
+
+    // A guest that ignores ACPI is powered off once the grace period runs out
+    run(&extension, "set_worker_metadata", json!({ "worker_name": "web-1", "key": "VBOXSIM/IgnoreAcpi", "value": "1" })).unwrap();
+    run(&extension, "start_worker", web.clone()).unwrap();
+    let forced = run(&extension, "stop_worker", json!({ "worker_name": "web-1", "grace_secs": 1 })).unwrap();
+    assert_eq!(forced["method"], "power_off");
+    run(&extension, "delete_worker", web).unwrap();
+}
+
+#[test]
+fn force_delete_running_worker_keeps_named_disk() {
+    let (home, extension) = simulator();
+    let extension = extension.with_poll_interval(Duration::from_millis(10));
+    let disk = |name: &str| home.path().join(name).to_str().unwrap().to_string();
+
+    run(&extension, "create_worker", json!({ "worker_name": "web-1" })).unwrap();
+    for (port, name) in [(1, "os.vdi"), (2, "data.vdi")] {
+        run(&extension, "create_volume", json!({ "disk_path": disk(name), "size_mb": 64 })).unwrap();
+        run(&extension, "attach_volume", json!({ "worker_name": "web-1", "port": port, "disk_path": disk(name), "create_controller": true })).unwrap();
+    }
+    run(&extension, "start_worker", json!({ "worker_name": "web-1" })).unwrap();
+
+    let err = run_err(&extension, "delete_worker", json!({ "worker_name": "web-1" }));
+    assert_eq!(err["kind"], "locked");
+
+    let report = run(&extension, "delete_worker", json!({ "worker_name": "web-1", "force": true, "keep_disks": "data.vdi" })).unwrap();
+    assert_eq!(report["powered_off"], true);
+    assert_eq!(report["kept_media"], json!([disk("data.vdi")]));
+    let deleted = report["deleted_files"].as_array().unwrap();
+    assert_eq!(deleted.len(), 2, "settings file and os disk: {:?}", deleted);
+    assert_eq!(deleted[1], disk("os.vdi").as_str());
+
+    let exists = run(&extension, "has_worker", json!({ "worker_name": "web-1" })).unwrap();
+    assert_eq!(exists["exists"], false);
+    let volumes = run(&extension, "get_volumes", json!({})).unwrap();
+    let paths: Vec<&str> = volumes["volumes"].as_array().unwrap().iter().map(|v| v["path"].as_str().unwrap()).collect();
+    assert_eq!(paths, vec![disk("data.vdi")]);
+}
+
+#[test]
+fn delete_reports_snapshot_images_saved_state_and_logs() {
+    let (home, extension) = simulator();
+    let base = home.path().to_str().unwrap();
+    run(
+        &extension,
+        "create_worker",
+        json!({ "worker_name": "template", "base_folder": base, "disks": [{ "size_mb": 64 }] }),
+    )
+    .unwrap();
+    let template = run(&extension, "get_worker", json!({ "worker_name": "template" })).unwrap();
+    let base_image = template["vm"]["storage_attachments"][0]["medium"].clone();
+    run(&extension, "clone_worker", json!({ "worker_name": "template", "new_name": "web-1", "linked": true, "base_folder": base })).unwrap();
+
+    // Files VirtualBox itself writes next to the settings file
+    let folder = home.path().join("web-1");
+    std::fs::create_dir_all(folder.join("Logs")).unwrap();
+    for file in ["Logs/VBox.log", "Logs/VBox.log.1", "Logs/notes.txt", "web-1.nvram"] {
+        std::fs::write(folder.join(file), b"").unwrap();
+    }
+    run(&extension, "start_worker", json!({ "worker_name": "web-1" })).unwrap();
+    run(&extension, "save_worker_state", json!({ "worker_name": "web-1" })).unwrap();
+    let worker = run(&extension, "get_worker", json!({ "worker_name": "web-1" })).unwrap();
+    let uuid = worker["vm"]["id"].as_str().unwrap();
+    let diff_image = worker["vm"]["storage_attachments"][0]["medium"].clone();
+
+    let report = run(&extension, "delete_worker", json!({ "worker_name": "web-1", "force": true })).unwrap();
+    assert_eq!(report["powered_off"], false);
+    let path = |file: &str| json!(folder.join(file).to_str().unwrap());
+    assert_eq!(
+        report["deleted_files"],
+        json!([
+            path("web-1.vbox"),
+            path("web-1.nvram"),
+            path(&format!("Snapshots/{{{}}}.sav", uuid)),
+            diff_image,
+            path("Logs/VBox.log"),
+            path("Logs/VBox.log.1")
+        ])
+    );
+    assert!(!report["deleted_files"].as_array().unwrap().contains(&base_image));
+    // Files VirtualBox doesn't own are left alone, and not reported
+    assert!(folder.join("Logs/notes.txt").exists());
+    assert!(!folder.join("web-1.vbox").exists());
+}
+
+#[test]
+fn worker_spec_apply_converges() {
+    let (home, extension) = simulator();
+    let base = home.path().to_str().unwrap();
+    let spec_file = home.path().join("web-1.toml");
+    let spec = format!(
+        r#"
+name = "web-1"
+base_folder = "{base}"
+memory_mb = 1024
+cpu_count = 2
+firmware = "efi"
+rtc_utc = true
+audio = false
+
+[[disks]]
+path = "{base}/web-1-os.vdi"
+size_mb = 2048
+
+[[nics]]
+index = 1
+type = "nat"
+port_forwards = [{{ name = "ssh", protocol = "tcp", host_port = 2222, guest_port = 22 }}]
+
+[[nics]]
+index = 2
+type = "hostonly"
+
+[metadata]
+role = "web"
+"#
+    );
+    std::fs::write(&spec_file, &spec).unwrap();
+    let spec_file = spec_file.to_str().unwrap();
+
+    let diff = run(&extension, "diff_worker_spec", json!({ "spec_file": spec_file })).unwrap();
+    assert_eq!(diff["exists"], false);
+    assert_eq!(diff["changes"][0]["target"], "vm");
+
+    let applied = run(&extension, "apply_worker_spec", json!({ "spec_file": spec_file })).unwrap();
+    assert_eq!(applied["created"], true);
+    assert_eq!(applied["changes"], diff["changes"]);
+
+    let diff = run(&extension, "diff_worker_spec", json!({ "spec": spec })).unwrap();
+    assert_eq!(diff["in_sync"], true, "{}", diff);
+
+    let worker = run(&extension, "get_worker", json!({ "worker_name": "web-1" })).unwrap();
+    assert_eq!(worker["vm"]["firmware"], "EFI");
+    assert_eq!(worker["vm"]["network_adapters"][0]["port_forwards"][0]["host_port"], 2222);
+    assert_eq!(worker["vm"]["storage_attachments"][0]["medium"], format!("{}/web-1-os.vdi", base));
+
+    // Only the changed setting and rule are touched
+    let spec = json!({
+        "name": "web-1",
+        "memory_mb": 2048,
+        "nics": [{ "index": 1, "port_forwards": [{ "name": "ssh", "protocol": "tcp", "host_port": 2200, "guest_port": 22 }] }],
+    });
+    let applied = run(&extension, "apply_worker_spec", json!({ "spec": spec })).unwrap();
+    let targets: Vec<_> = applied["changes"].as_array().unwrap().iter().map(|c| c["target"].clone()).collect();
+    assert_eq!(targets, vec![json!("memory_mb"), json!("port_forward nic1/ssh")]);
+    let diff = run(&extension, "diff_worker_spec", json!({ "spec": spec })).unwrap();
+    assert_eq!(diff["in_sync"], true, "{}", diff);
+}
+
+#[test]
+fn full_and_linked_clones() {
+    let (home, extension) = simulator();
+    let base = home.path().to_str().unwrap();
+    run(
+        &extension,
+        "create_worker",
+        json!({ "worker_name": "template", "base_folder": base, "disks": [{ "size_mb": 64 }] }),
+    )
+    .unwrap();
+
+    let full = run(
+        &extension,
+        "clone_worker",
+        json!({ "worker_name": "template", "new_name": "web-1", "base_folder": base, "groups": "/web" }),
+    )
+    .unwrap();
+    let uuid = "0f0e0d0c-0000-4000-8000-0000000000aa";
+    let linked = run(
+        &extension,
+        "clone_worker",
+        json!({ "worker_name": "template", "new_name": "web-2", "linked": true, "uuid": uuid, "base_folder": base }),
+    )
+    .unwrap();
+    assert_eq!(linked["uuid"], uuid);
+
+    let web_1 = run(&extension, "get_worker", json!({ "worker_name": "web-1" })).unwrap();
+    assert_eq!(web_1["vm"]["id"], full["uuid"]);
+    assert_eq!(web_1["vm"]["groups"], json!(["/web"]));
+    assert_eq!(web_1["vm"]["storage_attachments"][0]["medium"], format!("{}/web-1/template-disk1.vdi", base));
+
+    let web_2 = run(&extension, "get_worker", json!({ "worker_name": uuid })).unwrap();
+    let medium = web_2["vm"]["storage_attachments"][0]["medium"].as_str().unwrap();
+    assert!(medium.starts_with(&format!("{}/web-2/Snapshots/", base)), "{}", medium);
+
+    // The snapshot taken for the linked clone is reused next time
+    let snapshot = run(&extension, "has_snapshot", json!({ "worker_name": "template", "snapshot_name": "linked-clone-base" })).unwrap();
+    assert_eq!(snapshot["exists"], true);
+    run(&extension, "clone_worker", json!({ "worker_name": "template", "new_name": "web-3", "linked": true })).unwrap();
+    let snapshots = run(&extension, "get_worker", json!({ "worker_name": "template" })).unwrap();
+    assert_eq!(snapshots["vm"]["snapshots"].as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn worker_attachments_show_type_and_differencing_images() {
+    let (home, extension) = simulator();
+    let base = home.path().to_str().unwrap();
+    // An .img could be a disk or a floppy; only the registry knows it went into a DVD drive
+    let iso = home.path().join("tools.img").to_str().unwrap().to_string();
+    std::fs::write(&iso, b"").unwrap();
+    run(
+        &extension,
+        "create_worker",
+        json!({ "worker_name": "template", "base_folder": base, "disks": [{ "size_mb": 64 }] }),
+    )
+    .unwrap();
+    run(&extension, "clone_worker", json!({ "worker_name": "template", "new_name": "web-1", "linked": true, "base_folder": base })).unwrap();
+    run(
+        &extension,
+        "attach_volume",
+        json!({
+            "worker_name": "web-1",
+            "controller_name": "IDE",
+            "create_controller": true,
+            "controller_bus": "ide",
+            "port": 1,
+            "disk_path": iso,
+            "device_type": "dvddrive"
+        }),
+    )
+    .unwrap();
+
+    let listed = run(&extension, "list_worker_attachments", json!({ "worker_name": "web-1" })).unwrap();
+    let attachments = listed["attachments"].as_array().unwrap();
+    assert_eq!(attachments.len(), 2);
+    let disk = attachments.iter().find(|a| a["type"] == "hdd").unwrap();
+    assert_eq!(disk["port"], 0);
+    assert_eq!(disk["differencing"], true);
+    assert!(disk["medium_id"].is_string());
+    let dvd = attachments.iter().find(|a| a["type"] == "dvddrive").unwrap();
+    assert_eq!(dvd["controller"], "IDE");
+    assert_eq!(dvd["port"], 1);
+    assert_eq!(dvd["device"], 0);
+    assert_eq!(dvd["medium"], iso);
+    assert_eq!(dvd["differencing"], false);
+
+    // The template keeps its base image, which the clone's disk is based on
+    let listed = run(&extension, "list_worker_attachments", json!({ "worker_name": "template" })).unwrap();
+    assert_eq!(listed["attachments"][0]["differencing"], false);
+    assert!(listed["attachments"][0].get("parent_id").is_none());
+    assert_eq!(disk["parent_id"], listed["attachments"][0]["medium_id"]);
+    run_err(&extension, "list_worker_attachments", json!({ "worker_name": "ghost" }));
+}
+
+#[test]
+fn export_and_reimport_appliance() {
+    let (home, extension) = simulator();
+    let base = home.path().to_str().unwrap();
+    let ova = format!("{}/template.ova", base);
+    run(
+        &extension,
+        "create_worker",
+        json!({ "worker_name": "template", "memory_mb": 1024, "base_folder": base, "disks": [{ "size_mb": 64 }] }),
+    )
+    .unwrap();
+
+    let exported = run(
+        &extension,
+        "export_appliance",
+        json!({ "worker_name": "template", "output_path": ova, "product": "Web", "version": "1.2" }),
+    )
+    .unwrap();
+    assert_eq!(exported["path"], ova);
+    let err = run_err(&extension, "export_appliance", json!({ "worker_name": "template", "output_path": ova }));
+    assert_eq!(err["kind"], "file_error");
+
+    let dry_run = run(&extension, "import_appliance", json!({ "appliance_path": ova, "dry_run": true })).unwrap();
+    let entries = dry_run["entries"].as_array().unwrap();
+    let name = entries.iter().find(|e| e["kind"] == "name").unwrap();
+    assert_eq!(name["value"], "template");
+    let product = entries.iter().find(|e| e["kind"] == "product").unwrap();
+    assert_eq!(product["value"], "Web");
+    assert!(entries.iter().any(|e| e["kind"] == "hard_disk"));
+
+    let disk = format!("{}/imported.vmdk", base);
+    let imported = run(
+        &extension,
+        "import_appliance",
+        json!({ "appliance_path": ova, "new_name": "web-1", "memory_mb": 2048, "disk_paths": disk }),
+    )
+    .unwrap();
+    assert_eq!(imported["name"], "web-1");
+
+    let vm = run(&extension, "get_worker", json!({ "worker_name": "web-1" })).unwrap();
+    assert_eq!(vm["vm"]["id"], imported["uuid"]);
+    assert_eq!(vm["vm"]["memory_mb"], 2048);
+    assert_eq!(vm["vm"]["storage_attachments"][0]["medium"], disk);
+
+    let err = run_err(&extension, "import_appliance", json!({ "appliance_path": format!("{}/missing.ova", base) }));
+    assert_eq!(err["kind"], "file_error");
+}
+
+#[test]
+fn unattended_install_boots_and_waits_for_guest_additions() {
+    let (home, extension) = simulator();
+    let iso = home.path().join("ubuntu-22.04.3-live-server-amd64.iso");
+    std::fs::write(&iso, b"").unwrap();
+    let iso = iso.to_str().unwrap();
+    run(&extension, "create_worker", json!({ "worker_name": "web-1" })).unwrap();
+
+    let result = run(
+        &extension,
+        "install_os_unattended",
+        json!({
+            "worker_name": "web-1",
+            "iso_path": iso,
+            "password": "hunter2",
+            "hostname": "web-1.example.com",
+            "install_additions": true,
+            "start": true,
+            "wait": true,
+            "wait_timeout_secs": 5
+        }),
+    )
+    .unwrap();
+    assert_eq!(result["os"]["os_type"], "Ubuntu_64");
+    assert_eq!(result["os"]["version"], "22.04.3");
+    assert_eq!(result["state"], "running");
+    assert_eq!(result["history"].as_array().unwrap().last().unwrap()["run_level"], 2);
+
+    let vm = run(&extension, "get_worker", json!({ "worker_name": "web-1" })).unwrap();
+    let dvd = vm["vm"]["storage_attachments"].as_array().unwrap().iter().find(|a| a["medium"] == iso);
+    assert!(dvd.is_some(), "{}", vm);
+
+    let err = run_err(&extension, "install_os_unattended", json!({ "worker_name": "web-1", "iso_path": format!("{}/missing.iso", home.path().display()) }));
+    assert_eq!(err["kind"], "file_error");
+}
+
+#[test]
+fn cloud_init_seed_is_built_and_attached() {
+    let (home, extension) = simulator();
+    let base = home.path().to_str().unwrap();
+    run(&extension, "create_worker", json!({ "worker_name": "web-1", "base_folder": base })).unwrap();
+
+    let mut params = json!({
+        "worker_name": "web-1",
+        "controller_name": "IDE",
+        "ssh_authorized_keys": ["ssh-ed25519 AAAAC3Nza ops@example.com"],
+        "users": [{ "name": "deploy", "sudo": "ALL=(ALL) NOPASSWD:ALL" }],
+        "runcmd": ["touch /var/lib/seeded"]
+    });
+    // The controller is only added when asked for
+    let err = run_err(&extension, "create_cloud_init_seed", params.clone());
+    assert_eq!(err["kind"], "invalid_parameter");
+    assert_eq!(err["message"], "VM 'web-1' has no storage controller named 'IDE'");
+    let path = format!("{}/web-1/web-1-seed.iso", base);
+    assert!(!std::path::Path::new(&path).exists());
+
+    // The seed takes the free slave slot next to a drive on the same IDE port
+    let tools = home.path().join("tools.iso").to_str().unwrap().to_string();
+    std::fs::write(&tools, b"").unwrap();
+    run(
+        &extension,
+        "attach_volume",
+        json!({ "worker_name": "web-1", "controller_name": "IDE", "create_controller": true, "controller_bus": "ide", "port": 0, "disk_path": tools }),
+    )
+    .unwrap();
+    params["create_controller"] = json!(true);
+    params["controller_bus"] = json!("ide");
+    let seeded = run(&extension, "create_cloud_init_seed", params.clone()).unwrap();
+    assert_eq!(seeded["path"], path);
+    assert_eq!((seeded["port"].clone(), seeded["device"].clone()), (json!(0), json!(1)));
+
+    let image = std::fs::read(&path).unwrap();
+    let text = String::from_utf8_lossy(&image);
+    assert!(text.contains("CD001") && text.contains("cidata"));
+    assert!(text.contains("#cloud-config") && text.contains("ops@example.com"));
+    assert!(text.contains("\"local-hostname\":\"web-1\""));
+
+    let vm = run(&extension, "get_worker", json!({ "worker_name": "web-1" })).unwrap();
+    assert_eq!(vm["vm"]["storage_attachments"][1]["medium"], path);
+
+    // Rebuilding the seed reuses its drive
+    let reseeded = run(&extension, "create_cloud_init_seed", params.clone()).unwrap();
+    assert_eq!((reseeded["port"].clone(), reseeded["device"].clone()), (json!(0), json!(1)));
+    let vm = run(&extension, "get_worker", json!({ "worker_name": "web-1" })).unwrap();
+    assert_eq!(vm["vm"]["storage_attachments"].as_array().unwrap().len(), 2);
+
+    // A seed that could not be attached is not left behind
+    run(&extension, "start_worker", json!({ "worker_name": "web-1" })).unwrap();
+    let stray = home.path().join("stray-seed.iso");
+    params["iso_path"] = json!(stray.to_str().unwrap());
+    params["controller_name"] = json!("SATA");
+    let err = run_err(&extension, "create_cloud_init_seed", params);
+    assert_eq!(err["kind"], "locked");
+    assert!(!stray.exists());
+
+    let err = run_err(&extension, "create_cloud_init_seed", json!({ "worker_name": "web-1", "users": [{ "login": "x" }] }));
+    assert_eq!(err["kind"], "invalid_parameter");
+}
+
+#[test]
+fn start_order_boots_groups_in_turn() {
+    let (_home, extension) = simulator();
+    for name in ["db-1", "web-1", "web-2", "web-3"] {
+        run(&extension, "create_worker", json!({ "worker_name": name })).unwrap();
+    }
+
+    let result = run(
+        &extension,
+        "start_worker",
+        json!({
+            "start_order": [{ "workers": ["db-1"] }, { "workers": ["web-1", "web-2", "web-3"], "delay_secs": 1 }],
+            "parallelism": 2,
+            "wait": true,
+            "wait_timeout_secs": 5
+        }),
+    )
+    .unwrap();
+    assert_eq!(result["success"], true, "{}", result);
+    for worker in result["workers"].as_array().unwrap() {
+        assert_eq!(worker["state"], "running");
+    }
+    let running = run(&extension, "list_workers", json!({ "state": "running" })).unwrap();
+    assert_eq!(running["workers"].as_array().unwrap().len(), 4);
+
+    let err = run_err(&extension, "start_worker", json!({ "worker_name": "db-1", "start_type": "vnc" }));
+    assert_eq!(err["kind"], "invalid_parameter");
+}